@@ -0,0 +1,947 @@
+use scheduler::GeneratorTask;
+use scheduler::Task;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/// The frame sequencer runs at 512 Hz.
+pub const FRAME_SEQUENCER_CYCLES: u64 = 32768;
+/// Samples are generated at 32768 Hz, which is the GBA's default output rate.
+pub const CYCLES_PER_SAMPLE: u64 = 512;
+pub const SAMPLE_RATE: u32 = (1 << 24) / CYCLES_PER_SAMPLE as u32;
+
+/// Maximum number of samples kept in the output buffer. Older samples are dropped if the frontend
+/// doesn't consume them fast enough.
+const MAX_BUFFERED_SAMPLES: usize = 8192;
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct StereoSample {
+    pub left: i16,
+    pub right: i16,
+}
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+#[derive(Copy, Clone, Debug, Default)]
+struct LengthCounter {
+    counter: u16,
+    enabled: bool,
+}
+
+impl LengthCounter {
+    /// Clocked at 256 Hz. Returns false if the channel should be disabled.
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter != 0
+        } else {
+            true
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Envelope {
+    initial_volume: u8, // 0-15
+    increase: bool,
+    period: u8, // 0-7, 0 disables the envelope
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, data: u16) {
+        self.period = bit!(data[8:10]) as u8;
+        self.increase = bit!(data[11]) != 0;
+        self.initial_volume = bit!(data[12:15]) as u8;
+    }
+
+    fn read(&self) -> u16 {
+        (self.period as u16) << 8
+            | (self.increase as u16) << 11
+            | (self.initial_volume as u16) << 12
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    /// Clocked at 64 Hz.
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Sweep {
+    shift: u8, // 0-7
+    decrease: bool,
+    period: u8, // 0-7
+    enabled: bool,
+    timer: u8,
+    shadow_frequency: u16,
+}
+
+impl Sweep {
+    fn write(&mut self, data: u16) {
+        self.shift = bit!(data[0:2]) as u8;
+        self.decrease = bit!(data[3]) != 0;
+        self.period = bit!(data[4:6]) as u8;
+    }
+
+    fn read(&self) -> u16 {
+        self.shift as u16 | (self.decrease as u16) << 3 | (self.period as u16) << 4
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    /// Returns None if the new frequency overflows, which disables the channel.
+    fn calculate_frequency(&self) -> Option<u16> {
+        let delta = self.shadow_frequency >> self.shift;
+        let new_frequency = if self.decrease {
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        };
+
+        if new_frequency > 2047 {
+            None
+        } else {
+            Some(new_frequency)
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SquareChannel {
+    enabled: bool,
+    sweep: Sweep,
+    length: LengthCounter,
+    envelope: Envelope,
+    duty: u8,       // 0-3
+    frequency: u16, // 0-2047
+
+    duty_position: u8,
+    timer: u32,
+}
+
+impl SquareChannel {
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 16
+    }
+
+    /// SOUND1CNT_H/SOUND2CNT_L
+    fn write_duty_length_envelope(&mut self, data: u16) {
+        self.length.counter = 64 - bit!(data[0:5]);
+        self.duty = bit!(data[6:7]) as u8;
+        self.envelope.write(data);
+    }
+
+    fn read_duty_length_envelope(&self) -> u16 {
+        // Length is write-only
+        (self.duty as u16) << 6 | self.envelope.read()
+    }
+
+    /// SOUND1CNT_X/SOUND2CNT_H
+    fn write_frequency_control(&mut self, data: u16) {
+        self.frequency = bit!(data[0:10]);
+        self.length.enabled = bit!(data[14]) != 0;
+        if bit!(data[15]) != 0 {
+            self.trigger();
+        }
+    }
+
+    fn read_frequency_control(&self) -> u16 {
+        // Frequency is write-only
+        (self.length.enabled as u16) << 14
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = true;
+        if self.length.counter == 0 {
+            self.length.counter = 64;
+        }
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        self.sweep.shadow_frequency = self.frequency;
+        self.sweep.reload_timer();
+        self.sweep.enabled = self.sweep.period != 0 || self.sweep.shift != 0;
+        if self.sweep.shift != 0 && self.sweep.calculate_frequency().is_none() {
+            self.enabled = false;
+        }
+    }
+
+    fn step(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Clocked at 128 Hz. Only used by channel 1.
+    fn clock_sweep(&mut self) {
+        if self.sweep.timer > 0 {
+            self.sweep.timer -= 1;
+        }
+        if self.sweep.timer != 0 {
+            return;
+        }
+        self.sweep.reload_timer();
+
+        if self.sweep.enabled && self.sweep.period != 0 {
+            match self.sweep.calculate_frequency() {
+                Some(new_frequency) => {
+                    if self.sweep.shift != 0 {
+                        self.sweep.shadow_frequency = new_frequency;
+                        self.frequency = new_frequency;
+                        // The overflow check is done again with the new frequency
+                        if self.sweep.calculate_frequency().is_none() {
+                            self.enabled = false;
+                        }
+                    }
+                }
+                None => self.enabled = false,
+            }
+        }
+    }
+
+    /// Current digital output, 0-15.
+    fn output(&self) -> u8 {
+        if self.enabled && DUTY_TABLE[self.duty as usize][self.duty_position as usize] != 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    two_banks: bool,
+    selected_bank: u8, // Bank being played back
+    length: LengthCounter,
+    volume: u8, // 0-3, see table in GBATEK
+    force_75_percent: bool,
+    frequency: u16, // 0-2047
+
+    /// Two banks of 32 4-bit samples each, stored packed with the high nibble first.
+    wave_ram: [[u8; 16]; 2],
+    sample_position: u8, // 0-31 or 0-63 if playing back both banks
+    timer: u32,
+}
+
+impl WaveChannel {
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 8
+    }
+
+    /// SOUND3CNT_L
+    fn write_control(&mut self, data: u16) {
+        self.two_banks = bit!(data[5]) != 0;
+        self.selected_bank = bit!(data[6]) as u8;
+        self.dac_enabled = bit!(data[7]) != 0;
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+    }
+
+    fn read_control(&self) -> u16 {
+        (self.two_banks as u16) << 5
+            | (self.selected_bank as u16) << 6
+            | (self.dac_enabled as u16) << 7
+    }
+
+    /// SOUND3CNT_H
+    fn write_length_volume(&mut self, data: u16) {
+        self.length.counter = 256 - bit!(data[0:7]);
+        self.volume = bit!(data[13:14]) as u8;
+        self.force_75_percent = bit!(data[15]) != 0;
+    }
+
+    fn read_length_volume(&self) -> u16 {
+        // Length is write-only
+        (self.volume as u16) << 13 | (self.force_75_percent as u16) << 15
+    }
+
+    /// SOUND3CNT_X
+    fn write_frequency_control(&mut self, data: u16) {
+        self.frequency = bit!(data[0:10]);
+        self.length.enabled = bit!(data[14]) != 0;
+        if bit!(data[15]) != 0 {
+            self.trigger();
+        }
+    }
+
+    fn read_frequency_control(&self) -> u16 {
+        // Frequency is write-only
+        (self.length.enabled as u16) << 14
+    }
+
+    /// The CPU can only access the bank which is not currently being played back.
+    fn write_wave_ram(&mut self, offset: usize, data: u16) {
+        let bank = &mut self.wave_ram[self.selected_bank as usize ^ 1];
+        bank[offset] = data as u8;
+        bank[offset + 1] = (data >> 8) as u8;
+    }
+
+    fn read_wave_ram(&self, offset: usize) -> u16 {
+        let bank = &self.wave_ram[self.selected_bank as usize ^ 1];
+        bank[offset] as u16 | (bank[offset + 1] as u16) << 8
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length.counter == 0 {
+            self.length.counter = 256;
+        }
+        self.timer = self.period();
+        self.sample_position = 0;
+    }
+
+    fn step(&mut self, mut cycles: u32) {
+        let num_samples = if self.two_banks { 64 } else { 32 };
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.sample_position = (self.sample_position + 1) % num_samples;
+            if self.two_banks && self.sample_position % 32 == 0 {
+                // Playback alternates between the banks
+                self.selected_bank ^= 1;
+            }
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Current digital output, 0-15.
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        let position = self.sample_position as usize % 32;
+        let byte = self.wave_ram[self.selected_bank as usize][position / 2];
+        let sample = if position % 2 == 0 {
+            byte >> 4
+        } else {
+            byte & 0xF
+        };
+
+        if self.force_75_percent {
+            sample * 3 / 4
+        } else {
+            match self.volume {
+                0 => 0,
+                1 => sample,
+                2 => sample >> 1,
+                3 => sample >> 2,
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NoiseChannel {
+    enabled: bool,
+    length: LengthCounter,
+    envelope: Envelope,
+    divisor_code: u8, // 0-7
+    narrow: bool,     // 7-bit LFSR mode
+    shift: u8,        // 0-15
+
+    lfsr: u16,
+    timer: u32,
+}
+
+impl NoiseChannel {
+    fn period(&self) -> u32 {
+        (NOISE_DIVISORS[self.divisor_code as usize] << self.shift) * 4
+    }
+
+    /// SOUND4CNT_L
+    fn write_length_envelope(&mut self, data: u16) {
+        self.length.counter = 64 - bit!(data[0:5]);
+        self.envelope.write(data);
+    }
+
+    fn read_length_envelope(&self) -> u16 {
+        // Length is write-only
+        self.envelope.read()
+    }
+
+    /// SOUND4CNT_H
+    fn write_frequency_control(&mut self, data: u16) {
+        self.divisor_code = bit!(data[0:2]) as u8;
+        self.narrow = bit!(data[3]) != 0;
+        self.shift = bit!(data[4:7]) as u8;
+        self.length.enabled = bit!(data[14]) != 0;
+        if bit!(data[15]) != 0 {
+            self.trigger();
+        }
+    }
+
+    fn read_frequency_control(&self) -> u16 {
+        self.divisor_code as u16
+            | (self.narrow as u16) << 3
+            | (self.shift as u16) << 4
+            | (self.length.enabled as u16) << 14
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = true;
+        if self.length.counter == 0 {
+            self.length.counter = 64;
+        }
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn clock_lfsr(&mut self) {
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.narrow {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+
+    fn step(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.clock_lfsr();
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Current digital output, 0-15.
+    fn output(&self) -> u8 {
+        // The output is the inverse of the LFSR's low bit
+        if self.enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+}
+
+pub struct Apu {
+    // SOUNDCNT_L
+    master_volume_right: u8,  // 0-7
+    master_volume_left: u8,   // 0-7
+    channel_enable_right: u8, // bitmask of channels 1-4
+    channel_enable_left: u8,
+    // SOUNDCNT_H
+    psg_volume: u8,           // 0-3, 0=25%, 1=50%, 2=100%, 3=prohibited
+    soundcnt_h_dma_bits: u16, // TODO: DMA sound
+    // SOUNDCNT_X
+    master_enable: bool,
+    // SOUNDBIAS
+    bias_level: u16,          // 0-0x3FE, bit 0 is unused
+    amplitude_resolution: u8, // 0-3
+
+    square1: SquareChannel,
+    square2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+
+    frame_sequencer_step: u8,
+    samples: VecDeque<StereoSample>,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            master_volume_right: 0,
+            master_volume_left: 0,
+            channel_enable_right: 0,
+            channel_enable_left: 0,
+            psg_volume: 0,
+            soundcnt_h_dma_bits: 0,
+            master_enable: false,
+            bias_level: 0x200,
+            amplitude_resolution: 0,
+
+            square1: SquareChannel::default(),
+            square2: SquareChannel::default(),
+            wave: WaveChannel::default(),
+            noise: NoiseChannel::default(),
+
+            frame_sequencer_step: 0,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn write(&mut self, address: u32, data: u16) {
+        match address & 0xFFE {
+            0x060 => self.square1.sweep.write(data),
+            0x062 => self.square1.write_duty_length_envelope(data),
+            0x064 => self.square1.write_frequency_control(data),
+            0x068 => self.square2.write_duty_length_envelope(data),
+            0x06C => self.square2.write_frequency_control(data),
+            0x070 => self.wave.write_control(data),
+            0x072 => self.wave.write_length_volume(data),
+            0x074 => self.wave.write_frequency_control(data),
+            0x078 => self.noise.write_length_envelope(data),
+            0x07C => self.noise.write_frequency_control(data),
+            0x080 => self.write_soundcnt_l(data),
+            0x082 => self.write_soundcnt_h(data),
+            0x084 => self.write_soundcnt_x(data),
+            0x088 => self.write_soundbias(data),
+            offset @ 0x090..=0x09E => self.wave.write_wave_ram((offset - 0x090) as usize, data),
+            _ => println!(
+                "Unsupported sound write: [0x{:08X}] <= 0x{:04X}",
+                address, data
+            ),
+        }
+    }
+
+    pub fn read(&self, address: u32) -> u16 {
+        match address & 0xFFE {
+            0x060 => self.square1.sweep.read(),
+            0x062 => self.square1.read_duty_length_envelope(),
+            0x064 => self.square1.read_frequency_control(),
+            0x068 => self.square2.read_duty_length_envelope(),
+            0x06C => self.square2.read_frequency_control(),
+            0x070 => self.wave.read_control(),
+            0x072 => self.wave.read_length_volume(),
+            0x074 => self.wave.read_frequency_control(),
+            0x078 => self.noise.read_length_envelope(),
+            0x07C => self.noise.read_frequency_control(),
+            0x080 => self.read_soundcnt_l(),
+            0x082 => self.read_soundcnt_h(),
+            0x084 => self.read_soundcnt_x(),
+            0x088 => self.read_soundbias(),
+            offset @ 0x090..=0x09E => self.wave.read_wave_ram((offset - 0x090) as usize),
+            _ => {
+                println!("Unsupported sound read: [0x{:08X}]", address);
+                0
+            }
+        }
+    }
+
+    fn write_soundcnt_l(&mut self, data: u16) {
+        self.master_volume_right = bit!(data[0:2]) as u8;
+        self.master_volume_left = bit!(data[4:6]) as u8;
+        self.channel_enable_right = bit!(data[8:11]) as u8;
+        self.channel_enable_left = bit!(data[12:15]) as u8;
+    }
+
+    fn read_soundcnt_l(&self) -> u16 {
+        self.master_volume_right as u16
+            | (self.master_volume_left as u16) << 4
+            | (self.channel_enable_right as u16) << 8
+            | (self.channel_enable_left as u16) << 12
+    }
+
+    fn write_soundcnt_h(&mut self, data: u16) {
+        self.psg_volume = bit!(data[0:1]) as u8;
+        self.soundcnt_h_dma_bits = data & 0xFF0C;
+    }
+
+    fn read_soundcnt_h(&self) -> u16 {
+        // The DMA FIFO reset bits always read as 0
+        self.psg_volume as u16 | (self.soundcnt_h_dma_bits & 0x770C)
+    }
+
+    fn write_soundcnt_x(&mut self, data: u16) {
+        self.master_enable = bit!(data[7]) != 0;
+        if !self.master_enable {
+            // Turning off the APU resets all PSG registers
+            let wave_ram = self.wave.wave_ram;
+            self.square1 = SquareChannel::default();
+            self.square2 = SquareChannel::default();
+            self.wave = WaveChannel::default();
+            self.wave.wave_ram = wave_ram;
+            self.noise = NoiseChannel::default();
+            self.write_soundcnt_l(0);
+        }
+    }
+
+    fn read_soundcnt_x(&self) -> u16 {
+        self.square1.enabled as u16
+            | (self.square2.enabled as u16) << 1
+            | (self.wave.enabled as u16) << 2
+            | (self.noise.enabled as u16) << 3
+            | (self.master_enable as u16) << 7
+    }
+
+    fn write_soundbias(&mut self, data: u16) {
+        self.bias_level = data & 0x3FE;
+        self.amplitude_resolution = bit!(data[14:15]) as u8;
+    }
+
+    fn read_soundbias(&self) -> u16 {
+        self.bias_level | (self.amplitude_resolution as u16) << 14
+    }
+
+    /// Sampling rate implied by the amplitude resolution in SOUNDBIAS. Higher rates trade off
+    /// sample resolution. Samples are currently always generated at `SAMPLE_RATE`, so this is only
+    /// informational.
+    pub fn resolution_sample_rate(&self) -> u32 {
+        32768 << self.amplitude_resolution
+    }
+
+    /// Advances the frame sequencer by one step (at 512 Hz).
+    pub fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        if step % 2 == 0 {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.square1.clock_sweep();
+        }
+        if step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_sequencer_step = (step + 1) % 8;
+    }
+
+    /// Advances all channel timers by `cycles`.
+    fn step_channels(&mut self, cycles: u32) {
+        self.square1.step(cycles);
+        self.square2.step(cycles);
+        self.wave.step(cycles);
+        self.noise.step(cycles);
+    }
+
+    /// Mixes the PSG channels into a value for each side, scaled by the master and PSG volumes.
+    fn mix_psg(&self) -> (i32, i32) {
+        if !self.master_enable {
+            return (0, 0);
+        }
+
+        let outputs = [
+            self.square1.output(),
+            self.square2.output(),
+            self.wave.output(),
+            self.noise.output(),
+        ];
+        let mix_side = |enable_mask: u8, master_volume: u8| -> i32 {
+            let mut sum = 0;
+            for (i, &output) in outputs.iter().enumerate() {
+                if enable_mask & (1 << i) != 0 {
+                    sum += output as i32;
+                }
+            }
+            let scaled = sum * (master_volume as i32 + 1);
+            match self.psg_volume {
+                0 => scaled >> 2,
+                1 => scaled >> 1,
+                2 => scaled,
+                _ => 0, // Prohibited
+            }
+        };
+
+        (
+            mix_side(self.channel_enable_left, self.master_volume_left),
+            mix_side(self.channel_enable_right, self.master_volume_right),
+        )
+    }
+
+    /// Produces the final 10-bit output level of each side, as fed to the PWM DAC. The bias is
+    /// added to the mix of all channels and the result clamped to the DAC's range.
+    fn mix(&self) -> (u16, u16) {
+        let (left, right) = self.mix_psg();
+        let apply_bias = |x: i32| (x + self.bias_level as i32).max(0).min(0x3FF) as u16;
+        (apply_bias(left), apply_bias(right))
+    }
+
+    fn generate_sample(&mut self) {
+        let (left, right) = self.mix();
+        if self.samples.len() >= MAX_BUFFERED_SAMPLES {
+            self.samples.pop_front();
+        }
+        // Re-center the output around the default bias level and scale it to 16 bits.
+        let to_host = |x: u16| ((x as i16 - 0x200) << 6);
+        self.samples.push_back(StereoSample {
+            left: to_host(left),
+            right: to_host(right),
+        });
+    }
+
+    /// Removes and returns all generated samples.
+    pub fn drain_samples(&mut self) -> ::std::collections::vec_deque::Drain<StereoSample> {
+        self.samples.drain(..)
+    }
+
+    pub fn buffered_samples(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn run_frame_sequencer_task(apu: Rc<RefCell<Apu>>) -> impl Task<'static, Return = ()> {
+        GeneratorTask::new(move || loop {
+            wait_cycles!(FRAME_SEQUENCER_CYCLES);
+            apu.borrow_mut().clock_frame_sequencer();
+        })
+    }
+
+    pub fn run_sample_task(apu: Rc<RefCell<Apu>>) -> impl Task<'static, Return = ()> {
+        GeneratorTask::new(move || loop {
+            wait_cycles!(CYCLES_PER_SAMPLE);
+            let mut apu = apu.borrow_mut();
+            apu.step_channels(CYCLES_PER_SAMPLE as u32);
+            apu.generate_sample();
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Steps the channel one sample at a time and collects its output.
+    macro_rules! collect_outputs {
+        ($channel:expr, $cycles:expr, $count:expr) => {{
+            let mut outputs = Vec::new();
+            for _ in 0..$count {
+                $channel.step($cycles);
+                outputs.push($channel.output());
+            }
+            outputs
+        }};
+    }
+
+    #[test]
+    fn test_square_duty_cycles() {
+        let mut channel = SquareChannel::default();
+        // Frequency chosen so that each duty step lasts exactly 512 cycles
+        channel.write_duty_length_envelope(0xF000 | 2 << 6);
+        channel.write_frequency_control(0x8000 | 2016);
+        assert_eq!(channel.period(), 512);
+        assert_eq!(
+            collect_outputs!(channel, 512, 8),
+            [0, 0, 0, 0, 15, 15, 15, 15]
+        );
+
+        channel.write_duty_length_envelope(0xA000 | 0 << 6);
+        channel.write_frequency_control(0x8000 | 2016);
+        assert_eq!(collect_outputs!(channel, 512, 8), [0, 0, 0, 0, 0, 0, 10, 0]);
+    }
+
+    #[test]
+    fn test_square_envelope_and_length() {
+        let mut channel = SquareChannel::default();
+        // Initial volume 3, decreasing, step time 1, length 62
+        channel.write_duty_length_envelope(0x3100 | 3 << 6 | 2);
+        channel.write_frequency_control(0xC000 | 2016);
+        channel.step(512); // Move to a high part of the 75% duty cycle
+        assert_eq!(channel.output(), 3);
+
+        channel.envelope.clock();
+        assert_eq!(channel.output(), 2);
+        channel.envelope.clock();
+        channel.envelope.clock();
+        channel.envelope.clock();
+        assert_eq!(channel.output(), 0);
+
+        for _ in 0..61 {
+            channel.clock_length();
+            assert!(channel.enabled);
+        }
+        channel.clock_length();
+        assert!(!channel.enabled);
+    }
+
+    #[test]
+    fn test_frame_sequencer_rates() {
+        let mut apu = Apu::new();
+        apu.write(0x0400_0084, 0x0080);
+        // Channel 2 at volume 15, decreasing every envelope clock, with length 64
+        apu.write(0x0400_0068, 0xF100);
+        apu.write(0x0400_006C, 0xC000);
+
+        // Of the 512 Hz steps, every 8th clocks the envelope
+        for _ in 0..63 {
+            apu.clock_frame_sequencer();
+        }
+        assert_eq!(apu.square2.envelope.volume, 15 - 7);
+
+        // Every other one clocks the length counter
+        for _ in 63..126 {
+            apu.clock_frame_sequencer();
+        }
+        assert!(apu.square2.enabled);
+        apu.clock_frame_sequencer();
+        assert!(!apu.square2.enabled);
+        assert_eq!(apu.read(0x0400_0084), 0x0080);
+    }
+
+    #[test]
+    fn test_square_sweep() {
+        let mut channel = SquareChannel::default();
+        // Sweep time 1, increasing, shift 2
+        channel.sweep.write(0x0012);
+        channel.write_duty_length_envelope(0xF000);
+        channel.write_frequency_control(0x8000 | 0x500);
+
+        channel.clock_sweep();
+        assert_eq!(channel.frequency, 0x640);
+        assert!(channel.enabled);
+        // The frequency is updated, but the following overflow check disables the channel
+        channel.clock_sweep();
+        assert_eq!(channel.frequency, 0x7D0);
+        assert!(!channel.enabled);
+    }
+
+    #[test]
+    fn test_wave_playback() {
+        let mut channel = WaveChannel::default();
+        // Write to bank 1 while bank 0 is selected, then switch to it
+        channel.write_control(0x0080);
+        channel.write_wave_ram(0, 0x3210);
+        channel.write_wave_ram(2, 0x7654);
+        channel.write_control(0x00C0);
+        channel.write_length_volume(1 << 13);
+        // Each sample lasts exactly 512 cycles
+        channel.write_frequency_control(0x8000 | 1984);
+        assert_eq!(channel.period(), 512);
+
+        assert_eq!(channel.output(), 1);
+        assert_eq!(collect_outputs!(channel, 512, 7), [0, 3, 2, 5, 4, 7, 6]);
+
+        // 50% volume
+        channel.write_length_volume(2 << 13);
+        assert_eq!(channel.output(), 3);
+    }
+
+    #[test]
+    fn test_noise_lfsr() {
+        let mut channel = NoiseChannel::default();
+        channel.write_length_envelope(0xF000);
+        channel.write_frequency_control(0x8000);
+        assert_eq!(channel.period(), 32);
+
+        // Starting from all ones, the feedback is 0 until a 0 reaches the bottom two bits
+        assert_eq!(collect_outputs!(channel, 32, 14), [0; 14]);
+        assert_eq!(channel.lfsr, 0x0001);
+        channel.step(32);
+        assert_eq!(channel.lfsr, 0x4000);
+        assert_eq!(channel.output(), 15);
+    }
+
+    #[test]
+    fn test_noise_lfsr_period() {
+        fn lfsr_period(narrow: bool) -> usize {
+            let mut channel = NoiseChannel::default();
+            channel.write_frequency_control(0x8000 | (narrow as u16) << 3);
+            // In 7-bit mode, the upper bits don't feed back into the sequence
+            let mask = if narrow { 0x7F } else { 0x7FFF };
+            let initial = channel.lfsr & mask;
+            let mut period = 0;
+            loop {
+                channel.clock_lfsr();
+                period += 1;
+                if channel.lfsr & mask == initial {
+                    return period;
+                }
+            }
+        }
+
+        assert_eq!(lfsr_period(false), 32767);
+        assert_eq!(lfsr_period(true), 127);
+    }
+
+    #[test]
+    fn test_mixer_volume() {
+        let mut apu = Apu::new();
+        apu.write(0x0400_0084, 0x0080);
+        // Left volume 7 with channel 2, right volume 3 with channels 2 and 4 (silent)
+        apu.write(0x0400_0080, 0x2A73);
+        apu.write(0x0400_0082, 0x0002);
+        apu.write(0x0400_0068, 0xF000 | 3 << 6);
+        apu.write(0x0400_006C, 0x8000 | 2016);
+        apu.step_channels(512);
+
+        assert_eq!(apu.mix_psg(), (15 * 8, 15 * 4));
+
+        // 25% PSG volume
+        apu.write(0x0400_0082, 0x0000);
+        assert_eq!(apu.mix_psg(), (15 * 8 / 4, 15 * 4 / 4));
+    }
+
+    #[test]
+    fn test_soundbias() {
+        let mut apu = Apu::new();
+        assert_eq!(apu.read(0x0400_0088), 0x0200);
+
+        apu.write(0x0400_0088, 0xFFFF);
+        assert_eq!(apu.read(0x0400_0088), 0xC3FE);
+        assert_eq!(apu.resolution_sample_rate(), 262144);
+    }
+
+    #[test]
+    fn test_bias_mixing() {
+        let mut apu = Apu::new();
+        apu.write(0x0400_0084, 0x0080);
+
+        // With no channels playing, the output is just the bias level
+        assert_eq!(apu.mix(), (0x200, 0x200));
+        apu.write(0x0400_0088, 0x0100);
+        assert_eq!(apu.mix(), (0x100, 0x100));
+
+        // All channels at maximum volume, clamped at the upper rail
+        apu.write(0x0400_0088, 0x03FE);
+        apu.write(0x0400_0080, 0xFF77);
+        apu.write(0x0400_0082, 0x0002);
+        apu.write(0x0400_0062, 0xF000 | 3 << 6);
+        apu.write(0x0400_0064, 0x8000 | 2016);
+        apu.write(0x0400_0068, 0xF000 | 3 << 6);
+        apu.write(0x0400_006C, 0x8000 | 2016);
+        apu.step_channels(512);
+        assert_eq!(apu.mix_psg(), (15 * 2 * 8, 15 * 2 * 8));
+        assert_eq!(apu.mix(), (0x3FF, 0x3FF));
+    }
+}
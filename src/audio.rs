@@ -0,0 +1,140 @@
+use advance::apu;
+use advance::apu::StereoSample;
+use sdl2::audio::AudioQueue;
+use sdl2::audio::AudioSpecDesired;
+use sdl2::AudioSubsystem;
+
+pub const OUTPUT_SAMPLE_RATE: i32 = 48000;
+const NUM_CHANNELS: usize = 2;
+
+/// Amount of audio (in stereo frames) we try to keep queued in SDL. ~43 ms at 48 kHz.
+const TARGET_QUEUED_FRAMES: usize = 2048;
+/// Maximum deviation from the nominal resampling ratio used to keep the queue at the target fill.
+const MAX_RATIO_ADJUSTMENT: f64 = 0.005;
+
+/// Returns a factor to apply to the resampling ratio, so that the output buffer tends towards
+/// `target_fill`. When the buffer is running low, slightly more samples are generated, and vice
+/// versa. The adjustment is proportional to the error and limited to ±0.5%, which isn't audible
+/// as a pitch change.
+pub fn rate_control_factor(buffer_fill: usize, target_fill: usize) -> f64 {
+    let error = (target_fill as f64 - buffer_fill as f64) / target_fill as f64;
+    1.0 + error.max(-1.0).min(1.0) * MAX_RATIO_ADJUSTMENT
+}
+
+fn lerp(a: i16, b: i16, t: f64) -> i16 {
+    (a as f64 + (b as f64 - a as f64) * t) as i16
+}
+
+/// Linear interpolation resampler for interleaved stereo output.
+pub struct Resampler {
+    /// Position of the next output sample between `previous` and the next input sample.
+    position: f64,
+    previous: StereoSample,
+}
+
+impl Resampler {
+    pub fn new() -> Resampler {
+        Resampler {
+            position: 0.0,
+            previous: StereoSample::default(),
+        }
+    }
+
+    /// Resamples `input`, producing `ratio` output samples for each input sample.
+    pub fn resample<I>(&mut self, input: I, ratio: f64, output: &mut Vec<i16>)
+    where
+        I: Iterator<Item = StereoSample>,
+    {
+        let step = 1.0 / ratio;
+        for sample in input {
+            while self.position < 1.0 {
+                output.push(lerp(self.previous.left, sample.left, self.position));
+                output.push(lerp(self.previous.right, sample.right, self.position));
+                self.position += step;
+            }
+            self.position -= 1.0;
+            self.previous = sample;
+        }
+    }
+}
+
+pub struct AudioOutput {
+    queue: AudioQueue<i16>,
+    resampler: Resampler,
+    buffer: Vec<i16>,
+}
+
+impl AudioOutput {
+    pub fn new(audio: &AudioSubsystem) -> Result<AudioOutput, String> {
+        let desired = AudioSpecDesired {
+            freq: Some(OUTPUT_SAMPLE_RATE),
+            channels: Some(NUM_CHANNELS as u8),
+            samples: Some(1024),
+        };
+        // SDL plays silence if the queue runs dry, so underruns never repeat stale samples.
+        let queue = audio.open_queue(None, &desired)?;
+        queue.resume();
+
+        Ok(AudioOutput {
+            queue,
+            resampler: Resampler::new(),
+            buffer: Vec::new(),
+        })
+    }
+
+    fn queued_frames(&self) -> usize {
+        self.queue.size() as usize / (NUM_CHANNELS * 2)
+    }
+
+    /// Consumes all samples generated by the APU and queues them for playback. If `muted`, the
+    /// samples are discarded instead, e.g. while fast-forwarding.
+    pub fn push_samples(&mut self, samples: &[StereoSample], muted: bool) {
+        if muted {
+            return;
+        }
+
+        let nominal_ratio = OUTPUT_SAMPLE_RATE as f64 / apu::SAMPLE_RATE as f64;
+        let ratio = nominal_ratio * rate_control_factor(self.queued_frames(), TARGET_QUEUED_FRAMES);
+
+        self.buffer.clear();
+        self.resampler
+            .resample(samples.iter().cloned(), ratio, &mut self.buffer);
+        self.queue.queue(&self.buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_control_factor() {
+        assert_eq!(rate_control_factor(1000, 1000), 1.0);
+        // Running low: speed up generation, up to the limit
+        assert!(rate_control_factor(500, 1000) > 1.0);
+        assert_eq!(rate_control_factor(0, 1000), 1.005);
+        // Running high: slow down generation, up to the limit
+        assert!(rate_control_factor(1500, 1000) < 1.0);
+        assert_eq!(rate_control_factor(2000, 1000), 0.995);
+        assert_eq!(rate_control_factor(100000, 1000), 0.995);
+    }
+
+    #[test]
+    fn test_resampler_ratio() {
+        let input = vec![
+            StereoSample {
+                left: 100,
+                right: -100
+            };
+            1000
+        ];
+        let mut output = Vec::new();
+        let mut resampler = Resampler::new();
+        resampler.resample(input.into_iter(), 1.5, &mut output);
+
+        let output_frames = (output.len() / NUM_CHANNELS) as i64;
+        assert!((output_frames - 1500).abs() <= 1, "{}", output_frames);
+        // After the initial ramp from silence, the output matches the input
+        assert_eq!(&output[10..14], &[100, -100, 100, -100]);
+    }
+}
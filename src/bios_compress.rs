@@ -0,0 +1,453 @@
+//! Decompression of the formats supported by the BIOS decompression functions, usable on their
+//! own for tooling as well as by the HLE BIOS.
+//!
+//! All formats start with a header word holding the format in bits 4-7, format-specific
+//! parameters in bits 0-3, and the decompressed size in bits 8-31.
+
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DecompressError {
+    /// The header doesn't match the format being decompressed, or has invalid parameters.
+    InvalidHeader,
+    /// The compressed data ended before all the declared output was produced.
+    Truncated,
+    /// The compressed data produced a different amount of data than declared in the header.
+    SizeMismatch,
+    /// An LZ77 copy referenced data from before the start of the output.
+    WindowUnderflow,
+}
+
+impl fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecompressError::InvalidHeader => write!(f, "invalid compression header"),
+            DecompressError::Truncated => write!(f, "compressed data is truncated"),
+            DecompressError::SizeMismatch => {
+                write!(f, "decompressed size doesn't match the header")
+            }
+            DecompressError::WindowUnderflow => {
+                write!(f, "copy from before the start of the output")
+            }
+        }
+    }
+}
+
+const FORMAT_LZ77: u8 = 0x10;
+const FORMAT_HUFFMAN: u8 = 0x20;
+const FORMAT_RLE: u8 = 0x30;
+const FORMAT_DIFF: u8 = 0x80;
+
+/// Access to the compressed input and the decompressed output, with offsets relative to the start
+/// of each. The HLE BIOS implements this on top of emulated memory.
+pub trait DecompressBuffers {
+    fn read_input(&mut self, offset: u32) -> Result<u8, DecompressError>;
+
+    /// Reads back the output byte `distance` bytes before `position`, for LZ77 copies.
+    fn read_back(&mut self, position: u32, distance: u32) -> Result<u8, DecompressError>;
+
+    fn write_output(&mut self, offset: u32, data: u8);
+
+    fn write_output_u16(&mut self, offset: u32, data: u16) {
+        self.write_output(offset, data as u8);
+        self.write_output(offset + 1, (data >> 8) as u8);
+    }
+
+    fn read_input_u16(&mut self, offset: u32) -> Result<u16, DecompressError> {
+        Ok(self.read_input(offset)? as u16 | (self.read_input(offset + 1)? as u16) << 8)
+    }
+
+    fn read_input_u32(&mut self, offset: u32) -> Result<u32, DecompressError> {
+        Ok(self.read_input_u16(offset)? as u32 | (self.read_input_u16(offset + 2)? as u32) << 16)
+    }
+}
+
+/// Writes output either a byte at a time (the WRAM variants), or a halfword at a time (the VRAM
+/// variants, since VRAM doesn't support byte writes).
+struct OutputStream {
+    position: u32,
+    halfword_writes: bool,
+    pending_byte: Option<u8>,
+}
+
+impl OutputStream {
+    fn new(halfword_writes: bool) -> OutputStream {
+        OutputStream {
+            position: 0,
+            halfword_writes,
+            pending_byte: None,
+        }
+    }
+
+    fn push<B: DecompressBuffers + ?Sized>(&mut self, buffers: &mut B, data: u8) {
+        if !self.halfword_writes {
+            buffers.write_output(self.position, data);
+        } else if let Some(low) = self.pending_byte.take() {
+            buffers.write_output_u16(self.position - 1, low as u16 | (data as u16) << 8);
+        } else {
+            self.pending_byte = Some(data);
+        }
+        self.position += 1;
+    }
+}
+
+/// Decompressed size, from bits 8-31 of the header word common to all compression formats.
+pub fn header_size(header: u32) -> u32 {
+    header >> 8
+}
+
+fn check_size(output: &OutputStream, size: u32) -> Result<(), DecompressError> {
+    if output.position == size {
+        Ok(())
+    } else {
+        Err(DecompressError::SizeMismatch)
+    }
+}
+
+/// Decompresses LZ77 data. With `halfword_writes`, copies that read back the byte still waiting
+/// to be written see stale data, like LZ77UnCompVram does when the displacement is 1.
+pub fn lz77_uncomp<B: DecompressBuffers + ?Sized>(
+    buffers: &mut B,
+    halfword_writes: bool,
+) -> Result<(), DecompressError> {
+    let size = header_size(buffers.read_input_u32(0)?);
+    let mut src = 4;
+
+    let mut output = OutputStream::new(halfword_writes);
+    'blocks: while output.position < size {
+        let flags = buffers.read_input(src)?;
+        src += 1;
+
+        for i in (0..8).rev() {
+            if output.position >= size {
+                break 'blocks;
+            }
+
+            if flags & (1 << i) == 0 {
+                let data = buffers.read_input(src)?;
+                src += 1;
+                output.push(buffers, data);
+            } else {
+                let b0 = buffers.read_input(src)? as u32;
+                let b1 = buffers.read_input(src + 1)? as u32;
+                src += 2;
+
+                let length = (b0 >> 4) + 3;
+                let disp = ((b0 & 0xF) << 8 | b1) + 1;
+                for _ in 0..length {
+                    let data = buffers.read_back(output.position, disp)?;
+                    output.push(buffers, data);
+                }
+            }
+        }
+    }
+    check_size(&output, size)
+}
+
+pub fn rl_uncomp<B: DecompressBuffers + ?Sized>(
+    buffers: &mut B,
+    halfword_writes: bool,
+) -> Result<(), DecompressError> {
+    let size = header_size(buffers.read_input_u32(0)?);
+    let mut src = 4;
+
+    let mut output = OutputStream::new(halfword_writes);
+    while output.position < size {
+        let flag = buffers.read_input(src)?;
+        src += 1;
+
+        if flag & 0x80 != 0 {
+            let length = (flag & 0x7F) + 3;
+            let data = buffers.read_input(src)?;
+            src += 1;
+            for _ in 0..length {
+                output.push(buffers, data);
+            }
+        } else {
+            let length = (flag & 0x7F) + 1;
+            for _ in 0..length {
+                let data = buffers.read_input(src)?;
+                src += 1;
+                output.push(buffers, data);
+            }
+        }
+    }
+    check_size(&output, size)
+}
+
+/// Decompresses Huffman coded data with 4 or 8-bit symbols. The output is written a halfword at a
+/// time, so this is always safe to use with VRAM.
+pub fn huff_uncomp<B: DecompressBuffers + ?Sized>(buffers: &mut B) -> Result<(), DecompressError> {
+    let header = buffers.read_input_u32(0)?;
+    let data_bits = bit!(header[0:3]);
+    if data_bits != 4 && data_bits != 8 {
+        return Err(DecompressError::InvalidHeader);
+    }
+    let size = header_size(header);
+
+    let tree_size = buffers.read_input(4)? as u32;
+    let root = 5;
+    let mut stream = 4 + (tree_size + 1) * 2;
+
+    let mut bits = 0u32;
+    let mut bits_left = 0;
+    let mut output_word = 0u32;
+    let mut output_bits = 0;
+    let mut written = 0;
+    while written < size {
+        let mut node_offset = root;
+        let symbol = loop {
+            if bits_left == 0 {
+                bits = buffers.read_input_u32(stream)?;
+                stream += 4;
+                bits_left = 32;
+            }
+            let direction = bits >> 31;
+            bits <<= 1;
+            bits_left -= 1;
+
+            let node = buffers.read_input(node_offset)? as u32;
+            // Node offsets are relative to the start of the data, which is word aligned
+            let child_offset = (node_offset & !1) + bit!(node[0:5]) * 2 + 2 + direction;
+            if node & (0x80 >> direction) != 0 {
+                break buffers.read_input(child_offset)? as u32;
+            }
+            node_offset = child_offset;
+        };
+
+        output_word |= (symbol & ((1 << data_bits) - 1)) << output_bits;
+        output_bits += data_bits;
+        if output_bits == 32 {
+            buffers.write_output_u16(written, output_word as u16);
+            buffers.write_output_u16(written + 2, (output_word >> 16) as u16);
+            written += 4;
+            output_word = 0;
+            output_bits = 0;
+        }
+    }
+    if written == size {
+        Ok(())
+    } else {
+        Err(DecompressError::SizeMismatch)
+    }
+}
+
+pub fn diff8_unfilter<B: DecompressBuffers + ?Sized>(
+    buffers: &mut B,
+    halfword_writes: bool,
+) -> Result<(), DecompressError> {
+    let size = header_size(buffers.read_input_u32(0)?);
+
+    let mut output = OutputStream::new(halfword_writes);
+    let mut value = 0u8;
+    for i in 0..size {
+        value = value.wrapping_add(buffers.read_input(4 + i)?);
+        output.push(buffers, value);
+    }
+    Ok(())
+}
+
+pub fn diff16_unfilter<B: DecompressBuffers + ?Sized>(
+    buffers: &mut B,
+) -> Result<(), DecompressError> {
+    let size = header_size(buffers.read_input_u32(0)?);
+
+    let mut value = 0u16;
+    for i in (0..size).step_by(2) {
+        value = value.wrapping_add(buffers.read_input_u16(4 + i)?);
+        buffers.write_output_u16(i, value);
+    }
+    Ok(())
+}
+
+/// Decompresses from a slice into a new buffer. Bytes read back before being written read as 0.
+struct SliceBuffers<'a> {
+    input: &'a [u8],
+    output: Vec<u8>,
+}
+
+impl<'a> DecompressBuffers for SliceBuffers<'a> {
+    fn read_input(&mut self, offset: u32) -> Result<u8, DecompressError> {
+        self.input
+            .get(offset as usize)
+            .cloned()
+            .ok_or(DecompressError::Truncated)
+    }
+
+    fn read_back(&mut self, position: u32, distance: u32) -> Result<u8, DecompressError> {
+        let offset = position
+            .checked_sub(distance)
+            .ok_or(DecompressError::WindowUnderflow)?;
+        Ok(self.output.get(offset as usize).cloned().unwrap_or(0))
+    }
+
+    fn write_output(&mut self, offset: u32, data: u8) {
+        let offset = offset as usize;
+        if offset >= self.output.len() {
+            self.output.resize(offset + 1, 0);
+        }
+        self.output[offset] = data;
+    }
+}
+
+fn decompress_slice<F>(input: &[u8], format: u8, uncomp: F) -> Result<Vec<u8>, DecompressError>
+where
+    F: FnOnce(&mut SliceBuffers) -> Result<(), DecompressError>,
+{
+    match input.first() {
+        Some(&header) if header & 0xF0 == format => {}
+        Some(_) => return Err(DecompressError::InvalidHeader),
+        None => return Err(DecompressError::Truncated),
+    }
+
+    let mut buffers = SliceBuffers {
+        input,
+        output: Vec::new(),
+    };
+    uncomp(&mut buffers)?;
+    Ok(buffers.output)
+}
+
+pub fn lz77_decompress(input: &[u8]) -> Result<Vec<u8>, DecompressError> {
+    decompress_slice(input, FORMAT_LZ77, |buffers| lz77_uncomp(buffers, false))
+}
+
+/// Decompresses LZ77 data with the semantics of LZ77UnCompVram, as if decompressing into a
+/// zero-filled buffer.
+pub fn lz77_decompress_vram(input: &[u8]) -> Result<Vec<u8>, DecompressError> {
+    decompress_slice(input, FORMAT_LZ77, |buffers| lz77_uncomp(buffers, true))
+}
+
+pub fn rle_decompress(input: &[u8]) -> Result<Vec<u8>, DecompressError> {
+    decompress_slice(input, FORMAT_RLE, |buffers| rl_uncomp(buffers, false))
+}
+
+pub fn huffman_decompress(input: &[u8]) -> Result<Vec<u8>, DecompressError> {
+    decompress_slice(input, FORMAT_HUFFMAN, |buffers| huff_uncomp(buffers))
+}
+
+pub fn diff8_decode(input: &[u8]) -> Result<Vec<u8>, DecompressError> {
+    if input.first().map_or(false, |&header| header & 0x0F != 1) {
+        return Err(DecompressError::InvalidHeader);
+    }
+    decompress_slice(input, FORMAT_DIFF, |buffers| diff8_unfilter(buffers, false))
+}
+
+pub fn diff16_decode(input: &[u8]) -> Result<Vec<u8>, DecompressError> {
+    if input.first().map_or(false, |&header| header & 0x0F != 2) {
+        return Err(DecompressError::InvalidHeader);
+    }
+    decompress_slice(input, FORMAT_DIFF, |buffers| diff16_unfilter(buffers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lz77() {
+        let input = [
+            0x10, 12, 0, 0,    // LZ77, 12 bytes
+            0x10, // Flags: 3 literals, 1 copy
+            b'a', b'b', b'c', //
+            0x60, 0x02, // Copy 9 bytes from 3 bytes back
+        ];
+        assert_eq!(lz77_decompress(&input).unwrap(), b"abcabcabcabc");
+        assert_eq!(lz77_decompress_vram(&input).unwrap(), b"abcabcabcabc");
+
+        let input = [
+            0x10, 6, 0, 0,    // LZ77, 6 bytes
+            0x40, // Flags: 1 literal, 1 copy
+            b'a', //
+            0x20, 0x00, // Copy 5 bytes from 1 byte back
+        ];
+        assert_eq!(lz77_decompress(&input).unwrap(), b"aaaaaa");
+        // The byte being copied is still waiting to be written together with the next one
+        assert_eq!(lz77_decompress_vram(&input).unwrap(), b"a\0\0\0\0\0");
+    }
+
+    #[test]
+    fn test_rle() {
+        let input = [
+            0x30, 8, 0, 0, // RLE, 8 bytes
+            0x82, b'A', // 5 repeated bytes
+            0x02, b'x', b'y', b'z', // 3 literal bytes
+        ];
+        assert_eq!(rle_decompress(&input).unwrap(), b"AAAAAxyz");
+    }
+
+    #[test]
+    fn test_huffman() {
+        let input = [
+            0x28, 4, 0, 0,    // Huffman with 8-bit data, 4 bytes
+            1,    // Tree size
+            0xC0, // Root node, both children are data
+            b'a', b'b', //
+            0x00, 0x00, 0x00, 0x60, // Bitstream: 0, 1, 1, 0
+        ];
+        assert_eq!(huffman_decompress(&input).unwrap(), b"abba");
+
+        let input = [
+            0x24, 4, 0, 0,    // Huffman with 4-bit data, 4 bytes
+            1,    // Tree size
+            0xC0, // Root node, both children are data
+            0x1, 0x2, //
+            0x55, 0x55, 0x55, 0x55, // Bitstream: 0, 1, 0, 1...
+        ];
+        // The first symbol goes in the low nibble
+        assert_eq!(huffman_decompress(&input).unwrap(), [0x21; 4]);
+    }
+
+    #[test]
+    fn test_diff() {
+        let input = [0x81, 4, 0, 0, 10, 1, 0xFF, 2];
+        assert_eq!(diff8_decode(&input).unwrap(), [10, 11, 10, 12]);
+
+        let input = [0x82, 4, 0, 0, 0x00, 0x10, 0x01, 0x01];
+        assert_eq!(diff16_decode(&input).unwrap(), [0x00, 0x10, 0x01, 0x11]);
+        assert_eq!(diff8_decode(&input), Err(DecompressError::InvalidHeader));
+    }
+
+    #[test]
+    fn test_malformed_data() {
+        assert_eq!(lz77_decompress(&[]), Err(DecompressError::Truncated));
+        assert_eq!(
+            lz77_decompress(&[0x30, 1, 0, 0, 0, 0]),
+            Err(DecompressError::InvalidHeader)
+        );
+        assert_eq!(
+            huffman_decompress(&[0x22, 4, 0, 0, 1, 0xC0, 0, 1, 0, 0, 0, 0]),
+            Err(DecompressError::InvalidHeader)
+        );
+
+        // Declared size larger than the data
+        assert_eq!(
+            lz77_decompress(&[0x10, 0xFF, 0xFF, 0xFF, 0x00, b'a', b'b']),
+            Err(DecompressError::Truncated)
+        );
+        assert_eq!(
+            rle_decompress(&[0x30, 8, 0, 0, 0x82, b'A']),
+            Err(DecompressError::Truncated)
+        );
+        assert_eq!(
+            diff16_decode(&[0x82, 8, 0, 0, 1, 0]),
+            Err(DecompressError::Truncated)
+        );
+
+        // A run or a copy going past the declared size
+        assert_eq!(
+            rle_decompress(&[0x30, 4, 0, 0, 0x82, b'A']),
+            Err(DecompressError::SizeMismatch)
+        );
+        assert_eq!(
+            lz77_decompress(&[0x10, 4, 0, 0, 0x40, b'a', 0x20, 0x00]),
+            Err(DecompressError::SizeMismatch)
+        );
+
+        // Copying from 2 bytes back after outputting a single byte
+        assert_eq!(
+            lz77_decompress(&[0x10, 4, 0, 0, 0x40, b'a', 0x00, 0x01]),
+            Err(DecompressError::WindowUnderflow)
+        );
+    }
+}
@@ -0,0 +1,293 @@
+//! Cartridge header parsing and validation, and detection of the cart's save memory.
+
+use std::fmt;
+
+/// Size of the header at the start of the ROM.
+pub const HEADER_SIZE: usize = 0xC0;
+
+/// Logo bitmap that the BIOS checks before booting a cart.
+const NINTENDO_LOGO: [u8; 156] = [
+    0x24, 0xFF, 0xAE, 0x51, 0x69, 0x9A, 0xA2, 0x21, 0x3D, 0x84, 0x82, 0x0A, //
+    0x84, 0xE4, 0x09, 0xAD, 0x11, 0x24, 0x8B, 0x98, 0xC0, 0x81, 0x7F, 0x21, //
+    0xA3, 0x52, 0xBE, 0x19, 0x93, 0x09, 0xCE, 0x20, 0x10, 0x46, 0x4A, 0x4A, //
+    0xF8, 0x27, 0x31, 0xEC, 0x58, 0xC7, 0xE8, 0x33, 0x82, 0xE3, 0xCE, 0xBF, //
+    0x85, 0xF4, 0xDF, 0x94, 0xCE, 0x4B, 0x09, 0xC1, 0x94, 0x56, 0x8A, 0xC0, //
+    0x13, 0x72, 0xA7, 0xFC, 0x9F, 0x84, 0x4D, 0x73, 0xA3, 0xCA, 0x9A, 0x61, //
+    0x58, 0x97, 0xA3, 0x27, 0xFC, 0x03, 0x98, 0x76, 0x23, 0x1D, 0xC7, 0x61, //
+    0x03, 0x04, 0xAE, 0x56, 0xBF, 0x38, 0x84, 0x00, 0x40, 0xA7, 0x0E, 0xFD, //
+    0xFF, 0x52, 0xFE, 0x03, 0x6F, 0x95, 0x30, 0xF1, 0x97, 0xFB, 0xC0, 0x85, //
+    0x60, 0xD6, 0x80, 0x25, 0xA9, 0x63, 0xBE, 0x03, 0x01, 0x4E, 0x38, 0xE2, //
+    0xF9, 0xA2, 0x34, 0xFF, 0xBB, 0x3E, 0x03, 0x44, 0x78, 0x00, 0x90, 0xCB, //
+    0x88, 0x11, 0x3A, 0x94, 0x65, 0xC0, 0x7C, 0x63, 0x87, 0xF0, 0x3C, 0xAF, //
+    0xD6, 0x25, 0xE4, 0x8B, 0x38, 0x0A, 0xAC, 0x72, 0x21, 0xD4, 0xF8, 0x07, //
+];
+
+const LOGO_OFFSET: usize = 0x04;
+const TITLE_OFFSET: usize = 0xA0;
+const GAME_CODE_OFFSET: usize = 0xAC;
+const MAKER_CODE_OFFSET: usize = 0xB0;
+const FIXED_VALUE_OFFSET: usize = 0xB2;
+const VERSION_OFFSET: usize = 0xBC;
+const CHECKSUM_OFFSET: usize = 0xBD;
+
+const FIXED_VALUE: u8 = 0x96;
+
+/// A reason for the header to be rejected. Carts with any of these won't boot on hardware.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HeaderProblem {
+    /// The ROM is too small to contain a header.
+    TooShort(usize),
+    BadLogo,
+    /// The byte at 0xB2 isn't 0x96.
+    BadFixedValue(u8),
+    BadChecksum {
+        expected: u8,
+        actual: u8,
+    },
+}
+
+impl fmt::Display for HeaderProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeaderProblem::TooShort(size) => write!(f, "ROM is too short ({} bytes)", size),
+            HeaderProblem::BadLogo => write!(f, "Nintendo logo doesn't match"),
+            HeaderProblem::BadFixedValue(value) => write!(
+                f,
+                "fixed value is 0x{:02X}, should be 0x{:02X}",
+                value, FIXED_VALUE
+            ),
+            HeaderProblem::BadChecksum { expected, actual } => write!(
+                f,
+                "header checksum is 0x{:02X}, should be 0x{:02X}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+/// Information from the cart header, along with any problems found while validating it.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CartHeader {
+    pub title: String,
+    pub game_code: String,
+    pub maker_code: String,
+    pub version: u8,
+    pub problems: Vec<HeaderProblem>,
+}
+
+/// Header complement check, computed over 0xA0-0xBC.
+fn header_checksum(rom: &[u8]) -> u8 {
+    rom[TITLE_OFFSET..CHECKSUM_OFFSET]
+        .iter()
+        .fold(0u8, |sum, &b| sum.wrapping_sub(b))
+        .wrapping_sub(0x19)
+}
+
+/// Header fields are uppercase ASCII, padded with zeroes.
+fn read_ascii(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|&&b| b != 0)
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '?'
+            }
+        })
+        .collect()
+}
+
+impl CartHeader {
+    pub fn parse(rom: &[u8]) -> CartHeader {
+        if rom.len() < HEADER_SIZE {
+            return CartHeader {
+                problems: vec![HeaderProblem::TooShort(rom.len())],
+                ..CartHeader::default()
+            };
+        }
+
+        let mut problems = Vec::new();
+        if rom[LOGO_OFFSET..LOGO_OFFSET + NINTENDO_LOGO.len()] != NINTENDO_LOGO[..] {
+            problems.push(HeaderProblem::BadLogo);
+        }
+        if rom[FIXED_VALUE_OFFSET] != FIXED_VALUE {
+            problems.push(HeaderProblem::BadFixedValue(rom[FIXED_VALUE_OFFSET]));
+        }
+        let expected_checksum = header_checksum(rom);
+        if rom[CHECKSUM_OFFSET] != expected_checksum {
+            problems.push(HeaderProblem::BadChecksum {
+                expected: expected_checksum,
+                actual: rom[CHECKSUM_OFFSET],
+            });
+        }
+
+        CartHeader {
+            title: read_ascii(&rom[TITLE_OFFSET..GAME_CODE_OFFSET]),
+            game_code: read_ascii(&rom[GAME_CODE_OFFSET..MAKER_CODE_OFFSET]),
+            maker_code: read_ascii(&rom[MAKER_CODE_OFFSET..FIXED_VALUE_OFFSET]),
+            version: rom[VERSION_OFFSET],
+            problems,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for CartHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "\"{}\" (AGB-{}, maker {}, version {})",
+            self.title, self.game_code, self.maker_code, self.version
+        )
+    }
+}
+
+/// Kinds of save memory found in carts.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SaveChip {
+    Eeprom,
+    Sram,
+    Flash64K,
+    Flash128K,
+}
+
+impl SaveChip {
+    pub fn name(self) -> &'static str {
+        match self {
+            SaveChip::Eeprom => "EEPROM",
+            SaveChip::Sram => "SRAM",
+            SaveChip::Flash64K => "Flash 64 KiB",
+            SaveChip::Flash128K => "Flash 128 KiB",
+        }
+    }
+}
+
+/// ID strings the SDK's save libraries leave in the ROM, followed by their version, like
+/// `SRAM_V113`.
+const SAVE_LIBRARY_IDS: [(&[u8], SaveChip); 6] = [
+    (b"EEPROM_V", SaveChip::Eeprom),
+    (b"SRAM_V", SaveChip::Sram),
+    (b"SRAM_F_V", SaveChip::Sram),
+    (b"FLASH_V", SaveChip::Flash64K),
+    (b"FLASH512_V", SaveChip::Flash64K),
+    (b"FLASH1M_V", SaveChip::Flash128K),
+];
+
+/// Guesses the cart's save memory from the save library linked into the ROM, which is what games
+/// built with the SDK use to access it. The IDs are word aligned.
+pub fn detect_save_chip(rom: &[u8]) -> Option<SaveChip> {
+    for offset in (0..rom.len()).step_by(4) {
+        for &(id, chip) in SAVE_LIBRARY_IDS.iter() {
+            if rom[offset..].starts_with(id) {
+                return Some(chip);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x200];
+        rom[LOGO_OFFSET..LOGO_OFFSET + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        rom[TITLE_OFFSET..TITLE_OFFSET + 9].copy_from_slice(b"TEST GAME");
+        rom[GAME_CODE_OFFSET..MAKER_CODE_OFFSET].copy_from_slice(b"ATSE");
+        rom[MAKER_CODE_OFFSET..FIXED_VALUE_OFFSET].copy_from_slice(b"01");
+        rom[FIXED_VALUE_OFFSET] = FIXED_VALUE;
+        rom[VERSION_OFFSET] = 2;
+        rom[CHECKSUM_OFFSET] = header_checksum(&rom);
+        rom
+    }
+
+    #[test]
+    fn test_valid_header() {
+        let header = CartHeader::parse(&make_rom());
+        assert_eq!(
+            header,
+            CartHeader {
+                title: "TEST GAME".to_string(),
+                game_code: "ATSE".to_string(),
+                maker_code: "01".to_string(),
+                version: 2,
+                problems: vec![],
+            }
+        );
+        assert!(header.is_valid());
+        assert_eq!(
+            header.to_string(),
+            "\"TEST GAME\" (AGB-ATSE, maker 01, version 2)"
+        );
+    }
+
+    #[test]
+    fn test_bad_headers() {
+        assert_eq!(
+            CartHeader::parse(&[0; 0x20]).problems,
+            [HeaderProblem::TooShort(0x20)]
+        );
+
+        let mut rom = make_rom();
+        rom[LOGO_OFFSET + 100] ^= 0xFF;
+        assert_eq!(CartHeader::parse(&rom).problems, [HeaderProblem::BadLogo]);
+
+        let mut rom = make_rom();
+        rom[FIXED_VALUE_OFFSET] = 0x00;
+        rom[CHECKSUM_OFFSET] = header_checksum(&rom);
+        assert_eq!(
+            CartHeader::parse(&rom).problems,
+            [HeaderProblem::BadFixedValue(0x00)]
+        );
+
+        let mut rom = make_rom();
+        let expected = rom[CHECKSUM_OFFSET];
+        rom[CHECKSUM_OFFSET] = expected.wrapping_add(1);
+        assert_eq!(
+            CartHeader::parse(&rom).problems,
+            [HeaderProblem::BadChecksum {
+                expected,
+                actual: expected.wrapping_add(1),
+            }]
+        );
+
+        // Changing the title without updating the checksum invalidates it
+        let mut rom = make_rom();
+        rom[TITLE_OFFSET] = b'X';
+        let header = CartHeader::parse(&rom);
+        assert_eq!(header.title, "XEST GAME");
+        assert!(!header.is_valid());
+    }
+
+    #[test]
+    fn test_detect_save_chip() {
+        let mut rom = make_rom();
+        assert_eq!(detect_save_chip(&rom), None);
+
+        let with_id = |rom: &[u8], id: &[u8]| {
+            let mut rom = rom.to_vec();
+            rom.extend_from_slice(id);
+            rom
+        };
+        assert_eq!(
+            detect_save_chip(&with_id(&rom, b"FLASH1M_V103")),
+            Some(SaveChip::Flash128K)
+        );
+        assert_eq!(
+            detect_save_chip(&with_id(&rom, b"SRAM_F_V100")),
+            Some(SaveChip::Sram)
+        );
+        assert_eq!(
+            detect_save_chip(&with_id(&rom, b"EEPROM_V124")),
+            Some(SaveChip::Eeprom)
+        );
+        // Only word aligned IDs count
+        rom.push(0);
+        assert_eq!(detect_save_chip(&with_id(&rom, b"SRAM_V113")), None);
+    }
+}
@@ -0,0 +1,504 @@
+//! Cheat codes, applied as RAM patches once per frame.
+//!
+//! Codes are written one per line, optionally prefixed by their format:
+//!
+//! - `raw: AAAAAAAA:VV` (or without prefix) writes VV to address AAAAAAAA. The number of value
+//!   digits (2, 4 or 8) selects the write width.
+//! - `gs1: XXXXXXXX YYYYYYYY` is an encrypted GameShark v1/v2 code.
+//! - `gs3: XXXXXXXX YYYYYYYY` is an encrypted GameShark v3 (Action Replay v3) code.
+//!
+//! Multiple codes can be given on the same line, separated by spaces, to form a single cheat.
+//! Conditional codes apply to the code that follows them in the same cheat.
+
+use hle::HleMemory;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use system::AccessWidth;
+
+const GAMESHARK_V1_SEEDS: [u32; 4] = [0x09F4FBBD, 0x9681884A, 0x352027E9, 0xF3DEE5A7];
+const GAMESHARK_V3_SEEDS: [u32; 4] = [0x7AA9648F, 0x7FAE6994, 0xC0EFAAD5, 0x42712C57];
+
+const TEA_DELTA: u32 = 0x9E3779B9;
+
+/// GameShark codes are encrypted using TEA, with a different key for each version.
+pub fn gameshark_decrypt(mut address: u32, mut value: u32, seeds: &[u32; 4]) -> (u32, u32) {
+    let mut sum = TEA_DELTA.wrapping_mul(32);
+    for _ in 0..32 {
+        value = value.wrapping_sub(
+            (address << 4).wrapping_add(seeds[2])
+                ^ address.wrapping_add(sum)
+                ^ (address >> 5).wrapping_add(seeds[3]),
+        );
+        address = address.wrapping_sub(
+            (value << 4).wrapping_add(seeds[0])
+                ^ value.wrapping_add(sum)
+                ^ (value >> 5).wrapping_add(seeds[1]),
+        );
+        sum = sum.wrapping_sub(TEA_DELTA);
+    }
+    (address, value)
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum CheatError {
+    Syntax(String),
+    /// The code decrypted correctly, but its type isn't supported. Contains the decrypted code.
+    UnsupportedCode(u32, u32),
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CheatError::Syntax(ref message) => write!(f, "invalid cheat: {}", message),
+            CheatError::UnsupportedCode(address, value) => {
+                write!(f, "unsupported code: {:08X} {:08X}", address, value)
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CheatOp {
+    Write {
+        address: u32,
+        width: AccessWidth,
+        value: u32,
+    },
+    /// The next op is only executed if the value in memory is (or isn't, if `negate`) equal to
+    /// `value`.
+    IfEqual {
+        address: u32,
+        width: AccessWidth,
+        value: u32,
+        negate: bool,
+    },
+}
+
+fn parse_hex(text: &str) -> Result<u32, CheatError> {
+    u32::from_str_radix(text, 16).map_err(|_| CheatError::Syntax(format!("bad number: {}", text)))
+}
+
+fn parse_raw_code(code: &str) -> Result<CheatOp, CheatError> {
+    let mut parts = code.splitn(2, ':');
+    let address = parse_hex(parts.next().unwrap())?;
+    let value_text = parts
+        .next()
+        .ok_or_else(|| CheatError::Syntax(format!("missing value: {}", code)))?;
+    let width = match value_text.len() {
+        2 => AccessWidth::Bit8,
+        4 => AccessWidth::Bit16,
+        8 => AccessWidth::Bit32,
+        _ => return Err(CheatError::Syntax(format!("bad value width: {}", code))),
+    };
+    Ok(CheatOp::Write {
+        address,
+        width,
+        value: parse_hex(value_text)?,
+    })
+}
+
+fn decode_gameshark_v1(address: u32, value: u32) -> Result<CheatOp, CheatError> {
+    let target = address & 0x0FFF_FFFF;
+    match address >> 28 {
+        0x0 => Ok(CheatOp::Write {
+            address: target,
+            width: AccessWidth::Bit8,
+            value: value & 0xFF,
+        }),
+        0x1 => Ok(CheatOp::Write {
+            address: target,
+            width: AccessWidth::Bit16,
+            value: value & 0xFFFF,
+        }),
+        0x2 => Ok(CheatOp::Write {
+            address: target,
+            width: AccessWidth::Bit32,
+            value,
+        }),
+        0xD => Ok(CheatOp::IfEqual {
+            address: target,
+            width: AccessWidth::Bit16,
+            value: value & 0xFFFF,
+            negate: false,
+        }),
+        _ => Err(CheatError::UnsupportedCode(address, value)),
+    }
+}
+
+fn decode_gameshark_v3(address: u32, value: u32) -> Result<CheatOp, CheatError> {
+    // Addresses are packed into 24 bits, with the region in bits 20-23
+    let target = (address & 0x000F_FFFF) | (address << 4) & 0x0F00_0000;
+    let width = match bit!(address[25:26]) {
+        0 => AccessWidth::Bit8,
+        1 => AccessWidth::Bit16,
+        2 => AccessWidth::Bit32,
+        _ => return Err(CheatError::UnsupportedCode(address, value)),
+    };
+    let value = match width {
+        AccessWidth::Bit8 => value & 0xFF,
+        AccessWidth::Bit16 => value & 0xFFFF,
+        AccessWidth::Bit32 => value,
+    };
+
+    // Only plain writes, and equality conditions affecting the next code are supported
+    match (bit!(address[27:29]), bit!(address[30:31])) {
+        _ if address == 0 => Err(CheatError::UnsupportedCode(address, value)),
+        (0, 0) => Ok(CheatOp::Write {
+            address: target,
+            width,
+            value,
+        }),
+        (condition @ 1..=2, 0) => Ok(CheatOp::IfEqual {
+            address: target,
+            width,
+            value,
+            negate: condition == 2,
+        }),
+        _ => Err(CheatError::UnsupportedCode(address, value)),
+    }
+}
+
+/// A cheat made up of one or more codes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Cheat {
+    /// The text the cheat was parsed from.
+    pub source: String,
+    pub ops: Vec<CheatOp>,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    pub fn parse(line: &str) -> Result<Cheat, CheatError> {
+        let line = line.trim();
+        let (format, codes) = match line.find(": ") {
+            Some(i) => (&line[..i], &line[i + 2..]),
+            None => ("raw", line),
+        };
+
+        let words: Vec<&str> = codes.split_whitespace().collect();
+        let ops = match format {
+            "raw" => words
+                .iter()
+                .map(|code| parse_raw_code(code))
+                .collect::<Result<Vec<_>, _>>()?,
+            "gs1" | "gs3" => {
+                if words.is_empty() || words.len() % 2 != 0 {
+                    return Err(CheatError::Syntax(format!("incomplete code: {}", line)));
+                }
+                let mut ops = Vec::new();
+                for pair in words.chunks(2) {
+                    let (address, value) = (parse_hex(pair[0])?, parse_hex(pair[1])?);
+                    ops.push(if format == "gs1" {
+                        let (address, value) =
+                            gameshark_decrypt(address, value, &GAMESHARK_V1_SEEDS);
+                        decode_gameshark_v1(address, value)?
+                    } else {
+                        let (address, value) =
+                            gameshark_decrypt(address, value, &GAMESHARK_V3_SEEDS);
+                        decode_gameshark_v3(address, value)?
+                    });
+                }
+                ops
+            }
+            _ => return Err(CheatError::Syntax(format!("unknown format: {}", format))),
+        };
+
+        if ops.is_empty() {
+            return Err(CheatError::Syntax("empty cheat".to_string()));
+        }
+        Ok(Cheat {
+            source: line.to_string(),
+            ops,
+            enabled: true,
+        })
+    }
+}
+
+fn read_width(memory: &mut HleMemory, address: u32, width: AccessWidth) -> u32 {
+    match width {
+        AccessWidth::Bit8 => memory.read_u8(address) as u32,
+        AccessWidth::Bit16 => memory.read_u16(address) as u32,
+        AccessWidth::Bit32 => memory.read_u32(address),
+    }
+}
+
+fn write_width(memory: &mut HleMemory, address: u32, width: AccessWidth, value: u32) {
+    match width {
+        AccessWidth::Bit8 => memory.write_u8(address, value as u8),
+        AccessWidth::Bit16 => memory.write_u16(address, value as u16),
+        AccessWidth::Bit32 => memory.write_u32(address, value),
+    }
+}
+
+#[derive(Default)]
+pub struct CheatEngine {
+    cheats: Vec<Cheat>,
+}
+
+impl CheatEngine {
+    pub fn new() -> CheatEngine {
+        CheatEngine { cheats: Vec::new() }
+    }
+
+    pub fn add(&mut self, cheat: Cheat) {
+        self.cheats.push(cheat);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Cheat {
+        self.cheats.remove(index)
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn cheats_mut(&mut self) -> &mut [Cheat] {
+        &mut self.cheats
+    }
+
+    /// Loads cheats from a file with one cheat per line. Empty lines and lines starting with `#`
+    /// are skipped. Invalid cheats are reported and skipped. Returns the number of cheats loaded.
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<usize> {
+        let text = fs::read_to_string(path)?;
+        let mut loaded = 0;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match Cheat::parse(line) {
+                Ok(cheat) => {
+                    self.add(cheat);
+                    loaded += 1;
+                }
+                Err(e) => println!("Line {}: {}", i + 1, e),
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// Applies all enabled cheats, in the order they were added.
+    pub fn apply(&self, memory: &mut HleMemory) {
+        for cheat in self.cheats.iter().filter(|cheat| cheat.enabled) {
+            let mut skip_next = false;
+            for op in cheat.ops.iter() {
+                if skip_next {
+                    skip_next = false;
+                    continue;
+                }
+                match *op {
+                    CheatOp::Write {
+                        address,
+                        width,
+                        value,
+                    } => write_width(memory, address, width, value),
+                    CheatOp::IfEqual {
+                        address,
+                        width,
+                        value,
+                        negate,
+                    } => {
+                        let equal = read_width(memory, address, width) == value;
+                        skip_next = equal == negate;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gameshark_encrypt(mut address: u32, mut value: u32, seeds: &[u32; 4]) -> (u32, u32) {
+        let mut sum = 0u32;
+        for _ in 0..32 {
+            sum = sum.wrapping_add(TEA_DELTA);
+            address = address.wrapping_add(
+                (value << 4).wrapping_add(seeds[0])
+                    ^ value.wrapping_add(sum)
+                    ^ (value >> 5).wrapping_add(seeds[1]),
+            );
+            value = value.wrapping_add(
+                (address << 4).wrapping_add(seeds[2])
+                    ^ address.wrapping_add(sum)
+                    ^ (address >> 5).wrapping_add(seeds[3]),
+            );
+        }
+        (address, value)
+    }
+
+    fn encrypted(address: u32, value: u32, seeds: &[u32; 4]) -> String {
+        let (address, value) = gameshark_encrypt(address, value, seeds);
+        format!("{:08X} {:08X}", address, value)
+    }
+
+    struct TestMemory(Vec<u8>);
+
+    impl HleMemory for TestMemory {
+        fn read_u8(&mut self, address: u32) -> u8 {
+            self.0[(address - 0x0200_0000) as usize]
+        }
+
+        fn write_u8(&mut self, address: u32, data: u8) {
+            self.0[(address - 0x0200_0000) as usize] = data;
+        }
+    }
+
+    #[test]
+    fn test_gameshark_decrypt() {
+        // Master code for Pokemon Emerald (U): the game ID ("BPEE") and the v3 ID marker
+        assert_eq!(
+            gameshark_decrypt(0xA86CDBA5, 0x19BA49B3, &GAMESHARK_V3_SEEDS),
+            (0x4545_5042, 0x001D_C0DE)
+        );
+        assert_eq!(
+            gameshark_decrypt(0xD8BAE4D9, 0x4864DCE5, &GAMESHARK_V3_SEEDS),
+            (0xC400_05EC, 0x0000_8401)
+        );
+
+        for seeds in [GAMESHARK_V1_SEEDS, GAMESHARK_V3_SEEDS].iter() {
+            let (address, value) = gameshark_encrypt(0x1200_1234, 0x0000_5678, seeds);
+            assert_ne!((address, value), (0x1200_1234, 0x0000_5678));
+            assert_eq!(
+                gameshark_decrypt(address, value, seeds),
+                (0x1200_1234, 0x0000_5678)
+            );
+        }
+        // Different versions use different keys
+        let (address, value) = gameshark_encrypt(0x1200_1234, 0x0000_5678, &GAMESHARK_V1_SEEDS);
+        assert_ne!(
+            gameshark_decrypt(address, value, &GAMESHARK_V3_SEEDS),
+            (0x1200_1234, 0x0000_5678)
+        );
+    }
+
+    #[test]
+    fn test_parse_raw() {
+        let cheat = Cheat::parse("raw: 02000010:7F 03000020:BEEF 02000000:12345678").unwrap();
+        assert_eq!(
+            cheat.ops,
+            [
+                CheatOp::Write {
+                    address: 0x0200_0010,
+                    width: AccessWidth::Bit8,
+                    value: 0x7F,
+                },
+                CheatOp::Write {
+                    address: 0x0300_0020,
+                    width: AccessWidth::Bit16,
+                    value: 0xBEEF,
+                },
+                CheatOp::Write {
+                    address: 0x0200_0000,
+                    width: AccessWidth::Bit32,
+                    value: 0x1234_5678,
+                },
+            ]
+        );
+
+        assert!(Cheat::parse("02000010:123").is_err());
+        assert!(Cheat::parse("02000010").is_err());
+        assert!(Cheat::parse("xyz: 02000010:12").is_err());
+    }
+
+    #[test]
+    fn test_parse_gameshark_v1() {
+        let line = format!(
+            "gs1: {} {} {}",
+            encrypted(0x0200_0010, 0x0000_0063, &GAMESHARK_V1_SEEDS),
+            encrypted(0xD300_0020, 0x0000_0001, &GAMESHARK_V1_SEEDS),
+            encrypted(0x2300_0040, 0xCAFE_F00D, &GAMESHARK_V1_SEEDS)
+        );
+        let cheat = Cheat::parse(&line).unwrap();
+        assert_eq!(
+            cheat.ops,
+            [
+                CheatOp::Write {
+                    address: 0x0200_0010,
+                    width: AccessWidth::Bit8,
+                    value: 0x63,
+                },
+                CheatOp::IfEqual {
+                    address: 0x0300_0020,
+                    width: AccessWidth::Bit16,
+                    value: 1,
+                    negate: false,
+                },
+                CheatOp::Write {
+                    address: 0x0300_0040,
+                    width: AccessWidth::Bit32,
+                    value: 0xCAFE_F00D,
+                },
+            ]
+        );
+
+        let line = format!("gs1: {}", encrypted(0x8000_0000, 0, &GAMESHARK_V1_SEEDS));
+        assert_eq!(
+            Cheat::parse(&line),
+            Err(CheatError::UnsupportedCode(0x8000_0000, 0))
+        );
+        assert!(Cheat::parse("gs1: 12345678").is_err());
+    }
+
+    #[test]
+    fn test_parse_gameshark_v3() {
+        let line = format!(
+            "gs3: {} {}",
+            encrypted(0x0220_0010, 0x0000_1234, &GAMESHARK_V3_SEEDS),
+            encrypted(0x1430_0020, 0x0000_0005, &GAMESHARK_V3_SEEDS)
+        );
+        let cheat = Cheat::parse(&line).unwrap();
+        assert_eq!(
+            cheat.ops,
+            [
+                CheatOp::Write {
+                    address: 0x0200_0010,
+                    width: AccessWidth::Bit16,
+                    value: 0x1234,
+                },
+                CheatOp::IfEqual {
+                    address: 0x0300_0020,
+                    width: AccessWidth::Bit32,
+                    value: 5,
+                    negate: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut engine = CheatEngine::new();
+        engine.add(Cheat::parse("02000000:11223344").unwrap());
+        // Later cheats override earlier ones
+        engine.add(Cheat::parse("02000001:AA").unwrap());
+        // Conditional cheats: only the first condition is true
+        let condition_true = format!(
+            "gs1: {} {}",
+            encrypted(0xD200_0000, 0x0000_AA44, &GAMESHARK_V1_SEEDS),
+            encrypted(0x0200_0010, 0x0000_0001, &GAMESHARK_V1_SEEDS)
+        );
+        let condition_false = format!(
+            "gs1: {} {}",
+            encrypted(0xD200_0000, 0x0000_0000, &GAMESHARK_V1_SEEDS),
+            encrypted(0x0200_0011, 0x0000_0001, &GAMESHARK_V1_SEEDS)
+        );
+        engine.add(Cheat::parse(&condition_true).unwrap());
+        engine.add(Cheat::parse(&condition_false).unwrap());
+        let mut disabled = Cheat::parse("02000012:FF").unwrap();
+        disabled.enabled = false;
+        engine.add(disabled);
+
+        let mut memory = TestMemory(vec![0; 0x20]);
+        engine.apply(&mut memory);
+        assert_eq!(&memory.0[..4], &[0x44, 0xAA, 0x22, 0x11]);
+        assert_eq!(&memory.0[0x10..0x13], &[1, 0, 0]);
+
+        assert_eq!(engine.remove(0).source, "02000000:11223344");
+        assert_eq!(engine.cheats().len(), 4);
+    }
+}
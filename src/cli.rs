@@ -0,0 +1,604 @@
+//! Subcommands of the command line frontend that don't need a window, like `advance header`. Each
+//! one takes its parsed options and writes its report to any output, so they can be run in tests.
+
+use cart;
+use cart::CartHeader;
+use cart::SaveChip;
+use config::Config;
+use cpu::disasm::disassemble_arm;
+use cpu::disasm::disassemble_range;
+use cpu::disasm::disassemble_thumb;
+use debugger::Debugger;
+use debugger::DebuggerExit;
+use frame_sink::PngSequenceSink;
+use hle::HleMemory;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use system::System;
+
+pub const USAGE: &str = "usage: advance [options] <bios> [rom]
+       advance [options] <rom>, with a BIOS set in advance.toml or --hle-bios
+       advance run <rom> [options] [--headless <frames>] [--trace <file>]
+       advance disasm <rom> [--start <address>] [--count <instructions>] [--thumb]
+       advance header <rom>
+       advance dump <rom> --out <dir> [--frames <n>] [settings]
+       advance --write-default-config > advance.toml
+options: [--force] [--mem-stats] [--debug] [settings]
+settings, which override advance.toml: [--bios=<path>] [--save-dir=<path>] [--no-audio] [--hle-bios] [--skip-bios] [--sram-fill=<hex byte>] [--accuracy=fast|accurate] [--scale=<n>] [--color-correction[=raw|gba-lcd|gbc-lcd]] [--scaling=integer|bilinear]";
+
+const BIOS_SIZE: usize = 16 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Help,
+    Run(RunOptions),
+    Disasm(DisasmOptions),
+    Header(HeaderOptions),
+    Dump(DumpOptions),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunOptions {
+    pub rom: PathBuf,
+    pub bios: Option<PathBuf>,
+    /// Runs this many frames without a window instead of opening one.
+    pub headless_frames: Option<u64>,
+    /// Logs every instruction executed during a headless run to this file.
+    pub trace: Option<PathBuf>,
+    /// Starts in the debugger, before running anything.
+    pub debug: bool,
+    /// Opens the window even if the ROM doesn't look like a GBA ROM.
+    pub force: bool,
+    /// Counts memory accesses, and prints them when the run ends.
+    pub mem_stats: bool,
+    /// Flags overriding the settings file, as taken by `Config::apply_args`.
+    pub settings: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DisasmOptions {
+    pub rom: PathBuf,
+    pub start: u32,
+    pub count: u32,
+    pub thumb: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeaderOptions {
+    pub rom: PathBuf,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DumpOptions {
+    pub rom: PathBuf,
+    pub bios: Option<PathBuf>,
+    pub frames: u64,
+    /// Directory the frames are written to, as numbered PNGs.
+    pub out: PathBuf,
+    /// Flags overriding the settings file, as taken by `Config::apply_args`.
+    pub settings: Vec<String>,
+}
+
+/// Parses the arguments after the program name. Returns None if they don't start with a
+/// subcommand, leaving them to the options of the windowed frontend, and an error message if the
+/// subcommand is used wrong.
+pub fn parse_command(args: &[String]) -> Result<Option<Command>, String> {
+    let (name, args) = match args.split_first() {
+        Some((name, args)) => (name, args),
+        None => return Ok(None),
+    };
+    let command = match name.as_str() {
+        "help" => Command::Help,
+        "run" => Command::Run(parse_run(args)?),
+        "disasm" => Command::Disasm(parse_disasm(args)?),
+        "header" => Command::Header(parse_header(args)?),
+        "dump" => Command::Dump(parse_dump(args)?),
+        _ => return Ok(None),
+    };
+    Ok(Some(command))
+}
+
+/// Splits the arguments of a subcommand into its positional arguments, its options, and any other
+/// options, which are left as they were given. Options in `valued` take a value, either as
+/// `--name value` or `--name=value`, and `flags` don't.
+fn split_args(
+    args: &[String],
+    valued: &[&str],
+    flags: &[&str],
+) -> Result<(Vec<String>, Vec<(String, String)>, Vec<String>), String> {
+    let mut positional = Vec::new();
+    let mut options = Vec::new();
+    let mut others = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg.clone());
+            continue;
+        }
+        let (name, value) = match arg.find('=') {
+            Some(equals) => (&arg[..equals], Some(arg[equals + 1..].to_string())),
+            None => (arg.as_str(), None),
+        };
+        if valued.contains(&name) {
+            let value = match value {
+                Some(value) => value,
+                None => args
+                    .next()
+                    .cloned()
+                    .ok_or_else(|| format!("{} needs a value", name))?,
+            };
+            options.push((name.to_string(), value));
+        } else if flags.contains(&name) && value.is_none() {
+            options.push((name.to_string(), String::new()));
+        } else {
+            others.push(arg.clone());
+        }
+    }
+    Ok((positional, options, others))
+}
+
+/// Rejects the options a subcommand doesn't know, for the ones that don't take settings.
+fn no_other_options(others: &[String]) -> Result<(), String> {
+    match others.first() {
+        Some(arg) => Err(format!("unknown option {}", arg)),
+        None => Ok(()),
+    }
+}
+
+/// Checks that the options a subcommand doesn't know are settings, with valid values, so that
+/// they can be applied over the settings file later.
+fn setting_options(others: Vec<String>) -> Result<Vec<String>, String> {
+    if let Some(arg) = others.iter().find(|arg| !Config::is_setting_flag(arg)) {
+        return Err(format!("unknown option {}", arg));
+    }
+    Config::default().apply_args(&others)?;
+    Ok(others)
+}
+
+/// Applies the BIOS and settings given to `run` or `dump` over the ones in the settings file.
+pub fn override_settings(
+    file_settings: &Config,
+    bios: Option<&Path>,
+    settings: &[String],
+) -> Result<Config, String> {
+    let mut config = file_settings.clone();
+    config.apply_args(settings)?;
+    if let Some(bios) = bios {
+        config.bios = Some(bios.to_path_buf());
+    }
+    Ok(config)
+}
+
+/// The ROM path, which is the only positional argument of every subcommand.
+fn single_rom(positional: Vec<String>) -> Result<PathBuf, String> {
+    match positional.len() {
+        0 => Err("missing the ROM path".to_string()),
+        1 => Ok(PathBuf::from(&positional[0])),
+        _ => Err(format!("unexpected argument {}", positional[1])),
+    }
+}
+
+/// Parses a decimal or `0x` prefixed hexadecimal number.
+fn parse_number(option: &str, text: &str) -> Result<u64, String> {
+    let parsed = if text.starts_with("0x") || text.starts_with("0X") {
+        u64::from_str_radix(&text[2..], 16)
+    } else {
+        text.parse()
+    };
+    parsed.map_err(|_| format!("{} should be a number, not {:?}", option, text))
+}
+
+fn parse_u32(option: &str, text: &str) -> Result<u32, String> {
+    let number = parse_number(option, text)?;
+    if number > u32::max_value() as u64 {
+        return Err(format!("{} is out of range: {}", option, text));
+    }
+    Ok(number as u32)
+}
+
+fn parse_run(args: &[String]) -> Result<RunOptions, String> {
+    let (positional, options, others) = split_args(
+        args,
+        &["--bios", "--headless", "--trace"],
+        &["--debug", "--force", "--mem-stats"],
+    )?;
+    let mut run = RunOptions {
+        rom: single_rom(positional)?,
+        bios: None,
+        headless_frames: None,
+        trace: None,
+        debug: false,
+        force: false,
+        mem_stats: false,
+        settings: setting_options(others)?,
+    };
+    for (name, value) in options {
+        match name.as_str() {
+            "--bios" => run.bios = Some(PathBuf::from(value)),
+            "--headless" => run.headless_frames = Some(parse_number(&name, &value)?),
+            "--trace" => run.trace = Some(PathBuf::from(value)),
+            "--debug" => run.debug = true,
+            "--force" => run.force = true,
+            "--mem-stats" => run.mem_stats = true,
+            _ => unreachable!(),
+        }
+    }
+    if run.trace.is_some() && run.headless_frames.is_none() {
+        return Err("--trace only works with --headless".to_string());
+    }
+    Ok(run)
+}
+
+fn parse_disasm(args: &[String]) -> Result<DisasmOptions, String> {
+    let (positional, options, others) = split_args(args, &["--start", "--count"], &["--thumb"])?;
+    no_other_options(&others)?;
+    let mut disasm = DisasmOptions {
+        rom: single_rom(positional)?,
+        start: 0x0800_0000,
+        count: 100,
+        thumb: false,
+    };
+    for (name, value) in options {
+        match name.as_str() {
+            "--start" => disasm.start = parse_u32(&name, &value)?,
+            "--count" => disasm.count = parse_u32(&name, &value)?,
+            "--thumb" => disasm.thumb = true,
+            _ => unreachable!(),
+        }
+    }
+    Ok(disasm)
+}
+
+fn parse_header(args: &[String]) -> Result<HeaderOptions, String> {
+    let (positional, _, others) = split_args(args, &[], &[])?;
+    no_other_options(&others)?;
+    Ok(HeaderOptions {
+        rom: single_rom(positional)?,
+    })
+}
+
+fn parse_dump(args: &[String]) -> Result<DumpOptions, String> {
+    let (positional, options, others) = split_args(args, &["--bios", "--frames", "--out"], &[])?;
+    let rom = single_rom(positional)?;
+    let mut bios = None;
+    let mut frames = 1;
+    let mut out = None;
+    for (name, value) in options {
+        match name.as_str() {
+            "--bios" => bios = Some(PathBuf::from(value)),
+            "--frames" => frames = parse_number(&name, &value)?,
+            "--out" => out = Some(PathBuf::from(value)),
+            _ => unreachable!(),
+        }
+    }
+    Ok(DumpOptions {
+        rom,
+        bios,
+        frames,
+        out: out.ok_or_else(|| "missing --out".to_string())?,
+        settings: setting_options(others)?,
+    })
+}
+
+/// Loads the ROM, and the BIOS in the settings if there's one. Without a BIOS, its functions are
+/// run with HLE.
+fn load_system(rom: &Path, settings: &Config) -> io::Result<System> {
+    let bios = match settings.bios {
+        Some(ref path) => {
+            let bios = fs::read(path)?;
+            if bios.len() != BIOS_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} isn't a {} byte BIOS", path.display(), BIOS_SIZE),
+                ));
+            }
+            bios
+        }
+        None => Vec::new(),
+    };
+    Ok(System::with_config(
+        &bios,
+        &fs::read(rom)?,
+        &settings.system_config(),
+    ))
+}
+
+/// Runs a number of frames without presenting them anywhere, optionally logging each instruction.
+/// `settings` should already have the options of the command applied, with `override_settings`.
+pub fn run_headless(options: &RunOptions, settings: &Config, out: &mut Write) -> io::Result<()> {
+    let mut system = load_system(&options.rom, settings)?;
+    system.attach_save_file(settings.save_path(&options.rom))?;
+    if options.mem_stats {
+        system.memory.borrow_mut().set_stats_enabled(true);
+    }
+    if options.debug {
+        let stdin = io::stdin();
+        let exit = Debugger::new().run(&mut system, &mut stdin.lock(), out)?;
+        if exit == DebuggerExit::Quit {
+            return Ok(());
+        }
+    }
+    let frames = options.headless_frames.unwrap_or(0);
+    match options.trace {
+        Some(ref trace_path) => {
+            let mut trace = BufWriter::new(File::create(trace_path)?);
+            while system.ppu.borrow().frame_count() < frames {
+                system.step_instruction();
+                trace_instruction(&system, &mut trace)?;
+            }
+            trace.flush()?;
+        }
+        None => {
+            for _ in 0..frames {
+                system.step_frame();
+            }
+        }
+    }
+    system.flush_save_file()?;
+
+    let cpu = system.cpu.borrow();
+    writeln!(
+        out,
+        "Ran {} frames, stopped at PC 0x{:08X} with CPSR {}",
+        frames,
+        cpu.regs()[15],
+        cpu.cpsr()
+    )?;
+    if let Some(stats) = system.memory.borrow().access_stats() {
+        write!(out, "{}", stats)?;
+    }
+    Ok(())
+}
+
+/// Logs the instruction the CPU is about to execute, with its address and registers.
+fn trace_instruction(system: &System, trace: &mut Write) -> io::Result<()> {
+    let cpu = system.cpu.borrow();
+    let regs = cpu.regs();
+    let thumb = cpu.cpsr().thumb();
+    let address = cpu.next_instruction_address();
+
+    let mut memory = system.memory.borrow_mut();
+    let text = if thumb {
+        let instr = memory.read_u16(address);
+        let next_instr = memory.read_u16(address.wrapping_add(2));
+        disassemble_thumb(instr, next_instr, address)
+    } else {
+        disassemble_arm(memory.read_u32(address), address)
+    };
+    write!(trace, "{:08X}  {:<32}", address, text)?;
+    for (i, reg) in regs[..15].iter().enumerate() {
+        write!(trace, " r{}={:08X}", i, reg)?;
+    }
+    writeln!(trace)
+}
+
+pub fn disasm(options: &DisasmOptions, out: &mut Write) -> io::Result<()> {
+    let system = load_system(&options.rom, &Config::default())?;
+    let instr_size = if options.thumb { 2 } else { 4 };
+    let end = options
+        .start
+        .wrapping_add(options.count.wrapping_mul(instr_size));
+    let lines = disassemble_range(
+        &mut *system.memory.borrow_mut(),
+        options.start,
+        end,
+        options.thumb,
+    );
+    for (address, text) in lines {
+        writeln!(out, "{:08X}  {}", address, text)?;
+    }
+    Ok(())
+}
+
+/// Prints the cart header, any problems with it, and the save memory the cart seems to use.
+pub fn header(options: &HeaderOptions, out: &mut Write) -> io::Result<()> {
+    let rom = fs::read(&options.rom)?;
+    let header = CartHeader::parse(&rom);
+    writeln!(out, "{}", header)?;
+    for problem in header.problems.iter() {
+        writeln!(out, "Bad cart header: {}", problem)?;
+    }
+    match cart::detect_save_chip(&rom) {
+        Some(SaveChip::Sram) => writeln!(out, "Save type: {}", SaveChip::Sram.name()),
+        Some(chip) => writeln!(out, "Save type: {} (not emulated)", chip.name()),
+        None => writeln!(out, "Save type: none detected"),
+    }
+}
+
+/// Runs a number of frames, writing each one as a PNG in the output directory. `settings` should
+/// already have the options of the command applied, with `override_settings`.
+pub fn dump(options: &DumpOptions, settings: &Config, out: &mut Write) -> io::Result<()> {
+    let mut system = load_system(&options.rom, settings)?;
+    fs::create_dir_all(&options.out)?;
+    system.set_frame_sink(Box::new(PngSequenceSink::new(&options.out)));
+    for _ in 0..options.frames {
+        system.step_frame();
+    }
+    writeln!(
+        out,
+        "Wrote {} frames to {}",
+        options.frames,
+        options.out.display()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn args(text: &str) -> Vec<String> {
+        text.split_whitespace().map(|arg| arg.to_string()).collect()
+    }
+
+    /// Writes a ROM that loops forever at its entry point to a temporary file.
+    fn write_idle_rom(name: &str) -> PathBuf {
+        let mut rom = vec![0xFE, 0xFF, 0xFF, 0xEA]; // b .
+        rom.resize(cart::HEADER_SIZE, 0);
+        rom.extend_from_slice(b"SRAM_V113");
+        let path = env::temp_dir().join(format!("advance_cli_{}.gba", name));
+        fs::write(&path, &rom).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command(&args("bios.bin game.gba")), Ok(None));
+        assert_eq!(parse_command(&args("help")), Ok(Some(Command::Help)));
+        assert_eq!(
+            parse_command(&args("run game.gba --bios=bios.bin --headless 10 --debug")),
+            Ok(Some(Command::Run(RunOptions {
+                rom: PathBuf::from("game.gba"),
+                bios: Some(PathBuf::from("bios.bin")),
+                headless_frames: Some(10),
+                trace: None,
+                debug: true,
+                force: false,
+                mem_stats: false,
+                settings: vec![],
+            })))
+        );
+        assert_eq!(
+            parse_command(&args("run game.gba --hle-bios --mem-stats --scale=2")),
+            Ok(Some(Command::Run(RunOptions {
+                rom: PathBuf::from("game.gba"),
+                bios: None,
+                headless_frames: None,
+                trace: None,
+                debug: false,
+                force: false,
+                mem_stats: true,
+                settings: args("--hle-bios --scale=2"),
+            })))
+        );
+        assert_eq!(
+            parse_command(&args(
+                "disasm game.gba --start 0x08000100 --count=8 --thumb"
+            )),
+            Ok(Some(Command::Disasm(DisasmOptions {
+                rom: PathBuf::from("game.gba"),
+                start: 0x0800_0100,
+                count: 8,
+                thumb: true,
+            })))
+        );
+        assert_eq!(
+            parse_command(&args("dump --out frames/ game.gba")),
+            Ok(Some(Command::Dump(DumpOptions {
+                rom: PathBuf::from("game.gba"),
+                bios: None,
+                frames: 1,
+                out: PathBuf::from("frames/"),
+                settings: vec![],
+            })))
+        );
+
+        for bad in &[
+            "header",
+            "header a.gba b.gba",
+            "header a.gba --verbose",
+            "disasm a.gba --start",
+            "disasm a.gba --start=zero",
+            "disasm a.gba --thumb=yes",
+            "dump a.gba",
+            "run a.gba --trace trace.txt",
+            "run a.gba --verbose",
+            "run a.gba --scale=0",
+            "disasm a.gba --hle-bios",
+            "dump a.gba --out frames/ --debug",
+        ] {
+            assert!(parse_command(&args(bad)).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_override_settings() {
+        let mut file_settings = Config::default();
+        file_settings.bios = Some(PathBuf::from("file_bios.bin"));
+        file_settings.sram_fill = 0x00;
+
+        let settings = override_settings(&file_settings, None, &args("--skip-bios")).unwrap();
+        assert_eq!(settings.bios, Some(PathBuf::from("file_bios.bin")));
+        assert!(settings.system_config().skip_bios_boot);
+        assert_eq!(settings.sram_fill, 0x00);
+
+        let bios = Path::new("bios.bin");
+        let settings = override_settings(&file_settings, Some(bios), &args("--bios=other.bin"));
+        assert_eq!(settings.unwrap().bios, Some(PathBuf::from("bios.bin")));
+    }
+
+    #[test]
+    fn test_disasm() {
+        let rom = write_idle_rom("disasm");
+        let mut out = Vec::new();
+        let options = DisasmOptions {
+            rom,
+            start: 0x0800_0000,
+            count: 2,
+            thumb: false,
+        };
+        disasm(&options, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "08000000  b $08000000\n08000004  andeq r0, r0, r0\n"
+        );
+    }
+
+    #[test]
+    fn test_header() {
+        let rom = write_idle_rom("header");
+        let mut out = Vec::new();
+        header(&HeaderOptions { rom }, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Bad cart header: Nintendo logo doesn't match\n"));
+        assert!(out.ends_with("Save type: SRAM\n"));
+    }
+
+    #[test]
+    fn test_run_headless_and_dump() {
+        let rom = write_idle_rom("run");
+        let trace = env::temp_dir().join("advance_cli_trace.txt");
+        let mut out = Vec::new();
+        let options = RunOptions {
+            rom: rom.clone(),
+            bios: None,
+            headless_frames: Some(1),
+            trace: Some(trace.clone()),
+            debug: false,
+            force: false,
+            mem_stats: false,
+            settings: vec![],
+        };
+        run_headless(&options, &Config::default(), &mut out).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .starts_with("Ran 1 frames, stopped at PC 0x08000008"));
+        let trace = fs::read_to_string(trace).unwrap();
+        assert!(trace.starts_with("08000000  b $08000000"));
+
+        let mut out = Vec::new();
+        let options = RunOptions {
+            trace: None,
+            mem_stats: true,
+            ..options
+        };
+        run_headless(&options, &Config::default(), &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("wait cycles"));
+
+        let frames_dir = env::temp_dir().join("advance_cli_frames");
+        let options = DumpOptions {
+            rom,
+            bios: None,
+            frames: 1,
+            out: frames_dir.clone(),
+            settings: vec![],
+        };
+        dump(&options, &Config::default(), &mut Vec::new()).unwrap();
+        assert!(frames_dir.join("frame_000000.png").exists());
+    }
+}
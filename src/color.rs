@@ -0,0 +1,218 @@
+/// Expands a 5-bit color channel to 8 bits, replicating the top bits so that the full output range
+/// is used.
+#[inline]
+pub fn expand_channel(c: u16) -> u8 {
+    ((c << 3) | (c >> 2)) as u8
+}
+
+/// Converts a BGR555 color to XRGB8888, without any color correction.
+pub fn bgr555_to_rgb888(bgr555: u16) -> u32 {
+    let r = expand_channel(bit!(bgr555[0:4])) as u32;
+    let g = expand_channel(bit!(bgr555[5:9])) as u32;
+    let b = expand_channel(bit!(bgr555[10:14])) as u32;
+    r << 16 | g << 8 | b
+}
+
+/// Converts a BGR555 color to RGB565. The extra green bit is filled in by replicating the top bit,
+/// so that white stays white.
+pub fn bgr555_to_rgb565(bgr555: u16) -> u16 {
+    let r = bit!(bgr555[0:4]);
+    let g = bit!(bgr555[5:9]);
+    let b = bit!(bgr555[10:14]);
+    r << 11 | g << 6 | (g >> 4) << 5 | b
+}
+
+/// Gamma of the GBA's LCD panel, which is much darker than a typical monitor.
+const LCD_GAMMA: f64 = 4.0;
+const OUTPUT_GAMMA: f64 = 2.2;
+
+/// Approximates how a BGR555 color looks on the GBA's LCD. This is the commonly used color
+/// emulation formula from higan, which darkens the image and bleeds some of each channel into the
+/// others.
+pub fn correct_color_gba_lcd(bgr555: u16) -> [u8; 3] {
+    let linearize = |x: u16| (x as f64 / 31.0).powf(LCD_GAMMA);
+    let lr = linearize(bit!(bgr555[0:4]));
+    let lg = linearize(bit!(bgr555[5:9]));
+    let lb = linearize(bit!(bgr555[10:14]));
+
+    let encode = |x: f64| {
+        let value = (x / 255.0).powf(1.0 / OUTPUT_GAMMA) * (255.0 * 255.0 / 280.0);
+        value.round().min(255.0) as u8
+    };
+    [
+        encode(0.0 * lb + 50.0 * lg + 255.0 * lr),
+        encode(30.0 * lb + 230.0 * lg + 10.0 * lr),
+        encode(220.0 * lb + 10.0 * lg + 50.0 * lr),
+    ]
+}
+
+/// Approximates how a BGR555 color looks on the GBC's LCD, using higan's formula. It's less
+/// saturated than the raw colors, but not as dark as the GBA's screen.
+pub fn correct_color_gbc_lcd(bgr555: u16) -> [u8; 3] {
+    let r = bit!(bgr555[0:4]) as u32;
+    let g = bit!(bgr555[5:9]) as u32;
+    let b = bit!(bgr555[10:14]) as u32;
+
+    let encode = |x: u32| (x.min(960) >> 2) as u8;
+    [
+        encode(r * 26 + g * 4 + b * 2),
+        encode(g * 24 + b * 8),
+        encode(r * 6 + g * 4 + b * 22),
+    ]
+}
+
+/// Color correction filters that can be applied when displaying frames.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ColorCorrection {
+    /// Colors are displayed as-is, which looks oversaturated compared to the real screens.
+    Raw,
+    GbaLcd,
+    GbcLcd,
+}
+
+impl ColorCorrection {
+    pub const ALL: [ColorCorrection; 3] = [
+        ColorCorrection::Raw,
+        ColorCorrection::GbaLcd,
+        ColorCorrection::GbcLcd,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ColorCorrection::Raw => "raw",
+            ColorCorrection::GbaLcd => "gba-lcd",
+            ColorCorrection::GbcLcd => "gbc-lcd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ColorCorrection> {
+        ColorCorrection::ALL
+            .iter()
+            .cloned()
+            .find(|mode| mode.name() == name)
+    }
+
+    /// The mode after this one, for cycling through all of them.
+    pub fn next(self) -> ColorCorrection {
+        match self {
+            ColorCorrection::Raw => ColorCorrection::GbaLcd,
+            ColorCorrection::GbaLcd => ColorCorrection::GbcLcd,
+            ColorCorrection::GbcLcd => ColorCorrection::Raw,
+        }
+    }
+
+    pub fn correct_color(self, bgr555: u16) -> [u8; 3] {
+        match self {
+            ColorCorrection::Raw => [
+                expand_channel(bit!(bgr555[0:4])),
+                expand_channel(bit!(bgr555[5:9])),
+                expand_channel(bit!(bgr555[10:14])),
+            ],
+            ColorCorrection::GbaLcd => correct_color_gba_lcd(bgr555),
+            ColorCorrection::GbcLcd => correct_color_gbc_lcd(bgr555),
+        }
+    }
+}
+
+/// Lookup table with the XRGB8888 corrected color for every BGR555 value.
+pub struct ColorCorrectionLut {
+    mode: ColorCorrection,
+    table: Vec<u32>,
+}
+
+impl ColorCorrectionLut {
+    pub fn new(mode: ColorCorrection) -> ColorCorrectionLut {
+        let table = (0..0x8000)
+            .map(|bgr555| {
+                let [r, g, b] = mode.correct_color(bgr555);
+                (r as u32) << 16 | (g as u32) << 8 | b as u32
+            })
+            .collect();
+        ColorCorrectionLut { mode, table }
+    }
+
+    pub fn mode(&self) -> ColorCorrection {
+        self.mode
+    }
+
+    #[inline]
+    pub fn convert(&self, bgr555: u16) -> u32 {
+        self.table[(bgr555 & 0x7FFF) as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bgr555_to_rgb888() {
+        assert_eq!(bgr555_to_rgb888(0x0000), 0x000000);
+        assert_eq!(bgr555_to_rgb888(0x7FFF), 0xFFFFFF);
+        assert_eq!(bgr555_to_rgb888(0x001F), 0xFF0000);
+        assert_eq!(bgr555_to_rgb888(0x03E0), 0x00FF00);
+        assert_eq!(bgr555_to_rgb888(0x7C00), 0x0000FF);
+        assert_eq!(bgr555_to_rgb888(0x4210), 0x848484);
+    }
+
+    #[test]
+    fn test_bgr555_to_rgb565() {
+        assert_eq!(bgr555_to_rgb565(0x0000), 0x0000);
+        assert_eq!(bgr555_to_rgb565(0x7FFF), 0xFFFF);
+        assert_eq!(bgr555_to_rgb565(0x001F), 0xF800);
+        assert_eq!(bgr555_to_rgb565(0x03E0), 0x07E0);
+        assert_eq!(bgr555_to_rgb565(0x7C00), 0x001F);
+        // Green without the top bit set doesn't get the extra bit
+        assert_eq!(bgr555_to_rgb565(0x01E0), 0x03C0);
+    }
+
+    #[test]
+    fn test_raw_colors() {
+        let mode = ColorCorrection::Raw;
+        assert_eq!(mode.correct_color(0x7FFF), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(mode.correct_color(0x001F), [0xFF, 0x00, 0x00]);
+        assert_eq!(mode.correct_color(0x4210), [0x84, 0x84, 0x84]);
+    }
+
+    #[test]
+    fn test_gba_lcd_colors() {
+        assert_eq!(correct_color_gba_lcd(0x0000), [0x00, 0x00, 0x00]);
+        assert_eq!(correct_color_gba_lcd(0x7FFF), [0xFC, 0xEE, 0xF2]);
+        assert_eq!(correct_color_gba_lcd(0x001F), [0xE8, 0x35, 0x6F]);
+        assert_eq!(correct_color_gba_lcd(0x7C00), [0x00, 0x58, 0xD9]);
+    }
+
+    #[test]
+    fn test_gbc_lcd_colors() {
+        assert_eq!(correct_color_gbc_lcd(0x0000), [0, 0, 0]);
+        assert_eq!(correct_color_gbc_lcd(0x7FFF), [240, 240, 240]);
+        assert_eq!(correct_color_gbc_lcd(0x001F), [201, 0, 46]);
+        assert_eq!(correct_color_gbc_lcd(0x03E0), [31, 186, 31]);
+        assert_eq!(correct_color_gbc_lcd(0x7C00), [15, 62, 170]);
+        assert_eq!(correct_color_gbc_lcd(0x4210), [128, 128, 128]);
+    }
+
+    #[test]
+    fn test_color_correction_names() {
+        for &mode in ColorCorrection::ALL.iter() {
+            assert_eq!(ColorCorrection::from_name(mode.name()), Some(mode));
+        }
+        assert_eq!(ColorCorrection::from_name("vivid"), None);
+    }
+
+    #[test]
+    fn test_color_correction() {
+        let lut = ColorCorrectionLut::new(ColorCorrection::GbaLcd);
+        assert_eq!(lut.convert(0x0000), 0x000000);
+        assert_eq!(lut.convert(0x7FFF), 0xFCEEF2);
+        assert_eq!(lut.convert(0x001F), 0xE8356F);
+        assert_eq!(lut.convert(0x03E0), 0x6FDE35);
+        assert_eq!(lut.convert(0x7C00), 0x0058D9);
+        assert_eq!(lut.convert(0x4210), 0x4C4849);
+        // The unused top bit is ignored
+        assert_eq!(lut.convert(0xFFFF), lut.convert(0x7FFF));
+
+        let lut = ColorCorrectionLut::new(ColorCorrection::GbcLcd);
+        assert_eq!(lut.convert(0x001F), 0xC9002E);
+    }
+}
@@ -0,0 +1,752 @@
+//! Frontend settings, loaded from an `advance.toml` file and overridden by command line flags.
+//!
+//! The file is written in a subset of TOML: `key = value` lines, grouped under `[section]`
+//! headers, with `#` comments. Values can be strings in double quotes, integers (decimal, or hex
+//! with `0x`), floats and booleans. Unknown keys are reported and skipped, so that files written
+//! for other versions still load.
+
+use color::ColorCorrection;
+use keypad::Button;
+use scaling::Scaling;
+use std::env;
+use std::fmt;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use system::SystemConfig;
+use system::TimingAccuracy;
+
+pub const FILE_NAME: &str = "advance.toml";
+
+/// Largest window scale accepted, which is already more than any screen can fit.
+const MAX_WINDOW_SCALE: i64 = 16;
+
+/// Flags taken by `Config::apply_args`.
+const SETTING_FLAGS: [&str; 10] = [
+    "--bios",
+    "--save-dir",
+    "--no-audio",
+    "--hle-bios",
+    "--skip-bios",
+    "--sram-fill",
+    "--accuracy",
+    "--scale",
+    "--scaling",
+    "--color-correction",
+];
+
+/// Name of each button in the `[keys]` section, and the key bound to it by default. Keys are named
+/// like SDL names scancodes.
+const BUTTON_KEYS: [(Button, &str, &str); 10] = [
+    (Button::A, "a", "X"),
+    (Button::B, "b", "Z"),
+    (Button::Select, "select", "Backspace"),
+    (Button::Start, "start", "Return"),
+    (Button::Right, "right", "Right"),
+    (Button::Left, "left", "Left"),
+    (Button::Up, "up", "Up"),
+    (Button::Down, "down", "Down"),
+    (Button::R, "r", "S"),
+    (Button::L, "l", "A"),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl Value {
+    fn as_str(&self) -> Result<&str, String> {
+        match *self {
+            Value::String(ref value) => Ok(value),
+            _ => Err("expected a string".to_string()),
+        }
+    }
+
+    fn as_bool(&self) -> Result<bool, String> {
+        match *self {
+            Value::Bool(value) => Ok(value),
+            _ => Err("expected true or false".to_string()),
+        }
+    }
+
+    fn as_integer(&self, min: i64, max: i64) -> Result<i64, String> {
+        match *self {
+            Value::Integer(value) if value >= min && value <= max => Ok(value),
+            _ => Err(format!("expected an integer from {} to {}", min, max)),
+        }
+    }
+
+    /// Integers are accepted too, as `2` is as good a multiplier as `2.0`.
+    fn as_positive_float(&self) -> Result<f64, String> {
+        match *self {
+            Value::Float(value) if value > 0.0 => Ok(value),
+            Value::Integer(value) if value > 0 => Ok(value as f64),
+            _ => Err("expected a positive number".to_string()),
+        }
+    }
+
+    /// An empty string leaves the path unset.
+    fn as_optional_path(&self) -> Result<Option<PathBuf>, String> {
+        let path = self.as_str()?;
+        Ok(if path.is_empty() {
+            None
+        } else {
+            Some(PathBuf::from(path))
+        })
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct ConfigError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// A `key = value` line. Keys in a section are prefixed by its name, like `video.scaling`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Setting {
+    pub line: usize,
+    pub key: String,
+    pub value: Value,
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Whether `text`, found after a value or header, can be ignored.
+fn is_trailing_comment(text: &str) -> bool {
+    let text = text.trim();
+    text.is_empty() || text.starts_with('#')
+}
+
+fn parse_number(word: &str) -> Option<Value> {
+    let digits = word.replace('_', "");
+    if digits.starts_with("0x") {
+        i64::from_str_radix(&digits[2..], 16)
+            .ok()
+            .map(Value::Integer)
+    } else if digits.contains(|c| c == '.' || c == 'e' || c == 'E') {
+        digits.parse().ok().map(Value::Float)
+    } else {
+        digits.parse().ok().map(Value::Integer)
+    }
+}
+
+/// Parses the value at the start of `text`, returning it and the rest of the text.
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    if text.starts_with('"') {
+        let mut value = String::new();
+        let mut chars = text.char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(value), &text[i + 1..])),
+                '\\' => match chars.next() {
+                    Some((_, '"')) => value.push('"'),
+                    Some((_, '\\')) => value.push('\\'),
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    _ => return Err("unknown escape sequence in string".to_string()),
+                },
+                c => value.push(c),
+            }
+        }
+        return Err("unterminated string".to_string());
+    }
+
+    let end = text
+        .find(|c: char| c.is_whitespace() || c == '#')
+        .unwrap_or_else(|| text.len());
+    let (word, rest) = text.split_at(end);
+    let value = match word {
+        "" => return Err("missing value".to_string()),
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => parse_number(word).ok_or_else(|| format!("invalid value: {}", word))?,
+    };
+    Ok((value, rest))
+}
+
+/// Parses the settings in `text`, in the order they're given.
+pub fn parse(text: &str) -> Result<Vec<Setting>, ConfigError> {
+    let mut settings: Vec<Setting> = Vec::new();
+    let mut section = String::new();
+    for (i, line) in text.lines().enumerate() {
+        let error = |message: String| ConfigError {
+            line: i + 1,
+            message,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            let end = line
+                .find(']')
+                .ok_or_else(|| error("unterminated section header".to_string()))?;
+            let name = line[1..end].trim();
+            if !is_bare_key(name) || !is_trailing_comment(&line[end + 1..]) {
+                return Err(error(format!("invalid section header: {}", line)));
+            }
+            section = name.to_string();
+            continue;
+        }
+
+        let equals = line
+            .find('=')
+            .ok_or_else(|| error(format!("expected key = value: {}", line)))?;
+        let key = line[..equals].trim();
+        if !is_bare_key(key) {
+            return Err(error(format!("invalid key: {}", key)));
+        }
+        let (value, rest) = parse_value(line[equals + 1..].trim()).map_err(&error)?;
+        if !is_trailing_comment(rest) {
+            return Err(error(format!(
+                "unexpected text after value: {}",
+                rest.trim()
+            )));
+        }
+
+        let key = if section.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", section, key)
+        };
+        if settings.iter().any(|setting| setting.key == key) {
+            return Err(error(format!("duplicate key: {}", key)));
+        }
+        settings.push(Setting {
+            line: i + 1,
+            key,
+            value,
+        });
+    }
+    Ok(settings)
+}
+
+/// Quotes `text` as a string value.
+fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Places the settings file is looked for, in order: next to the executable, for portable setups,
+/// and then in the platform's config directory.
+pub fn file_locations() -> Vec<PathBuf> {
+    let mut locations = Vec::new();
+    if let Ok(exe) = env::current_exe() {
+        if let Some(dir) = exe.parent() {
+            locations.push(dir.join(FILE_NAME));
+        }
+    }
+    if let Some(dir) = platform_config_dir() {
+        locations.push(dir.join("advance").join(FILE_NAME));
+    }
+    locations
+}
+
+fn platform_config_dir() -> Option<PathBuf> {
+    let home = || env::var_os("HOME").map(PathBuf::from);
+    if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        home().map(|home| home.join("Library/Application Support"))
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home().map(|home| home.join(".config")))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// BIOS loaded when only a ROM is given on the command line.
+    pub bios: Option<PathBuf>,
+    /// Directory save files are kept in. By default, they're kept next to the ROM.
+    pub save_dir: Option<PathBuf>,
+    pub audio: bool,
+
+    pub hle_bios: bool,
+    pub skip_bios_boot: bool,
+    pub sram_fill: u8,
+    pub timing_accuracy: TimingAccuracy,
+    pub clock_multiplier: f64,
+
+    /// Initial size of the window, relative to the LCD.
+    pub window_scale: u32,
+    pub scaling: Scaling,
+    pub color_correction: ColorCorrection,
+
+    /// Name of the key bound to each button.
+    pub keys: Vec<(Button, String)>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let system = SystemConfig::default();
+        Config {
+            bios: None,
+            save_dir: None,
+            audio: true,
+            hle_bios: system.hle_bios,
+            skip_bios_boot: system.skip_bios_boot,
+            sram_fill: system.sram_fill,
+            timing_accuracy: system.timing_accuracy,
+            clock_multiplier: system.clock_multiplier,
+            window_scale: 3,
+            scaling: Scaling::Integer,
+            color_correction: ColorCorrection::Raw,
+            keys: BUTTON_KEYS
+                .iter()
+                .map(|&(button, _, key)| (button, key.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the settings in `text` over the defaults. Returns them along with a warning for each
+    /// setting that isn't known, which is skipped.
+    pub fn parse(text: &str) -> Result<(Config, Vec<String>), ConfigError> {
+        let mut config = Config::default();
+        let mut warnings = Vec::new();
+        for setting in parse(text)? {
+            let known = config
+                .set(&setting.key, &setting.value)
+                .map_err(|message| ConfigError {
+                    line: setting.line,
+                    message: format!("{}: {}", setting.key, message),
+                })?;
+            if !known {
+                warnings.push(format!(
+                    "line {}: unknown setting {}",
+                    setting.line, setting.key
+                ));
+            }
+        }
+        Ok((config, warnings))
+    }
+
+    /// Loads the settings in the file at `path`, reporting unknown settings.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Config> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        let (config, warnings) = Config::parse(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })?;
+        for warning in warnings {
+            println!("{}: {}", path.display(), warning);
+        }
+        Ok(config)
+    }
+
+    /// Loads the first settings file found in `file_locations`, or the defaults if there's none.
+    pub fn load_default_file() -> io::Result<Config> {
+        match file_locations().into_iter().find(|path| path.is_file()) {
+            Some(path) => {
+                println!("Loading settings from {}", path.display());
+                Config::load(path)
+            }
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// Changes a setting, returning false if `key` isn't known.
+    fn set(&mut self, key: &str, value: &Value) -> Result<bool, String> {
+        match key {
+            "bios" => self.bios = value.as_optional_path()?,
+            "save_dir" => self.save_dir = value.as_optional_path()?,
+            "audio" => self.audio = value.as_bool()?,
+            "system.hle_bios" => self.hle_bios = value.as_bool()?,
+            "system.skip_bios_boot" => self.skip_bios_boot = value.as_bool()?,
+            "system.sram_fill" => self.sram_fill = value.as_integer(0, 0xFF)? as u8,
+            "system.timing_accuracy" => {
+                let name = value.as_str()?;
+                self.timing_accuracy = TimingAccuracy::from_name(name)
+                    .ok_or_else(|| format!("unknown timing accuracy: {}", name))?;
+            }
+            "system.clock_multiplier" => self.clock_multiplier = value.as_positive_float()?,
+            "video.window_scale" => {
+                self.window_scale = value.as_integer(1, MAX_WINDOW_SCALE)? as u32
+            }
+            "video.scaling" => {
+                let name = value.as_str()?;
+                self.scaling =
+                    Scaling::from_name(name).ok_or_else(|| format!("unknown scaling: {}", name))?;
+            }
+            "video.color_correction" => {
+                let name = value.as_str()?;
+                self.color_correction = ColorCorrection::from_name(name)
+                    .ok_or_else(|| format!("unknown color correction mode: {}", name))?;
+            }
+            _ if key.starts_with("keys.") => {
+                let name = &key["keys.".len()..];
+                let button = match BUTTON_KEYS.iter().find(|&&(_, n, _)| n == name) {
+                    Some(&(button, _, _)) => button,
+                    None => return Ok(false),
+                };
+                let key = value.as_str()?.to_string();
+                for binding in self.keys.iter_mut().filter(|binding| binding.0 == button) {
+                    binding.1 = key.clone();
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Overrides settings with the flags in `args`, which take precedence over the file. Returns
+    /// the arguments that aren't flags, in order. Flags that aren't settings are left to the
+    /// caller.
+    pub fn apply_args(&mut self, args: &[String]) -> Result<Vec<String>, String> {
+        let mut paths = Vec::new();
+        for arg in args {
+            if !arg.starts_with("--") {
+                paths.push(arg.clone());
+                continue;
+            }
+            let (flag, value) = match arg.find('=') {
+                Some(i) => (&arg[..i], Some(&arg[i + 1..])),
+                None => (&arg[..], None),
+            };
+            match (flag, value) {
+                ("--bios", Some(path)) => self.bios = Some(PathBuf::from(path)),
+                ("--save-dir", Some(path)) => self.save_dir = Some(PathBuf::from(path)),
+                ("--no-audio", None) => self.audio = false,
+                ("--hle-bios", None) => self.hle_bios = true,
+                ("--skip-bios", None) => self.skip_bios_boot = true,
+                ("--sram-fill", Some(value)) => {
+                    let hex = if value.starts_with("0x") {
+                        &value[2..]
+                    } else {
+                        value
+                    };
+                    self.sram_fill = u8::from_str_radix(hex, 16)
+                        .map_err(|_| format!("invalid SRAM fill byte: {}", value))?;
+                }
+                ("--accuracy", Some(name)) => {
+                    self.timing_accuracy = TimingAccuracy::from_name(name)
+                        .ok_or_else(|| format!("unknown timing accuracy: {}", name))?;
+                }
+                ("--scale", Some(value)) => {
+                    self.window_scale = value
+                        .parse()
+                        .ok()
+                        .filter(|&scale| scale >= 1 && scale <= MAX_WINDOW_SCALE as u32)
+                        .ok_or_else(|| format!("invalid window scale: {}", value))?;
+                }
+                ("--scaling", Some(name)) => {
+                    self.scaling = Scaling::from_name(name)
+                        .ok_or_else(|| format!("unknown scaling: {}", name))?;
+                }
+                ("--color-correction", None) => self.color_correction = ColorCorrection::GbaLcd,
+                ("--color-correction", Some(name)) => {
+                    self.color_correction = ColorCorrection::from_name(name)
+                        .ok_or_else(|| format!("unknown color correction mode: {}", name))?;
+                }
+                _ => {}
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Whether `arg` is one of the flags `apply_args` takes, with or without a value.
+    pub fn is_setting_flag(arg: &str) -> bool {
+        let flag = arg.split('=').next().unwrap_or(arg);
+        SETTING_FLAGS.contains(&flag)
+    }
+
+    /// Settings of the console to create.
+    pub fn system_config(&self) -> SystemConfig {
+        SystemConfig {
+            hle_bios: self.hle_bios,
+            skip_bios_boot: self.skip_bios_boot,
+            sram_fill: self.sram_fill,
+            timing_accuracy: self.timing_accuracy,
+            clock_multiplier: self.clock_multiplier,
+            ..SystemConfig::default()
+        }
+    }
+
+    /// Where the save file for the ROM at `rom_path` is kept.
+    pub fn save_path(&self, rom_path: &Path) -> PathBuf {
+        let save_path = rom_path.with_extension("sav");
+        match (&self.save_dir, save_path.file_name()) {
+            (&Some(ref dir), Some(name)) => dir.join(name),
+            _ => save_path,
+        }
+    }
+
+    /// Writes the settings as a settings file, with comments explaining each of them. Unset paths
+    /// are left commented out.
+    pub fn to_toml(&self) -> String {
+        let mut text = String::new();
+        let optional_path = |name: &str, path: &Option<PathBuf>, example: &str| match *path {
+            Some(ref path) => format!("{} = {}\n", name, quote(&path.to_string_lossy())),
+            None => format!("# {} = {}\n", name, quote(example)),
+        };
+        let names = |names: &[&str]| names.join(", ");
+
+        text += "# Settings for Advance. Flags given on the command line take precedence.\n\n";
+        text += "# BIOS loaded when only a ROM is given.\n";
+        text += &optional_path("bios", &self.bios, "gba_bios.bin");
+        text += "# Directory for save files, instead of next to the ROM.\n";
+        text += &optional_path("save_dir", &self.save_dir, "saves");
+        writeln!(text, "audio = {}", self.audio).unwrap();
+
+        text += "\n[system]\n";
+        text += "# Runs BIOS functions with HLE even if a BIOS is loaded.\n";
+        writeln!(text, "hle_bios = {}", self.hle_bios).unwrap();
+        text += "# Starts the cart without the BIOS boot animation.\n";
+        writeln!(text, "skip_bios_boot = {}", self.skip_bios_boot).unwrap();
+        text += "# Value SRAM holds before a save file is loaded.\n";
+        writeln!(text, "sram_fill = 0x{:02X}", self.sram_fill).unwrap();
+        let accuracy_names: Vec<&str> = TimingAccuracy::ALL.iter().map(|a| a.name()).collect();
+        writeln!(text, "# One of: {}", names(&accuracy_names)).unwrap();
+        writeln!(
+            text,
+            "timing_accuracy = {}",
+            quote(self.timing_accuracy.name())
+        )
+        .unwrap();
+        text += "# Runs the console faster or slower than the real one, for testing.\n";
+        writeln!(text, "clock_multiplier = {:?}", self.clock_multiplier).unwrap();
+
+        text += "\n[video]\n";
+        text += "# Initial size of the window, relative to the screen.\n";
+        writeln!(text, "window_scale = {}", self.window_scale).unwrap();
+        let scaling_names: Vec<&str> = Scaling::ALL.iter().map(|s| s.name()).collect();
+        writeln!(text, "# One of: {}", names(&scaling_names)).unwrap();
+        writeln!(text, "scaling = {}", quote(self.scaling.name())).unwrap();
+        let color_names: Vec<&str> = ColorCorrection::ALL.iter().map(|c| c.name()).collect();
+        writeln!(text, "# One of: {}", names(&color_names)).unwrap();
+        writeln!(
+            text,
+            "color_correction = {}",
+            quote(self.color_correction.name())
+        )
+        .unwrap();
+
+        text += "\n[keys]\n";
+        text += "# Keys are given by their SDL names, like \"Return\" or \"Left Shift\".\n";
+        for &(button, name, _) in BUTTON_KEYS.iter() {
+            for &(_, ref key) in self.keys.iter().filter(|binding| binding.0 == button) {
+                writeln!(text, "{} = {}", name, quote(key)).unwrap();
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let text = r#"
+            # Comment
+            top = "a \"quoted\" \\ string" # Trailing comment
+            [section]
+            hex = 0xFF
+            negative = -12
+            big = 1_000
+            float = 1.5
+            flag = false
+        "#;
+        let settings = parse(text).unwrap();
+        let values: Vec<(&str, &Value)> = settings
+            .iter()
+            .map(|setting| (&setting.key[..], &setting.value))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("top", &Value::String("a \"quoted\" \\ string".to_string())),
+                ("section.hex", &Value::Integer(0xFF)),
+                ("section.negative", &Value::Integer(-12)),
+                ("section.big", &Value::Integer(1000)),
+                ("section.float", &Value::Float(1.5)),
+                ("section.flag", &Value::Bool(false)),
+            ]
+        );
+        assert_eq!(settings[1].line, 5);
+
+        let error = |text| parse(text).unwrap_err();
+        assert_eq!(error("a = 1\nb").line, 2);
+        assert_eq!(error("a = \"open").message, "unterminated string");
+        assert_eq!(error("a = yes").message, "invalid value: yes");
+        assert_eq!(error("a = 1 2").message, "unexpected text after value: 2");
+        assert_eq!(error("[a b]").message, "invalid section header: [a b]");
+        assert_eq!(error("a = 1\na = 2").message, "duplicate key: a");
+    }
+
+    #[test]
+    fn test_config_parse() {
+        let text = r#"
+            bios = "/bios/gba_bios.bin"
+            audio = false
+            colour = "typo"
+            [system]
+            sram_fill = 0x00
+            timing_accuracy = "accurate"
+            clock_multiplier = 2
+            [video]
+            scaling = "bilinear"
+            [keys]
+            a = "Space"
+        "#;
+        let (config, warnings) = Config::parse(text).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                bios: Some(PathBuf::from("/bios/gba_bios.bin")),
+                audio: false,
+                sram_fill: 0x00,
+                timing_accuracy: TimingAccuracy::Accurate,
+                clock_multiplier: 2.0,
+                scaling: Scaling::Bilinear,
+                keys: {
+                    let mut keys = Config::default().keys;
+                    keys[0].1 = "Space".to_string();
+                    keys
+                },
+                ..Config::default()
+            }
+        );
+        assert_eq!(warnings, ["line 4: unknown setting colour"]);
+
+        assert_eq!(
+            Config::parse("[video]\nwindow_scale = 0").unwrap_err(),
+            ConfigError {
+                line: 2,
+                message: "video.window_scale: expected an integer from 1 to 16".to_string(),
+            }
+        );
+        assert_eq!(
+            Config::parse("audio = 1").unwrap_err().message,
+            "audio: expected true or false"
+        );
+    }
+
+    #[test]
+    fn test_args_override_file() {
+        let (mut config, _) = Config::parse(
+            r#"
+            audio = true
+            [system]
+            sram_fill = 0x00
+            [video]
+            scaling = "bilinear"
+            window_scale = 2
+            "#,
+        )
+        .unwrap();
+        let paths = config
+            .apply_args(&args(&[
+                "--no-audio",
+                "--sram-fill=0xFF",
+                "bios.bin",
+                "--scale=4",
+                "--force",
+                "rom.gba",
+            ]))
+            .unwrap();
+
+        assert_eq!(paths, ["bios.bin", "rom.gba"]);
+        assert!(!config.audio);
+        assert_eq!(config.sram_fill, 0xFF);
+        assert_eq!(config.window_scale, 4);
+        // Not given as a flag, so the file's setting stays
+        assert_eq!(config.scaling, Scaling::Bilinear);
+        assert_eq!(config.system_config().sram_fill, 0xFF);
+
+        assert_eq!(
+            config.apply_args(&args(&["--scaling=blurry"])),
+            Err("unknown scaling: blurry".to_string())
+        );
+
+        assert!(Config::is_setting_flag("--hle-bios"));
+        assert!(Config::is_setting_flag("--scale=4"));
+        assert!(!Config::is_setting_flag("--force"));
+        assert!(!Config::is_setting_flag("--headless=10"));
+    }
+
+    #[test]
+    fn test_save_path() {
+        let mut config = Config::default();
+        let rom_path = Path::new("/games/game.gba");
+        assert_eq!(config.save_path(rom_path), Path::new("/games/game.sav"));
+        config.save_dir = Some(PathBuf::from("/saves"));
+        assert_eq!(config.save_path(rom_path), Path::new("/saves/game.sav"));
+    }
+
+    #[test]
+    fn test_template_round_trip() {
+        let template = Config::default().to_toml();
+        assert_eq!(Config::parse(&template), Ok((Config::default(), vec![])));
+        assert!(template.contains("# bios = \"gba_bios.bin\"\n"));
+
+        let config = Config {
+            bios: Some(PathBuf::from("C:\\BIOS\\gba \"bios\".bin")),
+            save_dir: Some(PathBuf::from("saves")),
+            audio: false,
+            hle_bios: true,
+            skip_bios_boot: true,
+            sram_fill: 0x12,
+            timing_accuracy: TimingAccuracy::Accurate,
+            clock_multiplier: 0.5,
+            window_scale: 5,
+            scaling: Scaling::Bilinear,
+            color_correction: ColorCorrection::GbcLcd,
+            keys: vec![(Button::A, "Left Shift".to_string())],
+        };
+        let (parsed, warnings) = Config::parse(&config.to_toml()).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(
+            parsed,
+            Config {
+                // Buttons left out keep their default keys
+                keys: {
+                    let mut keys = Config::default().keys;
+                    keys[0].1 = "Left Shift".to_string();
+                    keys
+                },
+                ..config
+            }
+        );
+    }
+}
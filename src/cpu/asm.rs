@@ -0,0 +1,854 @@
+//! Minimal ARM assembler, so that tests can be written as assembly instead of hex. It only covers
+//! what the CPU implements: data processing, B/BL, LDR/STR(B) with immediate offsets, LDM/STM and
+//! SWI, using the same syntax as the disassembler.
+//!
+//! Each line holds an instruction, optionally preceded by a `label:`. Comments start with `;`.
+//! Branch targets are labels, or `.` for the branch itself. Errors panic with the line number,
+//! since this is only meant for tests.
+//!
+//! There are also builder functions that encode a single ARM or Thumb instruction from its fields,
+//! for tests that compute them.
+
+const CONDITION_SUFFIXES: [&str; 16] = [
+    "eq", "ne", "cs", "cc", "mi", "pl", "vs", "vc", "hi", "ls", "ge", "lt", "gt", "le", "al", "nv",
+];
+
+const DATA_PROCESSING_MNEMONICS: [&str; 16] = [
+    "and", "eor", "sub", "rsb", "add", "adc", "sbc", "rsc", "tst", "teq", "cmp", "cmn", "orr",
+    "mov", "bic", "mvn",
+];
+
+const SHIFT_MNEMONICS: [&str; 4] = ["lsl", "lsr", "asr", "ror"];
+
+/// Addressing modes of LDM/STM, as the P and U bits, followed by the stack aliases of LDM and STM.
+const BLOCK_ADDRESSING_MODES: [(&str, bool, bool); 4] = [
+    ("ia", false, true),
+    ("ib", true, true),
+    ("da", false, false),
+    ("db", true, false),
+];
+const LDM_STACK_MODES: [(&str, &str); 4] = [("fd", "ia"), ("ed", "ib"), ("fa", "da"), ("ea", "db")];
+const STM_STACK_MODES: [(&str, &str); 4] = [("ea", "ia"), ("fa", "ib"), ("ed", "da"), ("fd", "db")];
+
+const COND_ALWAYS: u32 = 0b1110;
+
+/// Assembles `source`, with the first instruction at address 0.
+pub fn assemble(source: &str) -> Vec<u32> {
+    // Find the address of every label first, so that branches can jump forward
+    let mut labels = Vec::new();
+    let mut instructions = Vec::new();
+    for (line_index, line) in source.lines().enumerate() {
+        let mut line = line.split(';').next().unwrap().trim();
+        if let Some(colon) = line.find(':') {
+            labels.push((line[..colon].trim(), instructions.len() as u32 * 4));
+            line = line[colon + 1..].trim();
+        }
+        if !line.is_empty() {
+            instructions.push((line_index + 1, line));
+        }
+    }
+
+    instructions
+        .iter()
+        .enumerate()
+        .map(|(i, &(line_number, line))| {
+            assemble_instruction(line, i as u32 * 4, &labels)
+                .unwrap_or_else(|e| panic!("line {}: {}: {}", line_number, e, line))
+        })
+        .collect()
+}
+
+fn assemble_instruction(line: &str, address: u32, labels: &[(&str, u32)]) -> Result<u32, String> {
+    let line = line.to_lowercase();
+    let (mnemonic, operands) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], split_operands(&line[i..])),
+        None => (&line[..], Vec::new()),
+    };
+
+    if mnemonic.len() >= 3 {
+        let (base, suffix) = mnemonic.split_at(3);
+        if let Some(opcode) = DATA_PROCESSING_MNEMONICS.iter().position(|&m| m == base) {
+            let (cond, s) = parse_suffix(suffix, "s")?;
+            return assemble_data_processing(cond, opcode as u32, s, &operands);
+        }
+        if base == "ldr" || base == "str" {
+            let (cond, byte) = parse_suffix(suffix, "b")?;
+            return assemble_load_store(cond, base == "ldr", byte, &operands);
+        }
+        if (base == "ldm" || base == "stm") && suffix.len() >= 2 {
+            let (cond, mode) = suffix.split_at(suffix.len() - 2);
+            let (cond, _) = parse_suffix(cond, "")?;
+            return assemble_load_store_multiple(cond, base == "ldm", mode, &operands);
+        }
+        if base == "swi" {
+            let (cond, _) = parse_suffix(suffix, "")?;
+            let comment = expect_operands(&operands, 1).and_then(|o| parse_imm(o[0]))?;
+            if comment > 0xFF_FFFF {
+                return Err("SWI comment out of range".to_string());
+            }
+            return Ok(cond << 28 | 0b1111 << 24 | comment);
+        }
+    }
+    if mnemonic.starts_with('b') {
+        // `ble` is a conditional B, not a BL
+        let (cond, link) = match parse_suffix(&mnemonic[1..], "") {
+            Ok((cond, _)) => (cond, false),
+            Err(_) if mnemonic.starts_with("bl") => (parse_suffix(&mnemonic[2..], "")?.0, true),
+            Err(e) => return Err(e),
+        };
+        let target = expect_operands(&operands, 1)?[0];
+        let target = if target == "." {
+            address
+        } else {
+            labels
+                .iter()
+                .find(|&&(label, _)| label.to_lowercase() == target)
+                .map(|&(_, label_address)| label_address)
+                .ok_or_else(|| format!("unknown label {}", target))?
+        };
+        let offset = (target.wrapping_sub(address + 8) as i32) >> 2;
+        return Ok(cond << 28 | 0b101 << 25 | (link as u32) << 24 | offset as u32 & 0xFF_FFFF);
+    }
+    Err("unknown instruction".to_string())
+}
+
+/// Splits operands on commas, except for the ones inside an address in brackets or a register
+/// list in braces.
+fn split_operands(operands: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut start = 0;
+    let mut in_brackets = false;
+    for (i, c) in operands.char_indices() {
+        match c {
+            '[' | '{' => in_brackets = true,
+            ']' | '}' => in_brackets = false,
+            ',' if !in_brackets => {
+                result.push(operands[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    result.push(operands[start..].trim());
+    result
+}
+
+fn expect_operands<'a, 'b>(operands: &'a [&'b str], count: usize) -> Result<&'a [&'b str], String> {
+    if operands.len() == count {
+        Ok(operands)
+    } else {
+        Err(format!("expected {} operands", count))
+    }
+}
+
+fn parse_condition(suffix: &str) -> Option<u32> {
+    if suffix.is_empty() {
+        Some(COND_ALWAYS)
+    } else {
+        CONDITION_SUFFIXES
+            .iter()
+            .position(|&c| c == suffix)
+            .map(|cond| cond as u32)
+    }
+}
+
+/// Parses an optional condition followed by an optional `flag` suffix, like `eqs` in `addeqs`.
+fn parse_suffix(suffix: &str, flag: &str) -> Result<(u32, bool), String> {
+    // Conditions can end with the same letter as the flag, like `cs`
+    if let Some(cond) = parse_condition(suffix) {
+        return Ok((cond, false));
+    }
+    if !flag.is_empty() && suffix.ends_with(flag) {
+        if let Some(cond) = parse_condition(&suffix[..suffix.len() - flag.len()]) {
+            return Ok((cond, true));
+        }
+    }
+    Err(format!("unknown condition {}", suffix))
+}
+
+fn parse_reg(operand: &str) -> Result<u32, String> {
+    match operand {
+        "sp" => Ok(13),
+        "lr" => Ok(14),
+        "pc" => Ok(15),
+        _ if operand.starts_with('r') => match operand[1..].parse() {
+            Ok(reg) if reg < 16 => Ok(reg),
+            _ => Err(format!("invalid register {}", operand)),
+        },
+        _ => Err(format!("expected a register, got {}", operand)),
+    }
+}
+
+/// Parses a decimal or hex number, with an optional minus sign. Underscores are ignored.
+fn parse_number(text: &str) -> Result<i64, String> {
+    let (negative, digits) = if text.starts_with('-') {
+        (true, &text[1..])
+    } else {
+        (false, text)
+    };
+    let digits = digits.replace('_', "");
+    let value = if digits.starts_with("0x") {
+        i64::from_str_radix(&digits[2..], 16)
+    } else {
+        digits.parse()
+    }
+    .map_err(|_| format!("invalid number {}", text))?;
+    Ok(if negative { -value } else { value })
+}
+
+/// Parses an immediate offset, where the `#` is optional.
+fn parse_offset(operand: &str) -> Result<i64, String> {
+    if operand.starts_with('#') {
+        parse_number(&operand[1..])
+    } else {
+        parse_number(operand)
+    }
+}
+
+fn parse_imm(operand: &str) -> Result<u32, String> {
+    if !operand.starts_with('#') {
+        return Err(format!("expected an immediate, got {}", operand));
+    }
+    let value = parse_number(&operand[1..])?;
+    if value < 0 || value > 0xFFFF_FFFF {
+        return Err(format!("immediate out of range: {}", operand));
+    }
+    Ok(value as u32)
+}
+
+/// Parses a register list like `{r0-r3, lr}`.
+fn parse_reg_list(operand: &str) -> Result<u16, String> {
+    if !operand.starts_with('{') || !operand.ends_with('}') {
+        return Err(format!("expected a register list, got {}", operand));
+    }
+    let mut regs = 0;
+    for part in operand[1..operand.len() - 1].split(',') {
+        let part = part.trim();
+        if part.is_empty() {
+            continue;
+        }
+        let (first, last) = match part.find('-') {
+            Some(dash) => (
+                parse_reg(part[..dash].trim())?,
+                parse_reg(part[dash + 1..].trim())?,
+            ),
+            None => (parse_reg(part)?, parse_reg(part)?),
+        };
+        if first > last {
+            return Err(format!("invalid register range {}", part));
+        }
+        for reg in first..=last {
+            regs |= 1 << reg;
+        }
+    }
+    Ok(regs)
+}
+
+/// Encodes operand 2 of a data processing instruction, returning the bits for the immediate flag
+/// and bits 0-11.
+fn assemble_operand2(operands: &[&str]) -> Result<u32, String> {
+    match operands {
+        [imm] if imm.starts_with('#') => {
+            let value = parse_imm(imm)?;
+            // Immediates are 8-bit values rotated right by an even amount
+            (0..16)
+                .map(|rotate| (rotate, value.rotate_left(rotate * 2)))
+                .find(|&(_, imm8)| imm8 <= 0xFF)
+                .map(|(rotate, imm8)| 1 << 25 | rotate << 8 | imm8)
+                .ok_or_else(|| format!("immediate can't be encoded: {}", imm))
+        }
+        [rm] => parse_reg(rm),
+        [rm, shift] => {
+            let rm = parse_reg(rm)?;
+            if *shift == "rrx" {
+                return Ok(0b11 << 5 | rm);
+            }
+
+            let (mnemonic, amount) = shift.split_at(3);
+            let shift_type = SHIFT_MNEMONICS
+                .iter()
+                .position(|&m| m == mnemonic)
+                .ok_or_else(|| format!("unknown shift {}", mnemonic))?
+                as u32;
+            let amount = amount.trim();
+            if amount.starts_with('#') {
+                let amount = parse_imm(amount)?;
+                let encoded = match (shift_type, amount) {
+                    (0, 0..=31) | (3, 1..=31) => amount,
+                    // LSR and ASR encode a shift of 32 as 0
+                    (1, 1..=32) | (2, 1..=32) => amount & 0x1F,
+                    _ => return Err(format!("invalid shift amount: {}", shift)),
+                };
+                Ok(encoded << 7 | shift_type << 5 | rm)
+            } else {
+                Ok(parse_reg(amount)? << 8 | shift_type << 5 | 1 << 4 | rm)
+            }
+        }
+        _ => Err("invalid operand 2".to_string()),
+    }
+}
+
+fn assemble_load_store_multiple(
+    cond: u32,
+    load: bool,
+    mode: &str,
+    operands: &[&str],
+) -> Result<u32, String> {
+    let stack_modes = if load {
+        &LDM_STACK_MODES
+    } else {
+        &STM_STACK_MODES
+    };
+    let mode = stack_modes
+        .iter()
+        .find(|&&(alias, _)| alias == mode)
+        .map_or(mode, |&(_, mode)| mode);
+    let (pre, up) = BLOCK_ADDRESSING_MODES
+        .iter()
+        .find(|&&(name, _, _)| name == mode)
+        .map(|&(_, pre, up)| (pre, up))
+        .ok_or_else(|| format!("unknown addressing mode {}", mode))?;
+
+    let operands = expect_operands(operands, 2)?;
+    let (rn, writeback) = if operands[0].ends_with('!') {
+        (
+            parse_reg(operands[0][..operands[0].len() - 1].trim())?,
+            true,
+        )
+    } else {
+        (parse_reg(operands[0])?, false)
+    };
+    let (reg_list, user_bank) = if operands[1].ends_with('^') {
+        (operands[1][..operands[1].len() - 1].trim(), true)
+    } else {
+        (operands[1], false)
+    };
+    let regs = parse_reg_list(reg_list)?;
+    let instr = load_store_multiple(load, pre, up, rn, regs, writeback);
+    Ok(instr & !(0xF << 28) | cond << 28 | (user_bank as u32) << 22)
+}
+
+fn assemble_data_processing(
+    cond: u32,
+    opcode: u32,
+    s: bool,
+    operands: &[&str],
+) -> Result<u32, String> {
+    if operands.len() < 2 {
+        return Err("missing operands".to_string());
+    }
+    let (s, rn, rd, op2) = match opcode {
+        // Compares always set flags, and have no destination
+        0b1000..=0b1011 => (true, parse_reg(operands[0])?, 0, &operands[1..]),
+        // Moves have no first operand
+        0b1101 | 0b1111 => (s, 0, parse_reg(operands[0])?, &operands[1..]),
+        _ if operands.len() >= 3 => (
+            s,
+            parse_reg(operands[1])?,
+            parse_reg(operands[0])?,
+            &operands[2..],
+        ),
+        _ => return Err("missing operands".to_string()),
+    };
+    let op2 = assemble_operand2(op2)?;
+    Ok(cond << 28 | opcode << 21 | (s as u32) << 20 | rn << 16 | rd << 12 | op2)
+}
+
+fn assemble_load_store(
+    cond: u32,
+    load: bool,
+    byte: bool,
+    operands: &[&str],
+) -> Result<u32, String> {
+    if operands.len() < 2 || !operands[1].starts_with('[') {
+        return Err("expected an address in brackets".to_string());
+    }
+    let rd = parse_reg(operands[0])?;
+
+    let writeback = operands[1].ends_with('!');
+    let address = if writeback {
+        &operands[1][..operands[1].len() - 1]
+    } else {
+        operands[1]
+    };
+    if !address.ends_with(']') {
+        return Err("expected an address in brackets".to_string());
+    }
+    let address = split_operands(&address[1..address.len() - 1]);
+    let rn = parse_reg(address[0])?;
+
+    // Pre-indexed offsets are inside the brackets, post-indexed ones after them
+    let (pre_indexed, offset) = match (&address[1..], &operands[2..]) {
+        (&[], &[]) => (true, 0),
+        (&[offset], &[]) => (true, parse_offset(offset)?),
+        (&[], &[offset]) if !writeback => (false, parse_offset(offset)?),
+        _ => return Err("invalid address".to_string()),
+    };
+    if offset.abs() > 0xFFF {
+        return Err(format!("offset out of range: {}", offset));
+    }
+
+    Ok(cond << 28
+        | 0b01 << 26
+        | (pre_indexed as u32) << 24
+        | ((offset >= 0) as u32) << 23
+        | (byte as u32) << 22
+        | (writeback as u32) << 21
+        | (load as u32) << 20
+        | rn << 16
+        | rd << 12
+        | offset.abs() as u32)
+}
+
+// Builders for single instructions, for tests that need to compute fields. These always use the
+// AL condition, which can be changed with `with_cond`. Branch offsets are in words, as decoded.
+
+pub const OPCODE_ADD: u32 = 0b0100;
+pub const OPCODE_TST: u32 = 0b1000;
+pub const OPCODE_CMP: u32 = 0b1010;
+pub const OPCODE_MOV: u32 = 0b1101;
+
+pub const LSL: u32 = 0b00;
+pub const LSR: u32 = 0b01;
+pub const ASR: u32 = 0b10;
+pub const ROR: u32 = 0b11;
+
+pub fn with_cond(cond: u32, instr: u32) -> u32 {
+    cond << 28 | instr & 0x0FFF_FFFF
+}
+
+/// Sets the S bit of a data processing or multiply instruction.
+pub fn set_flags(instr: u32) -> u32 {
+    instr | 1 << 20
+}
+
+/// Data processing with `imm` rotated right by `rotate * 2` as operand 2.
+pub fn data_processing_imm(opcode: u32, s: bool, rd: u32, rn: u32, imm: u32, rotate: u32) -> u32 {
+    let mut instr = COND_ALWAYS << 28 | 1 << 25;
+    bits_set!(instr[21:24] = opcode);
+    bits_set!(instr[20] = s as u32);
+    bits_set!(instr[16:19] = rn);
+    bits_set!(instr[12:15] = rd);
+    bits_set!(instr[8:11] = rotate);
+    bits_set!(instr[0:7] = imm);
+    instr
+}
+
+/// Data processing with `rm` shifted by an immediate amount as operand 2.
+pub fn data_processing_imm_shift(
+    opcode: u32,
+    s: bool,
+    rd: u32,
+    rn: u32,
+    rm: u32,
+    shift_type: u32,
+    shift_imm: u32,
+) -> u32 {
+    let mut instr = COND_ALWAYS << 28;
+    bits_set!(instr[21:24] = opcode);
+    bits_set!(instr[20] = s as u32);
+    bits_set!(instr[16:19] = rn);
+    bits_set!(instr[12:15] = rd);
+    bits_set!(instr[7:11] = shift_imm);
+    bits_set!(instr[5:6] = shift_type);
+    bits_set!(instr[0:3] = rm);
+    instr
+}
+
+/// Data processing with `rm` shifted by the amount in `rs` as operand 2.
+pub fn data_processing_reg_shift(
+    opcode: u32,
+    s: bool,
+    rd: u32,
+    rn: u32,
+    rm: u32,
+    shift_type: u32,
+    rs: u32,
+) -> u32 {
+    let mut instr = COND_ALWAYS << 28 | 1 << 4;
+    bits_set!(instr[21:24] = opcode);
+    bits_set!(instr[20] = s as u32);
+    bits_set!(instr[16:19] = rn);
+    bits_set!(instr[12:15] = rd);
+    bits_set!(instr[8:11] = rs);
+    bits_set!(instr[5:6] = shift_type);
+    bits_set!(instr[0:3] = rm);
+    instr
+}
+
+pub fn mov_imm(rd: u32, imm: u32, rotate: u32) -> u32 {
+    data_processing_imm(OPCODE_MOV, false, rd, 0, imm, rotate)
+}
+
+pub fn cmp_imm(rn: u32, imm: u32, rotate: u32) -> u32 {
+    data_processing_imm(OPCODE_CMP, true, 0, rn, imm, rotate)
+}
+
+pub fn movs_rrx(rd: u32, rm: u32) -> u32 {
+    data_processing_imm_shift(OPCODE_MOV, true, rd, 0, rm, ROR, 0)
+}
+
+/// LDR/STR with an immediate offset, which is added to `rn` if `add`. The address is only
+/// offset before the access if `pre`, and is written back to `rn` if `wb` or post-indexed.
+pub fn load_store_imm(
+    load: bool,
+    byte: bool,
+    rd: u32,
+    rn: u32,
+    offset: u32,
+    pre: bool,
+    add: bool,
+    wb: bool,
+) -> u32 {
+    let mut instr = COND_ALWAYS << 28 | 0b01 << 26;
+    bits_set!(instr[24] = pre as u32);
+    bits_set!(instr[23] = add as u32);
+    bits_set!(instr[22] = byte as u32);
+    bits_set!(instr[21] = wb as u32);
+    bits_set!(instr[20] = load as u32);
+    bits_set!(instr[16:19] = rn);
+    bits_set!(instr[12:15] = rd);
+    bits_set!(instr[0:11] = offset);
+    instr
+}
+
+pub fn ldr_imm(rd: u32, rn: u32, offset: u32, pre: bool, add: bool, wb: bool) -> u32 {
+    load_store_imm(true, false, rd, rn, offset, pre, add, wb)
+}
+
+pub fn ldrb_imm(rd: u32, rn: u32, offset: u32, pre: bool, add: bool, wb: bool) -> u32 {
+    load_store_imm(true, true, rd, rn, offset, pre, add, wb)
+}
+
+pub fn str_imm(rd: u32, rn: u32, offset: u32, pre: bool, add: bool, wb: bool) -> u32 {
+    load_store_imm(false, false, rd, rn, offset, pre, add, wb)
+}
+
+pub fn strb_imm(rd: u32, rn: u32, offset: u32, pre: bool, add: bool, wb: bool) -> u32 {
+    load_store_imm(false, true, rd, rn, offset, pre, add, wb)
+}
+
+/// LDRH/STRH without the offset, which is a register by default. Bit 22 selects an immediate.
+pub fn load_store_half(load: bool, rd: u32, rn: u32, pre: bool, add: bool, wb: bool) -> u32 {
+    let mut instr = COND_ALWAYS << 28 | 0b1011 << 4;
+    bits_set!(instr[24] = pre as u32);
+    bits_set!(instr[23] = add as u32);
+    bits_set!(instr[21] = wb as u32);
+    bits_set!(instr[20] = load as u32);
+    bits_set!(instr[16:19] = rn);
+    bits_set!(instr[12:15] = rd);
+    instr
+}
+
+/// LDRH/STRH with an 8-bit immediate offset, split in two nibbles.
+fn load_store_half_imm(
+    load: bool,
+    rd: u32,
+    rn: u32,
+    offset: u32,
+    pre: bool,
+    add: bool,
+    wb: bool,
+) -> u32 {
+    let mut instr = load_store_half(load, rd, rn, pre, add, wb) | 1 << 22;
+    bits_set!(instr[8:11] = offset >> 4);
+    bits_set!(instr[0:3] = offset & 0xF);
+    instr
+}
+
+pub fn ldrh_imm(rd: u32, rn: u32, offset: u32, pre: bool, add: bool, wb: bool) -> u32 {
+    load_store_half_imm(true, rd, rn, offset, pre, add, wb)
+}
+
+pub fn strh_imm(rd: u32, rn: u32, offset: u32, pre: bool, add: bool, wb: bool) -> u32 {
+    load_store_half_imm(false, rd, rn, offset, pre, add, wb)
+}
+
+pub fn ldrh_reg(rd: u32, rn: u32, rm: u32, pre: bool, add: bool, wb: bool) -> u32 {
+    let mut instr = load_store_half(true, rd, rn, pre, add, wb);
+    bits_set!(instr[0:3] = rm);
+    instr
+}
+
+pub fn load_store_multiple(
+    load: bool,
+    pre: bool,
+    up: bool,
+    rn: u32,
+    reglist: u16,
+    wb: bool,
+) -> u32 {
+    let mut instr = COND_ALWAYS << 28 | 0b100 << 25;
+    bits_set!(instr[24] = pre as u32);
+    bits_set!(instr[23] = up as u32);
+    bits_set!(instr[21] = wb as u32);
+    bits_set!(instr[20] = load as u32);
+    bits_set!(instr[16:19] = rn);
+    bits_set!(instr[0:15] = reglist as u32);
+    instr
+}
+
+pub fn stmdb(rn: u32, reglist: u16, wb: bool) -> u32 {
+    load_store_multiple(false, true, false, rn, reglist, wb)
+}
+
+pub fn ldmia(rn: u32, reglist: u16, wb: bool) -> u32 {
+    load_store_multiple(true, false, true, rn, reglist, wb)
+}
+
+pub fn mul(rd: u32, rm: u32, rs: u32) -> u32 {
+    let mut instr = COND_ALWAYS << 28 | 0b1001 << 4;
+    bits_set!(instr[16:19] = rd);
+    bits_set!(instr[8:11] = rs);
+    bits_set!(instr[0:3] = rm);
+    instr
+}
+
+pub fn mla(rd: u32, rm: u32, rs: u32, rn: u32) -> u32 {
+    let mut instr = mul(rd, rm, rs) | 1 << 21;
+    bits_set!(instr[12:15] = rn);
+    instr
+}
+
+/// Long multiply, optionally signed and accumulating.
+pub fn multiply_long(
+    signed: bool,
+    accumulate: bool,
+    rd_lo: u32,
+    rd_hi: u32,
+    rm: u32,
+    rs: u32,
+) -> u32 {
+    let mut instr = COND_ALWAYS << 28 | 1 << 23 | 0b1001 << 4;
+    bits_set!(instr[22] = signed as u32);
+    bits_set!(instr[21] = accumulate as u32);
+    bits_set!(instr[16:19] = rd_hi);
+    bits_set!(instr[12:15] = rd_lo);
+    bits_set!(instr[8:11] = rs);
+    bits_set!(instr[0:3] = rm);
+    instr
+}
+
+pub fn b(offset: i32) -> u32 {
+    COND_ALWAYS << 28 | 0b101 << 25 | offset as u32 & 0xFF_FFFF
+}
+
+pub fn bl(offset: i32) -> u32 {
+    b(offset) | 1 << 24
+}
+
+pub fn bx(rm: u32) -> u32 {
+    let mut instr = COND_ALWAYS << 28 | 0x012F_FF10;
+    bits_set!(instr[0:3] = rm);
+    instr
+}
+
+pub fn swi(comment: u32) -> u32 {
+    let mut instr = COND_ALWAYS << 28 | 0b1111 << 24;
+    bits_set!(instr[0:23] = comment);
+    instr
+}
+
+/// MSR from a register, to the fields of CPSR (or SPSR if `saved`) selected by `field_mask`.
+pub fn msr_reg(saved: bool, field_mask: u32, rm: u32) -> u32 {
+    let mut instr = COND_ALWAYS << 28 | 0x0120_F000;
+    bits_set!(instr[22] = saved as u32);
+    bits_set!(instr[16:19] = field_mask);
+    bits_set!(instr[0:3] = rm);
+    instr
+}
+
+/// MSR from `imm` rotated right by `rotate * 2`.
+pub fn msr_imm(saved: bool, field_mask: u32, imm: u32, rotate: u32) -> u32 {
+    let mut instr = COND_ALWAYS << 28 | 0x0320_F000;
+    bits_set!(instr[22] = saved as u32);
+    bits_set!(instr[16:19] = field_mask);
+    bits_set!(instr[8:11] = rotate);
+    bits_set!(instr[0:7] = imm);
+    instr
+}
+
+/// Builders for Thumb instructions. Branch offsets are in halfwords.
+pub mod thumb {
+    pub const ALU_ORR: u16 = 0b1100;
+
+    pub fn lsl_imm(rd: u16, rs: u16, amount: u16) -> u16 {
+        let mut instr = 0;
+        bits_set!(instr[6:10] = amount);
+        bits_set!(instr[3:5] = rs);
+        bits_set!(instr[0:2] = rd);
+        instr
+    }
+
+    pub fn add_imm3(rd: u16, rs: u16, imm: u16) -> u16 {
+        let mut instr = 0x1C00;
+        bits_set!(instr[6:8] = imm);
+        bits_set!(instr[3:5] = rs);
+        bits_set!(instr[0:2] = rd);
+        instr
+    }
+
+    pub fn mov_imm(rd: u16, imm: u16) -> u16 {
+        let mut instr = 0x2000;
+        bits_set!(instr[8:10] = rd);
+        bits_set!(instr[0:7] = imm);
+        instr
+    }
+
+    pub fn alu(opcode: u16, rd: u16, rs: u16) -> u16 {
+        let mut instr = 0x4000;
+        bits_set!(instr[6:9] = opcode);
+        bits_set!(instr[3:5] = rs);
+        bits_set!(instr[0:2] = rd);
+        instr
+    }
+
+    /// BX to any register, including the high ones.
+    pub fn bx(rs: u16) -> u16 {
+        let mut instr = 0x4700;
+        bits_set!(instr[3:6] = rs);
+        instr
+    }
+
+    /// PC-relative load, with an offset in words.
+    pub fn ldr_pc(rd: u16, offset: u16) -> u16 {
+        let mut instr = 0x4800;
+        bits_set!(instr[8:10] = rd);
+        bits_set!(instr[0:7] = offset);
+        instr
+    }
+
+    pub fn push(reglist: u8, lr: bool) -> u16 {
+        0xB400 | (lr as u16) << 8 | reglist as u16
+    }
+
+    pub fn pop(reglist: u8, pc: bool) -> u16 {
+        0xBC00 | (pc as u16) << 8 | reglist as u16
+    }
+
+    pub fn b_cond(cond: u16, offset: i16) -> u16 {
+        0xD000 | cond << 8 | offset as u16 & 0xFF
+    }
+
+    pub fn b(offset: i16) -> u16 {
+        0xE000 | offset as u16 & 0x7FF
+    }
+
+    /// BL is split in two instructions, holding the high and low halves of the offset.
+    pub fn bl(offset: i32) -> [u16; 2] {
+        [
+            0xF000 | (offset >> 11) as u16 & 0x7FF,
+            0xF800 | offset as u16 & 0x7FF,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::disasm::disassemble_arm;
+    use super::*;
+
+    #[test]
+    fn test_assemble() {
+        let program = assemble(
+            "
+            start:
+                mov r0, #0x0800_0000
+                add r2, pc, pc, lsl r1 ; register-specified shift
+                movs r0, r1, rrx
+                cmp r3, #0
+                ldr r0, [r1]
+                strb r1, [r0, #1]
+                ldr r2, [r3, #-4]!
+                str r4, [sp], #8
+                swi #0x60000
+                ldmfd sp!, {r0-r3, r12, pc}^
+                stmnedb r0, {r1, lr}
+                bne start
+                bl end
+            end:
+                b .
+            ",
+        );
+        assert_eq!(
+            program,
+            [
+                0xE3A00302, 0xE08F211F, 0xE1B00061, 0xE3530000, 0xE5910000, 0xE5C01001, 0xE5332004,
+                0xE48D4008, 0xEF060000, 0xE8FD900F, 0x19004002, 0x1AFFFFF3, 0xEBFFFFFF, 0xEAFFFFFE,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        for &instr in &[
+            0xE3A00302, // mov r0, #0x8000000
+            0xE2800C03, // add r0, r0, #0x300
+            0x00910002, // addeqs r0, r1, r2
+            0x20910002, // addcs r0, r1, r2
+            0xE1A00FA1, // mov r0, r1, lsr #31
+            0xE1A00041, // mov r0, r1, asr #32
+            0xE1170351, // tst r7, r1, asr r3
+            0xE5D1F0FF, // ldrb pc, [r1, #0xFF]
+            0xE4100004, // ldr r0, [r0], #-4
+            0xEF00000A, // swi #0xA
+            0xE8BD8FF0, // ldmia sp!, {r4-r11, pc}
+            0x19E00003, // stmneib r0!, {r0, r1}^
+        ] {
+            let text = disassemble_arm(instr, 0);
+            assert_eq!(assemble(&text), [instr], "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_builders() {
+        assert_eq!(mov_imm(0, 0x02, 3), 0xE3A00302);
+        assert_eq!(cmp_imm(1, 0xEA, 0), 0xE35100EA);
+        assert_eq!(movs_rrx(0, 1), 0xE1B00061);
+        assert_eq!(
+            data_processing_reg_shift(OPCODE_ADD, false, 0, 1, 2, LSL, 3),
+            0xE0810312
+        );
+        assert_eq!(
+            data_processing_imm_shift(OPCODE_MOV, false, 0, 0, 1, LSR, 31),
+            0xE1A00FA1
+        );
+        assert_eq!(ldr_imm(13, 15, 0xB8, true, true, false), 0xE59FD0B8);
+        assert_eq!(ldrb_imm(1, 0, 3, true, true, false), 0xE5D01003);
+        assert_eq!(str_imm(0, 0, 520, true, true, false), 0xE5800208);
+        assert_eq!(ldr_imm(2, 3, 4, true, false, true), 0xE5332004);
+        assert_eq!(str_imm(4, 13, 8, false, true, false), 0xE48D4008);
+        assert_eq!(strh_imm(1, 0, 2, false, true, false), 0xE0C010B2);
+        assert_eq!(ldrh_reg(0, 1, 2, true, false, false), 0xE11100B2);
+        assert_eq!(stmdb(13, 0x400F, true), 0xE92D400F);
+        assert_eq!(mul(0, 1, 2), 0xE0000291);
+        assert_eq!(mla(0, 1, 2, 3), 0xE0203291);
+        assert_eq!(multiply_long(false, false, 0, 1, 2, 3), 0xE0810392);
+        assert_eq!(with_cond(0b0001, b(6)), 0x1A000006);
+        assert_eq!(bl(-1), 0xEBFFFFFF);
+        assert_eq!(bx(0), 0xE12FFF10);
+        assert_eq!(swi(0x60000), 0xEF060000);
+        assert_eq!(msr_reg(false, 0b1001, 0), 0xE129F000);
+    }
+
+    #[test]
+    fn test_thumb_builders() {
+        assert_eq!(thumb::mov_imm(0, 1), 0x2001);
+        assert_eq!(thumb::lsl_imm(1, 1, 2), 0x0089);
+        assert_eq!(thumb::add_imm3(2, 1, 1), 0x1C4A);
+        assert_eq!(thumb::alu(thumb::ALU_ORR, 0, 1), 0x4308);
+        assert_eq!(thumb::bx(14), 0x4770);
+        assert_eq!(thumb::ldr_pc(0, 1), 0x4801);
+        assert_eq!(thumb::push(1 << 4, true), 0xB510);
+        assert_eq!(thumb::pop(1 << 4, true), 0xBD10);
+        assert_eq!(thumb::b_cond(0, -2), 0xD0FE);
+        assert_eq!(thumb::b(-2), 0xE7FE);
+        assert_eq!(thumb::bl(0xC), [0xF000, 0xF80C]);
+    }
+
+    #[test]
+    #[should_panic(expected = "line 3: immediate can't be encoded")]
+    fn test_error_line() {
+        assemble("mov r0, #1\n\nmov r0, #0x101");
+    }
+}
@@ -0,0 +1,210 @@
+use std::fmt;
+
+/// Processor modes, as encoded in the mode field of the CPSR.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Mode {
+    User,
+    Fiq,
+    Irq,
+    Supervisor,
+    Abort,
+    Undefined,
+    System,
+    /// Any of the other encodings, which the CPU doesn't define. Holds the field's bits.
+    Invalid(u32),
+}
+
+impl Mode {
+    pub fn from_bits(bits: u32) -> Mode {
+        match bits & 0x1F {
+            0b10000 => Mode::User,
+            0b10001 => Mode::Fiq,
+            0b10010 => Mode::Irq,
+            0b10011 => Mode::Supervisor,
+            0b10111 => Mode::Abort,
+            0b11011 => Mode::Undefined,
+            0b11111 => Mode::System,
+            bits => Mode::Invalid(bits),
+        }
+    }
+
+    pub fn to_bits(self) -> u32 {
+        match self {
+            Mode::User => 0b10000,
+            Mode::Fiq => 0b10001,
+            Mode::Irq => 0b10010,
+            Mode::Supervisor => 0b10011,
+            Mode::Abort => 0b10111,
+            Mode::Undefined => 0b11011,
+            Mode::System => 0b11111,
+            Mode::Invalid(bits) => bits & 0x1F,
+        }
+    }
+
+    /// Abbreviation used by disassemblers and debuggers.
+    pub fn short_name(self) -> &'static str {
+        match self {
+            Mode::User => "usr",
+            Mode::Fiq => "fiq",
+            Mode::Irq => "irq",
+            Mode::Supervisor => "svc",
+            Mode::Abort => "abt",
+            Mode::Undefined => "und",
+            Mode::System => "sys",
+            Mode::Invalid(_) => "???",
+        }
+    }
+}
+
+/// A program status register, either the CPSR or an SPSR. The reserved bits are kept as written.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Cpsr(u32);
+
+macro_rules! flag_field {
+    ($getter:ident, $setter:ident, $bit:expr) => {
+        pub fn $getter(&self) -> bool {
+            self.0 & (1 << $bit) != 0
+        }
+
+        pub fn $setter(&mut self, c: bool) {
+            if c {
+                self.0 |= (1 << $bit);
+            } else {
+                self.0 &= !(1 << $bit);
+            }
+        }
+    };
+}
+
+impl Cpsr {
+    pub fn from_bits(bits: u32) -> Cpsr {
+        Cpsr(bits)
+    }
+
+    pub fn to_bits(self) -> u32 {
+        self.0
+    }
+
+    flag_field!(negative, set_negative, 31);
+    flag_field!(zero, set_zero, 30);
+    flag_field!(carry, set_carry, 29);
+    flag_field!(overflow, set_overflow, 28);
+    flag_field!(irq_disabled, set_irq_disabled, 7);
+    flag_field!(fiq_disabled, set_fiq_disabled, 6);
+    flag_field!(thumb, set_thumb, 5);
+
+    pub fn mode(&self) -> Mode {
+        Mode::from_bits(self.0)
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.0 = (self.0 & !0x1F) | mode.to_bits();
+    }
+
+    /// Replaces the bits selected by `mask` with those of `value`, like MSR does with its field
+    /// mask.
+    pub fn write_masked(&mut self, value: u32, mask: u32) {
+        self.0 = (self.0 & !mask) | (value & mask);
+    }
+}
+
+/// Summarizes the register like "nZCvIft svc", with a capital letter for each flag that's set.
+impl fmt::Display for Cpsr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = [
+            (self.negative(), 'N'),
+            (self.zero(), 'Z'),
+            (self.carry(), 'C'),
+            (self.overflow(), 'V'),
+            (self.irq_disabled(), 'I'),
+            (self.fiq_disabled(), 'F'),
+            (self.thumb(), 'T'),
+        ];
+        for &(set, letter) in flags.iter() {
+            let letter = if set {
+                letter
+            } else {
+                letter.to_ascii_lowercase()
+            };
+            write!(f, "{}", letter)?;
+        }
+        write!(f, " {}", self.mode().short_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flag_bits() {
+        let mut cpsr = Cpsr(0);
+        cpsr.set_overflow(true);
+        assert_eq!(cpsr.to_bits(), 1 << 28);
+        cpsr.set_carry(true);
+        assert_eq!(cpsr.to_bits(), 0b11 << 28);
+        cpsr.set_overflow(false);
+        assert!(cpsr.carry());
+
+        let flags: &[(fn(&mut Cpsr, bool), u32)] = &[
+            (Cpsr::set_negative, 1 << 31),
+            (Cpsr::set_zero, 1 << 30),
+            (Cpsr::set_carry, 1 << 29),
+            (Cpsr::set_overflow, 1 << 28),
+            (Cpsr::set_irq_disabled, 1 << 7),
+            (Cpsr::set_fiq_disabled, 1 << 6),
+            (Cpsr::set_thumb, 1 << 5),
+        ];
+        for &(setter, bit) in flags {
+            let mut cpsr = Cpsr(0);
+            setter(&mut cpsr, true);
+            assert_eq!(cpsr.to_bits(), bit);
+            let mut cpsr = Cpsr(!0);
+            setter(&mut cpsr, false);
+            assert_eq!(cpsr.to_bits(), !bit);
+        }
+    }
+
+    #[test]
+    fn test_modes() {
+        let modes = [
+            (Mode::User, 0b10000),
+            (Mode::Fiq, 0b10001),
+            (Mode::Irq, 0b10010),
+            (Mode::Supervisor, 0b10011),
+            (Mode::Abort, 0b10111),
+            (Mode::Undefined, 0b11011),
+            (Mode::System, 0b11111),
+        ];
+        for &(mode, bits) in modes.iter() {
+            assert_eq!(Mode::from_bits(bits), mode);
+            assert_eq!(mode.to_bits(), bits);
+        }
+        assert_eq!(Mode::from_bits(0b00101), Mode::Invalid(0b00101));
+        assert_eq!(Mode::Invalid(0b00101).to_bits(), 0b00101);
+
+        // Changing the mode leaves the other bits alone, including the reserved ones
+        let mut cpsr = Cpsr::from_bits(0xF000_FFD3);
+        assert_eq!(cpsr.mode(), Mode::Supervisor);
+        cpsr.set_mode(Mode::Irq);
+        assert_eq!(cpsr.to_bits(), 0xF000_FFD2);
+    }
+
+    #[test]
+    fn test_write_masked() {
+        let mut cpsr = Cpsr::from_bits(0x0000_00D3);
+        // Flags field only
+        cpsr.write_masked(0x6000_001F, 0xFF00_0000);
+        assert_eq!(cpsr.to_bits(), 0x6000_00D3);
+        // Control field only
+        cpsr.write_masked(0xFFFF_FF1F, 0x0000_00FF);
+        assert_eq!(cpsr.to_bits(), 0x6000_001F);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Cpsr::from_bits(0x0000_00D3).to_string(), "nzcvIFt svc");
+        assert_eq!(Cpsr::from_bits(0x6000_003F).to_string(), "nZCvifT sys");
+        assert_eq!(Cpsr::from_bits(0xF000_0005).to_string(), "NZCVift ???");
+    }
+}
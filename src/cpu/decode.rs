@@ -3,7 +3,7 @@ pub trait DecodeInstruction {
 }
 
 //#[derive(DecodeInstruction)] TODO: Optimize with procedural macro later
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum DecodedArmInstruction {
     DataProcessingImmediate {
         cond: u8,
@@ -14,6 +14,45 @@ pub enum DecodedArmInstruction {
         rotate: u8,
         imm: u8,
     },
+    DataProcessingImmShift {
+        cond: u8,
+        opcode: u8,
+        s: bool,
+        rn: u8,
+        rd: u8,
+        shift_imm: u8,
+        shift_type: u8,
+        rm: u8,
+    },
+    DataProcessingRegShift {
+        cond: u8,
+        opcode: u8,
+        s: bool,
+        rn: u8,
+        rd: u8,
+        rs: u8,
+        shift_type: u8,
+        rm: u8,
+    },
+    Multiply {
+        cond: u8,
+        accumulate: bool,
+        s: bool,
+        rd: u8,
+        rn: u8,
+        rs: u8,
+        rm: u8,
+    },
+    MultiplyLong {
+        cond: u8,
+        signed: bool,
+        accumulate: bool,
+        s: bool,
+        rd_hi: u8,
+        rd_lo: u8,
+        rs: u8,
+        rm: u8,
+    },
     LoadStoreImmOffset {
         cond: u8,
         indexing_p: bool,
@@ -37,6 +76,16 @@ pub enum DecodedArmInstruction {
         imm_high: u8,
         imm_low: u8,
     },
+    LoadStoreHalfRegOffset {
+        cond: u8,
+        indexing_p: bool,
+        reg_add: bool,
+        indexing_w: bool,
+        load: bool,
+        rn: u8,
+        rd: u8,
+        rm: u8,
+    },
     LoadStoreMultiple {
         cond: u8,
         indexing_p: bool,
@@ -50,26 +99,93 @@ pub enum DecodedArmInstruction {
     BranchImm {
         cond: u8,
         link: bool,
-        offset: i32, // pre sign-extended
+        offset: i32, // in bytes, sign-extended
     },
     BranchAndExchangeReg {
         cond: u8,
         rm: u8,
     },
+    SoftwareInterrupt {
+        cond: u8,
+        comment: u32, // 24 bits
+    },
     MoveToStatusReg {
         cond: u8,
         saved: bool, // operate on SPSR instead of CPSR
         field_mask: u8,
         rm: u8,
     },
-    UndefinedInstruction,
+    MoveToStatusRegImm {
+        cond: u8,
+        saved: bool,
+        field_mask: u8,
+        rotate: u8,
+        imm: u8,
+    },
+    UndefinedInstruction {
+        /// Mnemonic of the ARMv5 instruction it looks like, if any.
+        armv5: Option<&'static str>,
+    },
     UnknownInstruction,
 }
 
+// Bit patterns of the ARM instruction formats, for `test`. The data processing formats also
+// require `is_data_processing`. No instruction matches more than one format.
+const BX_REG: &[u8] = b"cccc0001_00101111_11111111_0001mmmm";
+const MSR_REG: &[u8] = b"cccc0001_0R10ffff_11110000_0000mmmm";
+const MULTIPLY: &[u8] = b"cccc0000_00ASdddd_nnnnssss_1001mmmm";
+const MULTIPLY_LONG: &[u8] = b"cccc0000_1UAShhhh_llllssss_1001mmmm";
+const LOAD_STORE_HALF_REG: &[u8] = b"cccc000P_U0WLnnnn_dddd0000_1011mmmm";
+const LOAD_STORE_HALF_IMM: &[u8] = b"cccc000P_U1WLnnnn_ddddhhhh_1011llll";
+const DATA_PROCESSING_REG_SHIFT: &[u8] = b"cccc000o_oooSnnnn_ddddssss_0tt1mmmm";
+const DATA_PROCESSING_IMM_SHIFT: &[u8] = b"cccc000o_oooSnnnn_ddddiiii_itt0mmmm";
+const MSR_IMM: &[u8] = b"cccc0011_0R10ffff_1111rrrr_iiiiiiii";
+const DATA_PROCESSING_IMM: &[u8] = b"cccc001o_oooSnnnn_ddddrrrr_iiiiiiii";
+const LOAD_STORE_IMM: &[u8] = b"cccc010P_UBWLnnnn_ddddiiii_iiiiiiii";
+const LOAD_STORE_MULTIPLE: &[u8] = b"cccc100P_USWLnnnn_rrrrrrrr_rrrrrrrr";
+const BRANCH_IMM: &[u8] = b"cccc101L_iiiiiiii_iiiiiiii_iiiiiiii";
+const SOFTWARE_INTERRUPT: &[u8] = b"cccc1111_iiiiiiii_iiiiiiii_iiiiiiii";
+const UNDEFINED: &[u8] = b"cccc011x_xxxxxxxx_xxxxxxxx_xxx1xxxx";
+
+// Bit patterns of common instructions added by ARMv5, with their mnemonics. They're checked
+// before the other formats, as some of them overlap with NV-conditioned ARMv4 instructions.
+const ARMV5_FORMATS: &[(&[u8], &str)] = &[
+    (b"1111101H_iiiiiiii_iiiiiiii_iiiiiiii", "BLX"),
+    (b"111101i1_U101nnnn_1111iiii_iiiiiiii", "PLD"),
+    (b"cccc0001_00101111_11111111_0011mmmm", "BLX"),
+    (b"cccc0001_01101111_dddd1111_0001mmmm", "CLZ"),
+    (b"cccc0001_0000nnnn_dddd0000_0101mmmm", "QADD"),
+    (b"cccc0001_0010nnnn_dddd0000_0101mmmm", "QSUB"),
+    (b"cccc0001_0100nnnn_dddd0000_0101mmmm", "QDADD"),
+    (b"cccc0001_0110nnnn_dddd0000_0101mmmm", "QDSUB"),
+    (b"11100001_0010iiii_iiiiiiii_0111iiii", "BKPT"),
+    (b"cccc0001_0oo0dddd_nnnnssss_1yx0mmmm", "SMLA/SMUL"),
+    (b"cccc000P_UiW0nnnn_ddddhhhh_1101llll", "LDRD"),
+    (b"cccc000P_UiW0nnnn_ddddhhhh_1111llll", "STRD"),
+];
+
+// Bit patterns of the Thumb instruction formats, for `test_thumb`.
+const PUSH_POP: &[u8] = b"1011L10R_rrrrrrrr";
+
+/// Compare opcodes with S=0 are used to encode miscellaneous instructions instead.
+fn is_data_processing(instr: u32) -> bool {
+    bit!(instr[23:24]) != 0b10 || bit!(instr[20]) != 0
+}
+
 /// Tests instr against a bit pattern. Positions where format is '0' or '1' must have 0 or 1. Any
 /// other character matches any bit, except for '_' which is skipped.
-fn test(mut instr: u32, format: &'static [u8]) -> bool {
+fn test(instr: u32, format: &'static [u8]) -> bool {
     assert_eq!(format.len(), 32 + 3);
+    matches_pattern(instr, format)
+}
+
+/// Like `test`, for 16-bit Thumb patterns.
+fn test_thumb(instr: u16, format: &'static [u8]) -> bool {
+    assert_eq!(format.len(), 16 + 1);
+    matches_pattern(instr as u32, format)
+}
+
+fn matches_pattern(mut instr: u32, format: &'static [u8]) -> bool {
     for c in format.iter().rev() {
         let bit = instr & 1;
         match c {
@@ -91,8 +207,16 @@ impl DecodeInstruction for DecodedArmInstruction {
         // b"ccccxxxx_xxxxxxxx_xxxxxxxx_xxxxxxxx"
         let cond = bit!(instr[28:31]) as u8;
 
+        for &(format, mnemonic) in ARMV5_FORMATS {
+            if test(instr, format) {
+                return UndefinedInstruction {
+                    armv5: Some(mnemonic),
+                };
+            }
+        }
+
         // (24 bits) TEQ with S=0
-        if test(instr, b"cccc0001_00101111_11111111_0001mmmm") {
+        if test(instr, BX_REG) {
             return BranchAndExchangeReg {
                 cond,
                 rm: bit!(instr[0:3]) as u8,
@@ -100,7 +224,7 @@ impl DecodeInstruction for DecodedArmInstruction {
         }
 
         // 19 bits, MSR reg
-        if test(instr, b"cccc0001_0R10ffff_11110000_0000mmmm") {
+        if test(instr, MSR_REG) {
             return MoveToStatusReg {
                 cond,
                 saved: bit!(instr[22]) != 0,
@@ -109,8 +233,63 @@ impl DecodeInstruction for DecodedArmInstruction {
             };
         }
 
+        // 19 bits, MSR imm
+        if test(instr, MSR_IMM) {
+            return MoveToStatusRegImm {
+                cond,
+                saved: bit!(instr[22]) != 0,
+                field_mask: bit!(instr[16:19]) as u8,
+                rotate: bit!(instr[8:11]) as u8,
+                imm: bit!(instr[0:7]) as u8,
+            };
+        }
+
+        // Multiplies and halfword transfers are encoded in the data processing space, using bit
+        // patterns with bits 7 and 4 set that aren't valid shifts. They must be matched first.
+
+        // 10 bits, MUL/MLA
+        if test(instr, MULTIPLY) {
+            return Multiply {
+                cond,
+                accumulate: bit!(instr[21]) != 0,
+                s: bit!(instr[20]) != 0,
+                rd: bit!(instr[16:19]) as u8,
+                rn: bit!(instr[12:15]) as u8,
+                rs: bit!(instr[8:11]) as u8,
+                rm: bit!(instr[0:3]) as u8,
+            };
+        }
+
+        // 9 bits, UMULL/UMLAL/SMULL/SMLAL
+        if test(instr, MULTIPLY_LONG) {
+            return MultiplyLong {
+                cond,
+                signed: bit!(instr[22]) != 0,
+                accumulate: bit!(instr[21]) != 0,
+                s: bit!(instr[20]) != 0,
+                rd_hi: bit!(instr[16:19]) as u8,
+                rd_lo: bit!(instr[12:15]) as u8,
+                rs: bit!(instr[8:11]) as u8,
+                rm: bit!(instr[0:3]) as u8,
+            };
+        }
+
+        // 12 bits, STRH/LDRH reg
+        if test(instr, LOAD_STORE_HALF_REG) {
+            return LoadStoreHalfRegOffset {
+                cond,
+                indexing_p: bit!(instr[24]) != 0,
+                reg_add: bit!(instr[23]) != 0,
+                indexing_w: bit!(instr[21]) != 0,
+                load: bit!(instr[20]) != 0,
+                rn: bit!(instr[16:19]) as u8,
+                rd: bit!(instr[12:15]) as u8,
+                rm: bit!(instr[0:3]) as u8,
+            };
+        }
+
         // 8 bits, STRH/LDRH imm
-        if test(instr, b"cccc000P_U1WLnnnn_ddddhhhh_1011llll") {
+        if test(instr, LOAD_STORE_HALF_IMM) {
             return LoadStoreHalfImmOffset {
                 cond,
                 indexing_p: bit!(instr[24]) != 0,
@@ -124,8 +303,36 @@ impl DecodeInstruction for DecodedArmInstruction {
             };
         }
 
+        // 4 bits
+        if test(instr, DATA_PROCESSING_REG_SHIFT) && is_data_processing(instr) {
+            return DataProcessingRegShift {
+                cond,
+                opcode: bit!(instr[21:24]) as u8,
+                s: bit!(instr[20]) != 0,
+                rn: bit!(instr[16:19]) as u8,
+                rd: bit!(instr[12:15]) as u8,
+                rs: bit!(instr[8:11]) as u8,
+                shift_type: bit!(instr[5:6]) as u8,
+                rm: bit!(instr[0:3]) as u8,
+            };
+        }
+
+        // 4 bits
+        if test(instr, DATA_PROCESSING_IMM_SHIFT) && is_data_processing(instr) {
+            return DataProcessingImmShift {
+                cond,
+                opcode: bit!(instr[21:24]) as u8,
+                s: bit!(instr[20]) != 0,
+                rn: bit!(instr[16:19]) as u8,
+                rd: bit!(instr[12:15]) as u8,
+                shift_imm: bit!(instr[7:11]) as u8,
+                shift_type: bit!(instr[5:6]) as u8,
+                rm: bit!(instr[0:3]) as u8,
+            };
+        }
+
         // 3 bits
-        if test(instr, b"cccc001o_oooSnnnn_ddddrrrr_iiiiiiii") {
+        if test(instr, DATA_PROCESSING_IMM) && is_data_processing(instr) {
             return DataProcessingImmediate {
                 cond,
                 opcode: bit!(instr[21:24]) as u8,
@@ -138,7 +345,7 @@ impl DecodeInstruction for DecodedArmInstruction {
         }
 
         // 3 bits, LDR/STR imm
-        if test(instr, b"cccc010P_UBWLnnnn_ddddiiii_iiiiiiii") {
+        if test(instr, LOAD_STORE_IMM) {
             return LoadStoreImmOffset {
                 cond,
                 indexing_p: bit!(instr[24]) != 0,
@@ -153,7 +360,7 @@ impl DecodeInstruction for DecodedArmInstruction {
         }
 
         // 3 bits, STM/LDM
-        if test(instr, b"cccc100P_USWLnnnn_rrrrrrrr_rrrrrrrr") {
+        if test(instr, LOAD_STORE_MULTIPLE) {
             return LoadStoreMultiple {
                 cond,
                 indexing_p: bit!(instr[24]) != 0,
@@ -167,11 +374,52 @@ impl DecodeInstruction for DecodedArmInstruction {
         }
 
         // 3 bits, B/BL imm
-        if test(instr, b"cccc101L_iiiiiiii_iiiiiiii_iiiiiiii") {
+        if test(instr, BRANCH_IMM) {
             return BranchImm {
                 cond,
                 link: bit!(instr[24]) != 0,
-                offset: ((bit!(instr[0:23]) << 8) as i32) >> 8,
+                // The offset is in words
+                offset: sign_extend!(instr[24]) << 2,
+            };
+        }
+
+        // 4 bits, SWI
+        if test(instr, SOFTWARE_INTERRUPT) {
+            return SoftwareInterrupt {
+                cond,
+                comment: bit!(instr[0:23]),
+            };
+        }
+
+        // 4 bits, the space the architecture leaves undefined
+        if test(instr, UNDEFINED) {
+            return UndefinedInstruction { armv5: None };
+        }
+
+        UnknownInstruction
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum DecodedThumbInstruction {
+    PushPop {
+        load: bool,
+        pc_lr: bool, // PUSH also stores LR, POP also loads PC
+        regs: u8,
+    },
+    UnknownInstruction,
+}
+
+impl DecodedThumbInstruction {
+    pub fn decode_thumb_instruction(instr: u16) -> DecodedThumbInstruction {
+        use self::DecodedThumbInstruction::*;
+
+        // 7 bits, PUSH/POP
+        if test_thumb(instr, PUSH_POP) {
+            return PushPop {
+                load: bit!(instr[11]) != 0,
+                pc_lr: bit!(instr[8]) != 0,
+                regs: bit!(instr[0:7]) as u8,
             };
         }
 
@@ -181,7 +429,12 @@ impl DecodeInstruction for DecodedArmInstruction {
 
 #[cfg(test)]
 mod tests {
+    use super::super::asm;
     use super::*;
+    use test;
+    use test::Bencher;
+
+    // This and decode_bx_reg use raw encodings, as anchors for the builders in `asm`.
 
     #[test]
     fn decode_mov_imm() {
@@ -201,7 +454,7 @@ mod tests {
 
     #[test]
     fn decode_cmp_imm() {
-        let instr = 0xE35100EA; // cmp r1, #234
+        let instr = asm::cmp_imm(1, 234, 0);
         let actual = DecodedArmInstruction::decode_arm_instruction(instr);
         let expected = DecodedArmInstruction::DataProcessingImmediate {
             cond: 0b1110,
@@ -215,9 +468,43 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn decode_movs_rrx() {
+        let instr = asm::movs_rrx(0, 1);
+        let actual = DecodedArmInstruction::decode_arm_instruction(instr);
+        let expected = DecodedArmInstruction::DataProcessingImmShift {
+            cond: 0b1110,
+            opcode: 0b1101,
+            s: true,
+            rn: 0,
+            rd: 0,
+            shift_imm: 0,
+            shift_type: 0b11,
+            rm: 1,
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn decode_add_reg_shift() {
+        let instr = asm::data_processing_reg_shift(asm::OPCODE_ADD, false, 0, 1, 2, asm::LSL, 3);
+        let actual = DecodedArmInstruction::decode_arm_instruction(instr);
+        let expected = DecodedArmInstruction::DataProcessingRegShift {
+            cond: 0b1110,
+            opcode: 0b0100,
+            s: false,
+            rn: 1,
+            rd: 0,
+            rs: 3,
+            shift_type: 0b00,
+            rm: 2,
+        };
+        assert_eq!(actual, expected);
+    }
+
     #[test]
     fn decode_ldr() {
-        let instr = 0xE59FD0B8; // ldr sp, [pc, #0xC0]
+        let instr = asm::ldr_imm(13, 15, 0xC0 - 8, true, true, false);
         let actual = DecodedArmInstruction::decode_arm_instruction(instr);
         let expected = DecodedArmInstruction::LoadStoreImmOffset {
             cond: 0b1110,
@@ -235,7 +522,7 @@ mod tests {
 
     #[test]
     fn decode_ldrb() {
-        let instr = 0xE5D01003; // ldrb r1, [r0, #3]
+        let instr = asm::ldrb_imm(1, 0, 3, true, true, false);
         let actual = DecodedArmInstruction::decode_arm_instruction(instr);
         let expected = DecodedArmInstruction::LoadStoreImmOffset {
             cond: 0b1110,
@@ -253,7 +540,7 @@ mod tests {
 
     #[test]
     fn decode_str() {
-        let instr = 0xE5800208; // str r0, [r0, #520]
+        let instr = asm::str_imm(0, 0, 520, true, true, false);
         let actual = DecodedArmInstruction::decode_arm_instruction(instr);
         let expected = DecodedArmInstruction::LoadStoreImmOffset {
             cond: 0b1110,
@@ -271,7 +558,7 @@ mod tests {
 
     #[test]
     fn decode_strh() {
-        let instr = 0xE0C010B2; // strh r1, [r0], #2
+        let instr = asm::strh_imm(1, 0, 2, false, true, false);
         let actual = DecodedArmInstruction::decode_arm_instruction(instr);
         let expected = DecodedArmInstruction::LoadStoreHalfImmOffset {
             cond: 0b1110,
@@ -287,14 +574,112 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn decode_mul() {
+        let instr = asm::mul(0, 1, 2);
+        let actual = DecodedArmInstruction::decode_arm_instruction(instr);
+        let expected = DecodedArmInstruction::Multiply {
+            cond: 0b1110,
+            accumulate: false,
+            s: false,
+            rd: 0,
+            rn: 0,
+            rs: 2,
+            rm: 1,
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn decode_smlals() {
+        let instr = asm::set_flags(asm::multiply_long(true, true, 0, 1, 2, 3));
+        let actual = DecodedArmInstruction::decode_arm_instruction(instr);
+        let expected = DecodedArmInstruction::MultiplyLong {
+            cond: 0b1110,
+            signed: true,
+            accumulate: true,
+            s: true,
+            rd_hi: 1,
+            rd_lo: 0,
+            rs: 3,
+            rm: 2,
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn decode_ldrh_reg() {
+        let instr = asm::ldrh_reg(0, 1, 2, true, false, false);
+        let actual = DecodedArmInstruction::decode_arm_instruction(instr);
+        let expected = DecodedArmInstruction::LoadStoreHalfRegOffset {
+            cond: 0b1110,
+            indexing_p: true,
+            reg_add: false,
+            indexing_w: false,
+            load: true,
+            rn: 1,
+            rd: 0,
+            rm: 2,
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn decode_multiply_space_not_data_processing() {
+        // Every combination of the opcode and S bits, and the bits between 7 and 4, with 7 and 4
+        // set. These would be register shifts by rs if bit 7 was clear.
+        for opcode_bits in 0..0x20 {
+            for sh in 0..4 {
+                let instr = 0xE0000090 | opcode_bits << 20 | sh << 5 | 0x0000_3201;
+                match DecodedArmInstruction::decode_arm_instruction(instr) {
+                    DecodedArmInstruction::DataProcessingImmShift { .. }
+                    | DecodedArmInstruction::DataProcessingRegShift { .. } => {
+                        panic!("0x{:08X} decoded as data processing", instr)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
     #[test]
     fn decode_b_imm() {
-        let instr = 0xEA000006; // b $00000020
+        let instr = asm::b((0x20 - 8) / 4);
         let actual = DecodedArmInstruction::decode_arm_instruction(instr);
         let expected = DecodedArmInstruction::BranchImm {
             cond: 0b1110,
             link: false,
-            offset: (0x20 - 8) / 4,
+            offset: 0x20 - 8,
+        };
+        assert_eq!(actual, expected);
+
+        // Backwards, like the b . at the end of a program
+        let instr = asm::bl(-2);
+        let actual = DecodedArmInstruction::decode_arm_instruction(instr);
+        let expected = DecodedArmInstruction::BranchImm {
+            cond: 0b1110,
+            link: true,
+            offset: -8,
+        };
+        assert_eq!(actual, expected);
+
+        // Furthest back
+        let actual = DecodedArmInstruction::decode_arm_instruction(0xEA80_0000);
+        let expected = DecodedArmInstruction::BranchImm {
+            cond: 0b1110,
+            link: false,
+            offset: -(1 << 25),
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn decode_swi() {
+        let instr = asm::swi(0x060000);
+        let actual = DecodedArmInstruction::decode_arm_instruction(instr);
+        let expected = DecodedArmInstruction::SoftwareInterrupt {
+            cond: 0b1110,
+            comment: 0x060000,
         };
         assert_eq!(actual, expected);
     }
@@ -312,7 +697,7 @@ mod tests {
 
     #[test]
     fn decode_msr() {
-        let instr = 0xE129F000; // msr cpsr_cf, r0
+        let instr = asm::msr_reg(false, 0b1001, 0);
         let actual = DecodedArmInstruction::decode_arm_instruction(instr);
         let expected = DecodedArmInstruction::MoveToStatusReg {
             cond: 0b1110,
@@ -325,7 +710,7 @@ mod tests {
 
     #[test]
     fn decode_stmdb() {
-        let instr = 0xE92D0003; // stmdb sp!, {r0-r1}
+        let instr = asm::stmdb(13, 0b11, true);
         let actual = DecodedArmInstruction::decode_arm_instruction(instr);
         let expected = DecodedArmInstruction::LoadStoreMultiple {
             cond: 0b1110,
@@ -339,4 +724,519 @@ mod tests {
         };
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn decode_msr_imm() {
+        let instr = asm::msr_imm(false, 0b1000, 0xF0, 4);
+        let actual = DecodedArmInstruction::decode_arm_instruction(instr);
+        let expected = DecodedArmInstruction::MoveToStatusRegImm {
+            cond: 0b1110,
+            saved: false,
+            field_mask: 0b1000,
+            rotate: 4,
+            imm: 0xF0,
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn decode_armv5() {
+        let decode = DecodedArmInstruction::decode_arm_instruction;
+        let armv5 = |mnemonic| DecodedArmInstruction::UndefinedInstruction {
+            armv5: Some(mnemonic),
+        };
+        // blx #0x100, which would otherwise be an NV branch
+        assert_eq!(decode(0xFA00003E), armv5("BLX"));
+        // blx r3
+        assert_eq!(decode(0xE12FFF33), armv5("BLX"));
+        // clz r0, r1
+        assert_eq!(decode(0xE16F0F11), armv5("CLZ"));
+        // ldrd r2, [r0]
+        assert_eq!(decode(0xE1C020D0), armv5("LDRD"));
+        // pld [r0]
+        assert_eq!(decode(0xF5D0F000), armv5("PLD"));
+        // Not an ARMv5 instruction either, but still undefined
+        assert_eq!(
+            decode(0xE7F000F0),
+            DecodedArmInstruction::UndefinedInstruction { armv5: None }
+        );
+    }
+
+    #[test]
+    fn decode_push_pop() {
+        assert_eq!(
+            DecodedThumbInstruction::decode_thumb_instruction(asm::thumb::push(0x0F, true)),
+            DecodedThumbInstruction::PushPop {
+                load: false,
+                pc_lr: true,
+                regs: 0x0F,
+            }
+        );
+        assert_eq!(
+            DecodedThumbInstruction::decode_thumb_instruction(asm::thumb::pop(0x80, false)),
+            DecodedThumbInstruction::PushPop {
+                load: true,
+                pc_lr: false,
+                regs: 0x80,
+            }
+        );
+        // ADD SP, #imm shares the top bits
+        assert_eq!(
+            DecodedThumbInstruction::decode_thumb_instruction(0xB004),
+            DecodedThumbInstruction::UnknownInstruction
+        );
+    }
+
+    const FORMATS: &[(&str, &[u8], bool)] = &[
+        // (name, pattern, requires is_data_processing)
+        ("bx reg", BX_REG, false),
+        ("msr reg", MSR_REG, false),
+        ("msr imm", MSR_IMM, false),
+        ("multiply", MULTIPLY, false),
+        ("multiply long", MULTIPLY_LONG, false),
+        ("half reg", LOAD_STORE_HALF_REG, false),
+        ("half imm", LOAD_STORE_HALF_IMM, false),
+        ("dp reg shift", DATA_PROCESSING_REG_SHIFT, true),
+        ("dp imm shift", DATA_PROCESSING_IMM_SHIFT, true),
+        ("dp imm", DATA_PROCESSING_IMM, true),
+        ("load/store imm", LOAD_STORE_IMM, false),
+        ("load/store multiple", LOAD_STORE_MULTIPLE, false),
+        ("branch imm", BRANCH_IMM, false),
+        ("swi", SOFTWARE_INTERRUPT, false),
+    ];
+
+    /// Returns the mask of the bits that a pattern fixes to 0 or 1, and their values.
+    fn fixed_bits(format: &[u8]) -> (u32, u32) {
+        let mut mask = 0;
+        let mut value = 0;
+        for &c in format.iter().filter(|&&c| c != b'_') {
+            mask <<= 1;
+            value <<= 1;
+            match c {
+                b'0' => mask |= 1,
+                b'1' => {
+                    mask |= 1;
+                    value |= 1;
+                }
+                _ => {}
+            }
+        }
+        (mask, value)
+    }
+
+    /// Encodes a decoded instruction back with the builders in `asm`.
+    fn encode(decoded: &DecodedArmInstruction) -> Option<u32> {
+        use self::DecodedArmInstruction::*;
+        let (cond, instr) = match *decoded {
+            DataProcessingImmediate {
+                cond,
+                opcode,
+                s,
+                rn,
+                rd,
+                rotate,
+                imm,
+            } => (
+                cond,
+                asm::data_processing_imm(
+                    opcode as u32,
+                    s,
+                    rd as u32,
+                    rn as u32,
+                    imm as u32,
+                    rotate as u32,
+                ),
+            ),
+            DataProcessingImmShift {
+                cond,
+                opcode,
+                s,
+                rn,
+                rd,
+                shift_imm,
+                shift_type,
+                rm,
+            } => (
+                cond,
+                asm::data_processing_imm_shift(
+                    opcode as u32,
+                    s,
+                    rd as u32,
+                    rn as u32,
+                    rm as u32,
+                    shift_type as u32,
+                    shift_imm as u32,
+                ),
+            ),
+            DataProcessingRegShift {
+                cond,
+                opcode,
+                s,
+                rn,
+                rd,
+                rs,
+                shift_type,
+                rm,
+            } => (
+                cond,
+                asm::data_processing_reg_shift(
+                    opcode as u32,
+                    s,
+                    rd as u32,
+                    rn as u32,
+                    rm as u32,
+                    shift_type as u32,
+                    rs as u32,
+                ),
+            ),
+            Multiply {
+                cond,
+                accumulate,
+                s,
+                rd,
+                rn,
+                rs,
+                rm,
+            } => {
+                // rn is ignored without accumulate, but still decoded
+                let mut instr = asm::mla(rd as u32, rm as u32, rs as u32, rn as u32);
+                if !accumulate {
+                    instr &= !(1 << 21);
+                }
+                if s {
+                    instr = asm::set_flags(instr);
+                }
+                (cond, instr)
+            }
+            MultiplyLong {
+                cond,
+                signed,
+                accumulate,
+                s,
+                rd_hi,
+                rd_lo,
+                rs,
+                rm,
+            } => {
+                let instr = asm::multiply_long(
+                    signed,
+                    accumulate,
+                    rd_lo as u32,
+                    rd_hi as u32,
+                    rm as u32,
+                    rs as u32,
+                );
+                (cond, if s { asm::set_flags(instr) } else { instr })
+            }
+            LoadStoreImmOffset {
+                cond,
+                indexing_p,
+                imm_add,
+                byte,
+                indexing_w,
+                load,
+                rn,
+                rd,
+                imm,
+            } => (
+                cond,
+                asm::load_store_imm(
+                    load, byte, rd as u32, rn as u32, imm as u32, indexing_p, imm_add, indexing_w,
+                ),
+            ),
+            LoadStoreHalfImmOffset {
+                cond,
+                indexing_p,
+                imm_add,
+                indexing_w,
+                load,
+                rn,
+                rd,
+                imm_high,
+                imm_low,
+            } => (
+                cond,
+                asm::load_store_half(load, rd as u32, rn as u32, indexing_p, imm_add, indexing_w)
+                    | 1 << 22
+                    | (imm_high as u32) << 8
+                    | imm_low as u32,
+            ),
+            LoadStoreHalfRegOffset {
+                cond,
+                indexing_p,
+                reg_add,
+                indexing_w,
+                load,
+                rn,
+                rd,
+                rm,
+            } => (
+                cond,
+                asm::load_store_half(load, rd as u32, rn as u32, indexing_p, reg_add, indexing_w)
+                    | rm as u32,
+            ),
+            LoadStoreMultiple {
+                cond,
+                indexing_p,
+                upwards,
+                use_banked_or_spsr,
+                indexing_w,
+                load,
+                rn,
+                regs,
+            } => (
+                cond,
+                asm::load_store_multiple(load, indexing_p, upwards, rn as u32, regs, indexing_w)
+                    | (use_banked_or_spsr as u32) << 22,
+            ),
+            BranchImm { cond, link, offset } => (
+                cond,
+                if link {
+                    asm::bl(offset >> 2)
+                } else {
+                    asm::b(offset >> 2)
+                },
+            ),
+            BranchAndExchangeReg { cond, rm } => (cond, asm::bx(rm as u32)),
+            SoftwareInterrupt { cond, comment } => (cond, asm::swi(comment)),
+            MoveToStatusReg {
+                cond,
+                saved,
+                field_mask,
+                rm,
+            } => (cond, asm::msr_reg(saved, field_mask as u32, rm as u32)),
+            MoveToStatusRegImm {
+                cond,
+                saved,
+                field_mask,
+                rotate,
+                imm,
+            } => (
+                cond,
+                asm::msr_imm(saved, field_mask as u32, imm as u32, rotate as u32),
+            ),
+            UndefinedInstruction { .. } | UnknownInstruction => return None,
+        };
+        Some(asm::with_cond(cond as u32, instr))
+    }
+
+    /// Checks that fields fit in as many bits as they were decoded from.
+    fn check_field_widths(decoded: &DecodedArmInstruction) {
+        use self::DecodedArmInstruction::*;
+        let mut fields: Vec<(u32, u32)> = Vec::new();
+        match *decoded {
+            DataProcessingImmediate {
+                cond,
+                opcode,
+                rn,
+                rd,
+                rotate,
+                ..
+            } => fields.extend(&[
+                (cond as u32, 4),
+                (opcode as u32, 4),
+                (rn as u32, 4),
+                (rd as u32, 4),
+                (rotate as u32, 4),
+            ]),
+            DataProcessingImmShift {
+                cond,
+                opcode,
+                rn,
+                rd,
+                shift_imm,
+                shift_type,
+                rm,
+                ..
+            } => fields.extend(&[
+                (cond as u32, 4),
+                (opcode as u32, 4),
+                (rn as u32, 4),
+                (rd as u32, 4),
+                (shift_imm as u32, 5),
+                (shift_type as u32, 2),
+                (rm as u32, 4),
+            ]),
+            DataProcessingRegShift {
+                cond,
+                opcode,
+                rn,
+                rd,
+                rs,
+                shift_type,
+                rm,
+                ..
+            } => fields.extend(&[
+                (cond as u32, 4),
+                (opcode as u32, 4),
+                (rn as u32, 4),
+                (rd as u32, 4),
+                (rs as u32, 4),
+                (shift_type as u32, 2),
+                (rm as u32, 4),
+            ]),
+            Multiply {
+                cond,
+                rd,
+                rn,
+                rs,
+                rm,
+                ..
+            } => fields.extend(&[
+                (cond as u32, 4),
+                (rd as u32, 4),
+                (rn as u32, 4),
+                (rs as u32, 4),
+                (rm as u32, 4),
+            ]),
+            MultiplyLong {
+                cond,
+                rd_hi,
+                rd_lo,
+                rs,
+                rm,
+                ..
+            } => fields.extend(&[
+                (cond as u32, 4),
+                (rd_hi as u32, 4),
+                (rd_lo as u32, 4),
+                (rs as u32, 4),
+                (rm as u32, 4),
+            ]),
+            LoadStoreImmOffset {
+                cond, rn, rd, imm, ..
+            } => fields.extend(&[
+                (cond as u32, 4),
+                (rn as u32, 4),
+                (rd as u32, 4),
+                (imm as u32, 12),
+            ]),
+            LoadStoreHalfImmOffset {
+                cond,
+                rn,
+                rd,
+                imm_high,
+                imm_low,
+                ..
+            } => fields.extend(&[
+                (cond as u32, 4),
+                (rn as u32, 4),
+                (rd as u32, 4),
+                (imm_high as u32, 4),
+                (imm_low as u32, 4),
+            ]),
+            LoadStoreHalfRegOffset {
+                cond, rn, rd, rm, ..
+            } => fields.extend(&[
+                (cond as u32, 4),
+                (rn as u32, 4),
+                (rd as u32, 4),
+                (rm as u32, 4),
+            ]),
+            LoadStoreMultiple { cond, rn, .. } => {
+                fields.extend(&[(cond as u32, 4), (rn as u32, 4)])
+            }
+            BranchImm { cond, offset, .. } => {
+                assert!(offset >= -(1 << 25) && offset < 1 << 25, "{:?}", decoded);
+                assert_eq!(offset & 0b11, 0, "{:?}", decoded);
+                fields.push((cond as u32, 4));
+            }
+            BranchAndExchangeReg { cond, rm } => fields.extend(&[(cond as u32, 4), (rm as u32, 4)]),
+            SoftwareInterrupt { cond, comment } => {
+                fields.extend(&[(cond as u32, 4), (comment, 24)])
+            }
+            MoveToStatusReg {
+                cond,
+                field_mask,
+                rm,
+                ..
+            } => fields.extend(&[(cond as u32, 4), (field_mask as u32, 4), (rm as u32, 4)]),
+            MoveToStatusRegImm {
+                cond,
+                field_mask,
+                rotate,
+                ..
+            } => fields.extend(&[(cond as u32, 4), (field_mask as u32, 4), (rotate as u32, 4)]),
+            UndefinedInstruction { .. } | UnknownInstruction => {}
+        }
+        for &(value, width) in &fields {
+            assert!(value < 1 << width, "{:?}", decoded);
+        }
+    }
+
+    /// Decodes pseudorandom words, half of them forced into one of the formats so that each is
+    /// well covered. Every instruction decoded must encode back to the same word.
+    #[test]
+    fn decode_random_round_trip() {
+        let formats: Vec<(u32, u32)> = FORMATS
+            .iter()
+            .map(|&(_, format, _)| fixed_bits(format))
+            .collect();
+        // xorshift32, with a fixed seed so that failures are reproducible
+        let mut state: u32 = 0x1234_5678;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        for i in 0..100_000 {
+            let mut instr = next();
+            if i % 2 == 1 {
+                let (mask, value) = formats[next() as usize % formats.len()];
+                instr = instr & !mask | value;
+            }
+
+            let decoded = DecodedArmInstruction::decode_arm_instruction(instr);
+            check_field_widths(&decoded);
+            if let Some(encoded) = encode(&decoded) {
+                assert_eq!(encoded, instr, "0x{:08X} decoded as {:?}", instr, decoded);
+            }
+
+            let matches: Vec<&str> = FORMATS
+                .iter()
+                .filter(|&&(_, format, guarded)| {
+                    test(instr, format) && (!guarded || is_data_processing(instr))
+                })
+                .map(|&(name, _, _)| name)
+                .collect();
+            assert!(matches.len() <= 1, "0x{:08X} matches {:?}", instr, matches);
+        }
+    }
+
+    /// Decodes 10k words per iteration, so instructions/sec is 1e13 / (ns/iter). The words repeat a
+    /// typical mix of compiled code: a function call with a copy loop, plus the other formats.
+    #[bench]
+    fn bench_decode_10k_instructions(b: &mut Bencher) {
+        let mut program = vec![asm::stmdb(13, 0x4070, true)];
+        program.extend(asm::assemble(
+            "
+            mov r4, r0
+            add r5, r1, r2, lsl #2
+            loop:
+            ldr r3, [r4], #4
+            str r3, [r5, #-4]!
+            ldrb r6, [r4, #1]
+            subs r2, r2, #1
+            bne loop
+            cmp r0, r6
+            bl loop
+            swi #0x60000
+            ",
+        ));
+        program.extend(&[
+            asm::ldrh_imm(0, 4, 2, true, true, false),
+            asm::mla(0, 1, 2, 3),
+            asm::msr_reg(false, 0b1001, 0),
+            asm::ldmia(13, 0x4070, true),
+            asm::bx(14),
+        ]);
+        let words: Vec<u32> = program.iter().cycle().take(10_000).cloned().collect();
+
+        b.iter(|| {
+            for &word in &words {
+                test::black_box(DecodedArmInstruction::decode_arm_instruction(word));
+            }
+        });
+    }
 }
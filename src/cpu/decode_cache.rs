@@ -0,0 +1,65 @@
+use super::decode::DecodeInstruction;
+use super::decode::DecodedArmInstruction;
+
+/// Number of entries, as a power of two. Enough to hold the hot loops of a game at once.
+const CACHE_SIZE: usize = 4096;
+
+/// Cache of decoded ARM instructions, so that loops don't go through the decoder every iteration.
+///
+/// It's direct-mapped by the address the instruction was fetched from. Each entry also keeps the
+/// instruction it was decoded from, which is compared on every lookup, so code modified in RAM is
+/// decoded again without needing to track writes to memory.
+pub struct DecodeCache {
+    entries: Box<[Option<(u32, DecodedArmInstruction)>]>,
+}
+
+impl DecodeCache {
+    pub fn new() -> DecodeCache {
+        DecodeCache {
+            entries: vec![None; CACHE_SIZE].into_boxed_slice(),
+        }
+    }
+
+    /// Decodes `instr`, fetched from `address`.
+    pub fn decode(&mut self, address: u32, instr: u32) -> DecodedArmInstruction {
+        let entry = &mut self.entries[(address >> 2) as usize & (CACHE_SIZE - 1)];
+        match *entry {
+            Some((cached_instr, decoded)) if cached_instr == instr => decoded,
+            _ => {
+                let decoded = DecodedArmInstruction::decode_arm_instruction(instr);
+                *entry = Some((instr, decoded));
+                decoded
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modified_instruction() {
+        let mut cache = DecodeCache::new();
+        let mov = 0xE3A00001; // mov r0, #1
+        let b = 0xEAFFFFFE; // b .
+        assert_eq!(
+            cache.decode(0x0300_0000, mov),
+            DecodedArmInstruction::decode_arm_instruction(mov)
+        );
+        assert_eq!(
+            cache.decode(0x0300_0000, b),
+            DecodedArmInstruction::decode_arm_instruction(b)
+        );
+        // Addresses sharing an entry evict each other
+        let aliased_address = 0x0300_0000 + CACHE_SIZE as u32 * 4;
+        assert_eq!(
+            cache.decode(aliased_address, mov),
+            DecodedArmInstruction::decode_arm_instruction(mov)
+        );
+        assert_eq!(
+            cache.decode(0x0300_0000, b),
+            DecodedArmInstruction::decode_arm_instruction(b)
+        );
+    }
+}
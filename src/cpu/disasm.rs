@@ -0,0 +1,705 @@
+//! Disassembler for ARM and Thumb code, for debugging.
+
+use super::decode::DecodeInstruction;
+use super::decode::DecodedArmInstruction;
+use hle::HleMemory;
+use std::fmt::Write;
+
+const CONDITION_SUFFIXES: [&str; 16] = [
+    "eq", "ne", "cs", "cc", "mi", "pl", "vs", "vc", "hi", "ls", "ge", "lt", "gt", "le", "", "nv",
+];
+
+const DATA_PROCESSING_MNEMONICS: [&str; 16] = [
+    "and", "eor", "sub", "rsb", "add", "adc", "sbc", "rsc", "tst", "teq", "cmp", "cmn", "orr",
+    "mov", "bic", "mvn",
+];
+
+const SHIFT_MNEMONICS: [&str; 4] = ["lsl", "lsr", "asr", "ror"];
+
+fn reg_name(reg: u8) -> String {
+    match reg {
+        13 => "sp".to_string(),
+        14 => "lr".to_string(),
+        15 => "pc".to_string(),
+        _ => format!("r{}", reg),
+    }
+}
+
+fn format_imm(value: u32) -> String {
+    if value < 10 {
+        format!("#{}", value)
+    } else {
+        format!("#0x{:X}", value)
+    }
+}
+
+/// Formats the destination of MSR, like `cpsr_fc`.
+fn format_status_fields(saved: bool, field_mask: u8) -> String {
+    let fields: String = "cxsf"
+        .chars()
+        .enumerate()
+        .filter(|&(i, _)| field_mask & (1 << i) != 0)
+        .map(|(_, c)| c)
+        .collect();
+    format!("{}_{}", if saved { "spsr" } else { "cpsr" }, fields)
+}
+
+/// Formats a register list like `{r0-r3, lr}`.
+fn format_reg_list(regs: u16) -> String {
+    let mut parts = Vec::new();
+    let mut reg = 0;
+    while reg < 16 {
+        if regs & (1 << reg) == 0 {
+            reg += 1;
+            continue;
+        }
+        let start = reg;
+        while reg + 1 < 16 && regs & (1 << (reg + 1)) != 0 {
+            reg += 1;
+        }
+        if reg - start >= 2 {
+            parts.push(format!("{}-{}", reg_name(start), reg_name(reg)));
+        } else {
+            for r in start..=reg {
+                parts.push(reg_name(r));
+            }
+        }
+        reg += 1;
+    }
+    format!("{{{}}}", parts.join(", "))
+}
+
+fn format_data_processing(cond: u8, opcode: u8, s: bool, rn: u8, rd: u8, op2: &str) -> String {
+    let mnemonic = DATA_PROCESSING_MNEMONICS[opcode as usize];
+    let cond = CONDITION_SUFFIXES[cond as usize];
+    match opcode {
+        // Compares always set flags, and have no destination
+        0b1000..=0b1011 => format!("{}{} {}, {}", mnemonic, cond, reg_name(rn), op2),
+        // Moves have no first operand
+        0b1101 | 0b1111 => format!(
+            "{}{}{} {}, {}",
+            mnemonic,
+            cond,
+            if s { "s" } else { "" },
+            reg_name(rd),
+            op2
+        ),
+        _ => format!(
+            "{}{}{} {}, {}, {}",
+            mnemonic,
+            cond,
+            if s { "s" } else { "" },
+            reg_name(rd),
+            reg_name(rn),
+            op2
+        ),
+    }
+}
+
+fn format_offset_address(rn: u8, offset: &str, pre_indexed: bool, writeback: bool) -> String {
+    if pre_indexed {
+        format!(
+            "[{}, {}]{}",
+            reg_name(rn),
+            offset,
+            if writeback { "!" } else { "" }
+        )
+    } else {
+        format!("[{}], {}", reg_name(rn), offset)
+    }
+}
+
+/// Disassembles an ARM instruction located at `address`. Branch targets and PC-relative load
+/// addresses are resolved to absolute addresses.
+pub fn disassemble_arm(instr: u32, address: u32) -> String {
+    use self::DecodedArmInstruction::*;
+
+    // Reading the PC returns the address of the current instruction + 8
+    let pc = address.wrapping_add(8);
+
+    match DecodedArmInstruction::decode_arm_instruction(instr) {
+        DataProcessingImmediate {
+            cond,
+            opcode,
+            s,
+            rn,
+            rd,
+            rotate,
+            imm,
+        } => {
+            let value = (imm as u32).rotate_right(rotate as u32 * 2);
+            format_data_processing(cond, opcode, s, rn, rd, &format_imm(value))
+        }
+        DataProcessingImmShift {
+            cond,
+            opcode,
+            s,
+            rn,
+            rd,
+            shift_imm,
+            shift_type,
+            rm,
+        } => {
+            let op2 = match (shift_type, shift_imm) {
+                (0, 0) => reg_name(rm),
+                (3, 0) => format!("{}, rrx", reg_name(rm)),
+                // LSR and ASR encode a shift of 32 as 0
+                (_, 0) => format!(
+                    "{}, {} #32",
+                    reg_name(rm),
+                    SHIFT_MNEMONICS[shift_type as usize]
+                ),
+                _ => format!(
+                    "{}, {} #{}",
+                    reg_name(rm),
+                    SHIFT_MNEMONICS[shift_type as usize],
+                    shift_imm
+                ),
+            };
+            format_data_processing(cond, opcode, s, rn, rd, &op2)
+        }
+        DataProcessingRegShift {
+            cond,
+            opcode,
+            s,
+            rn,
+            rd,
+            rs,
+            shift_type,
+            rm,
+        } => {
+            let op2 = format!(
+                "{}, {} {}",
+                reg_name(rm),
+                SHIFT_MNEMONICS[shift_type as usize],
+                reg_name(rs)
+            );
+            format_data_processing(cond, opcode, s, rn, rd, &op2)
+        }
+        Multiply {
+            cond,
+            accumulate,
+            s,
+            rd,
+            rn,
+            rs,
+            rm,
+        } => {
+            let mut text = format!(
+                "{}{}{} {}, {}, {}",
+                if accumulate { "mla" } else { "mul" },
+                CONDITION_SUFFIXES[cond as usize],
+                if s { "s" } else { "" },
+                reg_name(rd),
+                reg_name(rm),
+                reg_name(rs)
+            );
+            if accumulate {
+                write!(text, ", {}", reg_name(rn)).unwrap();
+            }
+            text
+        }
+        MultiplyLong {
+            cond,
+            signed,
+            accumulate,
+            s,
+            rd_hi,
+            rd_lo,
+            rs,
+            rm,
+        } => format!(
+            "{}{}{}{} {}, {}, {}, {}",
+            if signed { "s" } else { "u" },
+            if accumulate { "mlal" } else { "mull" },
+            CONDITION_SUFFIXES[cond as usize],
+            if s { "s" } else { "" },
+            reg_name(rd_lo),
+            reg_name(rd_hi),
+            reg_name(rm),
+            reg_name(rs)
+        ),
+        LoadStoreImmOffset {
+            cond,
+            indexing_p,
+            imm_add,
+            byte,
+            indexing_w,
+            load,
+            rn,
+            rd,
+            imm,
+        } => {
+            let offset = format!("#{}0x{:X}", if imm_add { "" } else { "-" }, imm);
+            let mut text = format!(
+                "{}{}{} {}, {}",
+                if load { "ldr" } else { "str" },
+                CONDITION_SUFFIXES[cond as usize],
+                if byte { "b" } else { "" },
+                reg_name(rd),
+                format_offset_address(rn, &offset, indexing_p, indexing_w)
+            );
+            if rn == 15 && indexing_p && !indexing_w {
+                let target = if imm_add {
+                    pc.wrapping_add(imm as u32)
+                } else {
+                    pc.wrapping_sub(imm as u32)
+                };
+                write!(text, " ; ${:08X}", target).unwrap();
+            }
+            text
+        }
+        LoadStoreHalfImmOffset {
+            cond,
+            indexing_p,
+            imm_add,
+            indexing_w,
+            load,
+            rn,
+            rd,
+            imm_high,
+            imm_low,
+        } => {
+            let imm = imm_high << 4 | imm_low;
+            let offset = format!("#{}0x{:X}", if imm_add { "" } else { "-" }, imm);
+            format!(
+                "{}{}h {}, {}",
+                if load { "ldr" } else { "str" },
+                CONDITION_SUFFIXES[cond as usize],
+                reg_name(rd),
+                format_offset_address(rn, &offset, indexing_p, indexing_w)
+            )
+        }
+        LoadStoreHalfRegOffset {
+            cond,
+            indexing_p,
+            reg_add,
+            indexing_w,
+            load,
+            rn,
+            rd,
+            rm,
+        } => {
+            let offset = format!("{}{}", if reg_add { "" } else { "-" }, reg_name(rm));
+            format!(
+                "{}{}h {}, {}",
+                if load { "ldr" } else { "str" },
+                CONDITION_SUFFIXES[cond as usize],
+                reg_name(rd),
+                format_offset_address(rn, &offset, indexing_p, indexing_w)
+            )
+        }
+        LoadStoreMultiple {
+            cond,
+            indexing_p,
+            upwards,
+            use_banked_or_spsr,
+            indexing_w,
+            load,
+            rn,
+            regs,
+        } => format!(
+            "{}{}{}{} {}{}, {}{}",
+            if load { "ldm" } else { "stm" },
+            CONDITION_SUFFIXES[cond as usize],
+            if upwards { "i" } else { "d" },
+            if indexing_p { "b" } else { "a" },
+            reg_name(rn),
+            if indexing_w { "!" } else { "" },
+            format_reg_list(regs),
+            if use_banked_or_spsr { "^" } else { "" }
+        ),
+        BranchImm { cond, link, offset } => format!(
+            "b{}{} ${:08X}",
+            if link { "l" } else { "" },
+            CONDITION_SUFFIXES[cond as usize],
+            pc.wrapping_add(offset as u32)
+        ),
+        BranchAndExchangeReg { cond, rm } => {
+            format!("bx{} {}", CONDITION_SUFFIXES[cond as usize], reg_name(rm))
+        }
+        SoftwareInterrupt { cond, comment } => format!(
+            "swi{} {}",
+            CONDITION_SUFFIXES[cond as usize],
+            format_imm(comment)
+        ),
+        MoveToStatusReg {
+            cond,
+            saved,
+            field_mask,
+            rm,
+        } => format!(
+            "msr{} {}, {}",
+            CONDITION_SUFFIXES[cond as usize],
+            format_status_fields(saved, field_mask),
+            reg_name(rm)
+        ),
+        MoveToStatusRegImm {
+            cond,
+            saved,
+            field_mask,
+            rotate,
+            imm,
+        } => format!(
+            "msr{} {}, {}",
+            CONDITION_SUFFIXES[cond as usize],
+            format_status_fields(saved, field_mask),
+            format_imm((imm as u32).rotate_right(rotate as u32 * 2))
+        ),
+        UndefinedInstruction { armv5: None } => "undefined".to_string(),
+        UndefinedInstruction {
+            armv5: Some(mnemonic),
+        } => format!("undefined ; ARMv5 {}", mnemonic),
+        UnknownInstruction => format!(".word 0x{:08X}", instr),
+    }
+}
+
+const THUMB_ALU_MNEMONICS: [&str; 16] = [
+    "and", "eor", "lsl", "lsr", "asr", "adc", "sbc", "ror", "tst", "neg", "cmp", "cmn", "orr",
+    "mul", "bic", "mvn",
+];
+
+/// Disassembles a Thumb instruction located at `address`. `next_instr` is the halfword following
+/// it, which is needed to resolve the target of BL, since it's split into two instructions.
+pub fn disassemble_thumb(instr: u16, next_instr: u16, address: u32) -> String {
+    // Reading the PC returns the address of the current instruction + 4
+    let pc = address.wrapping_add(4);
+
+    let r = |base: u16| reg_name(((instr >> base) & 0b111) as u8);
+    let imm8 = (instr & 0xFF) as u32;
+
+    match instr >> 11 {
+        // Move shifted register
+        0b00000..=0b00010 => format!(
+            "{} {}, {}, #{}",
+            SHIFT_MNEMONICS[(instr >> 11) as usize],
+            r(0),
+            r(3),
+            bit!(instr[6:10])
+        ),
+        // Add/subtract
+        0b00011 => {
+            let mnemonic = if bit!(instr[9]) != 0 { "sub" } else { "add" };
+            let operand = if bit!(instr[10]) != 0 {
+                format_imm(bit!(instr[6:8]) as u32)
+            } else {
+                r(6)
+            };
+            format!("{} {}, {}, {}", mnemonic, r(0), r(3), operand)
+        }
+        // Move/compare/add/subtract immediate
+        0b00100..=0b00111 => {
+            let mnemonic = ["mov", "cmp", "add", "sub"][bit!(instr[11:12]) as usize];
+            format!("{} {}, {}", mnemonic, r(8), format_imm(imm8))
+        }
+        0b01000 => {
+            if bit!(instr[10]) == 0 {
+                // ALU operations
+                let mnemonic = THUMB_ALU_MNEMONICS[bit!(instr[6:9]) as usize];
+                format!("{} {}, {}", mnemonic, r(0), r(3))
+            } else {
+                // Hi register operations/branch exchange
+                let rd = (bit!(instr[7]) << 3 | bit!(instr[0:2])) as u8;
+                let rs = bit!(instr[3:6]) as u8;
+                match bit!(instr[8:9]) {
+                    0b00 => format!("add {}, {}", reg_name(rd), reg_name(rs)),
+                    0b01 => format!("cmp {}, {}", reg_name(rd), reg_name(rs)),
+                    0b10 => format!("mov {}, {}", reg_name(rd), reg_name(rs)),
+                    _ => format!("bx {}", reg_name(rs)),
+                }
+            }
+        }
+        // PC-relative load
+        0b01001 => {
+            let target = (pc & !0b11).wrapping_add(imm8 * 4);
+            format!(
+                "ldr {}, [pc, {}] ; ${:08X}",
+                r(8),
+                format_imm(imm8 * 4),
+                target
+            )
+        }
+        // Load/store with register offset, and sign-extended byte/halfword
+        0b01010 | 0b01011 => {
+            let mnemonic = if bit!(instr[9]) == 0 {
+                ["str", "strb", "ldr", "ldrb"][bit!(instr[10:11]) as usize]
+            } else {
+                ["strh", "ldsb", "ldrh", "ldsh"][bit!(instr[10:11]) as usize]
+            };
+            format!("{} {}, [{}, {}]", mnemonic, r(0), r(3), r(6))
+        }
+        // Load/store with immediate offset
+        0b01100..=0b01111 => {
+            let byte = bit!(instr[12]) != 0;
+            let mnemonic = match (bit!(instr[11]) != 0, byte) {
+                (false, false) => "str",
+                (false, true) => "strb",
+                (true, false) => "ldr",
+                (true, true) => "ldrb",
+            };
+            let offset = bit!(instr[6:10]) as u32 * if byte { 1 } else { 4 };
+            format!("{} {}, [{}, {}]", mnemonic, r(0), r(3), format_imm(offset))
+        }
+        // Load/store halfword
+        0b10000 | 0b10001 => format!(
+            "{} {}, [{}, {}]",
+            if bit!(instr[11]) != 0 { "ldrh" } else { "strh" },
+            r(0),
+            r(3),
+            format_imm(bit!(instr[6:10]) as u32 * 2)
+        ),
+        // SP-relative load/store
+        0b10010 | 0b10011 => format!(
+            "{} {}, [sp, {}]",
+            if bit!(instr[11]) != 0 { "ldr" } else { "str" },
+            r(8),
+            format_imm(imm8 * 4)
+        ),
+        // Load address
+        0b10100 | 0b10101 => format!(
+            "add {}, {}, {}",
+            r(8),
+            if bit!(instr[11]) != 0 { "sp" } else { "pc" },
+            format_imm(imm8 * 4)
+        ),
+        0b10110 | 0b10111 => match bit!(instr[8:11]) {
+            // Add offset to stack pointer
+            0b0000 => format!(
+                "add sp, #{}{}",
+                if bit!(instr[7]) != 0 { "-" } else { "" },
+                bit!(instr[0:6]) * 4
+            ),
+            // Push/pop registers
+            0b0100 | 0b0101 => {
+                let regs = (instr & 0xFF) | bit!(instr[8]) << 14;
+                format!("push {}", format_reg_list(regs))
+            }
+            0b1100 | 0b1101 => {
+                let regs = (instr & 0xFF) | bit!(instr[8]) << 15;
+                format!("pop {}", format_reg_list(regs))
+            }
+            _ => format!(".hword 0x{:04X}", instr),
+        },
+        // Multiple load/store
+        0b11000 | 0b11001 => format!(
+            "{} {}!, {}",
+            if bit!(instr[11]) != 0 {
+                "ldmia"
+            } else {
+                "stmia"
+            },
+            r(8),
+            format_reg_list(instr & 0xFF)
+        ),
+        // Conditional branch and software interrupt
+        0b11010 | 0b11011 => match bit!(instr[8:11]) {
+            0b1111 => format!("swi {}", format_imm(imm8)),
+            0b1110 => format!(".hword 0x{:04X}", instr),
+            cond => {
+                let offset = sign_extend!(imm8[8]) << 1;
+                format!(
+                    "b{} ${:08X}",
+                    CONDITION_SUFFIXES[cond as usize],
+                    pc.wrapping_add(offset as u32)
+                )
+            }
+        },
+        // Unconditional branch
+        0b11100 => {
+            let offset = ((bit!(instr[0:10]) << 5) as i16 as i32) >> 4;
+            format!("b ${:08X}", pc.wrapping_add(offset as u32))
+        }
+        // Long branch with link, first half
+        0b11110 => {
+            if next_instr >> 11 == 0b11111 {
+                let high = ((bit!(instr[0:10]) as u32) << 21) as i32 >> 9;
+                let low = (bit!(next_instr[0:10]) as u32) << 1;
+                let target = pc.wrapping_add(high as u32).wrapping_add(low);
+                format!("bl ${:08X}", target)
+            } else {
+                format!(".hword 0x{:04X}", instr)
+            }
+        }
+        // Long branch with link, second half. Shown as part of the first one.
+        0b11111 => "(bl)".to_string(),
+        _ => format!(".hword 0x{:04X}", instr),
+    }
+}
+
+/// Disassembles the code between `start` and `end` (exclusive), returning the address and text of
+/// each instruction.
+pub fn disassemble_range(
+    memory: &mut HleMemory,
+    start: u32,
+    end: u32,
+    thumb: bool,
+) -> Vec<(u32, String)> {
+    let mut lines = Vec::new();
+    let mut address = start;
+    while address < end {
+        if thumb {
+            let instr = memory.read_u16(address);
+            let next_instr = memory.read_u16(address.wrapping_add(2));
+            lines.push((address, disassemble_thumb(instr, next_instr, address)));
+            address = address.wrapping_add(2);
+        } else {
+            let instr = memory.read_u32(address);
+            lines.push((address, disassemble_arm(instr, address)));
+            address = address.wrapping_add(4);
+        }
+    }
+    lines
+}
+
+/// Number of instructions shown by `format_disassembly_window`.
+pub const WINDOW_INSTRUCTIONS: u32 = 16;
+
+/// Formats the instructions around `pc` for display in a debugger. The current instruction is
+/// marked with `>` and breakpoints with `*`.
+pub fn format_disassembly_window(
+    memory: &mut HleMemory,
+    pc: u32,
+    thumb: bool,
+    breakpoints: &[u32],
+) -> String {
+    let instr_size = if thumb { 2 } else { 4 };
+    let start = pc.wrapping_sub(WINDOW_INSTRUCTIONS / 2 * instr_size);
+    let end = start.wrapping_add(WINDOW_INSTRUCTIONS * instr_size);
+
+    let mut text = String::new();
+    for (address, line) in disassemble_range(memory, start, end, thumb) {
+        writeln!(
+            text,
+            "{}{} {:08X}  {}",
+            if address == pc { ">" } else { " " },
+            if breakpoints.contains(&address) {
+                "*"
+            } else {
+                " "
+            },
+            address,
+            line
+        )
+        .unwrap();
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Code starting at address 0.
+    struct TestMemory(Vec<u8>);
+
+    impl HleMemory for TestMemory {
+        fn read_u8(&mut self, address: u32) -> u8 {
+            self.0.get(address as usize).cloned().unwrap_or(0)
+        }
+
+        fn write_u8(&mut self, _address: u32, _data: u8) {}
+    }
+
+    fn arm_blob(program: &[u32]) -> TestMemory {
+        TestMemory(
+            program
+                .iter()
+                .flat_map(|w| (0..4).map(move |i| (w >> (i * 8)) as u8))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_disassemble_arm() {
+        let mut memory = arm_blob(&[
+            0xE3A00302, // mov r0, #0x8000000
+            0xE35100EA, // cmp r1, #0xEA
+            0xE1B00061, // movs r0, r1, rrx
+            0xE0810312, // add r0, r1, r2, lsl r3
+            0xE59FD0B8, // ldr sp, [pc, #0xB8]
+            0xE5D01003, // ldrb r1, [r0, #0x3]
+            0xE0C010B2, // strh r1, [r0], #0x2
+            0xE11100B2, // ldrh r0, [r1, -r2]
+            0xE0203291, // mla r0, r1, r2, r3
+            0xE0810392, // umull r0, r1, r2, r3
+            0xE92D400F, // stmdb sp!, {r0-r3, lr}
+            0x1A000006, // bne $0000004C
+            0xE12FFF10, // bx r0
+            0xE129F000, // msr cpsr_cf, r0
+            0xEF060000, // swi #0x60000
+            0xE7000010, // undefined
+            0xE16F0F11, // clz r0, r1
+        ]);
+        let lines = disassemble_range(&mut memory, 0, 17 * 4, false);
+        let text: Vec<String> = lines
+            .iter()
+            .map(|&(address, ref line)| format!("{:08X} {}", address, line))
+            .collect();
+        assert_eq!(
+            text,
+            [
+                "00000000 mov r0, #0x8000000",
+                "00000004 cmp r1, #0xEA",
+                "00000008 movs r0, r1, rrx",
+                "0000000C add r0, r1, r2, lsl r3",
+                "00000010 ldr sp, [pc, #0xB8] ; $000000D0",
+                "00000014 ldrb r1, [r0, #0x3]",
+                "00000018 strh r1, [r0], #0x2",
+                "0000001C ldrh r0, [r1, -r2]",
+                "00000020 mla r0, r1, r2, r3",
+                "00000024 umull r0, r1, r2, r3",
+                "00000028 stmdb sp!, {r0-r3, lr}",
+                "0000002C bne $0000004C",
+                "00000030 bx r0",
+                "00000034 msr cpsr_cf, r0",
+                "00000038 swi #0x60000",
+                "0000003C undefined",
+                "00000040 undefined ; ARMv5 CLZ",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_thumb() {
+        let code: [u16; 12] = [
+            0x2001, // mov r0, #1
+            0x0089, // lsl r1, r1, #2
+            0x1C4A, // add r2, r1, #1
+            0x4308, // orr r0, r1
+            0x4770, // bx lr
+            0x4801, // ldr r0, [pc, #4]
+            0xB510, // push {r4, lr}
+            0xBD10, // pop {r4, pc}
+            0xD0FE, // beq $00000010
+            0xE7FE, // b $00000012
+            0xF000, // bl $00000030
+            0xF80C, //
+        ];
+        let mut memory = TestMemory(
+            code.iter()
+                .flat_map(|&h| vec![h as u8, (h >> 8) as u8])
+                .collect(),
+        );
+
+        let text = format_disassembly_window(&mut memory, 0x10, true, &[0x0C]);
+        assert_eq!(
+            text,
+            "   00000000  mov r0, #1\n\
+             \x20  00000002  lsl r1, r1, #2\n\
+             \x20  00000004  add r2, r1, #1\n\
+             \x20  00000006  orr r0, r1\n\
+             \x20  00000008  bx lr\n\
+             \x20  0000000A  ldr r0, [pc, #4] ; $00000010\n\
+             \x20* 0000000C  push {r4, lr}\n\
+             \x20  0000000E  pop {r4, pc}\n\
+             >  00000010  beq $00000010\n\
+             \x20  00000012  b $00000012\n\
+             \x20  00000014  bl $00000030\n\
+             \x20  00000016  (bl)\n\
+             \x20  00000018  lsl r0, r0, #0\n\
+             \x20  0000001A  lsl r0, r0, #0\n\
+             \x20  0000001C  lsl r0, r0, #0\n\
+             \x20  0000001E  lsl r0, r0, #0\n"
+        );
+    }
+}
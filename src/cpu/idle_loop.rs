@@ -0,0 +1,152 @@
+use system::MemoryRequest;
+use system::OperationType;
+
+/// Number of iterations a loop has to go through without any effect before it's considered idle.
+const IDLE_ITERATIONS: u32 = 4;
+
+/// Detects loops that only wait for something to happen, like `b .` waiting for an interrupt, or a
+/// loop polling VCOUNT, so that the CPU can skip ahead to the next event instead of running them.
+///
+/// The loop watched is the one closed by the last backwards branch. It's considered idle once it
+/// goes around several times without writing to memory, and with the same values in the registers
+/// at the start of each iteration. Only the flags can change, as polling loops compare what they
+/// read. This is conservative: a loop doing anything else is left running.
+pub struct IdleLoopDetector {
+    loop_start: Option<u32>,
+    /// Registers at the start of the current iteration, except PC. None right after a new loop
+    /// is found.
+    regs: Option<[u32; 15]>,
+    iterations: u32,
+    wrote: bool,
+    read: bool,
+    /// Whether the last iteration read from memory, other than fetching instructions.
+    polls: bool,
+}
+
+impl IdleLoopDetector {
+    pub fn new() -> IdleLoopDetector {
+        IdleLoopDetector {
+            loop_start: None,
+            regs: None,
+            iterations: 0,
+            wrote: false,
+            read: false,
+            polls: false,
+        }
+    }
+
+    /// Records a branch back to `target`, which starts watching the loop there.
+    pub fn backwards_branch(&mut self, target: u32) {
+        if self.loop_start != Some(target) {
+            self.loop_start = Some(target);
+            self.regs = None;
+            self.iterations = 0;
+        }
+    }
+
+    /// Records a memory access made by the CPU.
+    pub fn memory_access(&mut self, request: MemoryRequest) {
+        match request.op {
+            OperationType::Write => self.wrote = true,
+            OperationType::Read {
+                is_instruction: false,
+            } => self.read = true,
+            OperationType::Read {
+                is_instruction: true,
+            } => {}
+        }
+    }
+
+    /// Records the start of the instruction at `address`, returning true if it's the start of an
+    /// idle loop.
+    pub fn instruction(&mut self, address: u32, regs: &[u32; 16]) -> bool {
+        if self.loop_start != Some(address) {
+            return false;
+        }
+
+        let mut start_regs = [0; 15];
+        start_regs.copy_from_slice(&regs[..15]);
+        if !self.wrote && self.regs == Some(start_regs) {
+            self.iterations = self.iterations.saturating_add(1);
+        } else {
+            self.iterations = 0;
+        }
+        self.regs = Some(start_regs);
+        self.polls = self.read;
+        self.wrote = false;
+        self.read = false;
+        self.iterations >= IDLE_ITERATIONS
+    }
+
+    /// Whether the idle loop reads memory, which another unit can change to end it. A loop that
+    /// doesn't can only be left through an interrupt.
+    pub fn polls_memory(&self) -> bool {
+        self.polls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use system::AccessWidth;
+
+    fn request(op: OperationType) -> MemoryRequest {
+        MemoryRequest {
+            address: 0x0400_0006,
+            width: AccessWidth::Bit16,
+            op,
+            seq: false,
+        }
+    }
+
+    /// Runs iterations of a loop at 0x100 until it's detected as idle, returning how many it took
+    /// and whether it polls memory, or None if it isn't after 10.
+    fn iterations_until_idle<F: FnMut(&mut IdleLoopDetector, &mut [u32; 16])>(
+        mut body: F,
+    ) -> Option<(u32, bool)> {
+        let mut detector = IdleLoopDetector::new();
+        let mut regs = [0; 16];
+        detector.backwards_branch(0x100);
+        for i in 0..10 {
+            if detector.instruction(0x100, &regs) {
+                return Some((i, detector.polls_memory()));
+            }
+            body(&mut detector, &mut regs);
+            assert!(!detector.instruction(0x104, &regs));
+        }
+        None
+    }
+
+    #[test]
+    fn test_idle_loops() {
+        // b .
+        assert_eq!(
+            iterations_until_idle(|_, _| {}),
+            Some((IDLE_ITERATIONS, false))
+        );
+        // Polling loops load the same value every time. The first iteration doesn't count, as it
+        // loads it for the first time.
+        assert_eq!(
+            iterations_until_idle(|detector, regs| {
+                detector.memory_access(request(OperationType::Read {
+                    is_instruction: false,
+                }));
+                regs[0] = 0x1234;
+            }),
+            Some((IDLE_ITERATIONS + 1, true))
+        );
+    }
+
+    #[test]
+    fn test_busy_loops() {
+        // Writes to memory
+        assert_eq!(
+            iterations_until_idle(|detector, _| {
+                detector.memory_access(request(OperationType::Write))
+            }),
+            None
+        );
+        // Counts in a register
+        assert_eq!(iterations_until_idle(|_, regs| regs[1] += 1), None);
+    }
+}
@@ -1,42 +1,112 @@
+#[cfg(test)]
+pub mod asm;
+mod cpsr;
 mod decode;
+mod decode_cache;
+pub mod disasm;
+mod idle_loop;
 
-use self::decode::DecodeInstruction;
+pub use self::cpsr::Cpsr;
+pub use self::cpsr::Mode;
 use self::decode::DecodedArmInstruction;
+use self::decode::DecodedThumbInstruction;
+use self::decode_cache::DecodeCache;
+use self::idle_loop::IdleLoopDetector;
+use dma::DmaController;
+use hle;
+use hle::HleMemory;
+use scheduler::Clock;
 use scheduler::GeneratorTask;
 use scheduler::Task;
+use std::cell::RefCell;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
 use system::AccessWidth;
 use system::Bus;
 use system::MemoryRequest;
 use system::OperationType;
 
 // Named constants for common registers
+const SP: usize = 13;
 const LR: usize = 14;
 const PC: usize = 15;
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
-struct Cpsr(u32);
+/// Index into the banked register arrays for the registers of `mode`. User and System mode share
+/// the same registers, and invalid modes are treated like them.
+fn bank_index(mode: Mode) -> usize {
+    match mode {
+        Mode::Fiq => 1,
+        Mode::Irq => 2,
+        Mode::Supervisor => 3,
+        Mode::Abort => 4,
+        Mode::Undefined => 5,
+        _ => 0,
+    }
+}
 
-macro_rules! flag_field {
-    ($getter:ident, $setter:ident, $bit:expr) => {
-        fn $getter(&self) -> bool {
-            self.0 & (1 << $bit) != 0
-        }
+const UNDEFINED_VECTOR: u32 = 0x04;
+const SWI_VECTOR: u32 = 0x08;
+const IRQ_VECTOR: u32 = 0x18;
 
-        fn $setter(&mut self, c: bool) {
-            if c {
-                self.0 |= (1 << $bit);
-            } else {
-                self.0 &= !(1 << $bit);
-            }
-        }
-    };
+/// Describes an undefined instruction found at `address`. Code built for a later CPU is a common
+/// cause, so instructions added by ARMv5 are pointed out as such.
+fn describe_undefined(address: u32, instr: u32, armv5: Option<&str>) -> String {
+    let mut description = format!("Undefined instruction 0x{:08X} at 0x{:08X}", instr, address);
+    if let Some(mnemonic) = armv5 {
+        description += &format!(
+            "; this looks like an ARMv5 instruction ({}); the GBA CPU is ARMv4T",
+            mnemonic
+        );
+    }
+    description
+}
+
+/// Value the pipeline latches hold after reset. It is never executed, since execution always starts
+/// with a pipeline refill.
+const PIPELINE_RESET_VALUE: u32 = 0xFFFFFFFF;
+
+/// Reports software relying on unpredictable or misaligned behavior, which usually means it has
+/// gone off the rails. Only done in debug builds.
+fn warn_unpredictable(args: fmt::Arguments) {
+    if cfg!(debug_assertions) {
+        println!("Unpredictable: {}", args);
+    }
 }
 
-impl Cpsr {
-    flag_field!(negative, set_negative, 31);
-    flag_field!(zero, set_zero, 30);
-    flag_field!(carry, set_carry, 29);
-    flag_field!(overflow, set_overflow, 29);
+/// A single-register transfer computed by the first cycle of a load/store instruction.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct DataTransfer {
+    address: u32,
+    width: AccessWidth,
+    load: bool,
+    rd: u8,
+    /// The S bit of the LDM this is the last load of. See `BlockTransfer`.
+    use_banked_or_spsr: bool,
+}
+
+/// A multiple-register transfer computed by the first cycle of a block transfer instruction, at the
+/// register transferred in the current cycle.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct BlockTransfer {
+    address: u32,
+    // Registers left to transfer, starting with the current one, which is the lowest
+    regs: u16,
+    load: bool,
+    // Register transferred in the previous cycle, if any. Its loaded data is on the bus now.
+    previous_rd: Option<u8>,
+    /// Base register and the value written back to it, in the first transfer cycle. So a store of
+    /// the base stores the original value if it's the first register, and a load of it wins.
+    writeback: Option<(u8, u32)>,
+    /// The S bit. Transfers the User mode registers, unless PC is loaded, in which case the CPSR is
+    /// restored from the SPSR along with it, to return from an exception.
+    use_banked_or_spsr: bool,
+}
+
+impl BlockTransfer {
+    fn uses_user_bank(&self) -> bool {
+        self.use_banked_or_spsr && !(self.load && self.regs & 1 << PC != 0)
+    }
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -44,17 +114,43 @@ enum ExecuteState {
     PipelineRefill1,
     PipelineRefill2,
     FirstCycle, // for single-cycle instructions, this is the only cycle
+    DataCycle(DataTransfer),
+    BlockDataCycle(BlockTransfer),
+    LoadWriteback(DataTransfer), // internal cycle, loaded data is written to the register
+    InternalCycle,               // generic internal cycle with no bus activity
+    HleWait(u32),                // internal cycles taken by an HLE BIOS function
 }
 
-struct ArmCpu {
+pub struct ArmCpu {
     regs: [u32; 16],
     cpsr: Cpsr,
+
+    // r13 and r14 of each mode while it isn't active, indexed by `bank_index`
+    banked_r13_r14: [[u32; 2]; 6],
+    // r8-r12 of FIQ mode while in any other mode, or of the other modes while in FIQ mode
+    banked_r8_r12: [u32; 5],
+    // SPSR of each exception mode, indexed by `bank_index`. User/System mode don't have one.
+    spsrs: [Cpsr; 6],
+
     current_execute_state: ExecuteState,
 
+    // True if the request made in the previous cycle was an instruction fetch, meaning its result
+    // is on the bus and needs to be latched by the fetch stage.
+    fetch_in_flight: bool,
+    // Address of the last instruction fetch, to pick the fetched halfword out of the bus in Thumb
+    // state
+    fetch_address: u32,
     // Fetch stage output
     f_out_instr: u32,
     // Decode stage output
     d_out_instr: u32,
+    decode_cache: DecodeCache,
+    idle_loop: IdleLoopDetector,
+
+    // When set, SWIs call HLE implementations of the BIOS functions instead of entering the BIOS
+    hle_memory: Option<Rc<RefCell<HleMemory>>>,
+    // Interrupts an HLE IntrWait is halted waiting for, until its SWI is executed again
+    hle_intr_wait: Option<u16>,
 }
 
 fn decode_immediate(imm: u8, rotate: u8, carry_in: bool) -> (u32, bool) {
@@ -67,6 +163,55 @@ fn decode_immediate(imm: u8, rotate: u8, carry_in: bool) -> (u32, bool) {
     (result, carry_out)
 }
 
+/// Shifts `value` by an amount taken from a register. Only the bottom 8 bits of the register are
+/// used, so amounts of 32 and above are valid and have defined results.
+fn barrel_shift(value: u32, shift_type: u8, amount: u32, carry_in: bool) -> (u32, bool) {
+    if amount == 0 {
+        return (value, carry_in);
+    }
+
+    let sign_bit = bit!(value[31]) != 0;
+    match shift_type {
+        // LSL
+        0 => match amount {
+            1..=31 => (value << amount, bit!(value[32 - amount]) != 0),
+            32 => (0, bit!(value[0]) != 0),
+            _ => (0, false),
+        },
+        // LSR
+        1 => match amount {
+            1..=31 => (value >> amount, bit!(value[amount - 1]) != 0),
+            32 => (0, sign_bit),
+            _ => (0, false),
+        },
+        // ASR
+        2 => match amount {
+            1..=31 => (
+                ((value as i32) >> amount) as u32,
+                bit!(value[amount - 1]) != 0,
+            ),
+            _ => (((value as i32) >> 31) as u32, sign_bit),
+        },
+        // ROR
+        3 => match amount % 32 {
+            0 => (value, sign_bit),
+            amount => (value.rotate_right(amount), bit!(value[amount - 1]) != 0),
+        },
+        _ => unreachable!(),
+    }
+}
+
+/// Shifts `value` by an immediate amount. An amount of 0 encodes special cases: LSL #0 leaves the
+/// value and carry untouched, LSR #0 and ASR #0 mean a shift by 32, and ROR #0 means RRX.
+fn barrel_shift_imm(value: u32, shift_type: u8, shift_imm: u8, carry_in: bool) -> (u32, bool) {
+    match (shift_type, shift_imm) {
+        (1, 0) | (2, 0) => barrel_shift(value, shift_type, 32, carry_in),
+        // RRX: 33-bit rotate right through carry
+        (3, 0) => ((carry_in as u32) << 31 | value >> 1, bit!(value[0]) != 0),
+        _ => barrel_shift(value, shift_type, shift_imm as u32, carry_in),
+    }
+}
+
 fn add_has_signed_overflow(x: u32, y: u32, r: u32) -> bool {
     // Signed overflow happens when the carry into the MSB differs from the carry out of it. This
     // can be detected by comparing the output bit to both inputs. If they're both different, then
@@ -104,30 +249,18 @@ fn alu_operation(
             cpsr.set_carry(shifter_carry);
             op1 ^ op2
         }
-        // SUB, CMP
+        // SUB, CMP. Subtraction is addition of the complement, with C set when there's no borrow.
         2 | 10 => {
-            let (x, not_c) = op1.overflowing_sub(op2);
-            cpsr.set_carry(!not_c);
-            cpsr.set_overflow(add_has_signed_overflow(op1, op2, x));
-
-            let (x2, c2, v2) = add_with_carry(op1, !op2, true);
-            assert_eq!(x, x2);
-            assert_eq!(cpsr.carry(), c2);
-            assert_eq!(cpsr.overflow(), v2);
-
+            let (x, c, v) = add_with_carry(op1, !op2, true);
+            cpsr.set_carry(c);
+            cpsr.set_overflow(v);
             x
         }
         // RSB
         3 => {
-            let (x, not_c) = op2.overflowing_sub(op1);
-            cpsr.set_carry(!not_c);
-            cpsr.set_overflow(add_has_signed_overflow(op2, op1, x));
-
-            let (x2, c2, v2) = add_with_carry(op2, !op1, true);
-            assert_eq!(x, x2);
-            assert_eq!(cpsr.carry(), c2);
-            assert_eq!(cpsr.overflow(), v2);
-
+            let (x, c, v) = add_with_carry(op2, !op1, true);
+            cpsr.set_carry(c);
+            cpsr.set_overflow(v);
             x
         }
         // ADD, CMN
@@ -188,17 +321,273 @@ fn alu_operation(
 }
 
 impl ArmCpu {
-    fn new() -> ArmCpu {
+    pub fn new() -> ArmCpu {
         ArmCpu {
             regs: [0; 16],
-            cpsr: Cpsr(0),
+            // Reset enters Supervisor mode with interrupts disabled
+            cpsr: Cpsr::from_bits(0xC0 | Mode::Supervisor.to_bits()),
+
+            banked_r13_r14: [[0; 2]; 6],
+            banked_r8_r12: [0; 5],
+            spsrs: [Cpsr::from_bits(0); 6],
             current_execute_state: ExecuteState::PipelineRefill1,
 
-            f_out_instr: 0xFFFFFFFF,
-            d_out_instr: 0xFFFFFFFF,
+            fetch_in_flight: false,
+            fetch_address: 0,
+            f_out_instr: PIPELINE_RESET_VALUE,
+            d_out_instr: PIPELINE_RESET_VALUE,
+            decode_cache: DecodeCache::new(),
+            idle_loop: IdleLoopDetector::new(),
+
+            hle_memory: None,
+            hle_intr_wait: None,
+        }
+    }
+
+    /// Makes SWIs run HLE implementations of the BIOS functions, accessing `memory` directly.
+    /// Passing None goes back to using the BIOS.
+    pub fn set_hle_bios(&mut self, memory: Option<Rc<RefCell<HleMemory>>>) {
+        self.hle_memory = memory;
+    }
+
+    pub fn uses_hle_bios(&self) -> bool {
+        self.hle_memory.is_some()
+    }
+
+    /// Sets up the state the BIOS leaves when it jumps to the cart, for running without a BIOS.
+    pub fn skip_bios_boot(&mut self) {
+        self.boot_to(0x0800_0000);
+    }
+
+    /// Sets up the registers like the BIOS does before jumping to `entry_point`, both after the
+    /// boot animation and on SoftReset.
+    fn boot_to(&mut self, entry_point: u32) {
+        // Each mode's SP is set while in that mode, since the current mode's isn't in the banks
+        for &(mode, sp) in [
+            (Mode::Supervisor, 0x0300_7FE0),
+            (Mode::Irq, 0x0300_7FA0),
+            (Mode::System, 0x0300_7F00),
+        ]
+        .iter()
+        {
+            self.switch_mode(mode);
+            self.regs[SP] = sp;
+            self.regs[LR] = 0;
+            self.spsrs[bank_index(mode)] = Cpsr::from_bits(0);
+        }
+        // In ARM state, with both IRQs and FIQs enabled
+        self.cpsr = Cpsr::from_bits(Mode::System.to_bits());
+        for reg in &mut self.regs[..13] {
+            *reg = 0;
+        }
+        self.regs[PC] = entry_point;
+        self.current_execute_state = ExecuteState::PipelineRefill1;
+    }
+
+    pub fn regs(&self) -> &[u32; 16] {
+        &self.regs
+    }
+
+    pub fn cpsr(&self) -> Cpsr {
+        self.cpsr
+    }
+
+    /// Address of the instruction that starts executing next, while at an instruction boundary.
+    /// PC is two instructions ahead of it, because of the pipeline.
+    pub fn next_instruction_address(&self) -> u32 {
+        self.regs[PC].wrapping_sub(self.instr_size() * 2)
+    }
+
+    /// Switches to the registers of `mode` and sets it in the CPSR.
+    fn switch_mode(&mut self, mode: Mode) {
+        let old_bank = bank_index(self.cpsr.mode());
+        let new_bank = bank_index(mode);
+        if old_bank != new_bank {
+            self.banked_r13_r14[old_bank].copy_from_slice(&self.regs[13..15]);
+            self.regs[13..15].copy_from_slice(&self.banked_r13_r14[new_bank]);
+
+            let fiq_bank = bank_index(Mode::Fiq);
+            if (old_bank == fiq_bank) != (new_bank == fiq_bank) {
+                for (reg, banked) in self.regs[8..13].iter_mut().zip(&mut self.banked_r8_r12) {
+                    mem::swap(reg, banked);
+                }
+            }
+        }
+        self.cpsr.set_mode(mode);
+    }
+
+    /// Enters an exception `mode`, leaving `return_address` in its LR, and jumps to `vector`.
+    fn enter_exception(&mut self, mode: Mode, vector: u32, return_address: u32) -> ExecuteState {
+        let old_cpsr = self.cpsr;
+        self.switch_mode(mode);
+        self.spsrs[bank_index(mode)] = old_cpsr;
+        self.cpsr.set_irq_disabled(true);
+        // Exceptions are always handled in ARM state
+        self.cpsr.set_thumb(false);
+        self.regs[LR] = return_address;
+        self.regs[PC] = vector;
+        ExecuteState::PipelineRefill1
+    }
+
+    /// Takes an IRQ instead of executing the instruction that reached the execute stage, which is
+    /// discarded, to be executed when the handler returns with `subs pc, lr, #4`.
+    fn enter_irq(&mut self) -> ExecuteState {
+        // LR is left 4 bytes ahead of the discarded instruction. PC is 8 bytes ahead of it in ARM
+        // state, but only 4 in Thumb state.
+        let return_address = if self.cpsr.thumb() {
+            self.regs[PC]
+        } else {
+            self.regs[PC].wrapping_sub(4)
+        };
+        self.enter_exception(Mode::Irq, IRQ_VECTOR, return_address)
+    }
+
+    /// True if the IRQ line is raised and the CPU would take it at the next instruction boundary.
+    fn irq_pending(&self, bus: &Bus) -> bool {
+        bus.irq.get() && !self.cpsr.irq_disabled()
+    }
+
+    /// Copies the SPSR of the current mode to the CPSR, switching to the mode it holds, to return
+    /// from an exception.
+    fn restore_cpsr(&mut self) {
+        let mode = self.cpsr.mode();
+        if bank_index(mode) == 0 {
+            warn_unpredictable(format_args!(
+                "SPSR restore in {:?} mode, which has none",
+                mode
+            ));
+            return;
+        }
+        let spsr = self.spsrs[bank_index(mode)];
+        self.switch_mode(spsr.mode());
+        self.cpsr = spsr;
+    }
+
+    /// Writes the fields of the CPSR, or of the current mode's SPSR if `saved`, selected by the
+    /// `field_mask` of MSR. Each of its bits selects a byte, from the control field at the bottom.
+    fn execute_msr(&mut self, saved: bool, field_mask: u8, value: u32) {
+        let mut mask = 0;
+        for field in 0..4 {
+            if field_mask & 1 << field != 0 {
+                mask |= 0xFF << (field * 8);
+            }
+        }
+
+        let mode = self.cpsr.mode();
+        if saved {
+            if bank_index(mode) == 0 {
+                warn_unpredictable(format_args!(
+                    "MSR to SPSR in {:?} mode, which has none",
+                    mode
+                ));
+                return;
+            }
+            self.spsrs[bank_index(mode)].write_masked(value, mask);
+            return;
+        }
+
+        // User mode can only change the condition flags
+        if mode == Mode::User {
+            mask &= 0xFF00_0000;
+        }
+        let mut cpsr = self.cpsr;
+        cpsr.write_masked(value, mask);
+        if cpsr.thumb() != self.cpsr.thumb() {
+            warn_unpredictable(format_args!("MSR changing the T bit"));
+        }
+        self.switch_mode(cpsr.mode());
+        self.cpsr = cpsr;
+    }
+
+    /// Reads register `r` of User mode, whichever mode is active, for STM with the S bit.
+    fn user_reg(&self, r: usize) -> u32 {
+        let mode = self.cpsr.mode();
+        match r {
+            8..=12 if mode == Mode::Fiq => self.banked_r8_r12[r - 8],
+            13 | 14 if bank_index(mode) != 0 => self.banked_r13_r14[0][r - 13],
+            _ => self.regs[r],
+        }
+    }
+
+    /// Writes register `r` of User mode, whichever mode is active, for LDM with the S bit.
+    fn set_user_reg(&mut self, r: usize, value: u32) {
+        let mode = self.cpsr.mode();
+        match r {
+            8..=12 if mode == Mode::Fiq => self.banked_r8_r12[r - 8] = value,
+            13 | 14 if bank_index(mode) != 0 => self.banked_r13_r14[0][r - 13] = value,
+            _ => self.regs[r] = value,
+        }
+    }
+
+    /// Jumps to `target` in the current state. Loads and data processing instructions that write
+    /// PC don't change the state on ARMv4, unlike BX.
+    fn write_pc(&mut self, target: u32) {
+        let alignment = self.instr_size() - 1;
+        if target & alignment != 0 {
+            warn_unpredictable(format_args!(
+                "Write of unaligned address 0x{:08X} to PC",
+                target
+            ));
+        }
+        self.regs[PC] = target & !alignment;
+    }
+
+    /// Size of the instructions in the current state, which is how far PC advances with each fetch.
+    fn instr_size(&self) -> u32 {
+        if self.cpsr.thumb() {
+            2
+        } else {
+            4
         }
     }
 
+    /// True if the next cycle starts executing a new instruction.
+    pub fn at_instruction_boundary(&self) -> bool {
+        self.current_execute_state == ExecuteState::FirstCycle
+    }
+
+    /// Runs the CPU a cycle at a time, except for idle loops, which are skipped until the next
+    /// event announced on `clock` that could end them.
+    pub fn run_task(
+        cpu: Rc<RefCell<ArmCpu>>,
+        bus: Rc<Bus>,
+        dma: Rc<RefCell<DmaController>>,
+        clock: Rc<Clock>,
+    ) -> impl Task<'static, Return = ()> {
+        GeneratorTask::new(move || loop {
+            let idle_cycles = cpu.borrow_mut().idle_cycles(&bus, &dma.borrow(), &clock);
+            if idle_cycles > 0 {
+                wait_cycles!(idle_cycles);
+                continue;
+            }
+            cpu.borrow_mut().step(&bus);
+            wait_cycles!(1);
+        })
+    }
+
+    /// Returns the number of cycles until the next event if the CPU is at the start of an idle
+    /// loop, or 0 if it should keep running.
+    fn idle_cycles(&mut self, bus: &Bus, dma: &DmaController, clock: &Clock) -> u64 {
+        // A triggered DMA takes the bus a cycle or so later, and the CPU has to notice
+        if bus.should_cpu_wait()
+            || dma.active_channel().is_some()
+            || !self.at_instruction_boundary()
+        {
+            return 0;
+        }
+        let address = self.next_instruction_address();
+        if !self.idle_loop.instruction(address, &self.regs) {
+            return 0;
+        }
+
+        // Interrupts are taken at the start of the next instruction. If they can't be, a loop that
+        // doesn't read anything can never end, and is left running like on hardware.
+        if self.irq_pending(bus) || (self.cpsr.irq_disabled() && !self.idle_loop.polls_memory()) {
+            return 0;
+        }
+        clock.next_event().saturating_sub(clock.now())
+    }
+
     fn step(&mut self, bus: &Bus) {
         if bus.should_cpu_wait() {
             return;
@@ -207,25 +596,43 @@ impl ArmCpu {
         self.step_fetch_or_single_instruction(bus);
     }
 
+    /// Reads register `r` as an operand of the instruction being executed. While executing, PC
+    /// holds the address being fetched, which is already the architectural value of the
+    /// instruction's address + 8. Operands read in the extra cycle of a register-specified shift
+    /// see the PC one instruction further ahead, at + 12.
+    fn read_operand_reg(&self, r: u8, second_cycle: bool) -> u32 {
+        if r as usize == PC && second_cycle {
+            self.regs[PC].wrapping_add(4)
+        } else {
+            self.regs[r as usize]
+        }
+    }
+
     fn step_execute_fsm(
         &mut self,
         bus: &Bus,
         current_state: ExecuteState,
         in_instr: u32,
+        in_data: u32,
     ) -> ExecuteState {
         match current_state {
             ExecuteState::PipelineRefill1 => {
-                self.regs[PC] = self.regs[PC].wrapping_add(4);
+                self.regs[PC] = self.regs[PC].wrapping_add(self.instr_size());
                 ExecuteState::PipelineRefill2
             }
             ExecuteState::PipelineRefill2 => {
-                self.regs[PC] = self.regs[PC].wrapping_add(4);
+                self.regs[PC] = self.regs[PC].wrapping_add(self.instr_size());
                 ExecuteState::FirstCycle
             }
             ExecuteState::FirstCycle => {
-                println!("Executing {:08X}", in_instr);
+                println!("Executing {:08X} [{}]", in_instr, self.cpsr);
+                if self.cpsr.thumb() {
+                    return self.execute_thumb(in_instr as u16);
+                }
+
                 // TODO: Handle condition
-                let decoded_instr = DecodedArmInstruction::decode_arm_instruction(in_instr);
+                let instr_address = self.regs[PC].wrapping_sub(8);
+                let decoded_instr = self.decode_cache.decode(instr_address, in_instr);
                 match decoded_instr {
                     DecodedArmInstruction::DataProcessingImmediate {
                         cond,
@@ -238,92 +645,550 @@ impl ArmCpu {
                     } => {
                         let (imm_value, imm_carry) =
                             decode_immediate(imm, rotate, self.cpsr.carry());
-                        let (result, new_cpsr) = alu_operation(
-                            opcode,
-                            self.regs[rn as usize],
-                            imm_value,
-                            imm_carry,
-                            self.cpsr,
+                        let op1 = self.read_operand_reg(rn, false);
+                        if self.execute_data_processing(opcode, s, rd, op1, imm_value, imm_carry) {
+                            return ExecuteState::PipelineRefill1;
+                        }
+                    }
+                    DecodedArmInstruction::DataProcessingImmShift {
+                        cond,
+                        opcode,
+                        s,
+                        rn,
+                        rd,
+                        shift_imm,
+                        shift_type,
+                        rm,
+                    } => {
+                        let (op2, shifter_carry) = barrel_shift_imm(
+                            self.read_operand_reg(rm, false),
+                            shift_type,
+                            shift_imm,
+                            self.cpsr.carry(),
+                        );
+                        let op1 = self.read_operand_reg(rn, false);
+                        if self.execute_data_processing(opcode, s, rd, op1, op2, shifter_carry) {
+                            return ExecuteState::PipelineRefill1;
+                        }
+                    }
+                    DecodedArmInstruction::DataProcessingRegShift {
+                        cond,
+                        opcode,
+                        s,
+                        rn,
+                        rd,
+                        rs,
+                        shift_type,
+                        rm,
+                    } => {
+                        let amount = self.regs[rs as usize] & 0xFF;
+                        let (op2, shifter_carry) = barrel_shift(
+                            self.read_operand_reg(rm, true),
+                            shift_type,
+                            amount,
+                            self.cpsr.carry(),
                         );
+                        let op1 = self.read_operand_reg(rn, true);
+                        if self.execute_data_processing(opcode, s, rd, op1, op2, shifter_carry) {
+                            return ExecuteState::PipelineRefill1;
+                        }
 
-                        if rd as usize == PC {
-                            if s {
-                                unimplemented!("Handle restoring SPSR"); // TODO
-                            }
-                            unimplemented!("Handle PC writes"); // TODO
+                        self.regs[PC] = self.regs[PC].wrapping_add(4);
+                        return ExecuteState::InternalCycle;
+                    }
+                    DecodedArmInstruction::LoadStoreImmOffset {
+                        cond,
+                        indexing_p,
+                        imm_add,
+                        byte,
+                        indexing_w,
+                        load,
+                        rn,
+                        rd,
+                        imm,
+                    } => {
+                        let base = self.read_operand_reg(rn, false);
+                        let offset_address = if imm_add {
+                            base.wrapping_add(imm as u32)
                         } else {
-                            if s {
-                                unimplemented!("Handle flags update"); // TODO
-                            }
+                            base.wrapping_sub(imm as u32)
+                        };
+                        let address = if indexing_p { offset_address } else { base };
 
-                            match opcode {
-                                // TST, TEQ, CMP, CMN
-                                8 | 9 | 10 | 11 => (),
-                                _ => self.regs[rd as usize] = result,
+                        if !indexing_p || indexing_w {
+                            if rn as usize == PC {
+                                // Unpredictable. The writeback is dropped, so that the
+                                // instruction doesn't also act as a branch.
+                                warn_unpredictable(format_args!(
+                                    "Writeback to PC, base 0x{:08X}",
+                                    base
+                                ));
+                            } else {
+                                // If this is a load to the same register, it'll be overwritten
+                                // later
+                                self.regs[rn as usize] = offset_address;
                             }
                         }
+
+                        self.regs[PC] = self.regs[PC].wrapping_add(4);
+                        return ExecuteState::DataCycle(DataTransfer {
+                            address,
+                            width: if byte {
+                                AccessWidth::Bit8
+                            } else {
+                                AccessWidth::Bit32
+                            },
+                            load,
+                            rd,
+                            use_banked_or_spsr: false,
+                        });
+                    }
+                    DecodedArmInstruction::LoadStoreMultiple {
+                        cond,
+                        indexing_p,
+                        upwards,
+                        use_banked_or_spsr,
+                        indexing_w,
+                        load,
+                        rn,
+                        regs,
+                    } => {
+                        // An empty list transfers PC alone, but moves the base as if all 16
+                        // registers were transferred
+                        let (regs, size) = if regs == 0 {
+                            (1 << PC, 0x40)
+                        } else {
+                            (regs, regs.count_ones() * 4)
+                        };
+
+                        // Registers are always transferred from the lowest address up, with the
+                        // lowest register at the lowest address
+                        let base = self.read_operand_reg(rn, false);
+                        let (address, new_base) = match (indexing_p, upwards) {
+                            (false, true) => (base, base.wrapping_add(size)),
+                            (true, true) => (base.wrapping_add(4), base.wrapping_add(size)),
+                            (false, false) => (
+                                base.wrapping_sub(size).wrapping_add(4),
+                                base.wrapping_sub(size),
+                            ),
+                            (true, false) => (base.wrapping_sub(size), base.wrapping_sub(size)),
+                        };
+
+                        let loads_pc = load && regs & 1 << PC != 0;
+                        if use_banked_or_spsr && bank_index(self.cpsr.mode()) == 0 {
+                            warn_unpredictable(format_args!(
+                                "LDM/STM with the S bit in {:?} mode",
+                                self.cpsr.mode()
+                            ));
+                        }
+                        let writeback = if !indexing_w {
+                            None
+                        } else if rn as usize == PC {
+                            warn_unpredictable(format_args!(
+                                "Writeback to PC, base 0x{:08X}",
+                                base
+                            ));
+                            None
+                        } else {
+                            if use_banked_or_spsr && !loads_pc {
+                                // The base would be written in the current mode's bank
+                                warn_unpredictable(format_args!(
+                                    "Writeback with User mode registers, base r{}",
+                                    rn
+                                ));
+                            }
+                            Some((rn, new_base))
+                        };
+
+                        self.regs[PC] = self.regs[PC].wrapping_add(4);
+                        return ExecuteState::BlockDataCycle(BlockTransfer {
+                            // Block transfers ignore the low bits of the address
+                            address: address & !0b11,
+                            regs,
+                            load,
+                            previous_rd: None,
+                            writeback,
+                            use_banked_or_spsr,
+                        });
                     }
                     DecodedArmInstruction::BranchImm { cond, link, offset } => {
                         if link {
                             self.regs[LR] = self.regs[PC].wrapping_sub(4);
                         }
                         // TODO: Handle faulting on bad address
-                        self.regs[PC] = self.regs[PC].wrapping_add((offset * 4) as u32);
+                        self.regs[PC] = self.regs[PC].wrapping_add(offset as u32);
+                        if offset <= -8 {
+                            self.idle_loop.backwards_branch(self.regs[PC]);
+                        }
                         println!("Branching to PC={:0X}", self.regs[PC]);
                         return ExecuteState::PipelineRefill1;
                     }
+                    DecodedArmInstruction::BranchAndExchangeReg { cond, rm } => {
+                        // Bit 0 of the target selects the state to continue in
+                        let target = self.read_operand_reg(rm, false);
+                        self.cpsr.set_thumb(bit!(target[0]) != 0);
+                        if target & 0b11 == 0b10 {
+                            // PC keeps bit 1 set, but fetches are aligned by the bus
+                            warn_unpredictable(format_args!(
+                                "BX to unaligned ARM address 0x{:08X}",
+                                target
+                            ));
+                        }
+                        self.regs[PC] = target & !1;
+                        return ExecuteState::PipelineRefill1;
+                    }
+                    DecodedArmInstruction::SoftwareInterrupt { cond, comment } => {
+                        // The BIOS takes the function number from the top byte of the comment
+                        let function = bit!(comment[16:23]) as u8;
+                        let cycles = match self.hle_memory {
+                            Some(ref memory) => {
+                                let return_pc = self.regs[PC];
+                                let result = hle::dispatch_swi(
+                                    function,
+                                    &mut self.regs,
+                                    &mut *memory.borrow_mut(),
+                                    &mut self.hle_intr_wait,
+                                );
+                                if self.regs[PC] == instr_address {
+                                    // Halted by IntrWait. The SWI runs again after the interrupt
+                                    // handler returns to it.
+                                    return ExecuteState::PipelineRefill1;
+                                }
+                                if self.regs[PC] != return_pc {
+                                    // SoftReset jumps elsewhere, after resetting all modes
+                                    let entry_point = self.regs[PC];
+                                    self.boot_to(entry_point);
+                                    return ExecuteState::PipelineRefill1;
+                                }
+                                result.unwrap_or_else(|| {
+                                    println!("Unimplemented HLE SWI 0x{:02X}", function);
+                                    0
+                                })
+                            }
+                            None => {
+                                // LR points to the instruction after the SWI
+                                let return_address = self.regs[PC].wrapping_sub(4);
+                                return self.enter_exception(
+                                    Mode::Supervisor,
+                                    SWI_VECTOR,
+                                    return_address,
+                                );
+                            }
+                        };
+
+                        self.regs[PC] = self.regs[PC].wrapping_add(4);
+                        return if cycles > 1 {
+                            ExecuteState::HleWait(cycles - 1)
+                        } else {
+                            ExecuteState::FirstCycle
+                        };
+                    }
+                    DecodedArmInstruction::MoveToStatusReg {
+                        cond,
+                        saved,
+                        field_mask,
+                        rm,
+                    } => {
+                        let value = self.regs[rm as usize];
+                        self.execute_msr(saved, field_mask, value);
+                    }
+                    DecodedArmInstruction::MoveToStatusRegImm {
+                        cond,
+                        saved,
+                        field_mask,
+                        rotate,
+                        imm,
+                    } => {
+                        let (value, _) = decode_immediate(imm, rotate, self.cpsr.carry());
+                        self.execute_msr(saved, field_mask, value);
+                    }
+                    DecodedArmInstruction::UndefinedInstruction { armv5 } => {
+                        println!("{}", describe_undefined(instr_address, in_instr, armv5));
+                        // LR points to the instruction after the undefined one
+                        let return_address = self.regs[PC].wrapping_sub(4);
+                        return self.enter_exception(
+                            Mode::Undefined,
+                            UNDEFINED_VECTOR,
+                            return_address,
+                        );
+                    }
                     instr => unimplemented!("Unimplemented instruction execute: {:?}", instr),
                 }
 
-                self.regs[PC].wrapping_add(4);
+                self.regs[PC] = self.regs[PC].wrapping_add(4);
                 return ExecuteState::FirstCycle;
             }
+            ExecuteState::DataCycle(transfer) => {
+                if transfer.load {
+                    ExecuteState::LoadWriteback(transfer)
+                } else {
+                    // PC was already advanced in the first cycle, so a stored PC is the address of
+                    // the instruction + 12 instead of the + 8 it reads as in operands
+                    let value = self.regs[transfer.rd as usize];
+                    bus.drive_data(match transfer.width {
+                        AccessWidth::Bit8 => (value as u8 as u32) * 0x0101_0101,
+                        AccessWidth::Bit16 => (value as u16 as u32) * 0x0001_0001,
+                        AccessWidth::Bit32 => value,
+                    });
+                    ExecuteState::FirstCycle
+                }
+            }
+            ExecuteState::BlockDataCycle(transfer) => {
+                let rd = transfer.regs.trailing_zeros() as u8;
+                let user_bank = transfer.uses_user_bank();
+                if transfer.load {
+                    if let Some(previous_rd) = transfer.previous_rd {
+                        if user_bank {
+                            self.set_user_reg(previous_rd as usize, in_data);
+                        } else {
+                            self.regs[previous_rd as usize] = in_data;
+                        }
+                    }
+                } else if user_bank {
+                    bus.drive_data(self.user_reg(rd as usize));
+                } else {
+                    bus.drive_data(self.regs[rd as usize]);
+                }
+                if let Some((rn, new_base)) = transfer.writeback {
+                    self.regs[rn as usize] = new_base;
+                }
+
+                let regs = transfer.regs & (transfer.regs - 1);
+                if regs != 0 {
+                    ExecuteState::BlockDataCycle(BlockTransfer {
+                        address: transfer.address.wrapping_add(4),
+                        regs,
+                        previous_rd: Some(rd),
+                        writeback: None,
+                        ..transfer
+                    })
+                } else if transfer.load {
+                    // The last register is written in an internal cycle, like a single load
+                    ExecuteState::LoadWriteback(DataTransfer {
+                        address: transfer.address,
+                        width: AccessWidth::Bit32,
+                        load: true,
+                        rd,
+                        use_banked_or_spsr: transfer.use_banked_or_spsr,
+                    })
+                } else {
+                    ExecuteState::FirstCycle
+                }
+            }
+            ExecuteState::InternalCycle => ExecuteState::FirstCycle,
+            ExecuteState::HleWait(cycles) => {
+                if cycles > 1 {
+                    ExecuteState::HleWait(cycles - 1)
+                } else {
+                    ExecuteState::FirstCycle
+                }
+            }
+            ExecuteState::LoadWriteback(transfer) => {
+                // Unaligned word loads are rotated so that the addressed byte ends up in the LSB
+                let lane_shift = (transfer.address & 0b11) * 8;
+                let data = in_data;
+                let value = match transfer.width {
+                    AccessWidth::Bit8 => (data >> lane_shift) & 0xFF,
+                    AccessWidth::Bit16 => unimplemented!("Halfword loads"), // TODO
+                    AccessWidth::Bit32 => data.rotate_right(lane_shift),
+                };
+
+                if transfer.rd as usize == PC {
+                    if transfer.use_banked_or_spsr {
+                        // Returning from an exception can switch to Thumb state
+                        self.restore_cpsr();
+                    }
+                    self.write_pc(value);
+                    return ExecuteState::PipelineRefill1;
+                }
+                if transfer.use_banked_or_spsr {
+                    self.set_user_reg(transfer.rd as usize, value);
+                } else {
+                    self.regs[transfer.rd as usize] = value;
+                }
+                ExecuteState::FirstCycle
+            }
         }
     }
 
+    /// Executes the first cycle of a Thumb instruction, returning the state for the next one.
+    fn execute_thumb(&mut self, instr: u16) -> ExecuteState {
+        match DecodedThumbInstruction::decode_thumb_instruction(instr) {
+            DecodedThumbInstruction::PushPop { load, pc_lr, regs } => {
+                // PUSH is STMDB sp! and POP is LDMIA sp!, with the extra register being LR for
+                // PUSH and PC for POP
+                let mut regs = regs as u16;
+                if pc_lr {
+                    regs |= 1 << if load { PC } else { LR };
+                }
+                // Like LDM/STM, an empty list transfers PC alone and moves SP by 16 registers
+                let (regs, size) = if regs == 0 {
+                    (1 << PC, 0x40)
+                } else {
+                    (regs, regs.count_ones() * 4)
+                };
+
+                let sp = self.regs[SP];
+                let (address, new_sp) = if load {
+                    (sp, sp.wrapping_add(size))
+                } else {
+                    (sp.wrapping_sub(size), sp.wrapping_sub(size))
+                };
+                self.regs[SP] = new_sp;
+
+                self.regs[PC] = self.regs[PC].wrapping_add(2);
+                ExecuteState::BlockDataCycle(BlockTransfer {
+                    // Block transfers ignore the low bits of the address
+                    address: address & !0b11,
+                    regs,
+                    load,
+                    previous_rd: None,
+                    writeback: None,
+                    use_banked_or_spsr: false,
+                })
+            }
+            instr => unimplemented!("Unimplemented Thumb instruction execute: {:?}", instr),
+        }
+    }
+
+    /// Returns true if the result was written to PC, in which case the pipeline must be refilled.
+    fn execute_data_processing(
+        &mut self,
+        opcode: u8,
+        s: bool,
+        rd: u8,
+        op1: u32,
+        op2: u32,
+        shifter_carry: bool,
+    ) -> bool {
+        let (result, new_cpsr) = alu_operation(opcode, op1, op2, shifter_carry, self.cpsr);
+        let writes_rd = match opcode {
+            // TST, TEQ, CMP, CMN
+            8 | 9 | 10 | 11 => false,
+            _ => true,
+        };
+
+        if writes_rd && rd as usize == PC {
+            if s {
+                // Returns from an exception, like `subs pc, lr, #4`. The flags come from the SPSR,
+                // which can also switch to Thumb state.
+                self.restore_cpsr();
+            }
+            self.write_pc(result);
+            return true;
+        }
+        if s {
+            self.cpsr = new_cpsr;
+        }
+        if writes_rd {
+            self.regs[rd as usize] = result;
+        }
+        false
+    }
+
     fn bus_operation_for_state(&self, state: ExecuteState) -> Option<MemoryRequest> {
         match state {
             ExecuteState::PipelineRefill1
             | ExecuteState::PipelineRefill2
             | ExecuteState::FirstCycle => Some(MemoryRequest {
-                address: self.regs[PC],
-                width: AccessWidth::Bit32,
+                // Like on hardware, fetches ignore the low bits of an unaligned PC
+                address: self.regs[PC] & !(self.instr_size() - 1),
+                width: if self.cpsr.thumb() {
+                    AccessWidth::Bit16
+                } else {
+                    AccessWidth::Bit32
+                },
                 op: OperationType::Read {
                     is_instruction: true,
                 },
-                seq: state != ExecuteState::PipelineRefill1,
+                // Fetches following a data access are non-sequential
+                seq: state != ExecuteState::PipelineRefill1 && self.fetch_in_flight,
+            }),
+            ExecuteState::DataCycle(transfer) => Some(MemoryRequest {
+                address: transfer.address,
+                width: transfer.width,
+                op: if transfer.load {
+                    OperationType::Read {
+                        is_instruction: false,
+                    }
+                } else {
+                    OperationType::Write
+                },
+                seq: false,
             }),
+            ExecuteState::BlockDataCycle(transfer) => Some(MemoryRequest {
+                address: transfer.address,
+                width: AccessWidth::Bit32,
+                op: if transfer.load {
+                    OperationType::Read {
+                        is_instruction: false,
+                    }
+                } else {
+                    OperationType::Write
+                },
+                // Only the first transfer is non-sequential
+                seq: transfer.previous_rd.is_some(),
+            }),
+            ExecuteState::LoadWriteback(_)
+            | ExecuteState::InternalCycle
+            | ExecuteState::HleWait(_) => None,
         }
     }
 
     fn step_fetch_or_single_instruction(&mut self, bus: &Bus) {
-        // Pre-read
-        let d_in_instr = bus.data.get();
+        // Pre-read. The result of the last cycle's access is latched before issuing a new one.
+        let in_data = bus.read_data();
+        if self.fetch_in_flight {
+            self.f_out_instr = if self.cpsr.thumb() {
+                in_data >> ((self.fetch_address & 0b10) * 8) & 0xFFFF
+            } else {
+                in_data
+            };
+        }
         let e_in_instr = self.d_out_instr;
 
-        println!(
-            "-[${:X}]-> F -[{:08X}]-> D -[{:08X}]-> E",
-            self.regs[PC], d_in_instr, e_in_instr
-        );
-
         // Fetch stage
-        println!("Fetching from PC={:X}", self.regs[PC]);
-        if let Some(request) = self.bus_operation_for_state(self.current_execute_state) {
-            bus.make_request(request);
+        let current_state = self.current_execute_state;
+        let request = self.bus_operation_for_state(current_state);
+        if let Some(request) = request {
+            bus.begin(request);
+            self.idle_loop.memory_access(request);
         }
 
-        // Decode stage
-        self.d_out_instr = d_in_instr;
+        let is_fetch = request.map_or(false, |r| {
+            r.op == OperationType::Read {
+                is_instruction: true,
+            }
+        });
 
-        // Execute stage
-        let current_state = self.current_execute_state;
-        self.current_execute_state = self.step_execute_fsm(bus, current_state, e_in_instr);
+        // Decode stage. The pipeline only advances on cycles that fetch a new instruction.
+        if is_fetch {
+            println!(
+                "-[${:X}]-> F -[{:08X}]-> D -[{:08X}]-> E",
+                self.regs[PC], self.f_out_instr, e_in_instr
+            );
+            self.d_out_instr = self.f_out_instr;
+            self.fetch_address = request.unwrap().address;
+        }
+        self.fetch_in_flight = is_fetch;
+
+        // Execute stage. The IRQ line is only sampled at the boundary between instructions, on
+        // the cycle that would start executing the next one, so multi-cycle instructions like LDM
+        // always finish first. The prefetch issued above on that cycle is discarded with the
+        // instruction.
+        self.current_execute_state =
+            if current_state == ExecuteState::FirstCycle && self.irq_pending(bus) {
+                self.enter_irq()
+            } else {
+                self.step_execute_fsm(bus, current_state, e_in_instr, in_data)
+            };
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::collections::BTreeMap;
     use std::pin::Pin;
 
     fn step(
@@ -361,7 +1226,7 @@ mod test {
 
         cpu.step(&bus);
         assert_eq!(
-            bus.request.get(),
+            bus.take_pending(),
             Some(MemoryRequest {
                 address,
                 width,
@@ -369,7 +1234,10 @@ mod test {
                 seq,
             })
         );
-        bus.data.set(val);
+        match op {
+            OperationType::Read { .. } => bus.complete_read(val),
+            OperationType::Write => bus.complete_write(),
+        }
     }
 
     fn step_i(cpu: &mut ArmCpu, bus: &Bus, cycle_type: char) {
@@ -379,7 +1247,126 @@ mod test {
         };
 
         cpu.step(&bus);
-        assert_eq!(bus.request.get(), None);
+        assert_eq!(bus.pending(), None);
+    }
+
+    /// Memory with fixed contents that answers the CPU's requests right away, for tests that care
+    /// about what instructions do rather than about their exact cycles. Words missing from it read
+    /// as 0xFFFFFFFF.
+    struct FakeMemory {
+        /// Word contents, by word-aligned address.
+        words: BTreeMap<u32, u32>,
+    }
+
+    impl FakeMemory {
+        fn new(words: &[(u32, u32)]) -> FakeMemory {
+            FakeMemory {
+                words: words.iter().cloned().collect(),
+            }
+        }
+
+        fn read_word(&self, address: u32) -> u32 {
+            *self.words.get(&(address & !0b11)).unwrap_or(&0xFFFF_FFFF)
+        }
+
+        /// Steps the CPU one cycle and completes the request it makes, if any, which is returned.
+        fn step(&mut self, cpu: &mut ArmCpu, bus: &Bus) -> Option<MemoryRequest> {
+            cpu.step(bus);
+            let request = bus.take_pending()?;
+            match request.op {
+                // Like the real memory, the whole word is returned, and the CPU picks the lanes
+                OperationType::Read { .. } => bus.complete_read(self.read_word(request.address)),
+                OperationType::Write => {
+                    let lanes = match request.width {
+                        AccessWidth::Bit8 => 0xFF << ((request.address & 0b11) * 8),
+                        AccessWidth::Bit16 => 0xFFFF << ((request.address & 0b10) * 8),
+                        AccessWidth::Bit32 => 0xFFFF_FFFF,
+                    };
+                    let word = self.read_word(request.address) & !lanes | bus.data_lines() & lanes;
+                    self.words.insert(request.address & !0b11, word);
+                    bus.complete_write();
+                }
+            }
+            Some(request)
+        }
+    }
+
+    /// Runs an ALU operation with the given carry in, returning the result and the C and V flags.
+    fn alu_with_carry(opcode: u8, op1: u32, op2: u32, carry: bool) -> (u32, bool, bool) {
+        let mut cpsr = Cpsr::from_bits(Mode::System.to_bits());
+        cpsr.set_carry(carry);
+        // Start with V set, so that it's clear that the operation clears it
+        cpsr.set_overflow(true);
+        let (result, cpsr) = alu_operation(opcode, op1, op2, false, cpsr);
+        (result, cpsr.carry(), cpsr.overflow())
+    }
+
+    #[test]
+    fn test_sub_flags() {
+        const SUB: u8 = 2;
+        const RSB: u8 = 3;
+        // Borrow, without signed overflow
+        assert_eq!(
+            alu_with_carry(SUB, 0, 1, false),
+            (0xFFFF_FFFF, false, false)
+        );
+        assert_eq!(alu_with_carry(SUB, 1, 1, false), (0, true, false));
+        // Signed overflow, without borrow
+        assert_eq!(
+            alu_with_carry(SUB, 0x8000_0000, 1, false),
+            (0x7FFF_FFFF, true, true)
+        );
+        assert_eq!(
+            alu_with_carry(RSB, 1, 0x8000_0000, false),
+            (0x7FFF_FFFF, true, true)
+        );
+    }
+
+    #[test]
+    fn test_sbc_flags() {
+        const SBC: u8 = 6;
+        // With C set, there's no borrow in: 5 - 7 borrows
+        assert_eq!(alu_with_carry(SBC, 5, 7, true), (0xFFFF_FFFE, false, false));
+        // With C clear, an extra 1 is subtracted: 5 - 5 - 1 borrows
+        assert_eq!(
+            alu_with_carry(SBC, 5, 5, false),
+            (0xFFFF_FFFF, false, false)
+        );
+        assert_eq!(alu_with_carry(SBC, 7, 5, false), (1, true, false));
+        // The borrow in alone crosses the signed boundary
+        assert_eq!(
+            alu_with_carry(SBC, 0x8000_0000, 0, false),
+            (0x7FFF_FFFF, true, true)
+        );
+        assert_eq!(
+            alu_with_carry(SBC, 0x8000_0000, 0, true),
+            (0x8000_0000, true, false)
+        );
+    }
+
+    #[test]
+    fn test_rsc_flags() {
+        const RSC: u8 = 7;
+        // Computes op2 - op1 - !C. Right at the boundary, without overflow
+        assert_eq!(
+            alu_with_carry(RSC, 0, 0x7FFF_FFFF, true),
+            (0x7FFF_FFFF, true, false)
+        );
+        // Past it in both directions
+        assert_eq!(
+            alu_with_carry(RSC, 0xFFFF_FFFF, 0x7FFF_FFFF, true),
+            (0x8000_0000, false, true)
+        );
+        assert_eq!(
+            alu_with_carry(RSC, 0, 0x8000_0000, false),
+            (0x7FFF_FFFF, true, true)
+        );
+        // Borrow, with and without the borrow in
+        assert_eq!(alu_with_carry(RSC, 1, 0, true), (0xFFFF_FFFF, false, false));
+        assert_eq!(
+            alu_with_carry(RSC, 0, 0, false),
+            (0xFFFF_FFFF, false, false)
+        );
     }
 
     #[test]
@@ -394,6 +1381,29 @@ mod test {
         assert_eq!(cpu.regs[0], 0x0800_0000);
     }
 
+    #[test]
+    fn test_pc_operand() {
+        let bus = Default::default();
+        let mut cpu = ArmCpu::new();
+        cpu.regs[1] = 0;
+
+        let program = asm::assemble(
+            "
+            add r0, pc, #4
+            add r2, pc, pc, lsl r1
+            mov r0, r0
+            mov r0, r0
+            ",
+        );
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00000000, program[0]);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000004, program[1]);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000008, program[2]);
+        assert_eq!(cpu.regs[0], 0x0000_0000 + 8 + 4);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x0000000C, program[3]);
+        step_i(&mut cpu, &bus, 'I');
+        assert_eq!(cpu.regs[2], (0x0000_0004 + 12) * 2);
+    }
+
     #[test]
     fn test_branch() {
         let bus = Default::default();
@@ -402,9 +1412,732 @@ mod test {
         // b loc_0020
         step(&mut cpu, &bus, 'N', 'O', 32, 0x00000000, 0xEA000006);
         step(&mut cpu, &bus, 'S', 'O', 32, 0x00000004, 0xFFFFFFFF);
+        // A branch takes 2S+1N: the fetch made while executing it, which is discarded, followed by
+        // a non-sequential fetch of the target, and a sequential one of the instruction after it.
         step(&mut cpu, &bus, 'S', 'O', 32, 0x00000008, 0xFFFFFFFF);
         // mov r0, #0x0800'0000
         step(&mut cpu, &bus, 'N', 'O', 32, 0x00000020, 0xE3A00302);
         step(&mut cpu, &bus, 'S', 'O', 32, 0x00000024, 0xFFFFFFFF);
+        // Execution continues with sequential fetches
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000028, 0xFFFFFFFF);
+        assert_eq!(cpu.regs[0], 0x0800_0000);
+        assert_eq!(cpu.regs[LR], 0);
+    }
+
+    #[test]
+    fn test_branch_link() {
+        let bus = Default::default();
+        let mut cpu = ArmCpu::new();
+
+        // bl loc_0020, with the same cycles as a plain branch
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00000000, 0xEB000006);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000004, 0xFFFFFFFF);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000008, 0xFFFFFFFF);
+        // LR holds the address of the instruction after the BL
+        assert_eq!(cpu.regs[LR], 0x00000004);
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00000020, 0xE3A00302);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000024, 0xFFFFFFFF);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000028, 0xFFFFFFFF);
+        assert_eq!(cpu.regs[0], 0x0800_0000);
+    }
+
+    #[test]
+    fn test_bx_unaligned_arm() {
+        let bus = Default::default();
+        let mut cpu = ArmCpu::new();
+        cpu.regs[0] = 0x0800_0002;
+
+        // bx r0. Fetches are aligned, but PC keeps bit 1 set, which shows in reads of it.
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00000000, asm::bx(0));
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000004, 0xFFFFFFFF);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000008, 0xFFFFFFFF);
+        assert!(!cpu.cpsr.thumb());
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x08000000, 0xE1A00000); // nop
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x08000004, 0xE1A00000); // nop
+        assert_eq!(cpu.regs[PC], 0x0800_000A);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x08000008, 0xFFFFFFFF);
+    }
+
+    #[test]
+    fn test_str_pc_writeback() {
+        let bus = Default::default();
+        let mut cpu = ArmCpu::new();
+
+        // str r0, [pc, #4]!. The writeback is dropped instead of branching.
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00000000, 0xE5AF0004);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000004, 0xE1A00000); // nop
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000008, 0xFFFFFFFF);
+        cpu.step(&bus);
+        assert_eq!(bus.take_pending().unwrap().address, 0x0000000C);
+        bus.complete_write();
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x0000000C, 0xFFFFFFFF);
+    }
+
+    #[test]
+    fn test_ldr() {
+        let bus = Default::default();
+        let mut cpu = ArmCpu::new();
+        cpu.regs[1] = 0x0300_0000;
+        let mut memory = FakeMemory::new(&[
+            (0x0000_0000, 0xE5910000), // ldr r0, [r1]
+            (0x0000_0004, 0xE1A00000), // nop
+            (0x0300_0000, 0x12345678),
+        ]);
+
+        let addresses: Vec<Option<u32>> = (0..6)
+            .map(|_| memory.step(&mut cpu, &bus).map(|request| request.address))
+            .collect();
+        // The load is followed by an internal cycle, which makes no request
+        assert_eq!(
+            addresses,
+            [
+                Some(0x0000_0000),
+                Some(0x0000_0004),
+                Some(0x0000_0008),
+                Some(0x0300_0000),
+                None,
+                Some(0x0000_000C),
+            ]
+        );
+        assert_eq!(cpu.regs[0], 0x12345678);
+    }
+
+    #[test]
+    fn test_ldr_pc() {
+        let bus = Default::default();
+        let mut cpu = ArmCpu::new();
+        cpu.regs[1] = 0x0300_0000;
+
+        // ldr pc, [r1]
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00000000, 0xE591F000);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000004, 0xE1A00000); // nop
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000008, 0xFFFFFFFF);
+        // The low bits are ignored, without switching to Thumb like BX
+        step(&mut cpu, &bus, 'N', 'R', 32, 0x03000000, 0x00001003);
+        step_i(&mut cpu, &bus, 'I');
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00001000, 0xE1A00000); // nop
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00001004, 0xE1A00000); // nop
+        assert!(!cpu.cpsr.thumb());
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00001008, 0xFFFFFFFF);
+    }
+
+    #[test]
+    fn test_str() {
+        let bus = Default::default();
+        let mut cpu = ArmCpu::new();
+        cpu.regs[0] = 0x0403;
+        cpu.regs[1] = 0x0400_0000;
+
+        // str r0, [r1]
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00000000, 0xE5810000);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000004, 0xE3A02000); // mov r2, #0
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000008, 0xFFFFFFFF);
+        cpu.step(&bus);
+        assert_eq!(
+            bus.take_pending(),
+            Some(MemoryRequest {
+                address: 0x0400_0000,
+                width: AccessWidth::Bit32,
+                op: OperationType::Write,
+                seq: false,
+            })
+        );
+        assert_eq!(bus.data_lines(), 0x0403);
+        bus.complete_write();
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x0000000C, 0xFFFFFFFF);
+    }
+
+    #[test]
+    fn test_str_pc() {
+        let bus = Default::default();
+        let mut cpu = ArmCpu::new();
+        cpu.regs[0] = 0x0300_0000;
+
+        // str pc, [r0]. The stored value is 12 bytes ahead of the instruction, not 8 like when
+        // PC is read as an operand.
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00000000, 0xE580F000);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000004, 0xE1A00000); // nop
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000008, 0xFFFFFFFF);
+        cpu.step(&bus);
+        assert_eq!(bus.take_pending().unwrap().address, 0x0300_0000);
+        assert_eq!(bus.data_lines(), 0x0000_000C);
+        bus.complete_write();
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x0000000C, 0xFFFFFFFF);
+    }
+
+    #[test]
+    fn test_thumb_push_pop() {
+        let bus: Bus = Default::default();
+        let mut cpu = ArmCpu::new();
+        for i in 0..4 {
+            cpu.regs[i] = 0x100 + i as u32;
+        }
+        cpu.regs[5] = 0x0000_0101;
+        cpu.regs[SP] = 0x0300_7F00;
+        cpu.regs[LR] = 0x0000_0201;
+        let push = asm::thumb::push(0x0F, true) as u32;
+        let pop = asm::thumb::pop(0x0F, true) as u32;
+
+        // bx r5
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00000000, asm::bx(5));
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000004, 0xFFFFFFFF);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000008, 0xFFFFFFFF);
+        assert!(cpu.cpsr.thumb());
+
+        // push {r0-r3, lr}, with halfwords in their lane of the bus
+        step(&mut cpu, &bus, 'N', 'O', 16, 0x00000100, push);
+        step(&mut cpu, &bus, 'S', 'O', 16, 0x00000102, pop << 16);
+        step(&mut cpu, &bus, 'S', 'O', 16, 0x00000104, 0xFFFFFFFF);
+        assert_eq!(cpu.regs[SP], 0x0300_7EEC);
+        let mut stack = Vec::new();
+        for i in 0..5 {
+            cpu.step(&bus);
+            assert_eq!(
+                bus.take_pending(),
+                Some(MemoryRequest {
+                    address: 0x0300_7EEC + i * 4,
+                    width: AccessWidth::Bit32,
+                    op: OperationType::Write,
+                    seq: i != 0,
+                })
+            );
+            stack.push(bus.data_lines());
+            bus.complete_write();
+        }
+        assert_eq!(stack, [0x100, 0x101, 0x102, 0x103, 0x201]);
+        for i in 0..4 {
+            cpu.regs[i] = 0;
+        }
+
+        // pop {r0-r3, pc}
+        step(&mut cpu, &bus, 'N', 'O', 16, 0x00000106, 0xFFFFFFFF);
+        for i in 0..5 {
+            let cycle_type = if i == 0 { 'N' } else { 'S' };
+            let address = 0x0300_7EEC + i as u32 * 4;
+            step(&mut cpu, &bus, cycle_type, 'R', 32, address, stack[i]);
+        }
+        step_i(&mut cpu, &bus, 'I');
+        assert_eq!(&cpu.regs[..4], &[0x100, 0x101, 0x102, 0x103]);
+        assert_eq!(cpu.regs[SP], 0x0300_7F00);
+
+        // Returns to the Thumb code at LR, staying in Thumb state
+        step(&mut cpu, &bus, 'N', 'O', 16, 0x00000200, 0xFFFFFFFF);
+        step(&mut cpu, &bus, 'S', 'O', 16, 0x00000202, 0xFFFFFFFF);
+        assert!(cpu.cpsr.thumb());
+        assert_eq!(cpu.regs[PC], 0x00000204);
+    }
+
+    #[test]
+    fn test_movs_rrx() {
+        let bus = Default::default();
+
+        for &(carry_in, input, output, carry_out) in [
+            (true, 0x0000_0003, 0x8000_0001, true),
+            (false, 0x0000_0003, 0x0000_0001, true),
+            (true, 0x8000_0002, 0xC000_0001, false),
+            (false, 0x8000_0002, 0x4000_0001, false),
+        ]
+        .iter()
+        {
+            let mut cpu = ArmCpu::new();
+            cpu.regs[1] = input;
+            cpu.cpsr.set_carry(carry_in);
+
+            // movs r0, r1, rrx
+            step(&mut cpu, &bus, 'N', 'O', 32, 0x00000000, 0xE1B00061);
+            step(&mut cpu, &bus, 'S', 'O', 32, 0x00000004, 0xFFFFFFFF);
+            step(&mut cpu, &bus, 'S', 'O', 32, 0x00000008, 0xFFFFFFFF);
+            assert_eq!(cpu.regs[0], output);
+            assert_eq!(cpu.cpsr.carry(), carry_out);
+            assert_eq!(cpu.cpsr.negative(), output & (1 << 31) != 0);
+        }
+    }
+
+    #[test]
+    fn test_logical_op_flags() {
+        let bus = Default::default();
+
+        // Logical ops set N and Z from the result and C from the shifter, and leave V alone
+        for &(source, op1, op2, carry_in, result, negative, zero, carry) in [
+            (
+                "movs r0, r2, lsl #1",
+                0,
+                0x8000_0000,
+                false,
+                0,
+                false,
+                true,
+                true,
+            ),
+            (
+                "movs r0, r2",
+                0,
+                0x8000_0000,
+                true,
+                0x8000_0000,
+                true,
+                false,
+                true,
+            ),
+            (
+                "ands r0, r1, r2",
+                0xF000_0000,
+                0x8000_000F,
+                false,
+                0x8000_0000,
+                true,
+                false,
+                false,
+            ),
+            (
+                "ands r0, r1, r2, asr #1",
+                0x0000_0001,
+                0x0000_0001,
+                false,
+                0,
+                false,
+                true,
+                true,
+            ),
+            (
+                "bics r0, r1, r2, lsr #4",
+                0xFF,
+                0xF0,
+                true,
+                0xF0,
+                false,
+                false,
+                false,
+            ),
+            (
+                "bics r0, r1, r2, lsr #4",
+                0xFF,
+                0xF8,
+                false,
+                0xF0,
+                false,
+                false,
+                true,
+            ),
+        ]
+        .iter()
+        {
+            for &overflow in [false, true].iter() {
+                let mut cpu = ArmCpu::new();
+                cpu.regs[1] = op1;
+                cpu.regs[2] = op2;
+                cpu.cpsr.set_carry(carry_in);
+                cpu.cpsr.set_overflow(overflow);
+                let mut memory = FakeMemory::new(&[(0x0000_0000, asm::assemble(source)[0])]);
+                for _ in 0..3 {
+                    memory.step(&mut cpu, &bus);
+                }
+
+                assert_eq!(cpu.regs[0], result, "{}", source);
+                assert_eq!(
+                    (cpu.cpsr.negative(), cpu.cpsr.zero(), cpu.cpsr.carry()),
+                    (negative, zero, carry),
+                    "{}",
+                    source
+                );
+                assert_eq!(cpu.cpsr.overflow(), overflow, "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ldm_stm_writeback() {
+        let bus = Default::default();
+        let mut cpu = ArmCpu::new();
+        cpu.regs[0] = 0x0300_0010;
+        cpu.regs[1] = 0x0300_0020;
+        cpu.regs[2] = 2;
+        let mut memory = FakeMemory::new(&[
+            (0x0000_0000, asm::assemble("stmdb r0!, {r0, r2}")[0]),
+            (0x0000_0004, asm::assemble("ldmia r1!, {r1, r3}")[0]),
+            (0x0000_0008, 0xE1A00000), // nop
+            (0x0300_0020, 0x1111_1111),
+            (0x0300_0024, 0x2222_2222),
+        ]);
+        // Both transfers, up to the internal cycle of the load
+        for _ in 0..9 {
+            memory.step(&mut cpu, &bus);
+        }
+
+        // Stored at the lowest addresses first, with the base before writeback, since it's the
+        // first register stored
+        assert_eq!(memory.read_word(0x0300_0008), 0x0300_0010);
+        assert_eq!(memory.read_word(0x0300_000C), 2);
+        assert_eq!(cpu.regs[0], 0x0300_0008);
+        // A loaded base overrides the writeback
+        assert_eq!(cpu.regs[1], 0x1111_1111);
+        assert_eq!(cpu.regs[3], 0x2222_2222);
+    }
+
+    #[test]
+    fn test_ldm_stm_empty_list() {
+        let bus = Default::default();
+        let mut cpu = ArmCpu::new();
+        cpu.regs[0] = 0x0300_0100;
+        cpu.regs[1] = 0x0300_0200;
+        let mut memory = FakeMemory::new(&[
+            (0x0000_0000, 0xE9200000), // stmdb r0!, {}
+            (0x0000_0004, 0xE8B10000), // ldmia r1!, {}
+            (0x0300_0200, 0x0000_0100),
+        ]);
+        let requests: Vec<MemoryRequest> =
+            (0..8).filter_map(|_| memory.step(&mut cpu, &bus)).collect();
+
+        // Each transfers PC alone, at the bottom of the 0x40 bytes that 16 registers would take
+        let data_addresses: Vec<u32> = requests
+            .iter()
+            .filter(|r| {
+                r.op != OperationType::Read {
+                    is_instruction: true,
+                }
+            })
+            .map(|r| r.address)
+            .collect();
+        assert_eq!(data_addresses, [0x0300_00C0, 0x0300_0200]);
+        assert_eq!(memory.read_word(0x0300_00C0), 0x0000_0000 + 12);
+        assert_eq!(cpu.regs[0], 0x0300_00C0);
+        assert_eq!(cpu.regs[1], 0x0300_0240);
+        // The loaded PC is jumped to
+        assert_eq!(requests.last().unwrap().address, 0x0000_0100);
+    }
+
+    /// A CPU in IRQ mode, interrupted from User mode, with different r13 and r14 in each mode.
+    fn cpu_in_irq_mode() -> ArmCpu {
+        let mut cpu = ArmCpu::new();
+        cpu.switch_mode(Mode::User);
+        cpu.cpsr.set_irq_disabled(false);
+        cpu.regs[SP] = 0x0300_7F00;
+        cpu.regs[LR] = 0x0800_1234;
+        let user_cpsr = cpu.cpsr;
+
+        cpu.switch_mode(Mode::Irq);
+        cpu.spsrs[bank_index(Mode::Irq)] = user_cpsr;
+        cpu.cpsr.set_irq_disabled(true);
+        cpu.regs[SP] = 0x0300_7FA0;
+        cpu.regs[LR] = 0x0800_5678;
+        cpu
+    }
+
+    #[test]
+    fn test_stm_user_bank() {
+        let bus = Default::default();
+        let mut cpu = cpu_in_irq_mode();
+        cpu.regs[0] = 0x0300_0000;
+        cpu.regs[8] = 8;
+        let mut memory = FakeMemory::new(&[
+            (0x0000_0000, asm::assemble("stmia r0, {r8, sp, lr}^")[0]),
+            (0x0300_0000, 0),
+        ]);
+        let addresses: Vec<Option<u32>> = (0..6)
+            .map(|_| memory.step(&mut cpu, &bus).map(|request| request.address))
+            .collect();
+        assert_eq!(
+            addresses[2..],
+            [
+                Some(0x0000_0008),
+                Some(0x0300_0000),
+                Some(0x0300_0004),
+                Some(0x0300_0008),
+            ]
+        );
+
+        // The User mode registers are stored, and the IRQ mode ones are left alone
+        assert_eq!(memory.read_word(0x0300_0000), 8);
+        assert_eq!(memory.read_word(0x0300_0004), 0x0300_7F00);
+        assert_eq!(memory.read_word(0x0300_0008), 0x0800_1234);
+        assert_eq!(cpu.cpsr.mode(), Mode::Irq);
+        assert_eq!(&cpu.regs[13..15], &[0x0300_7FA0, 0x0800_5678]);
+    }
+
+    #[test]
+    fn test_ldm_user_bank() {
+        let bus = Default::default();
+        let mut cpu = cpu_in_irq_mode();
+        cpu.regs[0] = 0x0300_0000;
+        let mut memory = FakeMemory::new(&[
+            (0x0000_0000, asm::assemble("ldmia r0, {sp, lr}^")[0]),
+            (0x0000_0004, 0xE1A00000), // nop
+            (0x0300_0000, 0x0300_7E00),
+            (0x0300_0004, 0x0800_4321),
+        ]);
+        // Two loads and the internal cycle writing the last register
+        for _ in 0..6 {
+            memory.step(&mut cpu, &bus);
+        }
+
+        assert_eq!(
+            cpu.banked_r13_r14[bank_index(Mode::User)],
+            [0x0300_7E00, 0x0800_4321]
+        );
+        assert_eq!(cpu.cpsr.mode(), Mode::Irq);
+        assert_eq!(&cpu.regs[13..15], &[0x0300_7FA0, 0x0800_5678]);
+    }
+
+    #[test]
+    fn test_ldm_exception_return() {
+        let bus = Default::default();
+        let mut cpu = cpu_in_irq_mode();
+        let mut words = vec![(0x0000_0000, asm::assemble("ldmfd sp!, {r0-r12, pc}^")[0])];
+        // The handler's stack holds r0-r12 and the return address
+        for i in 0..13 {
+            words.push((0x0300_7FA0 + i * 4, 0x100 + i));
+        }
+        words.push((0x0300_7FA0 + 13 * 4, 0x0800_0100));
+        let mut memory = FakeMemory::new(&words);
+
+        // 14 loads and an internal cycle, then the pipeline refills from the return address
+        let addresses: Vec<Option<u32>> = (0..20)
+            .map(|_| memory.step(&mut cpu, &bus).map(|request| request.address))
+            .collect();
+        assert_eq!(
+            addresses[16..],
+            [
+                Some(0x0300_7FD4),
+                None,
+                Some(0x0800_0100),
+                Some(0x0800_0104)
+            ]
+        );
+
+        assert_eq!(cpu.cpsr, cpu.spsrs[bank_index(Mode::Irq)]);
+        assert_eq!(cpu.cpsr.mode(), Mode::User);
+        assert!(!cpu.cpsr.irq_disabled());
+        for i in 0..13 {
+            assert_eq!(cpu.regs[i], 0x100 + i as u32);
+        }
+        // The writeback went to the IRQ mode SP, and the User mode registers are back
+        assert_eq!(
+            cpu.banked_r13_r14[bank_index(Mode::Irq)],
+            [0x0300_7FA0 + 14 * 4, 0x0800_5678]
+        );
+        assert_eq!(&cpu.regs[13..15], &[0x0300_7F00, 0x0800_1234]);
+    }
+
+    #[test]
+    fn test_data_processing_exception_return() {
+        let bus = Default::default();
+        let mut cpu = cpu_in_irq_mode();
+        let mut memory = FakeMemory::new(&[
+            (0x0000_0000, asm::assemble("subs pc, lr, #4")[0]),
+            (0x0000_0004, asm::assemble("movs r0, #0")[0]),
+        ]);
+
+        let addresses: Vec<Option<u32>> = (0..5)
+            .map(|_| memory.step(&mut cpu, &bus).map(|request| request.address))
+            .collect();
+        assert_eq!(addresses[3..], [Some(0x0800_5674), Some(0x0800_5678)]);
+        // The flags come from the SPSR, not from the subtraction
+        assert_eq!(cpu.cpsr, cpu.spsrs[bank_index(Mode::Irq)]);
+        assert_eq!(cpu.cpsr.mode(), Mode::User);
+        assert_eq!(cpu.regs[LR], 0x0800_1234);
+    }
+
+    #[test]
+    fn test_irq_during_ldr() {
+        let bus: Bus = Default::default();
+        let mut cpu = ArmCpu::new();
+        cpu.cpsr.set_irq_disabled(false);
+        cpu.regs[1] = 0x0300_0000;
+        cpu.regs[LR] = 0x1234;
+        let old_cpsr = cpu.cpsr;
+
+        // ldr r0, [r1]
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00000000, 0xE5910000);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000004, 0xE1A00000); // nop
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000008, 0xE1A00000); // nop
+        bus.irq.set(true);
+        // The load isn't interrupted
+        step(&mut cpu, &bus, 'N', 'R', 32, 0x03000000, 0x12345678);
+        step_i(&mut cpu, &bus, 'I');
+        assert_eq!(cpu.regs[0], 0x12345678);
+
+        // The IRQ is taken instead of executing the nop at 0x4
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x0000000C, 0xFFFFFFFF);
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00000018, 0xFFFFFFFF);
+        assert_eq!(cpu.cpsr.mode(), Mode::Irq);
+        assert!(cpu.cpsr.irq_disabled());
+        assert_eq!(cpu.spsrs[bank_index(Mode::Irq)], old_cpsr);
+        // Returning with `subs pc, lr, #4` resumes from the nop
+        assert_eq!(cpu.regs[LR], 0x4 + 4);
+        assert_eq!(cpu.banked_r13_r14[bank_index(Mode::Supervisor)][1], 0x1234);
+        assert_eq!(cpu.regs[1], 0x0300_0000);
+    }
+
+    #[test]
+    fn test_irq_during_ldm() {
+        let bus: Bus = Default::default();
+        let mut cpu = ArmCpu::new();
+        cpu.cpsr.set_irq_disabled(false);
+        cpu.regs[0] = 0x0300_0000;
+
+        // ldmia r0, {r1-r8}
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00000000, 0xE89001FE);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000004, 0xE1A00000); // nop
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000008, 0xE1A00000); // nop
+        step(&mut cpu, &bus, 'N', 'R', 32, 0x03000000, 1);
+        // Raised on the third cycle of the LDM, none of the remaining ones are skipped
+        bus.irq.set(true);
+        for i in 1..8 {
+            step(&mut cpu, &bus, 'S', 'R', 32, 0x0300_0000 + i * 4, i + 1);
+        }
+        step_i(&mut cpu, &bus, 'I');
+        assert_eq!(&cpu.regs[1..9], &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(cpu.cpsr.mode(), Mode::Supervisor);
+
+        // The IRQ is taken right after the last cycle, instead of executing the nop at 0x4
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x0000000C, 0xFFFFFFFF);
+        assert_eq!(cpu.cpsr.mode(), Mode::Irq);
+        assert_eq!(cpu.regs[LR], 0x4 + 4);
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00000018, 0xFFFFFFFF);
+    }
+
+    #[test]
+    fn test_irq_after_msr() {
+        let bus: Bus = Default::default();
+        let mut cpu = ArmCpu::new();
+        bus.irq.set(true);
+
+        // msr cpsr_c, #0x13, keeping Supervisor mode and clearing I
+        step(
+            &mut cpu,
+            &bus,
+            'N',
+            'O',
+            32,
+            0x00000000,
+            asm::msr_imm(false, 0b0001, 0x13, 0),
+        );
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000004, 0xE1A00000); // nop
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000008, 0xE1A00000); // nop
+        assert!(!cpu.cpsr.irq_disabled());
+        assert_eq!(cpu.cpsr.mode(), Mode::Supervisor);
+
+        // Taken at the very next instruction
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x0000000C, 0xFFFFFFFF);
+        assert_eq!(cpu.cpsr.mode(), Mode::Irq);
+        assert!(!cpu.spsrs[bank_index(Mode::Irq)].irq_disabled());
+        assert_eq!(cpu.regs[LR], 0x4 + 4);
+    }
+
+    #[test]
+    fn test_msr() {
+        let bus = Default::default();
+        let mut cpu = ArmCpu::new();
+        cpu.regs[0] = 0x2000_0010;
+        cpu.regs[13] = 0x0300_7FE0;
+        let mut memory = FakeMemory::new(&[
+            (0x0000_0000, asm::msr_imm(true, 0b1000, 0xF0, 4)), // msr spsr_f, #0xF0000000
+            (0x0000_0004, asm::msr_reg(false, 0b1001, 0)),      // msr cpsr_fc, r0
+            (0x0000_0008, asm::msr_imm(false, 0b1001, 0x1F, 0)), // msr cpsr_fc, #0x1F
+        ]);
+        for _ in 0..5 {
+            memory.step(&mut cpu, &bus);
+        }
+
+        assert_eq!(cpu.spsrs[bank_index(Mode::Supervisor)].to_bits() >> 28, 0xF);
+        // Switched to User mode, where only the flags can be written, so the last MSR can't leave
+        assert_eq!(cpu.cpsr, Cpsr::from_bits(0x0000_0010));
+        assert_eq!(
+            cpu.banked_r13_r14[bank_index(Mode::Supervisor)][0],
+            0x0300_7FE0
+        );
+        assert_eq!(cpu.regs[13], 0);
+    }
+
+    #[test]
+    fn test_armv5_undefined() {
+        let bus = Default::default();
+        let mut cpu = ArmCpu::new();
+        let old_cpsr = cpu.cpsr;
+
+        // clz r0, r1
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00000000, 0xE16F0F11);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000004, 0xFFFFFFFF);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000008, 0xFFFFFFFF);
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00000004, 0xFFFFFFFF);
+        assert_eq!(cpu.cpsr.mode(), Mode::Undefined);
+        assert_eq!(cpu.spsrs[bank_index(Mode::Undefined)], old_cpsr);
+        assert_eq!(cpu.regs[LR], 0x00000004);
+
+        assert_eq!(
+            describe_undefined(0x0800_0100, 0xE16F0F11, Some("CLZ")),
+            "Undefined instruction 0xE16F0F11 at 0x08000100; this looks like an ARMv5 instruction \
+             (CLZ); the GBA CPU is ARMv4T"
+        );
+        assert_eq!(
+            describe_undefined(0x0800_0100, 0xE7F000F0, None),
+            "Undefined instruction 0xE7F000F0 at 0x08000100"
+        );
+    }
+
+    #[test]
+    fn test_irq_disabled() {
+        let bus: Bus = Default::default();
+        let mut cpu = ArmCpu::new();
+        bus.irq.set(true);
+
+        // mov r0, #0x0800'0000
+        step(&mut cpu, &bus, 'N', 'O', 32, 0x00000000, 0xE3A00302);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000004, 0xFFFFFFFF);
+        step(&mut cpu, &bus, 'S', 'O', 32, 0x00000008, 0xFFFFFFFF);
+        assert_eq!(cpu.regs[0], 0x0800_0000);
+        assert_eq!(cpu.cpsr.mode(), Mode::Supervisor);
+    }
+
+    #[test]
+    fn test_skip_bios_boot() {
+        let mut cpu = ArmCpu::new();
+        cpu.skip_bios_boot();
+        assert_eq!(cpu.cpsr, Cpsr::from_bits(Mode::System.to_bits()));
+        assert_eq!(cpu.regs[PC], 0x0800_0000);
+        assert_eq!(cpu.current_execute_state, ExecuteState::PipelineRefill1);
+
+        for &(mode, sp) in [
+            (Mode::System, 0x0300_7F00),
+            (Mode::User, 0x0300_7F00),
+            (Mode::Irq, 0x0300_7FA0),
+            (Mode::Supervisor, 0x0300_7FE0),
+        ]
+        .iter()
+        {
+            cpu.switch_mode(mode);
+            assert_eq!(cpu.regs[SP], sp, "{:?}", mode);
+            assert_eq!(cpu.regs[LR], 0, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn test_fiq_banked_registers() {
+        let mut cpu = ArmCpu::new();
+        for i in 0..15 {
+            cpu.regs[i] = i as u32;
+        }
+        cpu.switch_mode(Mode::Fiq);
+        assert_eq!(&cpu.regs[..8], &[0, 1, 2, 3, 4, 5, 6, 7]);
+        for i in 8..15 {
+            cpu.regs[i] = 0x100 + i as u32;
+        }
+        cpu.switch_mode(Mode::User);
+        assert_eq!(&cpu.regs[8..15], &[8, 9, 10, 11, 12, 0, 0]);
+        cpu.switch_mode(Mode::System);
+        cpu.switch_mode(Mode::Fiq);
+        assert_eq!(cpu.regs[14], 0x10E);
+    }
+
+    #[test]
+    fn test_barrel_shift_register_amounts() {
+        assert_eq!(barrel_shift(0x8000_0001, 0, 0, true), (0x8000_0001, true));
+        assert_eq!(barrel_shift(0x8000_0001, 0, 1, false), (0x0000_0002, true));
+        assert_eq!(barrel_shift(0x8000_0001, 0, 32, false), (0, true));
+        assert_eq!(barrel_shift(0x8000_0001, 0, 33, true), (0, false));
+        assert_eq!(barrel_shift(0x8000_0001, 1, 32, false), (0, true));
+        assert_eq!(barrel_shift(0x8000_0001, 2, 40, false), (0xFFFF_FFFF, true));
+        assert_eq!(barrel_shift(0x8000_0001, 3, 32, false), (0x8000_0001, true));
+        assert_eq!(barrel_shift(0x8000_0001, 3, 33, false), (0xC000_0000, true));
     }
 }
@@ -0,0 +1,411 @@
+//! A command line debugger, entered with `--debug` before the emulator starts running. It reads
+//! commands like `step` or `break 08000100` a line at a time and writes its replies to any output,
+//! so it can also be driven by a script.
+
+use cheats::Cheat;
+use cpu::disasm::disassemble_range;
+use cpu::disasm::format_disassembly_window;
+use cpu::disasm::WINDOW_INSTRUCTIONS;
+use hle::HleMemory;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use system::System;
+
+pub const HELP: &str = "commands:
+  step [n], s        execute n instructions, 1 by default
+  continue, c        run until a breakpoint, or leave the debugger if there are none
+  break <addr>, b    stop before executing the instruction at addr
+  delete <addr>      remove the breakpoint at addr
+  regs, r            show the registers
+  mem <addr> [len]   show len bytes of memory, 16 by default
+  disasm [addr]      disassemble from addr, or around the next instruction
+  cheat add <code>   add a cheat, in any of the formats of cheats.txt
+  cheat remove <n>   remove cheat n, as numbered by cheat list
+  cheat list         list the cheats
+  quit, q            exit without running
+addresses are hexadecimal, with or without 0x";
+
+const PROMPT: &str = "(advance) ";
+
+/// Bytes shown on each line of `mem`.
+const DUMP_LINE_BYTES: u32 = 16;
+
+const COMMAND_NAMES: [&str; 14] = [
+    "step", "s", "continue", "c", "break", "b", "delete", "regs", "r", "mem", "disasm", "cheat",
+    "quit", "q",
+];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DebugCommand {
+    Step(u32),
+    Continue,
+    Break(u32),
+    Delete(u32),
+    Regs,
+    Mem { address: u32, len: u32 },
+    Disasm(Option<u32>),
+    CheatAdd(Cheat),
+    CheatRemove(usize),
+    CheatList,
+    Help,
+    Quit,
+}
+
+/// How the debugger was left.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DebuggerExit {
+    /// The emulator should go on running normally.
+    Run,
+    /// The emulator should exit without running.
+    Quit,
+}
+
+/// Parses a line of input. Returns None for a blank line, and an error message for anything that
+/// isn't a command.
+pub fn parse_debug_command(line: &str) -> Result<Option<DebugCommand>, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (name, args) = match words.split_first() {
+        Some((name, args)) => (*name, args),
+        None => return Ok(None),
+    };
+    let command = match (name, args) {
+        ("step", []) | ("s", []) => DebugCommand::Step(1),
+        ("step", [count]) | ("s", [count]) => DebugCommand::Step(parse_count(count)?),
+        ("continue", []) | ("c", []) => DebugCommand::Continue,
+        ("break", [address]) | ("b", [address]) => DebugCommand::Break(parse_address(address)?),
+        ("delete", [address]) => DebugCommand::Delete(parse_address(address)?),
+        ("regs", []) | ("r", []) => DebugCommand::Regs,
+        ("mem", [address]) => DebugCommand::Mem {
+            address: parse_address(address)?,
+            len: DUMP_LINE_BYTES,
+        },
+        ("mem", [address, len]) => DebugCommand::Mem {
+            address: parse_address(address)?,
+            len: parse_count(len)?,
+        },
+        ("disasm", []) => DebugCommand::Disasm(None),
+        ("disasm", [address]) => DebugCommand::Disasm(Some(parse_address(address)?)),
+        ("cheat", args) if args.len() > 1 && args[0] == "add" => {
+            let cheat = Cheat::parse(&args[1..].join(" ")).map_err(|e| e.to_string())?;
+            DebugCommand::CheatAdd(cheat)
+        }
+        ("cheat", ["remove", index]) => DebugCommand::CheatRemove(
+            index
+                .parse()
+                .map_err(|_| format!("bad cheat number {}", index))?,
+        ),
+        ("cheat", ["list"]) => DebugCommand::CheatList,
+        ("help", []) => DebugCommand::Help,
+        ("quit", []) | ("q", []) => DebugCommand::Quit,
+        _ if COMMAND_NAMES.contains(&name) => {
+            return Err(format!("wrong arguments for {}, try help", name))
+        }
+        _ => return Err(format!("unknown command {}, try help", name)),
+    };
+    Ok(Some(command))
+}
+
+/// Parses an address, which is always hexadecimal.
+fn parse_address(text: &str) -> Result<u32, String> {
+    let digits = if text.starts_with("0x") || text.starts_with("0X") {
+        &text[2..]
+    } else {
+        text
+    };
+    u32::from_str_radix(digits, 16).map_err(|_| format!("bad address {}", text))
+}
+
+fn parse_count(text: &str) -> Result<u32, String> {
+    text.parse().map_err(|_| format!("bad count {}", text))
+}
+
+/// The breakpoints, which are kept between commands.
+#[derive(Clone, Debug, Default)]
+pub struct Debugger {
+    breakpoints: Vec<u32>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    pub fn breakpoints(&self) -> &[u32] {
+        &self.breakpoints
+    }
+
+    /// Reads and runs commands from `input` until one of them leaves the debugger. Running out of
+    /// input leaves it too, like `continue` without breakpoints.
+    pub fn run(
+        &mut self,
+        system: &mut System,
+        input: &mut BufRead,
+        out: &mut Write,
+    ) -> io::Result<DebuggerExit> {
+        // The system may start in the middle of refilling the pipeline
+        let at_boundary = system.cpu.borrow().at_instruction_boundary();
+        if !at_boundary {
+            system.step_instruction();
+        }
+        write_next_instruction(system, out)?;
+
+        loop {
+            write!(out, "{}", PROMPT)?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(out)?;
+                return Ok(DebuggerExit::Run);
+            }
+            match parse_debug_command(&line) {
+                Ok(Some(command)) => {
+                    if let Some(exit) = self.execute(system, command, out)? {
+                        return Ok(exit);
+                    }
+                }
+                Ok(None) => {}
+                Err(message) => writeln!(out, "{}", message)?,
+            }
+        }
+    }
+
+    /// Runs a single command. Returns how to leave the debugger, if the command leaves it.
+    pub fn execute(
+        &mut self,
+        system: &mut System,
+        command: DebugCommand,
+        out: &mut Write,
+    ) -> io::Result<Option<DebuggerExit>> {
+        match command {
+            DebugCommand::Step(count) => {
+                for _ in 0..count {
+                    system.step_instruction();
+                }
+                write_next_instruction(system, out)?;
+            }
+            DebugCommand::Continue => {
+                if self.breakpoints.is_empty() {
+                    return Ok(Some(DebuggerExit::Run));
+                }
+                // Always executes at least one instruction, to leave the current breakpoint
+                loop {
+                    system.step_instruction();
+                    let address = system.cpu.borrow().next_instruction_address();
+                    if self.breakpoints.contains(&address) {
+                        break;
+                    }
+                }
+                write_next_instruction(system, out)?;
+            }
+            DebugCommand::Break(address) => {
+                if !self.breakpoints.contains(&address) {
+                    self.breakpoints.push(address);
+                }
+                writeln!(out, "Breakpoint at {:08X}", address)?;
+            }
+            DebugCommand::Delete(address) => {
+                match self.breakpoints.iter().position(|&b| b == address) {
+                    Some(index) => {
+                        self.breakpoints.remove(index);
+                        writeln!(out, "Deleted the breakpoint at {:08X}", address)?;
+                    }
+                    None => writeln!(out, "No breakpoint at {:08X}", address)?,
+                }
+            }
+            DebugCommand::Regs => write_registers(system, out)?,
+            DebugCommand::Mem { address, len } => {
+                let mut memory = system.memory.borrow_mut();
+                for line_start in (0..len).step_by(DUMP_LINE_BYTES as usize) {
+                    let line_address = address.wrapping_add(line_start);
+                    write!(out, "{:08X} ", line_address)?;
+                    for offset in 0..DUMP_LINE_BYTES.min(len - line_start) {
+                        write!(
+                            out,
+                            " {:02X}",
+                            memory.read_u8(line_address.wrapping_add(offset))
+                        )?;
+                    }
+                    writeln!(out)?;
+                }
+            }
+            DebugCommand::Disasm(address) => {
+                let cpu = system.cpu.borrow();
+                let thumb = cpu.cpsr().thumb();
+                let mut memory = system.memory.borrow_mut();
+                match address {
+                    Some(start) => {
+                        let instr_size = if thumb { 2 } else { 4 };
+                        let end = start.wrapping_add(WINDOW_INSTRUCTIONS * instr_size);
+                        for (address, text) in disassemble_range(&mut *memory, start, end, thumb) {
+                            writeln!(out, "{:08X}  {}", address, text)?;
+                        }
+                    }
+                    None => write!(
+                        out,
+                        "{}",
+                        format_disassembly_window(
+                            &mut *memory,
+                            cpu.next_instruction_address(),
+                            thumb,
+                            &self.breakpoints
+                        )
+                    )?,
+                }
+            }
+            DebugCommand::CheatAdd(cheat) => {
+                system.cheats.add(cheat);
+                writeln!(out, "Added cheat {}", system.cheats.cheats().len() - 1)?;
+            }
+            DebugCommand::CheatRemove(index) => {
+                if index < system.cheats.cheats().len() {
+                    let cheat = system.cheats.remove(index);
+                    writeln!(out, "Removed cheat {}: {}", index, cheat.source)?;
+                } else {
+                    writeln!(out, "No cheat {}", index)?;
+                }
+            }
+            DebugCommand::CheatList => {
+                if system.cheats.cheats().is_empty() {
+                    writeln!(out, "No cheats")?;
+                }
+                for (i, cheat) in system.cheats.cheats().iter().enumerate() {
+                    let disabled = if cheat.enabled { "" } else { " (disabled)" };
+                    writeln!(out, "{}: {}{}", i, cheat.source, disabled)?;
+                }
+            }
+            DebugCommand::Help => writeln!(out, "{}", HELP)?,
+            DebugCommand::Quit => return Ok(Some(DebuggerExit::Quit)),
+        }
+        Ok(None)
+    }
+}
+
+/// Shows the instruction that executes next, with its address.
+fn write_next_instruction(system: &System, out: &mut Write) -> io::Result<()> {
+    let cpu = system.cpu.borrow();
+    let thumb = cpu.cpsr().thumb();
+    let address = cpu.next_instruction_address();
+    let instr_size = if thumb { 2 } else { 4 };
+    let lines = disassemble_range(
+        &mut *system.memory.borrow_mut(),
+        address,
+        address.wrapping_add(instr_size),
+        thumb,
+    );
+    writeln!(out, "{:08X}  {}", address, lines[0].1)
+}
+
+/// Shows r0-r14, the address of the next instruction as PC, and the CPSR.
+fn write_registers(system: &System, out: &mut Write) -> io::Result<()> {
+    let cpu = system.cpu.borrow();
+    for (i, reg) in cpu.regs()[..15].iter().enumerate() {
+        let separator = if i % 4 == 3 { "\n" } else { " " };
+        write!(out, "r{}={:08X}{}", i, reg, separator)?;
+    }
+    writeln!(out, "pc={:08X}", cpu.next_instruction_address())?;
+    writeln!(out, "cpsr={}", cpu.cpsr())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ByteOrder;
+    use byteorder::LE;
+    use cpu::asm;
+
+    #[test]
+    fn test_parse_debug_command() {
+        assert_eq!(parse_debug_command("  \n"), Ok(None));
+        assert_eq!(
+            parse_debug_command("s 10"),
+            Ok(Some(DebugCommand::Step(10)))
+        );
+        assert_eq!(
+            parse_debug_command("break 0x08000100"),
+            Ok(Some(DebugCommand::Break(0x0800_0100)))
+        );
+        assert_eq!(
+            parse_debug_command("mem 3000000 32"),
+            Ok(Some(DebugCommand::Mem {
+                address: 0x0300_0000,
+                len: 32,
+            }))
+        );
+        assert_eq!(
+            parse_debug_command("disasm"),
+            Ok(Some(DebugCommand::Disasm(None)))
+        );
+        assert_eq!(
+            parse_debug_command("cheat remove 2"),
+            Ok(Some(DebugCommand::CheatRemove(2)))
+        );
+        for bad in &[
+            "break",
+            "break 0x",
+            "step -1",
+            "regs r0",
+            "run",
+            "cheat",
+            "cheat add",
+            "cheat add 0300000:zz",
+            "cheat remove -1",
+        ] {
+            assert!(parse_debug_command(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_debugger_script() {
+        let code = asm::assemble(
+            "
+            mov r0, #1
+            mov r1, #2
+            add r2, r0, r1
+        loop:
+            b loop
+            ",
+        );
+        let mut rom = vec![0; code.len() * 4];
+        LE::write_u32_into(&code, &mut rom);
+        let mut system = System::new(&[], &rom);
+
+        let script = "regs\nbreak 08000008\nc\nstep\nr\nmem 8000000 20\nbogus\n\
+                      cheat add 03000000:2A\ncheat add gs1: 00000000\ncheat list\n\
+                      cheat remove 1\ncheat remove 0\ncheat list\nquit\nstep\n";
+        let mut out = Vec::new();
+        let exit = Debugger::new()
+            .run(&mut system, &mut script.as_bytes(), &mut out)
+            .unwrap();
+        // The last step is never run
+        assert_eq!(exit, DebuggerExit::Quit);
+        let expected = [
+            "08000000  mov r0, #1",
+            "(advance) r0=00000000 r1=00000000 r2=00000000 r3=00000000",
+            "r4=00000000 r5=00000000 r6=00000000 r7=00000000",
+            "r8=00000000 r9=00000000 r10=00000000 r11=00000000",
+            "r12=00000000 r13=03007F00 r14=00000000 pc=08000000",
+            "cpsr=nzcvift sys",
+            "(advance) Breakpoint at 08000008",
+            "(advance) 08000008  add r2, r0, r1",
+            "(advance) 0800000C  b $0800000C",
+            "(advance) r0=00000001 r1=00000002 r2=00000003 r3=00000000",
+            "r4=00000000 r5=00000000 r6=00000000 r7=00000000",
+            "r8=00000000 r9=00000000 r10=00000000 r11=00000000",
+            "r12=00000000 r13=03007F00 r14=00000000 pc=0800000C",
+            "cpsr=nzcvift sys",
+            "(advance) 08000000  01 00 A0 E3 02 10 A0 E3 01 20 80 E0 FE FF FF EA",
+            // Past the end of the ROM, which reads as the address in halfwords
+            "08000010  08 00 09 00",
+            "(advance) unknown command bogus, try help",
+            "(advance) Added cheat 0",
+            "(advance) invalid cheat: incomplete code: gs1: 00000000",
+            "(advance) 0: 03000000:2A",
+            "(advance) No cheat 1",
+            "(advance) Removed cheat 0: 03000000:2A",
+            "(advance) No cheats",
+            "(advance) ",
+        ];
+        assert_eq!(String::from_utf8(out).unwrap(), expected.join("\n"));
+    }
+}
@@ -0,0 +1,389 @@
+//! DMA controller. Its four channels copy data over the bus on their own, stalling the CPU while
+//! they hold it. When several channels are ready at once, the lowest numbered one goes first.
+//!
+//! HBlank and VBlank transfers are triggered by the PPU, at the start of the HBlank of each
+//! visible line and at the start of VBlank.
+//!
+//! TODO: Special timings never trigger yet.
+
+use interrupt::Interrupt;
+use interrupt::InterruptController;
+use scheduler::Clock;
+use scheduler::EventSource;
+use scheduler::GeneratorTask;
+use scheduler::Task;
+use std::cell::RefCell;
+use std::rc::Rc;
+use system::AccessWidth;
+use system::Bus;
+use system::MemoryRequest;
+use system::OperationType;
+
+const DMACNT_WORD: u16 = 1 << 10;
+const DMACNT_REPEAT: u16 = 1 << 9;
+const DMACNT_IRQ_ENABLE: u16 = 1 << 14;
+const DMACNT_ENABLE: u16 = 1 << 15;
+
+/// Writable bits of DMAxCNT_H. Only channel 3 has the Game Pak DRQ bit.
+const DMACNT_MASK: u16 = 0xF7E0;
+const DMA3CNT_MASK: u16 = 0xFFE0;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StartTiming {
+    Immediate,
+    VBlank,
+    HBlank,
+    /// Sound FIFO for channels 1 and 2, video capture for channel 3.
+    Special,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum AddressControl {
+    Increment,
+    Decrement,
+    Fixed,
+    /// Increments during the transfer, then goes back to the start when it repeats. Only valid for
+    /// the destination.
+    IncrementReload,
+}
+
+impl AddressControl {
+    fn from_bits(bits: u16) -> AddressControl {
+        match bits {
+            0 => AddressControl::Increment,
+            1 => AddressControl::Decrement,
+            2 => AddressControl::Fixed,
+            _ => AddressControl::IncrementReload,
+        }
+    }
+
+    fn step(self, address: u32, size: u32) -> u32 {
+        match self {
+            AddressControl::Increment | AddressControl::IncrementReload => {
+                address.wrapping_add(size)
+            }
+            AddressControl::Decrement => address.wrapping_sub(size),
+            AddressControl::Fixed => address,
+        }
+    }
+}
+
+#[derive(Default)]
+struct DmaChannel {
+    // DMAxSAD/DMAxDAD/DMAxCNT_L, write-only
+    source: u32,
+    dest: u32,
+    count: u16,
+    // DMAxCNT_H
+    control: u16,
+
+    // Transfer progress, loaded from the registers when the transfer starts
+    current_source: u32,
+    current_dest: u32,
+    remaining: u32,
+    /// Set while triggered and waiting for or holding the bus.
+    active: bool,
+}
+
+impl DmaChannel {
+    fn width(&self) -> AccessWidth {
+        if self.control & DMACNT_WORD != 0 {
+            AccessWidth::Bit32
+        } else {
+            AccessWidth::Bit16
+        }
+    }
+
+    fn unit_size(&self) -> u32 {
+        if self.control & DMACNT_WORD != 0 {
+            4
+        } else {
+            2
+        }
+    }
+
+    fn dest_control(&self) -> AddressControl {
+        let control = self.control;
+        AddressControl::from_bits(bit!(control[5:6]))
+    }
+
+    fn source_control(&self) -> AddressControl {
+        let control = self.control;
+        // The prohibited setting increments, like 0
+        match bit!(control[7:8]) {
+            3 => AddressControl::Increment,
+            bits => AddressControl::from_bits(bits),
+        }
+    }
+
+    fn start_timing(&self) -> StartTiming {
+        let control = self.control;
+        match bit!(control[12:13]) {
+            0 => StartTiming::Immediate,
+            1 => StartTiming::VBlank,
+            2 => StartTiming::HBlank,
+            _ => StartTiming::Special,
+        }
+    }
+
+    /// Loads the number of units to transfer from DMAxCNT_L, where 0 means the maximum.
+    fn reload_count(&mut self, max_count: u32) {
+        self.remaining = match self.count as u32 & (max_count - 1) {
+            0 => max_count,
+            count => count,
+        };
+    }
+}
+
+pub struct DmaController {
+    channels: [DmaChannel; 4],
+    interrupts: Rc<InterruptController>,
+}
+
+/// Masks of the addresses each channel can access, and the number of units it can transfer at
+/// most. Channel 0 can't reach the cart, and only channel 3 can write to it.
+const SOURCE_MASKS: [u32; 4] = [0x07FF_FFFF, 0x0FFF_FFFF, 0x0FFF_FFFF, 0x0FFF_FFFF];
+const DEST_MASKS: [u32; 4] = [0x07FF_FFFF, 0x07FF_FFFF, 0x07FF_FFFF, 0x0FFF_FFFF];
+const MAX_COUNTS: [u32; 4] = [0x4000, 0x4000, 0x4000, 0x10000];
+
+const CHANNEL_INTERRUPTS: [Interrupt; 4] = [
+    Interrupt::Dma0,
+    Interrupt::Dma1,
+    Interrupt::Dma2,
+    Interrupt::Dma3,
+];
+
+impl DmaController {
+    pub fn new(interrupts: Rc<InterruptController>) -> DmaController {
+        DmaController {
+            channels: Default::default(),
+            interrupts,
+        }
+    }
+
+    pub fn read(&self, address: u32) -> u16 {
+        let offset = (address & 0xFFF) - 0xB0;
+        let channel = &self.channels[(offset / 12) as usize];
+        match offset % 12 {
+            // Only the control register can be read back
+            0xA => channel.control,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, address: u32, data: u16) {
+        let offset = (address & 0xFFF) - 0xB0;
+        let n = (offset / 12) as usize;
+        let channel = &mut self.channels[n];
+        match offset % 12 {
+            0x0 => channel.source = channel.source & 0xFFFF_0000 | data as u32,
+            0x2 => channel.source = channel.source & 0xFFFF | (data as u32) << 16,
+            0x4 => channel.dest = channel.dest & 0xFFFF_0000 | data as u32,
+            0x6 => channel.dest = channel.dest & 0xFFFF | (data as u32) << 16,
+            0x8 => channel.count = data,
+            0xA => {
+                let was_enabled = channel.control & DMACNT_ENABLE != 0;
+                channel.control = data & if n == 3 { DMA3CNT_MASK } else { DMACNT_MASK };
+                if channel.control & DMACNT_ENABLE == 0 {
+                    channel.active = false;
+                } else if !was_enabled {
+                    // The addresses are only latched when the channel is enabled
+                    let alignment = !(channel.unit_size() - 1);
+                    channel.current_source = channel.source & SOURCE_MASKS[n] & alignment;
+                    channel.current_dest = channel.dest & DEST_MASKS[n] & alignment;
+                    channel.reload_count(MAX_COUNTS[n]);
+                    channel.active = channel.start_timing() == StartTiming::Immediate;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Starts the transfers of the enabled channels waiting for `timing`. Repeating channels stay
+    /// enabled after their transfer, and start again on the next trigger.
+    pub fn trigger(&mut self, timing: StartTiming) {
+        for channel in self.channels.iter_mut() {
+            if channel.control & DMACNT_ENABLE != 0 && channel.start_timing() == timing {
+                channel.active = true;
+            }
+        }
+    }
+
+    /// Returns the channel that gets the bus next, if any are waiting for it.
+    pub fn active_channel(&self) -> Option<usize> {
+        self.channels.iter().position(|channel| channel.active)
+    }
+
+    /// Earliest time channel `n` can finish its transfer, with every unit's read and write taking a
+    /// single cycle.
+    fn earliest_completion(&self, n: usize, now: u64) -> u64 {
+        now + self.channels[n].remaining as u64 * 2
+    }
+
+    /// Returns the source and destination requests for the next unit of channel `n`.
+    fn next_unit(&self, n: usize) -> (MemoryRequest, MemoryRequest) {
+        let channel = &self.channels[n];
+        let read = MemoryRequest {
+            address: channel.current_source,
+            width: channel.width(),
+            op: OperationType::Read {
+                is_instruction: false,
+            },
+            seq: false,
+        };
+        let write = MemoryRequest {
+            address: channel.current_dest,
+            width: channel.width(),
+            op: OperationType::Write,
+            seq: false,
+        };
+        (read, write)
+    }
+
+    /// Advances channel `n` past the unit just transferred, finishing the transfer after the last.
+    fn finish_unit(&mut self, n: usize) {
+        let channel = &mut self.channels[n];
+        let size = channel.unit_size();
+        channel.current_source = channel.source_control().step(channel.current_source, size);
+        channel.current_dest = channel.dest_control().step(channel.current_dest, size);
+        channel.remaining -= 1;
+        if channel.remaining > 0 {
+            return;
+        }
+
+        channel.active = false;
+        if channel.control & DMACNT_REPEAT != 0 && channel.start_timing() != StartTiming::Immediate
+        {
+            // Stays enabled, waiting for the next trigger
+            channel.reload_count(MAX_COUNTS[n]);
+            if channel.dest_control() == AddressControl::IncrementReload {
+                let alignment = !(channel.unit_size() - 1);
+                channel.current_dest = channel.dest & DEST_MASKS[n] & alignment;
+            }
+        } else {
+            channel.control &= !DMACNT_ENABLE;
+        }
+        if channel.control & DMACNT_IRQ_ENABLE != 0 {
+            self.interrupts.request(CHANNEL_INTERRUPTS[n]);
+        }
+    }
+
+    /// Takes the bus from the CPU while any channel is active, and transfers one unit at a time,
+    /// each read followed by a write. The channel is picked again for each unit, so a higher
+    /// priority channel that becomes active interrupts the one running. The earliest time the
+    /// running channel can finish is announced on `clock`, since it may raise an IRQ then.
+    pub fn run_task(
+        dma: Rc<RefCell<DmaController>>,
+        bus: Rc<Bus>,
+        clock: Rc<Clock>,
+    ) -> impl Task<'static, Return = ()> {
+        GeneratorTask::new(move || loop {
+            if dma.borrow().active_channel().is_none() {
+                clock.announce_event(EventSource::Dma, u64::max_value());
+                wait_cycles!(1);
+                continue;
+            }
+
+            // Let the CPU's access in progress finish first
+            while bus.should_dma_wait() {
+                wait_cycles!(1);
+            }
+            // The CPU hasn't latched the result of its last read yet, so it's put back on the bus
+            // for it when the transfer is done
+            let cpu_data = bus.read_data();
+            bus.dma_active.set(true);
+
+            loop {
+                let n = match dma.borrow().active_channel() {
+                    Some(n) => n,
+                    None => break,
+                };
+                let (read, write) = dma.borrow().next_unit(n);
+                let completion = dma.borrow().earliest_completion(n, clock.now());
+                clock.announce_event(EventSource::Dma, completion);
+
+                bus.begin(read);
+                wait_cycles!(1);
+                while bus.should_dma_wait() {
+                    wait_cycles!(1);
+                }
+                // Halfwords are read from their lane, and written mirrored to both
+                let data = match read.width {
+                    AccessWidth::Bit16 => {
+                        let halfword = bus.read_data() >> ((read.address & 0b10) * 8) & 0xFFFF;
+                        halfword * 0x0001_0001
+                    }
+                    _ => bus.read_data(),
+                };
+
+                bus.begin(write);
+                bus.drive_data(data);
+                wait_cycles!(1);
+                while bus.should_dma_wait() {
+                    wait_cycles!(1);
+                }
+
+                dma.borrow_mut().finish_unit(n);
+            }
+
+            bus.drive_data(cpu_data);
+            bus.dma_active.set(false);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> DmaController {
+        let interrupts = Rc::new(InterruptController::new(Rc::new(Bus::default())));
+        DmaController::new(interrupts)
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut dma = setup();
+        // Only DMAxCNT_H reads back, without its unused bits
+        dma.write(0x0400_00B0, 0x1234);
+        dma.write(0x0400_00B8, 0x0010);
+        dma.write(0x0400_00BA, 0x5FFF);
+        assert_eq!(dma.read(0x0400_00B0), 0);
+        assert_eq!(dma.read(0x0400_00B8), 0);
+        assert_eq!(dma.read(0x0400_00BA), 0x57E0);
+        dma.write(0x0400_00DE, 0x5FFF);
+        assert_eq!(dma.read(0x0400_00DE), 0x5FE0);
+    }
+
+    #[test]
+    fn test_start() {
+        let mut dma = setup();
+        // Channel 1, 0 units meaning the maximum, from an unaligned word address
+        dma.write(0x0400_00BC, 0x0003);
+        dma.write(0x0400_00BE, 0x0300);
+        dma.write(0x0400_00C4, 0x0000);
+        assert_eq!(dma.active_channel(), None);
+        dma.write(0x0400_00C6, DMACNT_ENABLE | DMACNT_WORD);
+        assert_eq!(dma.active_channel(), Some(1));
+        assert_eq!(dma.channels[1].current_source, 0x0300_0000);
+        assert_eq!(dma.channels[1].remaining, 0x4000);
+
+        // Channel 0 goes first once it starts too
+        dma.write(0x0400_00B8, 1);
+        dma.write(0x0400_00BA, DMACNT_ENABLE);
+        assert_eq!(dma.active_channel(), Some(0));
+        dma.finish_unit(0);
+        assert_eq!(dma.read(0x0400_00BA), 0);
+        assert_eq!(dma.active_channel(), Some(1));
+
+        // VBlank timing waits for its trigger
+        dma.write(0x0400_00D2, DMACNT_ENABLE | 1 << 12);
+        assert_eq!(dma.active_channel(), Some(1));
+        dma.write(0x0400_00C6, 0);
+        assert_eq!(dma.active_channel(), None);
+        dma.trigger(StartTiming::HBlank);
+        assert_eq!(dma.active_channel(), None);
+        dma.trigger(StartTiming::VBlank);
+        assert_eq!(dma.active_channel(), Some(2));
+    }
+}
@@ -0,0 +1,180 @@
+//! Destinations for rendered frames, so the emulator core doesn't depend on any particular
+//! frontend.
+
+use byteorder::ByteOrder;
+use byteorder::BE;
+use byteorder::LE;
+use color::expand_channel;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// Receives every completed frame.
+pub trait FrameSink {
+    /// `framebuffer` contains 240x160 BGR555 pixels, line by line.
+    fn present(&mut self, framebuffer: &[u16]);
+}
+
+/// Lets a frontend keep a handle to its sink after handing it over to the `System`.
+impl<T: FrameSink> FrameSink for Rc<RefCell<T>> {
+    fn present(&mut self, framebuffer: &[u16]) {
+        self.borrow_mut().present(framebuffer);
+    }
+}
+
+/// Discards all frames, for headless runs.
+pub struct NullSink;
+
+impl FrameSink for NullSink {
+    fn present(&mut self, _framebuffer: &[u16]) {}
+}
+
+/// Writes every frame as a numbered PNG file in a directory, for recording video.
+pub struct PngSequenceSink {
+    directory: PathBuf,
+    frame_number: u32,
+}
+
+impl PngSequenceSink {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> PngSequenceSink {
+        PngSequenceSink {
+            directory: directory.into(),
+            frame_number: 0,
+        }
+    }
+
+    fn write_frame(&self, framebuffer: &[u16]) -> io::Result<()> {
+        let path = self
+            .directory
+            .join(format!("frame_{:06}.png", self.frame_number));
+        File::create(path)?.write_all(&encode_png(framebuffer))
+    }
+}
+
+impl FrameSink for PngSequenceSink {
+    fn present(&mut self, framebuffer: &[u16]) {
+        if let Err(e) = self.write_frame(framebuffer) {
+            println!("Failed to write frame {}: {}", self.frame_number, e);
+        }
+        self.frame_number += 1;
+    }
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+fn push_u32_be(buf: &mut Vec<u8>, value: u32) {
+    let mut bytes = [0; 4];
+    BE::write_u32(&mut bytes, value);
+    buf.extend_from_slice(&bytes);
+}
+
+fn push_u16_le(buf: &mut Vec<u8>, value: u16) {
+    let mut bytes = [0; 2];
+    LE::write_u16(&mut bytes, value);
+    buf.extend_from_slice(&bytes);
+}
+
+fn write_png_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    let start = png.len();
+    push_u32_be(png, data.len() as u32);
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start + 4..]);
+    push_u32_be(png, crc);
+}
+
+/// Wraps `data` in a zlib stream using uncompressed deflate blocks. Frames are small enough that
+/// compressing them isn't worth a dependency.
+fn zlib_store(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    while let Some(block) = blocks.next() {
+        let is_final = blocks.peek().is_none();
+        stream.push(is_final as u8);
+        push_u16_le(&mut stream, block.len() as u16);
+        push_u16_le(&mut stream, !(block.len() as u16));
+        stream.extend_from_slice(block);
+    }
+    push_u32_be(&mut stream, adler32(data));
+    stream
+}
+
+/// Encodes a 240x160 BGR555 frame as an RGB PNG image.
+pub fn encode_png(framebuffer: &[u16]) -> Vec<u8> {
+    assert_eq!(framebuffer.len(), 240 * 160);
+
+    let mut header = Vec::new();
+    push_u32_be(&mut header, 240);
+    push_u32_be(&mut header, 160);
+    // 8 bits per channel, RGB, default compression/filter, no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut image_data = Vec::with_capacity(160 * (1 + 240 * 3));
+    for line in framebuffer.chunks(240) {
+        image_data.push(0); // No filter
+        for &color in line {
+            image_data.push(expand_channel(bit!(color[0:4])));
+            image_data.push(expand_channel(bit!(color[5:9])));
+            image_data.push(expand_channel(bit!(color[10:14])));
+        }
+    }
+
+    let mut png = PNG_SIGNATURE.to_vec();
+    write_png_chunk(&mut png, b"IHDR", &header);
+    write_png_chunk(&mut png, b"IDAT", &zlib_store(&image_data));
+    write_png_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE426082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+
+    #[test]
+    fn test_encode_png() {
+        let mut framebuffer = vec![0; 240 * 160];
+        framebuffer[0] = 0x001F;
+        let png = encode_png(&framebuffer);
+
+        assert_eq!(&png[..8], &PNG_SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+        // The first pixel is the first byte after the zlib, block and filter headers
+        let idat = 8 + 12 + 13;
+        assert_eq!(&png[idat + 4..idat + 8], b"IDAT");
+        assert_eq!(&png[idat + 8 + 2 + 5 + 1..][..6], &[0xFF, 0, 0, 0, 0, 0]);
+    }
+}
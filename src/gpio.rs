@@ -0,0 +1,190 @@
+//! General purpose I/O port on the cart, used to connect extra hardware like an RTC. Its registers
+//! are mapped over the cart ROM, at 0x080000C4-0x080000C9.
+
+use cart::CartHeader;
+use rtc::Rtc;
+use sensors::GyroSensor;
+use sensors::SolarSensor;
+
+/// Offsets of the GPIO registers in cart ROM.
+pub const GPIO_DATA: u32 = 0xC4;
+pub const GPIO_DIRECTION: u32 = 0xC6;
+pub const GPIO_CONTROL: u32 = 0xC8;
+
+/// Carts with an RTC contain the name of Nintendo's RTC library.
+const RTC_LIBRARY_SIGNATURE: &[u8] = b"SIIRTC_V";
+
+/// Game codes, without the region letter, of carts with a solar sensor (the Boktai series).
+const SOLAR_SENSOR_GAMES: [&str; 3] = ["U3I", "U32", "U33"];
+/// Game codes, without the region letter, of carts with a gyro sensor (WarioWare: Twisted!).
+const GYRO_SENSOR_GAMES: [&str; 1] = ["RZW"];
+
+/// A device connected to the 4 GPIO pins.
+pub trait GpioDevice {
+    /// Updates the levels of the pins driven by the GBA. Pins configured as inputs are 0.
+    fn write_pins(&mut self, pins: u8);
+    /// Returns the levels of the pins driven by the device. Only the ones configured as inputs
+    /// are seen by the GBA.
+    fn read_pins(&self) -> u8;
+    /// Changes the value measured by a sensor by `steps`, for devices which have one. This is for
+    /// frontends to let the user control the sensor.
+    fn adjust_sensor(&mut self, _steps: i32) {}
+}
+
+pub struct CartGpio {
+    /// Levels output by the GBA on the 4 pins.
+    data: u8,
+    /// Pins set to 1 are outputs from the GBA, 0 are inputs.
+    direction: u8,
+    /// When clear, the registers are write-only and reads return the ROM data under them.
+    read_enable: bool,
+
+    /// Devices share the pins. Each one only drives them while it's selected.
+    devices: Vec<Box<GpioDevice>>,
+}
+
+impl CartGpio {
+    pub fn new(devices: Vec<Box<GpioDevice>>) -> CartGpio {
+        CartGpio {
+            data: 0,
+            direction: 0,
+            read_enable: false,
+            devices,
+        }
+    }
+
+    /// Returns a GPIO port with the devices that the cart looks like it uses, or None if there
+    /// aren't any.
+    pub fn detect(cart_rom: &[u8], header: &CartHeader) -> Option<CartGpio> {
+        let mut devices: Vec<Box<GpioDevice>> = Vec::new();
+        if cart_rom
+            .windows(RTC_LIBRARY_SIGNATURE.len())
+            .any(|window| window == RTC_LIBRARY_SIGNATURE)
+        {
+            devices.push(Box::new(Rtc::new()));
+        }
+        let game = header.game_code.get(..3).unwrap_or("");
+        if SOLAR_SENSOR_GAMES.contains(&game) {
+            devices.push(Box::new(SolarSensor::new()));
+        }
+        if GYRO_SENSOR_GAMES.contains(&game) {
+            devices.push(Box::new(GyroSensor::new()));
+        }
+
+        if devices.is_empty() {
+            None
+        } else {
+            Some(CartGpio::new(devices))
+        }
+    }
+
+    /// Forwards a sensor adjustment to all connected devices.
+    pub fn adjust_sensors(&mut self, steps: i32) {
+        for device in self.devices.iter_mut() {
+            device.adjust_sensor(steps);
+        }
+    }
+
+    /// Whether the halfword at `offset` in cart ROM is a GPIO register.
+    pub fn is_register(offset: u32) -> bool {
+        offset >= GPIO_DATA && offset <= GPIO_CONTROL + 1
+    }
+
+    /// Reads the register at `offset`, or returns None if reads are disabled.
+    pub fn read(&self, offset: u32) -> Option<u16> {
+        if !self.read_enable {
+            return None;
+        }
+        let value = match offset & !0b1 {
+            GPIO_DATA => {
+                let device_pins = self
+                    .devices
+                    .iter()
+                    .fold(0, |pins, device| pins | device.read_pins());
+                (self.data & self.direction | device_pins & !self.direction) as u16
+            }
+            GPIO_DIRECTION => self.direction as u16,
+            GPIO_CONTROL => self.read_enable as u16,
+            _ => unreachable!(),
+        };
+        Some(value)
+    }
+
+    pub fn write(&mut self, offset: u32, data: u16) {
+        match offset & !0b1 {
+            GPIO_DATA => {
+                self.data = bit!(data[0:3]) as u8;
+                let pins = self.data & self.direction;
+                for device in self.devices.iter_mut() {
+                    device.write_pins(pins);
+                }
+            }
+            GPIO_DIRECTION => self.direction = bit!(data[0:3]) as u8,
+            GPIO_CONTROL => self.read_enable = bit!(data[0]) != 0,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rtc::DateTime;
+    use rtc::PIN_CS;
+    use rtc::PIN_SCK;
+    use rtc::PIN_SIO;
+
+    #[test]
+    fn test_detect_devices() {
+        let mut header = CartHeader::default();
+        assert!(CartGpio::detect(&[0; 0x100], &header).is_none());
+
+        let mut rom = vec![0; 0x100];
+        rom[0x80..0x88].copy_from_slice(b"SIIRTC_V");
+        assert_eq!(CartGpio::detect(&rom, &header).unwrap().devices.len(), 1);
+        // Boktai has both an RTC and a solar sensor
+        header.game_code = "U3IE".to_string();
+        assert_eq!(CartGpio::detect(&rom, &header).unwrap().devices.len(), 2);
+
+        header.game_code = "RZWE".to_string();
+        assert_eq!(
+            CartGpio::detect(&[0; 0x100], &header)
+                .unwrap()
+                .devices
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_rtc_over_gpio() {
+        let clock = || DateTime::from_unix_time(1710510330);
+        let mut gpio = CartGpio::new(vec![Box::new(Rtc::with_clock(Box::new(clock)))]);
+        gpio.write(GPIO_CONTROL, 1);
+
+        // Send the time command (0x67) MSB first
+        gpio.write(GPIO_DIRECTION, 0b111);
+        gpio.write(GPIO_DATA, PIN_SCK as u16);
+        gpio.write(GPIO_DATA, (PIN_SCK | PIN_CS) as u16);
+        for i in (0..8).rev() {
+            let sio = (0x67 >> i & 1) * PIN_SIO as u16;
+            gpio.write(GPIO_DATA, PIN_CS as u16 | sio);
+            gpio.write(GPIO_DATA, (PIN_CS | PIN_SCK) as u16 | sio);
+        }
+
+        // Receive hours, minutes and seconds, with SIO as an input
+        gpio.write(GPIO_DIRECTION, 0b101);
+        let mut time = [0; 3];
+        for byte in time.iter_mut() {
+            for i in 0..8 {
+                gpio.write(GPIO_DATA, PIN_CS as u16);
+                gpio.write(GPIO_DATA, (PIN_CS | PIN_SCK) as u16);
+                let sio = (gpio.read(GPIO_DATA).unwrap() as u8 & PIN_SIO) >> 1;
+                *byte |= sio << i;
+            }
+        }
+        assert_eq!(time, [0x13, 0x45, 0x30]);
+        // Output pins read back what was written
+        assert_eq!(gpio.read(GPIO_DATA).unwrap() & 0b101, 0b101);
+    }
+}
@@ -0,0 +1,721 @@
+//! High-level emulation of BIOS functions, for running software without a BIOS dump.
+
+use bios_compress::{
+    diff16_unfilter, diff8_unfilter, header_size, huff_uncomp, lz77_uncomp, rl_uncomp,
+    DecompressBuffers, DecompressError,
+};
+use byteorder::ByteOrder;
+use byteorder::LE;
+
+/// Memory access used by HLE functions. These accesses don't take any bus cycles.
+pub trait HleMemory {
+    fn read_u8(&mut self, address: u32) -> u8;
+    fn write_u8(&mut self, address: u32, data: u8);
+
+    fn read_u16(&mut self, address: u32) -> u16 {
+        self.read_u8(address) as u16 | (self.read_u8(address + 1) as u16) << 8
+    }
+
+    fn read_u32(&mut self, address: u32) -> u32 {
+        self.read_u16(address) as u32 | (self.read_u16(address + 2) as u32) << 16
+    }
+
+    fn write_u16(&mut self, address: u32, data: u16) {
+        self.write_u8(address, data as u8);
+        self.write_u8(address + 1, (data >> 8) as u8);
+    }
+
+    fn write_u32(&mut self, address: u32, data: u32) {
+        self.write_u16(address, data as u16);
+        self.write_u16(address + 2, (data >> 16) as u16);
+    }
+}
+
+const SP: usize = 13;
+const PC: usize = 15;
+
+/// The BIOS's IRQ handler, placed where it is in the BIOS. It saves the registers the game's
+/// handler may change, calls it through the pointer at 0x03007FFC with r0 = 0x04000000, and
+/// returns from the interrupt.
+const IRQ_HANDLER_ADDRESS: usize = 0x128;
+const IRQ_HANDLER: [u32; 6] = [
+    0xE92D500F, // stmfd sp!, {r0-r3, r12, lr}
+    0xE3A00301, // mov r0, #0x04000000
+    0xE28FE000, // add lr, pc, #0
+    0xE510F004, // ldr pc, [r0, #-4]
+    0xE8BD500F, // ldmfd sp!, {r0-r3, r12, lr}
+    0xE25EF004, // subs pc, lr, #4
+];
+
+/// A stand-in for the BIOS when running without one, with only the IRQ vector and handler. The
+/// game's interrupt handler runs on the CPU, so the BIOS code calling it can't be done with HLE
+/// like the SWIs.
+pub fn stand_in_bios() -> Vec<u8> {
+    let mut bios = vec![0; 16 * 1024];
+    LE::write_u32(&mut bios[0x18..], 0xEA000042); // b 0x128
+    for (i, &instr) in IRQ_HANDLER.iter().enumerate() {
+        LE::write_u32(&mut bios[IRQ_HANDLER_ADDRESS + i * 4..], instr);
+    }
+    bios
+}
+
+/// Executes the BIOS function for SWI number `comment`, with arguments and return values in
+/// `regs`. Returns the approximate number of cycles the function takes on hardware, or None if
+/// it isn't implemented.
+///
+/// Functions that don't return to the instruction after the SWI change PC in `regs`. SoftReset
+/// jumps to the game, and IntrWait (0x04) and VBlankIntrWait (0x05) go back to the SWI itself
+/// while they have to wait, to run again after the interrupt that wakes the CPU up is handled.
+/// `intr_wait_flags` holds the interrupts they're waiting for meanwhile.
+pub fn dispatch_swi(
+    comment: u8,
+    regs: &mut [u32; 16],
+    memory: &mut HleMemory,
+    intr_wait_flags: &mut Option<u16>,
+) -> Option<u32> {
+    let (src, dst) = (regs[0], regs[1]);
+    let cycles = match comment {
+        0x00 => soft_reset(memory, regs),
+        0x01 => register_ram_reset(memory, regs[0]),
+        // Halt and Stop, which take effect once the SWI returns
+        0x02 => {
+            memory.write_u8(HALTCNT, 0x00);
+            10
+        }
+        0x03 => {
+            memory.write_u8(HALTCNT, 0x80);
+            10
+        }
+        0x04 => intr_wait(memory, regs, intr_wait_flags),
+        0x05 => {
+            // IntrWait for VBlank, discarding old flags
+            regs[0] = 1;
+            regs[1] = 1;
+            intr_wait(memory, regs, intr_wait_flags)
+        }
+        0x06 => div(regs, regs[0] as i32, regs[1] as i32),
+        0x07 => div(regs, regs[1] as i32, regs[0] as i32),
+        0x08 => sqrt(regs),
+        0x09 => {
+            let (result, a, b) = arc_tan(regs[0] as i32);
+            regs[0] = result as u32;
+            regs[1] = a as u32;
+            regs[3] = b as u32;
+            50
+        }
+        0x0A => arc_tan2(regs),
+        0x0B => cpu_set(memory, src, dst, regs[2]),
+        0x0C => cpu_fast_set(memory, src, dst, regs[2]),
+        0x0E => bg_affine_set(memory, src, dst, regs[2]),
+        0x0F => obj_affine_set(memory, src, dst, regs[2], regs[3]),
+        0x11 => uncomp(memory, src, dst, 10, |b| lz77_uncomp(b, false)),
+        0x12 => uncomp(memory, src, dst, 10, |b| lz77_uncomp(b, true)),
+        0x13 => uncomp(memory, src, dst, 30, |b| huff_uncomp(b)),
+        0x14 => uncomp(memory, src, dst, 8, |b| rl_uncomp(b, false)),
+        0x15 => uncomp(memory, src, dst, 8, |b| rl_uncomp(b, true)),
+        0x16 => uncomp(memory, src, dst, 6, |b| diff8_unfilter(b, false)),
+        0x17 => uncomp(memory, src, dst, 6, |b| diff8_unfilter(b, true)),
+        0x18 => uncomp(memory, src, dst, 3, |b| diff16_unfilter(b)),
+        _ => return None,
+    };
+    Some(cycles)
+}
+
+/// Clears the top of IWRAM, where the BIOS keeps its variables and stacks, and restarts the game.
+/// It's restarted from EWRAM instead of ROM if the byte at 0x03007FFA is set, as done by
+/// multiboot programs.
+fn soft_reset(memory: &mut HleMemory, regs: &mut [u32; 16]) -> u32 {
+    let entry_point = if memory.read_u8(0x0300_7FFA) != 0 {
+        0x0200_0000
+    } else {
+        0x0800_0000
+    };
+    for address in (0x0300_7E00..0x0300_8000).step_by(4) {
+        memory.write_u32(address, 0);
+    }
+
+    *regs = [0; 16];
+    regs[SP] = 0x0300_7F00;
+    regs[PC] = entry_point;
+    200
+}
+
+const HALTCNT: u32 = 0x0400_0301;
+const IME: u32 = 0x0400_0208;
+
+/// The BIOS IF mirror, where the game's interrupt handler sets the flags of the interrupts it
+/// handled, for IntrWait.
+const BIOS_IF: u32 = 0x0300_7FF8;
+
+/// Waits until one of the interrupts in r1 is set in the BIOS IF mirror, and clears it there. If
+/// r0 is set, the flags already in the mirror are discarded first. IME is enabled, even if the game
+/// left it disabled.
+///
+/// The interrupts are handled by the game's handler, which runs on the CPU, so the wait can't
+/// happen in a single call. Until the flags are set, the CPU is halted and PC is moved back to the
+/// SWI, with the flags left in `waiting` for the next call.
+fn intr_wait(memory: &mut HleMemory, regs: &mut [u32; 16], waiting: &mut Option<u16>) -> u32 {
+    let flags = match waiting.take() {
+        Some(flags) => flags,
+        None => {
+            memory.write_u16(IME, 1);
+            let flags = regs[1] as u16;
+            if regs[0] != 0 {
+                let mirror = memory.read_u16(BIOS_IF);
+                memory.write_u16(BIOS_IF, mirror & !flags);
+            }
+            flags
+        }
+    };
+
+    let mirror = memory.read_u16(BIOS_IF);
+    if mirror & flags != 0 {
+        memory.write_u16(BIOS_IF, mirror & !flags);
+    } else {
+        memory.write_u8(HALTCNT, 0x00);
+        *waiting = Some(flags);
+        // PC is 8 bytes ahead of the SWI, which is always an ARM instruction
+        regs[PC] = regs[PC].wrapping_sub(8);
+    }
+    30
+}
+
+/// I/O registers reset by RegisterRamReset, for the units that are emulated.
+const LCD_REGS: (u32, u32) = (0x0400_0000, 0x0400_0060);
+const SOUND_REGS: (u32, u32) = (0x0400_0060, 0x0400_00A8);
+
+fn clear_memory(memory: &mut HleMemory, (start, end): (u32, u32)) -> u32 {
+    for address in (start..end).step_by(4) {
+        memory.write_u32(address, 0);
+    }
+    (end - start) / 4
+}
+
+fn register_ram_reset(memory: &mut HleMemory, flags: u32) -> u32 {
+    let mut words = 0;
+    if bit!(flags[0]) != 0 {
+        words += clear_memory(memory, (0x0200_0000, 0x0204_0000));
+    }
+    if bit!(flags[1]) != 0 {
+        // The top of IWRAM is used by the BIOS and is left alone
+        words += clear_memory(memory, (0x0300_0000, 0x0300_7E00));
+    }
+    if bit!(flags[2]) != 0 {
+        words += clear_memory(memory, (0x0500_0000, 0x0500_0400));
+    }
+    if bit!(flags[3]) != 0 {
+        words += clear_memory(memory, (0x0600_0000, 0x0601_8000));
+    }
+    if bit!(flags[4]) != 0 {
+        words += clear_memory(memory, (0x0700_0000, 0x0700_0400));
+    }
+    // TODO: Bit 5 resets the SIO registers, once they're emulated
+    if bit!(flags[6]) != 0 {
+        words += clear_memory(memory, SOUND_REGS);
+    }
+    if bit!(flags[7]) != 0 {
+        words += clear_memory(memory, LCD_REGS);
+        // Forced blank
+        memory.write_u16(LCD_REGS.0, 0x0080);
+    }
+    100 + words * 2
+}
+
+/// Signed division, returning the quotient in r0, the remainder in r1 and the absolute value of
+/// the quotient in r3. The BIOS hangs when dividing by zero, but here a result with the same sign
+/// as the numerator is returned instead.
+fn div(regs: &mut [u32; 16], numerator: i32, denominator: i32) -> u32 {
+    let (quotient, remainder) = if denominator == 0 {
+        (if numerator < 0 { -1 } else { 1 }, numerator)
+    } else {
+        (
+            numerator.wrapping_div(denominator),
+            numerator.wrapping_rem(denominator),
+        )
+    };
+    regs[0] = quotient as u32;
+    regs[1] = remainder as u32;
+    regs[3] = quotient.wrapping_abs() as u32;
+    // The BIOS uses a bit-by-bit loop, so the time depends on the size of the quotient
+    20 + 4 * (32 - quotient.wrapping_abs().leading_zeros())
+}
+
+/// Integer square root, rounded down.
+fn sqrt(regs: &mut [u32; 16]) -> u32 {
+    let x = regs[0];
+    let mut root = 0u32;
+    let mut bit = 1u32 << 30;
+    let mut rest = x;
+    while bit > 0 {
+        if rest >= root + bit {
+            rest -= root + bit;
+            root = root >> 1 | bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    regs[0] = root;
+    60
+}
+
+/// Arctangent of `tan`, in 1.14 fixed point, using the BIOS' polynomial approximation. Returns
+/// the angle in the range -0x4000..0x4000 (for -pi/2..pi/2), along with the intermediate values
+/// that the BIOS leaves in r1 and r3.
+fn arc_tan(tan: i32) -> (i32, i32, i32) {
+    let a = -(tan.wrapping_mul(tan) >> 14);
+    let mut b = (0xA9 * a >> 14) + 0x390;
+    for &coefficient in [0x91C, 0xFB6, 0x16AA, 0x2081, 0x3651, 0xA2F9].iter() {
+        b = (b.wrapping_mul(a) >> 14) + coefficient;
+    }
+    (tan.wrapping_mul(b) >> 16, a, b)
+}
+
+/// Angle of the vector (x, y), in 1.14 fixed point, from 0 to 0xFFFF for 0..2pi.
+fn arc_tan2(regs: &mut [u32; 16]) -> u32 {
+    let x = regs[0] as i16 as i32;
+    let y = regs[1] as i16 as i32;
+    let angle = if y == 0 {
+        if x >= 0 {
+            0
+        } else {
+            0x8000
+        }
+    } else if x == 0 {
+        if y >= 0 {
+            0x4000
+        } else {
+            0xC000
+        }
+    } else {
+        // Use the octant where the tangent is at most 1
+        let tan_y_x = || arc_tan((y << 14) / x).0;
+        let tan_x_y = || arc_tan((x << 14) / y).0;
+        if y >= 0 {
+            if x >= 0 && x >= y {
+                tan_y_x()
+            } else if x < 0 && -x >= y {
+                tan_y_x() + 0x8000
+            } else {
+                0x4000 - tan_x_y()
+            }
+        } else {
+            if x <= 0 && -x > -y {
+                tan_y_x() + 0x8000
+            } else if x > 0 && x >= -y {
+                tan_y_x() + 0x10000
+            } else {
+                0xC000 - tan_x_y()
+            }
+        }
+    };
+    regs[0] = angle as u16 as u32;
+    100
+}
+
+/// Copies or fills memory. `control` has the number of units in bits 0-20, selects filling with
+/// the first unit of `src` with bit 24, and selects words instead of halfwords with bit 26.
+fn cpu_set(memory: &mut HleMemory, src: u32, dst: u32, control: u32) -> u32 {
+    let count = bit!(control[0:20]);
+    let fill = bit!(control[24]) != 0;
+    let words = bit!(control[26]) != 0;
+
+    if words {
+        let (src, dst) = (src & !0b11, dst & !0b11);
+        for i in 0..count {
+            let data = memory.read_u32(if fill { src } else { src + i * 4 });
+            memory.write_u32(dst + i * 4, data);
+        }
+    } else {
+        let (src, dst) = (src & !0b1, dst & !0b1);
+        for i in 0..count {
+            let data = memory.read_u16(if fill { src } else { src + i * 2 });
+            memory.write_u16(dst + i * 2, data);
+        }
+    }
+    30 + count * if fill { 2 } else { 4 }
+}
+
+/// Like CpuSet, but always with words, and in blocks of 8 words. The count is rounded up to a
+/// whole number of blocks.
+fn cpu_fast_set(memory: &mut HleMemory, src: u32, dst: u32, control: u32) -> u32 {
+    let count = (bit!(control[0:20]) + 7) & !7;
+    let fill = bit!(control[24]) != 0;
+
+    let (src, dst) = (src & !0b11, dst & !0b11);
+    for i in 0..count {
+        let data = memory.read_u32(if fill { src } else { src + i * 4 });
+        memory.write_u32(dst + i * 4, data);
+    }
+    30 + count * if fill { 1 } else { 2 }
+}
+
+/// Sine of `angle`, where a full turn is 0x100, in 1.14 fixed point. The BIOS uses a table with
+/// these same values.
+fn sin_lut(angle: u8) -> i32 {
+    let radians = angle as f64 * ::std::f64::consts::PI / 128.0;
+    (radians.sin() * 0x4000 as f64).round() as i32
+}
+
+fn cos_lut(angle: u8) -> i32 {
+    sin_lut(angle.wrapping_add(0x40))
+}
+
+/// Computes the 8.8 fixed point rotation/scaling matrix for a scale and an angle (in 1/0x10000
+/// of a turn, of which only the top 8 bits are used).
+fn affine_matrix(scale_x: i32, scale_y: i32, angle: u16) -> [i32; 4] {
+    let angle = (angle >> 8) as u8;
+    let (sin, cos) = (sin_lut(angle), cos_lut(angle));
+    [
+        scale_x * cos >> 14,
+        -(scale_x * sin) >> 14,
+        scale_y * sin >> 14,
+        scale_y * cos >> 14,
+    ]
+}
+
+/// Computes BG affine parameters. Each 20-byte source entry holds the center of rotation in the
+/// BG (2 x s32, 8.8 fixed point), the center on screen (2 x s16), the scale (2 x s16, 8.8 fixed
+/// point) and the angle. Each 16-byte destination entry holds PA-PD followed by the reference
+/// point X/Y, as laid out in the BG2/BG3 registers.
+fn bg_affine_set(memory: &mut HleMemory, mut src: u32, mut dst: u32, count: u32) -> u32 {
+    for _ in 0..count {
+        let center_x = memory.read_u32(src) as i32;
+        let center_y = memory.read_u32(src + 4) as i32;
+        let screen_x = memory.read_u16(src + 8) as i16 as i32;
+        let screen_y = memory.read_u16(src + 10) as i16 as i32;
+        let scale_x = memory.read_u16(src + 12) as i16 as i32;
+        let scale_y = memory.read_u16(src + 14) as i16 as i32;
+        let angle = memory.read_u16(src + 16);
+        src += 20;
+
+        let [pa, pb, pc, pd] = affine_matrix(scale_x, scale_y, angle);
+        let x = center_x - (pa * screen_x + pb * screen_y);
+        let y = center_y - (pc * screen_x + pd * screen_y);
+
+        memory.write_u16(dst, pa as u16);
+        memory.write_u16(dst + 2, pb as u16);
+        memory.write_u16(dst + 4, pc as u16);
+        memory.write_u16(dst + 6, pd as u16);
+        memory.write_u32(dst + 8, x as u32);
+        memory.write_u32(dst + 12, y as u32);
+        dst += 16;
+    }
+    30 + count * 60
+}
+
+/// Computes OBJ affine parameters. Each 8-byte source entry holds the scale (2 x s16, 8.8 fixed
+/// point) and the angle. PA-PD are written `stride` bytes apart, which is 2 for a packed array
+/// or 8 to write them directly into OAM.
+fn obj_affine_set(
+    memory: &mut HleMemory,
+    mut src: u32,
+    mut dst: u32,
+    count: u32,
+    stride: u32,
+) -> u32 {
+    for _ in 0..count {
+        let scale_x = memory.read_u16(src) as i16 as i32;
+        let scale_y = memory.read_u16(src + 2) as i16 as i32;
+        let angle = memory.read_u16(src + 4);
+        src += 8;
+
+        for &param in affine_matrix(scale_x, scale_y, angle).iter() {
+            memory.write_u16(dst, param as u16);
+            dst += stride;
+        }
+    }
+    30 + count * 40
+}
+
+/// Decompression buffers in emulated memory. Output that is read back before being written sees
+/// whatever was in memory before, like on hardware.
+struct MemoryBuffers<'a> {
+    memory: &'a mut HleMemory,
+    src: u32,
+    dst: u32,
+}
+
+impl<'a> DecompressBuffers for MemoryBuffers<'a> {
+    fn read_input(&mut self, offset: u32) -> Result<u8, DecompressError> {
+        Ok(self.memory.read_u8(self.src.wrapping_add(offset)))
+    }
+
+    fn read_back(&mut self, position: u32, distance: u32) -> Result<u8, DecompressError> {
+        let address = self.dst.wrapping_add(position).wrapping_sub(distance);
+        Ok(self.memory.read_u8(address))
+    }
+
+    fn write_output(&mut self, offset: u32, data: u8) {
+        self.memory.write_u8(self.dst.wrapping_add(offset), data);
+    }
+
+    fn write_output_u16(&mut self, offset: u32, data: u16) {
+        self.memory.write_u16(self.dst.wrapping_add(offset), data);
+    }
+}
+
+/// Runs one of the decompression functions from `src` to `dst`. Returns the approximate time
+/// taken, which is mostly spent per output byte.
+fn uncomp<F>(memory: &mut HleMemory, src: u32, dst: u32, cycles_per_byte: u32, uncomp: F) -> u32
+where
+    F: FnOnce(&mut MemoryBuffers) -> Result<(), DecompressError>,
+{
+    let size = header_size(memory.read_u32(src));
+    // The BIOS doesn't validate the data either, so malformed data is decompressed as far as it
+    // goes.
+    let _ = uncomp(&mut MemoryBuffers { memory, src, dst });
+    50 + size * cycles_per_byte
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: u32 = 0x0200_0000;
+    const DST: u32 = 0x0200_1000;
+
+    /// Flat memory starting at `SRC`.
+    struct TestMemory(Vec<u8>);
+
+    impl HleMemory for TestMemory {
+        fn read_u8(&mut self, address: u32) -> u8 {
+            self.0[(address - SRC) as usize]
+        }
+
+        fn write_u8(&mut self, address: u32, data: u8) {
+            self.0[(address - SRC) as usize] = data;
+        }
+    }
+
+    /// Calls a function which only uses registers.
+    fn call_swi(comment: u8, args: &[u32]) -> [u32; 16] {
+        let mut regs = [0; 16];
+        regs[..args.len()].copy_from_slice(args);
+        assert!(dispatch_swi(comment, &mut regs, &mut TestMemory(vec![]), &mut None).is_some());
+        regs
+    }
+
+    #[test]
+    fn test_div() {
+        let regs = call_swi(0x06, &[-7i32 as u32, 2]);
+        assert_eq!(&regs[..4], &[-3i32 as u32, -1i32 as u32, 0, 3]);
+        let regs = call_swi(0x07, &[2, -7i32 as u32]);
+        assert_eq!(&regs[..4], &[-3i32 as u32, -1i32 as u32, 0, 3]);
+        let regs = call_swi(0x06, &[100, 10]);
+        assert_eq!(&regs[..4], &[10, 0, 0, 10]);
+        let regs = call_swi(0x06, &[0x8000_0000, -1i32 as u32]);
+        assert_eq!(&regs[..4], &[0x8000_0000, 0, 0, 0x8000_0000]);
+    }
+
+    #[test]
+    fn test_sqrt() {
+        for &(x, root) in [
+            (0, 0),
+            (1, 1),
+            (15, 3),
+            (16, 4),
+            (1_000_000, 1000),
+            (0xFFFF_FFFF, 0xFFFF),
+        ]
+        .iter()
+        {
+            assert_eq!(call_swi(0x08, &[x])[0], root, "sqrt({})", x);
+        }
+    }
+
+    #[test]
+    fn test_arc_tan() {
+        let regs = call_swi(0x09, &[0x4000]);
+        assert_eq!(&regs[..4], &[0x2000, -0x4000i32 as u32, 0, 0x8000]);
+        assert_eq!(call_swi(0x09, &[0x2000])[0], 0x12E4);
+        assert_eq!(call_swi(0x09, &[-0x4000i32 as u32])[0], -0x2000i32 as u32);
+        assert_eq!(call_swi(0x09, &[0])[0], 0);
+    }
+
+    #[test]
+    fn test_arc_tan2() {
+        for &(x, y, angle) in [
+            (1, 0, 0x0000),
+            (0, 1, 0x4000),
+            (-1, 0, 0x8000),
+            (0, -1, 0xC000),
+            (0x100, 0x100, 0x2000),
+            (-0x100, 0x100, 0x6000),
+            (-0x100, -0x100, 0xA000),
+            (0x100, -0x100, 0xE000),
+            (0x100, 0x80, 0x12E4),
+            (3, -5, 0xD605),
+        ]
+        .iter()
+        {
+            let regs = call_swi(0x0A, &[x as u32, y as u32]);
+            assert_eq!(regs[0], angle, "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn test_cpu_set() {
+        let mut memory = TestMemory(vec![0xEE; 0x2000]);
+        for i in 0..0x40 {
+            memory.0[i] = i as u8;
+        }
+
+        // Halfword copy
+        let mut regs = [SRC + 1, DST, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        dispatch_swi(0x0B, &mut regs, &mut memory, &mut None);
+        assert_eq!(&memory.0[0x1000..0x1008], &[0, 1, 2, 3, 4, 5, 0xEE, 0xEE]);
+
+        // Word fill
+        let mut regs = [
+            SRC + 4,
+            DST,
+            2 | 1 << 24 | 1 << 26,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        dispatch_swi(0x0B, &mut regs, &mut memory, &mut None);
+        assert_eq!(
+            &memory.0[0x1000..0x100A],
+            &[4, 5, 6, 7, 4, 5, 6, 7, 0xEE, 0xEE]
+        );
+
+        // Fast copy, rounded up to 8 words
+        let mut regs = [SRC, DST, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        dispatch_swi(0x0C, &mut regs, &mut memory, &mut None);
+        assert_eq!(&memory.0[0x1000..0x1020], &memory.0[0..0x20].to_vec()[..]);
+        assert_eq!(memory.0[0x1020], 0xEE);
+    }
+
+    #[test]
+    fn test_obj_affine_set() {
+        let mut memory = TestMemory(vec![0; 0x2000]);
+        // No rotation, and a quarter turn at 2x zoom (half the scale)
+        memory.write_u16(SRC, 0x100);
+        memory.write_u16(SRC + 2, 0x100);
+        memory.write_u16(SRC + 4, 0);
+        memory.write_u16(SRC + 8, 0x80);
+        memory.write_u16(SRC + 10, 0x80);
+        memory.write_u16(SRC + 12, 0x4000);
+
+        let mut regs = [SRC, DST, 2, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        dispatch_swi(0x0F, &mut regs, &mut memory, &mut None);
+        let params: Vec<u16> = (0..8).map(|i| memory.read_u16(DST + i * 8)).collect();
+        assert_eq!(params, [0x100, 0, 0, 0x100, 0, 0xFF80, 0x80, 0]);
+    }
+
+    #[test]
+    fn test_bg_affine_set() {
+        let mut memory = TestMemory(vec![0; 0x2000]);
+        // Rotate around (64, 32) in the BG, shown at the center of the screen
+        memory.write_u32(SRC, 64 << 8);
+        memory.write_u32(SRC + 4, 32 << 8);
+        memory.write_u16(SRC + 8, 120);
+        memory.write_u16(SRC + 10, 80);
+        memory.write_u16(SRC + 12, 0x100);
+        memory.write_u16(SRC + 14, 0x100);
+        memory.write_u16(SRC + 16, 0x4000);
+
+        let mut regs = [SRC, DST, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        dispatch_swi(0x0E, &mut regs, &mut memory, &mut None);
+        let matrix: Vec<u16> = (0..4).map(|i| memory.read_u16(DST + i * 2)).collect();
+        assert_eq!(matrix, [0, 0xFF00, 0x100, 0]);
+        // The screen center maps to the BG center: x = 64 + 80, y = 32 - 120
+        assert_eq!(memory.read_u32(DST + 8) as i32, (64 + 80) << 8);
+        assert_eq!(memory.read_u32(DST + 12) as i32, (32 - 120) << 8);
+    }
+
+    #[test]
+    fn test_register_ram_reset() {
+        let mut memory = TestMemory(vec![0xEE; 0x4_0004]);
+        let mut regs = [0; 16];
+        regs[0] = 0x01;
+        dispatch_swi(0x01, &mut regs, &mut memory, &mut None);
+        assert!(memory.0[..0x4_0000].iter().all(|&b| b == 0));
+        assert_eq!(memory.0[0x4_0000], 0xEE);
+    }
+
+    #[test]
+    fn test_uncomp_swis() {
+        let lz77 = [0x10, 4, 0, 0, 0x00, b'a', b'b', b'c', b'd'];
+        let huffman = [0x28, 4, 0, 0, 1, 0xC0, b'a', b'b', 0x00, 0x00, 0x00, 0x50];
+        let rl = [0x30, 4, 0, 0, 0x03, b'a', b'b', b'c', b'd'];
+        let diff8 = [0x81, 4, 0, 0, 1, 1, 1, 1];
+        let diff16 = [0x82, 4, 0, 0, 1, 0, 1, 0];
+        let cases: [(u8, &[u8], [u8; 4], u32); 8] = [
+            (0x11, &lz77, *b"abcd", 10),
+            (0x12, &lz77, *b"abcd", 10),
+            (0x13, &huffman, *b"abab", 30),
+            (0x14, &rl, *b"abcd", 8),
+            (0x15, &rl, *b"abcd", 8),
+            (0x16, &diff8, [1, 2, 3, 4], 6),
+            (0x17, &diff8, [1, 2, 3, 4], 6),
+            (0x18, &diff16, [1, 0, 2, 0], 3),
+        ];
+
+        // Each function reads the data from r0 and writes it to r1, taking time for each byte
+        let (src, dst) = (SRC + 0x100, DST + 0x20);
+        for &(comment, input, expected, cycles_per_byte) in cases.iter() {
+            let mut memory = TestMemory(vec![0xEE; 0x2000]);
+            for (i, &byte) in input.iter().enumerate() {
+                memory.write_u8(src + i as u32, byte);
+            }
+            let mut regs = [0; 16];
+            regs[0] = src;
+            regs[1] = dst;
+            let cycles = dispatch_swi(comment, &mut regs, &mut memory, &mut None);
+            assert_eq!(
+                cycles,
+                Some(50 + 4 * cycles_per_byte),
+                "SWI 0x{:02X}",
+                comment
+            );
+
+            let output = (dst - SRC) as usize;
+            assert_eq!(memory.0[output - 1], 0xEE, "SWI 0x{:02X}", comment);
+            assert_eq!(
+                memory.0[output..output + 4],
+                expected,
+                "SWI 0x{:02X}",
+                comment
+            );
+            assert_eq!(memory.0[output + 4], 0xEE, "SWI 0x{:02X}", comment);
+        }
+    }
+
+    #[test]
+    fn test_lz77_vram_halfwords() {
+        let input = [
+            0x10, 8, 0, 0,    // LZ77, 8 bytes
+            0x20, // Flags: 2 literals, 1 copy
+            b'x', b'y', // Literals
+            0x30, 0x00, // Copy 6 bytes from 1 byte back
+        ];
+        let mut memory = TestMemory(vec![0xEE; 0x2000]);
+        memory.0[..input.len()].copy_from_slice(&input);
+        let mut regs = [0; 16];
+        regs[0] = SRC;
+        regs[1] = DST;
+        dispatch_swi(0x11, &mut regs, &mut memory, &mut None);
+        let output = (DST - SRC) as usize;
+        assert_eq!(&memory.0[output..output + 8], b"xyyyyyyy");
+
+        // VRAM is written a halfword at a time, so the copy reads back each odd byte before it's
+        // written, and gets whatever memory held before instead
+        let mut memory = TestMemory(vec![0xEE; 0x2000]);
+        memory.0[..input.len()].copy_from_slice(&input);
+        dispatch_swi(0x12, &mut regs, &mut memory, &mut None);
+        assert_eq!(&memory.0[output..output + 8], b"xyy\xEE\xEE\xEE\xEE\xEE");
+    }
+}
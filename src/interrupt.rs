@@ -0,0 +1,195 @@
+//! Interrupt controller, along with the power-down modes that interrupts wake the system from.
+
+use std::cell::Cell;
+use std::rc::Rc;
+use system::Bus;
+
+/// Interrupt sources, in the order of their bits in IE/IF.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Interrupt {
+    VBlank,
+    HBlank,
+    VCount,
+    Timer0,
+    Timer1,
+    Timer2,
+    Timer3,
+    Serial,
+    Dma0,
+    Dma1,
+    Dma2,
+    Dma3,
+    Keypad,
+    GamePak,
+}
+
+impl Interrupt {
+    fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+const ALL_INTERRUPTS: u16 = 0x3FFF;
+
+/// Interrupts that can wake the system up from Stop mode. The other sources are all in units that
+/// are powered down.
+const STOP_WAKE_INTERRUPTS: u16 = (1 << Interrupt::Serial as u16)
+    | (1 << Interrupt::Keypad as u16)
+    | (1 << Interrupt::GamePak as u16);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PowerState {
+    Running,
+    /// The CPU is halted until an enabled interrupt is requested.
+    Halted,
+    /// The CPU, PPU and sound are stopped until an enabled keypad, serial or cart interrupt is
+    /// requested.
+    Stopped,
+}
+
+/// Collects interrupt requests from the other units, and drives the CPU's IRQ and halt lines on
+/// the bus. Its state is kept in cells so that units can request interrupts at any time.
+pub struct InterruptController {
+    bus: Rc<Bus>,
+    // IE
+    enabled: Cell<u16>,
+    // IF
+    requested: Cell<u16>,
+    // IME
+    master_enable: Cell<bool>,
+    // POSTFLG, set by the BIOS once it has booted
+    post_boot: Cell<bool>,
+    // Set by writing to HALTCNT
+    power_state: Cell<PowerState>,
+}
+
+impl InterruptController {
+    pub fn new(bus: Rc<Bus>) -> InterruptController {
+        InterruptController {
+            bus,
+            enabled: Cell::new(0),
+            requested: Cell::new(0),
+            master_enable: Cell::new(false),
+            post_boot: Cell::new(false),
+            power_state: Cell::new(PowerState::Running),
+        }
+    }
+
+    pub fn request(&self, interrupt: Interrupt) {
+        self.requested.set(self.requested.get() | interrupt.bit());
+        self.update();
+    }
+
+    pub fn power_state(&self) -> PowerState {
+        self.power_state.get()
+    }
+
+    pub fn read(&self, address: u32) -> u16 {
+        match address & 0x3FE {
+            0x200 => self.enabled.get(),
+            0x202 => self.requested.get(),
+            0x208 => self.master_enable.get() as u16,
+            // HALTCNT is write-only
+            0x300 => self.post_boot.get() as u16,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&self, address: u32, data: u16) {
+        match address & 0x3FE {
+            0x200 => self.enabled.set(data & ALL_INTERRUPTS),
+            // Writing 1 to a bit acknowledges the interrupt
+            0x202 => self.requested.set(self.requested.get() & !data),
+            0x208 => self.master_enable.set(bit!(data[0]) != 0),
+            0x300 => {
+                self.write8(address, data as u8);
+                self.write8(address | 1, (data >> 8) as u8);
+            }
+            _ => unreachable!(),
+        }
+        self.update();
+    }
+
+    /// Writes one of the byte-sized registers, POSTFLG and HALTCNT, without affecting the other.
+    pub fn write8(&self, address: u32, data: u8) {
+        match address & 0x3FF {
+            0x300 => self.post_boot.set(bit!(data[0]) != 0),
+            0x301 => self.power_state.set(if bit!(data[7]) != 0 {
+                PowerState::Stopped
+            } else {
+                PowerState::Halted
+            }),
+            _ => unreachable!(),
+        }
+        self.update();
+    }
+
+    fn update(&self) {
+        let pending = self.enabled.get() & self.requested.get();
+        let wakes_up = match self.power_state.get() {
+            PowerState::Running => false,
+            PowerState::Halted => pending != 0,
+            PowerState::Stopped => pending & STOP_WAKE_INTERRUPTS != 0,
+        };
+        if wakes_up {
+            self.power_state.set(PowerState::Running);
+        }
+
+        self.bus
+            .halted
+            .set(self.power_state.get() != PowerState::Running);
+        self.bus.irq.set(self.master_enable.get() && pending != 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_irq_line() {
+        let bus = Rc::new(Bus::default());
+        let interrupts = InterruptController::new(bus.clone());
+        interrupts.write(0x0400_0200, 1 << 0 | 1 << 12);
+        interrupts.request(Interrupt::HBlank);
+        interrupts.request(Interrupt::VBlank);
+        // Not raised until IME is set
+        assert!(!bus.irq.get());
+        interrupts.write(0x0400_0208, 1);
+        assert!(bus.irq.get());
+        assert_eq!(interrupts.read(0x0400_0202), 0b11);
+
+        // Acknowledging the enabled interrupt lowers the line
+        interrupts.write(0x0400_0202, 1);
+        assert!(!bus.irq.get());
+        assert_eq!(interrupts.read(0x0400_0202), 0b10);
+    }
+
+    #[test]
+    fn test_halt() {
+        let bus = Rc::new(Bus::default());
+        let interrupts = InterruptController::new(bus.clone());
+        interrupts.write(0x0400_0200, 1 << 0);
+
+        interrupts.write8(0x0400_0301, 0);
+        assert_eq!(interrupts.power_state(), PowerState::Halted);
+        assert!(bus.halted.get());
+        interrupts.request(Interrupt::HBlank);
+        assert!(bus.halted.get());
+        // Halt is exited by any enabled interrupt, even with IME clear
+        interrupts.request(Interrupt::VBlank);
+        assert_eq!(interrupts.power_state(), PowerState::Running);
+        assert!(!bus.halted.get());
+        assert!(!bus.irq.get());
+    }
+
+    #[test]
+    fn test_postflg() {
+        let bus = Rc::new(Bus::default());
+        let interrupts = InterruptController::new(bus.clone());
+        assert_eq!(interrupts.read(0x0400_0300), 0);
+        interrupts.write8(0x0400_0300, 0xFF);
+        assert_eq!(interrupts.read(0x0400_0300), 1);
+        assert_eq!(interrupts.power_state(), PowerState::Running);
+    }
+}
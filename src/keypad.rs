@@ -0,0 +1,107 @@
+use interrupt::Interrupt;
+use interrupt::InterruptController;
+use std::rc::Rc;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Right,
+    Left,
+    Up,
+    Down,
+    R,
+    L,
+}
+
+impl Button {
+    /// Bit of this button in KEYINPUT/KEYCNT.
+    fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+const ALL_KEYS_RELEASED: u16 = 0x03FF;
+
+const KEYCNT_IRQ_ENABLE: u16 = 1 << 14;
+/// Selects requiring all of the selected keys to be pressed, instead of any of them.
+const KEYCNT_IRQ_AND: u16 = 1 << 15;
+
+pub struct Keypad {
+    // KEYINPUT, active low
+    keyinput: u16,
+    // KEYCNT
+    keycnt: u16,
+    interrupts: Rc<InterruptController>,
+}
+
+impl Keypad {
+    pub fn new(interrupts: Rc<InterruptController>) -> Keypad {
+        Keypad {
+            keyinput: ALL_KEYS_RELEASED,
+            keycnt: 0,
+            interrupts,
+        }
+    }
+
+    pub fn set_pressed(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.keyinput &= !button.bit();
+        } else {
+            self.keyinput |= button.bit();
+        }
+        self.update_irq();
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.keyinput & button.bit() == 0
+    }
+
+    pub fn keyinput(&self) -> u16 {
+        self.keyinput
+    }
+
+    pub fn set_keyinput(&mut self, keyinput: u16) {
+        self.keyinput = keyinput & ALL_KEYS_RELEASED;
+        self.update_irq();
+    }
+
+    pub fn read(&self, address: u32) -> u16 {
+        match address & 0xFFF {
+            0x130 => self.keyinput,
+            0x132 => self.keycnt,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, address: u32, data: u16) {
+        match address & 0xFFF {
+            0x130 => {} // KEYINPUT is read-only
+            0x132 => {
+                self.keycnt = data & 0xC3FF;
+                self.update_irq();
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Requests the keypad interrupt if it's enabled and the keys selected in KEYCNT are pressed.
+    fn update_irq(&self) {
+        let selected = self.keycnt & ALL_KEYS_RELEASED;
+        if self.keycnt & KEYCNT_IRQ_ENABLE == 0 || selected == 0 {
+            return;
+        }
+
+        let pressed = !self.keyinput & selected;
+        let condition_met = if self.keycnt & KEYCNT_IRQ_AND != 0 {
+            pressed == selected
+        } else {
+            pressed != 0
+        };
+        if condition_met {
+            self.interrupts.request(Interrupt::Keypad);
+        }
+    }
+}
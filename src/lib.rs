@@ -0,0 +1,50 @@
+#![feature(arbitrary_self_types, generator_trait, generators, pin, test)]
+#![allow(unused)]
+
+extern crate byteorder;
+extern crate num;
+extern crate test;
+
+#[macro_use]
+pub mod util;
+#[macro_use]
+pub mod scheduler;
+
+pub mod apu;
+pub mod bios_compress;
+pub mod cart;
+pub mod cheats;
+pub mod cli;
+pub mod color;
+pub mod config;
+pub mod cpu;
+pub mod debugger;
+pub mod dma;
+pub mod frame_sink;
+pub mod gpio;
+pub mod hle;
+pub mod interrupt;
+pub mod keypad;
+#[cfg(feature = "libretro")]
+pub mod libretro;
+pub mod memory;
+pub mod memory_search;
+pub mod memory_stats;
+pub mod pacer;
+pub mod ppu;
+pub mod replay;
+pub mod rtc;
+pub mod save_file;
+pub mod scaling;
+pub mod sensors;
+pub mod sio;
+pub mod system;
+pub mod video_dirty;
+
+pub use frame_sink::FrameSink;
+pub use keypad::Button;
+pub use ppu::FrameBuffer;
+pub use system::FrameOutput;
+pub use system::System as GbaSystem;
+pub use system::SystemConfig;
+pub use system::TimingAccuracy;
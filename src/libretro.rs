@@ -0,0 +1,401 @@
+//! libretro core interface, allowing the emulator to be loaded by frontends such as RetroArch.
+//!
+//! Only the small subset of the C API needed by the core is declared here, instead of depending
+//! on a bindings crate.
+
+use apu;
+use color::bgr555_to_rgb565;
+use keypad::Button;
+use ppu::FrameBuffer;
+use std::ffi::CStr;
+use std::fs;
+use std::os::raw::c_char;
+use std::os::raw::c_uint;
+use std::os::raw::c_void;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use system::System;
+
+const RETRO_API_VERSION: c_uint = 1;
+
+const RETRO_ENVIRONMENT_GET_SYSTEM_DIRECTORY: c_uint = 9;
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+
+const RETRO_PIXEL_FORMAT_RGB565: c_uint = 2;
+
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+
+const RETRO_REGION_NTSC: c_uint = 0;
+
+const BIOS_FILENAME: &str = "gba_bios.bin";
+const BIOS_SIZE: usize = 16 * 1024;
+
+/// Refresh rate of the GBA LCD: 2^24 Hz / (228 lines * 1232 cycles).
+const FRAME_RATE: f64 = 59.727500569606;
+
+type EnvironmentFn = extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+type VideoRefreshFn =
+    extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type AudioSampleFn = extern "C" fn(left: i16, right: i16);
+type AudioSampleBatchFn = extern "C" fn(data: *const i16, frames: usize) -> usize;
+type InputPollFn = extern "C" fn();
+type InputStateFn = extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+#[repr(C)]
+pub struct RetroSystemInfo {
+    library_name: *const c_char,
+    library_version: *const c_char,
+    valid_extensions: *const c_char,
+    need_fullpath: bool,
+    block_extract: bool,
+}
+
+#[repr(C)]
+pub struct RetroGameGeometry {
+    base_width: c_uint,
+    base_height: c_uint,
+    max_width: c_uint,
+    max_height: c_uint,
+    aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct RetroSystemTiming {
+    fps: f64,
+    sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct RetroSystemAvInfo {
+    geometry: RetroGameGeometry,
+    timing: RetroSystemTiming,
+}
+
+#[repr(C)]
+pub struct RetroGameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+/// Mapping of libretro joypad button ids to GBA buttons.
+const JOYPAD_MAPPING: [(c_uint, Button); 10] = [
+    (0, Button::B),
+    (2, Button::Select),
+    (3, Button::Start),
+    (4, Button::Up),
+    (5, Button::Down),
+    (6, Button::Left),
+    (7, Button::Right),
+    (8, Button::A),
+    (10, Button::L),
+    (11, Button::R),
+];
+
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+struct Core {
+    system: System,
+    bios: Vec<u8>,
+    rom: Vec<u8>,
+    video_buffer: Vec<u16>,
+    audio_buffer: Vec<i16>,
+}
+
+// libretro is a single-threaded API, and all entry points are called from the same thread.
+static mut CALLBACKS: Callbacks = Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+};
+static mut CORE: Option<Core> = None;
+
+/// Converts a whole frame to RGB565, packed without any padding between lines.
+pub fn convert_frame(framebuffer: &FrameBuffer, output: &mut Vec<u16>) {
+    output.clear();
+    for line in framebuffer.iter() {
+        output.extend(line.iter().map(|&color| bgr555_to_rgb565(color)));
+    }
+}
+
+fn load_bios(environment: EnvironmentFn) -> Option<Vec<u8>> {
+    let mut system_dir: *const c_char = ptr::null();
+    let found = environment(
+        RETRO_ENVIRONMENT_GET_SYSTEM_DIRECTORY,
+        &mut system_dir as *mut _ as *mut c_void,
+    );
+    if !found || system_dir.is_null() {
+        println!("Frontend didn't provide a system directory");
+        return None;
+    }
+
+    let system_dir = unsafe { CStr::from_ptr(system_dir) }.to_string_lossy();
+    let bios_path = Path::new(&*system_dir).join(BIOS_FILENAME);
+    match fs::read(&bios_path) {
+        Ok(ref bios) if bios.len() != BIOS_SIZE => {
+            println!("{} has the wrong size", bios_path.display());
+            None
+        }
+        Ok(bios) => Some(bios),
+        Err(e) => {
+            println!("Failed to load {}: {}", bios_path.display(), e);
+            None
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    unsafe {
+        CORE = None;
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
+    *info = RetroSystemInfo {
+        library_name: b"Advance\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: b"gba|bin\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+    *info = RetroSystemAvInfo {
+        geometry: RetroGameGeometry {
+            base_width: 240,
+            base_height: 160,
+            max_width: 240,
+            max_height: 160,
+            aspect_ratio: 240.0 / 160.0,
+        },
+        timing: RetroSystemTiming {
+            fps: FRAME_RATE,
+            sample_rate: apu::SAMPLE_RATE as f64,
+        },
+    };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_environment(callback: EnvironmentFn) {
+    CALLBACKS.environment = Some(callback);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_video_refresh(callback: VideoRefreshFn) {
+    CALLBACKS.video_refresh = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: AudioSampleFn) {
+    // Samples are always sent in batches
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_audio_sample_batch(callback: AudioSampleBatchFn) {
+    CALLBACKS.audio_sample_batch = Some(callback);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_input_poll(callback: InputPollFn) {
+    CALLBACKS.input_poll = Some(callback);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_input_state(callback: InputStateFn) {
+    CALLBACKS.input_state = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_reset() {
+    if let Some(ref mut core) = CORE {
+        core.system = System::new(&core.bios, &core.rom);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_run() {
+    let core = match CORE {
+        Some(ref mut core) => core,
+        None => return,
+    };
+
+    if let (Some(input_poll), Some(input_state)) = (CALLBACKS.input_poll, CALLBACKS.input_state) {
+        input_poll();
+        let mut keypad = core.system.keypad.borrow_mut();
+        for &(id, button) in JOYPAD_MAPPING.iter() {
+            keypad.set_pressed(button, input_state(0, RETRO_DEVICE_JOYPAD, 0, id) != 0);
+        }
+    }
+
+    let frame = match core.system.run_frame() {
+        Some(frame) => frame,
+        None => return,
+    };
+
+    if let Some(video_refresh) = CALLBACKS.video_refresh {
+        convert_frame(frame.framebuffer(), &mut core.video_buffer);
+        video_refresh(
+            core.video_buffer.as_ptr() as *const c_void,
+            240,
+            160,
+            240 * 2,
+        );
+    }
+
+    core.audio_buffer.clear();
+    for sample in frame.samples() {
+        core.audio_buffer.push(sample.left);
+        core.audio_buffer.push(sample.right);
+    }
+    if let Some(audio_sample_batch) = CALLBACKS.audio_sample_batch {
+        audio_sample_batch(core.audio_buffer.as_ptr(), core.audio_buffer.len() / 2);
+    }
+}
+
+// TODO: Save states. The scheduler tasks can't be serialized yet, so the core reports that it
+// doesn't support them.
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize(_data: *mut c_void, _size: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unserialize(_data: *const c_void, _size: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
+    let environment = match CALLBACKS.environment {
+        Some(environment) => environment,
+        None => return false,
+    };
+
+    let mut pixel_format = RETRO_PIXEL_FORMAT_RGB565;
+    if !environment(
+        RETRO_ENVIRONMENT_SET_PIXEL_FORMAT,
+        &mut pixel_format as *mut _ as *mut c_void,
+    ) {
+        println!("Frontend doesn't support RGB565");
+        return false;
+    }
+
+    let bios = match load_bios(environment) {
+        Some(bios) => bios,
+        None => {
+            println!("Falling back to HLE BIOS functions");
+            Vec::new()
+        }
+    };
+    let rom = if game.is_null() || (*game).data.is_null() {
+        Vec::new()
+    } else {
+        slice::from_raw_parts((*game).data as *const u8, (*game).size).to_vec()
+    };
+
+    let system = System::new(&bios, &rom);
+    println!("Loaded {}", system.cart_header);
+    for problem in system.cart_header.problems.iter() {
+        println!("Bad cart header: {}", problem);
+    }
+
+    CORE = Some(Core {
+        system,
+        bios,
+        rom,
+        video_buffer: Vec::with_capacity(240 * 160),
+        audio_buffer: Vec::new(),
+    });
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _game_type: c_uint,
+    _info: *const RetroGameInfo,
+    _num_info: usize,
+) -> bool {
+    false
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unload_game() {
+    CORE = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(_id: c_uint) -> *mut c_void {
+    ptr::null_mut()
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(_id: c_uint) -> usize {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_frame() {
+        let mut framebuffer = [[0; 240]; 160];
+        framebuffer[0][0] = 0x001F;
+        framebuffer[159][239] = 0x7C00;
+
+        let mut output = Vec::new();
+        convert_frame(&framebuffer, &mut output);
+        assert_eq!(output.len(), 240 * 160);
+        assert_eq!(output[0], 0xF800);
+        assert_eq!(output[240 * 160 - 1], 0x001F);
+    }
+
+    #[test]
+    fn test_serialization_unsupported() {
+        assert_eq!(retro_serialize_size(), 0);
+        let mut buf = [0u8; 16];
+        assert!(!retro_serialize(buf.as_mut_ptr() as *mut c_void, buf.len()));
+        assert!(!retro_unserialize(buf.as_ptr() as *const c_void, buf.len()));
+    }
+}
@@ -1,41 +1,58 @@
-#![feature(
-    arbitrary_self_types,
-    generator_trait,
-    generators,
-    pin,
-    test
-)]
 #![allow(unused)]
 
+extern crate advance;
 extern crate byteorder;
-extern crate num;
 extern crate sdl2;
-extern crate test;
 
-#[macro_use]
-mod util;
-#[macro_use]
-mod scheduler;
-
-mod cpu;
-mod memory;
-mod ppu;
-mod system;
+mod audio;
 
+use advance::cli;
+use advance::cli::Command;
+use advance::color;
+use advance::color::ColorCorrection;
+use advance::color::ColorCorrectionLut;
+use advance::config::Config;
+use advance::debugger::Debugger;
+use advance::debugger::DebuggerExit;
+use advance::pacer;
+use advance::pacer::FramePacer;
+use advance::pacer::Speed;
+use advance::pacer::SystemClock;
+use advance::scaling::Scaling;
+use advance::Button;
+use advance::FrameSink;
+use advance::GbaSystem;
+use audio::AudioOutput;
 use byteorder::ByteOrder;
 use byteorder::NativeEndian;
-use byteorder::LE;
-use ppu::LcdControllerRegs;
 use sdl2::event::Event;
+use sdl2::event::WindowEvent;
+use sdl2::hint;
 use sdl2::keyboard::Scancode;
+use sdl2::keyboard::LCTRLMOD;
+use sdl2::keyboard::RCTRLMOD;
 use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
 use sdl2::render::Texture;
+use sdl2::render::TextureCreator;
+use sdl2::video::Window;
+use sdl2::video::WindowContext;
+use std::cell::RefCell;
+use std::env;
 use std::error::Error;
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::Read;
-use std::mem;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
 
-fn load_file(filename: &str, expected_size: usize) -> Result<Vec<u8>, Box<Error>> {
+fn load_file(filename: &Path, expected_size: usize) -> Result<Vec<u8>, Box<Error>> {
     let mut file = File::open(filename)?;
     let mut buf = Vec::new();
     let read_size = file.read_to_end(&mut buf)?;
@@ -47,108 +64,558 @@ fn load_file(filename: &str, expected_size: usize) -> Result<Vec<u8>, Box<Error>
     }
 }
 
-fn copy_line(rgbx_pixels: &mut [u8], line: &[u16]) {
+/// Pixel formats that can be used for the LCD texture, in order of preference.
+const LCD_TEXTURE_FORMATS: [PixelFormatEnum; 3] = [
+    // GBA colors are already in BGR555, so there's no conversion needed
+    PixelFormatEnum::BGR555,
+    PixelFormatEnum::RGB888,
+    PixelFormatEnum::RGB565,
+];
+
+/// Creates the LCD texture using the first supported format. Not all renderers support BGR555.
+fn create_lcd_texture(
+    texture_creator: &TextureCreator<WindowContext>,
+) -> Result<Texture, Box<Error>> {
+    let mut last_error = None;
+    for &format in LCD_TEXTURE_FORMATS.iter() {
+        match texture_creator.create_texture_streaming(format, 240, 160) {
+            Ok(texture) => {
+                println!("Using {:?} for the LCD texture", format);
+                return Ok(texture);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap().into())
+}
+
+/// Creates the raw and color corrected LCD textures, filtered as needed for `scaling`.
+fn create_lcd_textures(
+    texture_creator: &TextureCreator<WindowContext>,
+    scaling: Scaling,
+) -> Result<(Texture, Texture), Box<Error>> {
+    // The filtering mode is taken from this hint when a texture is created
+    let scale_quality = if scaling.uses_linear_filtering() {
+        "linear"
+    } else {
+        "nearest"
+    };
+    hint::set("SDL_RENDER_SCALE_QUALITY", scale_quality);
+
+    let lcd_texture = create_lcd_texture(texture_creator)?;
+    let corrected_lcd_texture =
+        texture_creator.create_texture_streaming(PixelFormatEnum::RGB888, 240, 160)?;
+    Ok((lcd_texture, corrected_lcd_texture))
+}
+
+/// Byte order of the pixels in the LCD textures. SDL's packed formats, like BGR555, hold each pixel
+/// as an integer in the host's byte order rather than as a fixed sequence of bytes.
+type TextureByteOrder = NativeEndian;
+
+/// Converts a line of BGR555 pixels to `format`, writing each pixel in byte order `B`.
+fn copy_line<B: ByteOrder>(pixels: &mut [u8], line: &[u16], format: PixelFormatEnum) {
+    assert_eq!(line.len(), 240);
+    match format {
+        PixelFormatEnum::BGR555 => {
+            for i in 0..240 {
+                B::write_u16(&mut pixels[i * 2..], line[i]);
+            }
+        }
+        PixelFormatEnum::RGB888 => {
+            for i in 0..240 {
+                B::write_u32(&mut pixels[i * 4..], color::bgr555_to_rgb888(line[i]));
+            }
+        }
+        PixelFormatEnum::RGB565 => {
+            for i in 0..240 {
+                B::write_u16(&mut pixels[i * 2..], color::bgr555_to_rgb565(line[i]));
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn draw_screen(texture: &mut Texture, framebuffer: &[u16]) {
+    let format = texture.query().format;
+    texture
+        .with_lock(None, |pixels: &mut [u8], stride| {
+            for (screen_y, line) in framebuffer.chunks(240).enumerate() {
+                let pixels = &mut pixels[screen_y * stride..][..stride];
+                copy_line::<TextureByteOrder>(pixels, line, format);
+            }
+        })
+        .unwrap();
+}
+
+fn copy_line_corrected(xrgb_pixels: &mut [u8], line: &[u16], lut: &ColorCorrectionLut) {
     assert_eq!(line.len(), 240);
     for i in 0..240 {
-        // GBA colors are already in the BGR555 format the texture needs, so there's no conversion
-        // needed.
-        NativeEndian::write_u16(&mut rgbx_pixels[i * 2..], line[i]);
+        TextureByteOrder::write_u32(&mut xrgb_pixels[i * 4..], lut.convert(line[i]));
     }
 }
 
-fn draw_screen(texture: &mut Texture, regs: &LcdControllerRegs, vram: &[u8], pals: &[u16]) {
+fn draw_screen_corrected(texture: &mut Texture, framebuffer: &[u16], lut: &ColorCorrectionLut) {
     texture
         .with_lock(None, |pixels: &mut [u8], stride| {
-            for screen_y in 0..160 {
-                let line_buf = ppu::render_lcd_line(screen_y as u16, regs, vram, pals);
-                copy_line(&mut pixels[screen_y * stride..][..stride], &line_buf);
+            for (screen_y, line) in framebuffer.chunks(240).enumerate() {
+                copy_line_corrected(&mut pixels[screen_y * stride..][..stride], line, lut);
             }
         })
         .unwrap();
 }
 
-fn convert_to_u16_vec(src: &[u8]) -> Vec<u16> {
-    let sizeof = mem::size_of::<u16>();
-    assert_eq!(src.len() % sizeof, 0);
+/// Displays frames in the SDL window.
+struct SdlFrameSink {
+    canvas: Canvas<Window>,
+    texture_creator: &'static TextureCreator<WindowContext>,
+    lcd_texture: Texture<'static>,
+    // Color correction outputs 8-bit channels, so it needs a separate texture
+    corrected_lcd_texture: Texture<'static>,
+    color_lut: ColorCorrectionLut,
+    scaling: Scaling,
+    /// Number of frames dropped between each displayed frame.
+    frame_skip: usize,
+    skipped_frames: usize,
+}
+
+impl SdlFrameSink {
+    fn cycle_color_correction(&mut self) {
+        let mode = self.color_lut.mode().next();
+        println!("Color correction: {}", mode.name());
+        self.color_lut = ColorCorrectionLut::new(mode);
+    }
+
+    fn cycle_scaling(&mut self) {
+        let scaling = self.scaling.next();
+        match create_lcd_textures(self.texture_creator, scaling) {
+            Ok((lcd_texture, corrected_lcd_texture)) => {
+                self.lcd_texture = lcd_texture;
+                self.corrected_lcd_texture = corrected_lcd_texture;
+                self.scaling = scaling;
+            }
+            Err(e) => println!("Failed to switch to {} scaling: {}", scaling.name(), e),
+        }
+    }
 
-    let mut new_vec = vec![0u16; src.len() / sizeof];
-    LE::read_u16_into(src.as_ref(), new_vec.as_mut());
-    new_vec
+    /// Describes how the LCD is currently scaled, like "3x integer".
+    fn scale_description(&self) -> String {
+        match self.canvas.output_size() {
+            Ok((width, height)) => format!(
+                "{}x {}",
+                self.scaling.viewport(width, height).scale(),
+                self.scaling.name()
+            ),
+            Err(_) => self.scaling.name().to_string(),
+        }
+    }
 }
 
-const _BRIN_REGS: &[(u32, u16)] = &[
-    (0x0400_0000, 0x0100),
-    (0x0400_0008, 0x5E00),
-    (0x0400_0010, 0x00C0),
-    (0x0400_0012, 0x0040),
-];
+impl FrameSink for SdlFrameSink {
+    fn present(&mut self, framebuffer: &[u16]) {
+        if self.skipped_frames < self.frame_skip {
+            self.skipped_frames += 1;
+            return;
+        }
+        self.skipped_frames = 0;
 
-const _PRIO_REGS: &[(u32, u16)] = &[
-    (0x0400_0000, 0x1F40),
-    (0x0400_0004, 0x0009),
-    (0x0400_0008, 0x1C08),
-    (0x0400_000A, 0x0584),
-    (0x0400_000C, 0x0685),
-    (0x0400_000E, 0x0786),
-];
+        let texture = if self.color_lut.mode() != ColorCorrection::Raw {
+            draw_screen_corrected(
+                &mut self.corrected_lcd_texture,
+                framebuffer,
+                &self.color_lut,
+            );
+            &self.corrected_lcd_texture
+        } else {
+            draw_screen(&mut self.lcd_texture, framebuffer);
+            &self.lcd_texture
+        };
 
-const BM_MODES_REGS: &[(u32, u16)] = &[
-    (0x0400_0000, 0x0403),
-    (0x0400_0004, 0x0002),
-    (0x0400_000C, 0x0000),
-];
+        let (width, height) = self.canvas.output_size().unwrap_or((240, 160));
+        let viewport = self.scaling.viewport(width, height);
+        let dst = Rect::new(viewport.x, viewport.y, viewport.width, viewport.height);
+
+        self.canvas.clear();
+        if let Err(e) = self.canvas.copy(texture, None, dst) {
+            println!("Failed to draw frame: {}", e);
+        }
+        self.canvas.present();
+    }
+}
+
+/// Looks up the keys bound to the buttons in the settings. Keys SDL doesn't know are left unbound.
+fn key_bindings(settings: &Config) -> Vec<(Scancode, Button)> {
+    let mut bindings = Vec::new();
+    for &(button, ref name) in settings.keys.iter() {
+        match Scancode::from_name(name) {
+            Some(scancode) => bindings.push((scancode, button)),
+            None => println!(
+                "Unknown key {:?} for {:?}, leaving it unbound",
+                name, button
+            ),
+        }
+    }
+    bindings
+}
+
+fn button_for_scancode(bindings: &[(Scancode, Button)], scancode: Scancode) -> Option<Button> {
+    bindings
+        .iter()
+        .find(|&&(key, _)| key == scancode)
+        .map(|&(_, button)| button)
+}
+
+/// Speed used while the fast-forward key is held.
+const FAST_FORWARD_SPEED: Speed = Speed::Quadruple;
+/// Number of frames dropped between each displayed frame when running uncapped.
+const UNCAPPED_FRAME_SKIP: usize = 15;
+
+/// Above 1x, only display about as many frames as at 1x, since they couldn't be seen anyway.
+fn frame_skip_for_speed(speed: Speed) -> usize {
+    match speed.multiplier() {
+        Some(multiplier) if multiplier > 1.0 => multiplier as usize - 1,
+        Some(_) => 0,
+        None => UNCAPPED_FRAME_SKIP,
+    }
+}
+
+fn update_title(frame_sink: &mut SdlFrameSink, speed: Speed, paused: bool) {
+    let scale = frame_sink.scale_description();
+    let title = if paused {
+        format!("Advance ({}, {}, paused)", speed.name(), scale)
+    } else {
+        format!("Advance ({}, {})", speed.name(), scale)
+    };
+    if let Err(e) = frame_sink.canvas.window_mut().set_title(&title) {
+        println!("Failed to set the window title: {}", e);
+    }
+}
+
+fn exit_with_usage(message: &str) -> ! {
+    eprintln!("{}\n{}", message, cli::USAGE);
+    process::exit(2);
+}
+
+fn run_command(command: Command) -> Result<(), Box<Error>> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match command {
+        Command::Help => println!("{}", cli::USAGE),
+        Command::Run(ref options) => {
+            let settings = cli::override_settings(
+                &Config::load_default_file()?,
+                options.bios.as_ref().map(|path| path.as_path()),
+                &options.settings,
+            )?;
+            if options.headless_frames.is_some() {
+                cli::run_headless(options, &settings, &mut out)?
+            } else {
+                run_windowed(
+                    &settings,
+                    settings.bios.clone(),
+                    Some(options.rom.clone()),
+                    options.force,
+                    options.mem_stats,
+                    options.debug,
+                )?
+            }
+        }
+        Command::Disasm(ref options) => cli::disasm(options, &mut out)?,
+        Command::Header(ref options) => cli::header(options, &mut out)?,
+        Command::Dump(ref options) => {
+            let settings = cli::override_settings(
+                &Config::load_default_file()?,
+                options.bios.as_ref().map(|path| path.as_path()),
+                &options.settings,
+            )?;
+            cli::dump(options, &settings, &mut out)?
+        }
+    }
+    Ok(())
+}
 
 fn main() -> Result<(), Box<Error>> {
-    let sdl_context = sdl2::init()?;
-    let sdl_video = sdl_context.video()?;
+    let args: Vec<String> = env::args().skip(1).collect();
+    match cli::parse_command(&args) {
+        Ok(Some(command)) => return run_command(command),
+        Ok(None) => {}
+        Err(message) => exit_with_usage(&message),
+    }
+    if args.iter().any(|arg| arg == "--write-default-config") {
+        print!("{}", Config::default().to_toml());
+        return Ok(());
+    }
+    let force = args.iter().any(|arg| arg == "--force");
+    let mem_stats = args.iter().any(|arg| arg == "--mem-stats");
+    let debug = args.iter().any(|arg| arg == "--debug");
+    let mut settings = Config::load_default_file()?;
+    let paths = match settings.apply_args(&args) {
+        Ok(paths) => paths,
+        Err(message) => exit_with_usage(&message),
+    };
+    if paths.is_empty() {
+        exit_with_usage("missing the BIOS or ROM path");
+    }
+
+    // A single path is the ROM if there's a BIOS to go with it, or if it can do without one
+    let (bios_path, rom_path) = match paths.len() {
+        1 if settings.bios.is_some() => (settings.bios.clone(), Some(PathBuf::from(&paths[0]))),
+        1 if settings.hle_bios => (None, Some(PathBuf::from(&paths[0]))),
+        1 => (Some(PathBuf::from(&paths[0])), None),
+        _ => (
+            Some(PathBuf::from(&paths[0])),
+            Some(PathBuf::from(&paths[1])),
+        ),
+    };
+    run_windowed(&settings, bios_path, rom_path, force, mem_stats, debug)
+}
 
-    let window = sdl_video.window("Advance", 240, 160).build()?;
-    let mut canvas = window.into_canvas().build()?;
+/// Runs the ROM, or only the BIOS, in a window until it's closed. With `debug`, the debugger runs
+/// in the terminal first, and the window only opens once it's left.
+fn run_windowed(
+    settings: &Config,
+    bios_path: Option<PathBuf>,
+    rom_path: Option<PathBuf>,
+    force: bool,
+    mem_stats: bool,
+    debug: bool,
+) -> Result<(), Box<Error>> {
+    let bios = match bios_path {
+        Some(path) => load_file(&path, 16 * 1024)?,
+        None => Vec::new(),
+    };
+    let rom = match rom_path {
+        Some(ref path) => fs::read(path)?,
+        None => Vec::new(),
+    };
+    let mut system = GbaSystem::with_config(&bios, &rom, &settings.system_config());
+    if mem_stats {
+        system.memory.borrow_mut().set_stats_enabled(true);
+    }
+    if let Some(ref rom_path) = rom_path {
+        println!("Loaded {}", system.cart_header);
+        for problem in system.cart_header.problems.iter() {
+            println!("Bad cart header: {}", problem);
+        }
+        if !system.cart_header.is_valid() && !force {
+            return Err(format!(
+                "{} doesn't look like a GBA ROM, use --force to run it anyway",
+                rom_path.display()
+            )
+            .into());
+        }
 
-    let texture_creator = canvas.texture_creator();
-    let mut lcd_texture =
-        texture_creator.create_texture_streaming(PixelFormatEnum::BGR555, 240, 160)?;
+        system.attach_save_file(&settings.save_path(rom_path))?;
 
-    let mut lcd_regs = LcdControllerRegs::new();
-    for &(addr, value) in BM_MODES_REGS.iter() {
-        lcd_regs.write(addr, value as u32);
+        let cheats_path = rom_path.with_file_name("cheats.txt");
+        if cheats_path.exists() {
+            let loaded = system.cheats.load_file(&cheats_path)?;
+            println!("Loaded {} cheats from {}", loaded, cheats_path.display());
+        }
     }
 
-    let pal_mem = convert_to_u16_vec(load_file("bm_modes-pal.bin", 1024)?.as_ref());
-    let vram_mem = load_file("bm_modes-vram.bin", 96 * 1024)?;
+    if debug {
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        let exit = Debugger::new().run(&mut system, &mut stdin.lock(), &mut stdout.lock())?;
+        if exit == DebuggerExit::Quit {
+            return Ok(());
+        }
+    }
+
+    let sdl_context = sdl2::init()?;
+    let sdl_video = sdl_context.video()?;
+
+    let window = sdl_video
+        .window(
+            "Advance",
+            240 * settings.window_scale,
+            160 * settings.window_scale,
+        )
+        .resizable()
+        .build()?;
+    // Frames are paced by FramePacer instead of vsync, so that they can run at any speed
+    let canvas = window.into_canvas().build()?;
+
+    // Textures borrow their creator, which lives as long as the program anyway
+    let texture_creator = Box::leak(Box::new(canvas.texture_creator()));
+    let (lcd_texture, corrected_lcd_texture) =
+        create_lcd_textures(texture_creator, settings.scaling)?;
+    let frame_sink = Rc::new(RefCell::new(SdlFrameSink {
+        canvas,
+        texture_creator,
+        lcd_texture,
+        corrected_lcd_texture,
+        color_lut: ColorCorrectionLut::new(settings.color_correction),
+        scaling: settings.scaling,
+        frame_skip: 0,
+        skipped_frames: 0,
+    }));
+    system.set_frame_sink(Box::new(frame_sink.clone()));
+
+    let mut audio_output = if settings.audio {
+        Some(AudioOutput::new(&sdl_context.audio()?)?)
+    } else {
+        None
+    };
+    let key_bindings = key_bindings(settings);
+    let mut pacer = FramePacer::new(SystemClock::new());
+    let mut speed = Speed::Normal;
+    let mut fast_forward = false;
+    let mut frame_advance = false;
+    update_title(&mut frame_sink.borrow_mut(), speed, false);
 
     let mut event_loop = sdl_context.event_pump()?;
     'main_loop: loop {
+        let previous_state = (speed, fast_forward, system.is_paused());
+        let mut scale_changed = false;
         for event in event_loop.poll_iter() {
             match event {
                 Event::Quit { .. } => break 'main_loop,
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(..),
+                    ..
+                } => scale_changed = true,
 
                 Event::KeyDown {
                     scancode: Some(scancode),
+                    keymod,
                     ..
-                } => {
-                    if scancode == Scancode::Escape {
-                        break 'main_loop;
+                } => match scancode {
+                    Scancode::Escape => break 'main_loop,
+                    Scancode::R if keymod.intersects(LCTRLMOD | RCTRLMOD) => system.reset(),
+                    Scancode::Tab => fast_forward = true,
+                    Scancode::Minus => speed = speed.slower(),
+                    Scancode::Equals => speed = speed.faster(),
+                    Scancode::P => {
+                        let paused = system.is_paused();
+                        system.set_paused(!paused);
                     }
-                    println!("Pressed {}", scancode);
-                }
-                Event::KeyUp { .. } => {}
+                    Scancode::Period if system.is_paused() => frame_advance = true,
+                    Scancode::F2 => frame_sink.borrow_mut().cycle_color_correction(),
+                    Scancode::F3 => {
+                        frame_sink.borrow_mut().cycle_scaling();
+                        scale_changed = true;
+                    }
+                    // Solar/gyro sensor value, for carts that have one
+                    Scancode::LeftBracket | Scancode::RightBracket => {
+                        let steps = if scancode == Scancode::LeftBracket {
+                            -1
+                        } else {
+                            1
+                        };
+                        if let Some(gpio) = system.memory.borrow_mut().cart_gpio_mut() {
+                            gpio.adjust_sensors(steps);
+                        }
+                    }
+                    // Layer toggles, for debugging
+                    Scancode::F5 | Scancode::F6 | Scancode::F7 | Scancode::F8 => {
+                        let bg = scancode as usize - Scancode::F5 as usize;
+                        let overrides = &mut system.ppu.borrow_mut().layer_overrides;
+                        overrides.force_disable_bg[bg] = !overrides.force_disable_bg[bg];
+                    }
+                    Scancode::F9 => {
+                        let overrides = &mut system.ppu.borrow_mut().layer_overrides;
+                        overrides.force_disable_obj = !overrides.force_disable_obj;
+                    }
+                    _ => {
+                        if let Some(button) = button_for_scancode(&key_bindings, scancode) {
+                            system.keypad.borrow_mut().set_pressed(button, true);
+                        }
+                    }
+                },
+                Event::KeyUp {
+                    scancode: Some(scancode),
+                    ..
+                } => match scancode {
+                    Scancode::Tab => fast_forward = false,
+                    _ => {
+                        if let Some(button) = button_for_scancode(&key_bindings, scancode) {
+                            system.keypad.borrow_mut().set_pressed(button, false);
+                        }
+                    }
+                },
 
                 _ => (),
             }
         }
 
-        draw_screen(
-            &mut lcd_texture,
-            &lcd_regs,
-            vram_mem.as_ref(),
-            pal_mem.as_ref(),
-        );
+        let current_speed = if fast_forward {
+            FAST_FORWARD_SPEED
+        } else {
+            speed
+        };
+        let paused = system.is_paused();
+        if (speed, fast_forward, paused) != previous_state {
+            pacer.set_speed(current_speed);
+            let mut frame_sink = frame_sink.borrow_mut();
+            frame_sink.frame_skip = frame_skip_for_speed(current_speed);
+            update_title(&mut frame_sink, current_speed, paused);
+        } else if scale_changed {
+            update_title(&mut frame_sink.borrow_mut(), current_speed, paused);
+        }
 
-        canvas.clear();
-        canvas.copy(&lcd_texture, None, None)?;
-        canvas.present();
+        if paused {
+            // Frame advance always displays the frame, even if frames would be skipped otherwise
+            if frame_advance {
+                {
+                    let mut frame_sink = frame_sink.borrow_mut();
+                    frame_sink.skipped_frames = frame_sink.frame_skip;
+                }
+                system.step_frame();
+                frame_advance = false;
+            }
+            thread::sleep(Duration::from_nanos(pacer::FRAME_DURATION_NANOS));
+            continue;
+        }
+
+        if let Some(frame) = system.run_frame() {
+            // The samples would need to be time-stretched to play at other speeds, so they're muted
+            if let Some(ref mut audio_output) = audio_output {
+                let muted = current_speed != Speed::Normal;
+                audio_output.push_samples(frame.samples(), muted);
+            }
+        }
+        pacer.wait_for_next_frame();
     }
 
+    system.flush_save_file()?;
+    if let Some(stats) = system.memory.borrow().access_stats() {
+        print!("{}", stats);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::BE;
+    use byteorder::LE;
+
+    #[test]
+    fn test_copy_line_byte_order() {
+        // Pure red
+        let line = [0x001F; 240];
+        let mut pixels = [0; 240 * 4];
+
+        copy_line::<LE>(&mut pixels, &line, PixelFormatEnum::BGR555);
+        assert_eq!(pixels[..4], [0x1F, 0x00, 0x1F, 0x00]);
+        copy_line::<BE>(&mut pixels, &line, PixelFormatEnum::BGR555);
+        assert_eq!(pixels[..4], [0x00, 0x1F, 0x00, 0x1F]);
+
+        copy_line::<LE>(&mut pixels, &line, PixelFormatEnum::RGB565);
+        assert_eq!(pixels[..4], [0x00, 0xF8, 0x00, 0xF8]);
+        copy_line::<BE>(&mut pixels, &line, PixelFormatEnum::RGB565);
+        assert_eq!(pixels[..4], [0xF8, 0x00, 0xF8, 0x00]);
+
+        copy_line::<LE>(&mut pixels, &line, PixelFormatEnum::RGB888);
+        assert_eq!(
+            pixels[..8],
+            [0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0x00]
+        );
+        copy_line::<BE>(&mut pixels, &line, PixelFormatEnum::RGB888);
+        assert_eq!(
+            pixels[..8],
+            [0x00, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x00]
+        );
+    }
+}
@@ -1,15 +1,33 @@
+use apu::Apu;
 use byteorder::ByteOrder;
 use byteorder::LE;
+use cart::CartHeader;
+use dma::DmaController;
+use gpio::CartGpio;
+use hle::HleMemory;
+use interrupt::InterruptController;
+use keypad::Keypad;
+use memory_stats::MemoryStats;
+use ppu::LcdControllerRegs;
+use scheduler::Clock;
 use scheduler::GeneratorTask;
 use scheduler::Task;
+use sio::Sio;
 use std::cell::Cell;
+use std::cell::RefCell;
 use std::rc::Rc;
 use system::AccessWidth;
 use system::Bus;
 use system::OperationType;
+use system::TimingAccuracy;
+use video_dirty::DirtyBits;
+use video_dirty::OAM_ENTRIES;
+use video_dirty::OAM_ENTRY_SIZE;
+use video_dirty::VRAM_BLOCKS;
+use video_dirty::VRAM_BLOCK_SIZE;
 
 /// Loose bits of memory not stored in other units
-struct Memory {
+pub struct Memory {
     bios: Box<[u8; 16 * 1024]>,
     bios_unlocked: bool,
     last_bios_read: u32,
@@ -20,9 +38,33 @@ struct Memory {
     palettes: Cell<[u16; 512]>,
     vram: Box<Cell<[u8; 96 * 1024]>>,
     oam: Cell<[u16; 128 * 4]>,
+    /// Parts of video memory written since they were last taken. Everything starts dirty.
+    vram_dirty: DirtyBits,
+    palettes_dirty: bool,
+    oam_dirty: DirtyBits,
 
     cart_rom: Box<[u8]>,
-    cart_sram: Box<Cell<[u8]>>,
+    cart_sram: Box<[u8]>,
+    save_type: SaveType,
+    /// Set when the CPU writes to SRAM, so that it can be saved.
+    cart_sram_written: bool,
+    /// Only present on carts with extra hardware connected to it.
+    cart_gpio: Option<CartGpio>,
+
+    io: IoUnits,
+    timing_accuracy: TimingAccuracy,
+    /// Access counts, while enabled.
+    stats: Option<Box<MemoryStats>>,
+}
+
+/// Units which have registers mapped in the I/O region.
+pub struct IoUnits {
+    pub lcd_regs: Rc<RefCell<LcdControllerRegs>>,
+    pub apu: Rc<RefCell<Apu>>,
+    pub dma: Rc<RefCell<DmaController>>,
+    pub keypad: Rc<RefCell<Keypad>>,
+    pub sio: Rc<RefCell<Sio>>,
+    pub interrupts: Rc<InterruptController>,
 }
 
 #[inline(always)]
@@ -110,12 +152,648 @@ fn do_write16(memory: &mut [u8], offset: usize, data: u16, width: AccessWidth) {
     }
 }
 
+/// Unmapped regions don't drive the bus, so reads return whatever was last on it. For the CPU, that
+/// is normally the most recently prefetched instruction, which is what ends up being executed if
+/// the PC wanders into unmapped memory.
+fn do_open_bus_rw(_data: &Cell<u32>, _op: OperationType) {
+    // Reads leave the previous value on the bus, writes are dropped.
+}
+
+/// Palette RAM, VRAM and OAM are on a 16-bit bus. Byte writes to them write the byte to both halves
+/// of the addressed halfword instead.
+fn do_video_rw16(
+    data: &Cell<u32>,
+    memory: &mut [u8],
+    offset: u32,
+    op: OperationType,
+    width: AccessWidth,
+) {
+    match op {
+        OperationType::Read { .. } => {
+            data.set(LE::read_u32(&memory[(offset & !0b11) as usize..]));
+        }
+        OperationType::Write => match width {
+            AccessWidth::Bit8 => {
+                let byte = data.get() as u8 as u16;
+                LE::write_u16(&mut memory[(offset & !0b1) as usize..], byte << 8 | byte);
+            }
+            AccessWidth::Bit16 => {
+                LE::write_u16(&mut memory[(offset & !0b1) as usize..], data.get() as u16);
+            }
+            AccessWidth::Bit32 => {
+                LE::write_u32(&mut memory[(offset & !0b11) as usize..], data.get());
+            }
+        },
+    }
+}
+
+/// Same as `do_video_rw16`, but for memories stored as halfwords. OAM ignores byte writes.
+fn do_video_halfword_rw16(
+    data: &Cell<u32>,
+    memory: &mut [u16],
+    offset: u32,
+    op: OperationType,
+    width: AccessWidth,
+    ignore_byte_writes: bool,
+) {
+    let index = (offset >> 1) as usize;
+    match op {
+        OperationType::Read { .. } => {
+            data.set(concat16(memory[index | 1], memory[index & !1]));
+        }
+        OperationType::Write => match width {
+            AccessWidth::Bit8 => {
+                if !ignore_byte_writes {
+                    let byte = data.get() as u8 as u16;
+                    memory[index] = byte << 8 | byte;
+                }
+            }
+            AccessWidth::Bit16 => {
+                memory[index] = data.get() as u16;
+            }
+            AccessWidth::Bit32 => {
+                memory[index & !1] = data.get() as u16;
+                memory[index | 1] = (data.get() >> 16) as u16;
+            }
+        },
+    }
+}
+
+/// VRAM is mirrored every 128 KB, with the last 32 KB mirroring the previous 32 KB.
+fn vram_offset(address: u32) -> u32 {
+    let offset = address & 0x1FFFF;
+    if offset >= 0x18000 {
+        offset - 0x8000
+    } else {
+        offset
+    }
+}
+
+/// Regions of the address map, selected by bits 24-31 of the address.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Region {
+    Bios,
+    Ewram,
+    Iwram,
+    Io,
+    Palettes,
+    Vram,
+    Oam,
+    CartRom,
+    CartSram,
+    Unmapped,
+}
+
+/// Decodes `address` into the region it belongs to and the offset into that region, taking
+/// mirroring into account. I/O registers are dispatched on the full address instead.
+fn decode_address(address: u32) -> (Region, u32) {
+    match bit!(address[24:31]) {
+        0x0 => (Region::Bios, address & 0x3FFF),
+        0x2 => (Region::Ewram, address & 0x3FFFF),
+        0x3 => (Region::Iwram, address & 0x7FFF),
+        0x4 => (Region::Io, address),
+        0x5 => (Region::Palettes, address & 0x3FF),
+        0x6 => (Region::Vram, vram_offset(address)),
+        0x7 => (Region::Oam, address & 0x3FF),
+        0x8..=0xD => (Region::CartRom, address & 0x1FF_FFFF),
+        0xE => (Region::CartSram, address & 0xFFFF),
+        // TODO: 0xF Unused, or Cart SRAM?
+        _ => (Region::Unmapped, address),
+    }
+}
+
+/// Reads a halfword of cart ROM, or of the GPIO registers mapped over it if they're readable. Past
+/// the end of the ROM, the cart returns the low bits of the halfword address, since the address
+/// and data share the same pins.
+fn read_cart_rom16(rom: &[u8], gpio: Option<&CartGpio>, offset: u32) -> u16 {
+    let offset = offset & !0b1;
+    if let Some(gpio) = gpio {
+        if CartGpio::is_register(offset) {
+            if let Some(value) = gpio.read(offset) {
+                return value;
+            }
+        }
+    }
+    match rom.get(offset as usize..offset as usize + 2) {
+        Some(halfword) => LE::read_u16(halfword),
+        None => (offset >> 1) as u16,
+    }
+}
+
+/// Writes a halfword to cart ROM, which only has an effect on the GPIO registers.
+fn write_cart_rom16(gpio: Option<&mut CartGpio>, offset: u32, data: u16) {
+    if let Some(gpio) = gpio {
+        if CartGpio::is_register(offset) {
+            gpio.write(offset, data);
+        }
+    }
+}
+
+/// Cart ROM is on a 16-bit bus. Writes are ignored, except by the GPIO registers.
+fn do_cart_rom_rw(
+    data: &Cell<u32>,
+    rom: &[u8],
+    gpio: Option<&mut CartGpio>,
+    offset: u32,
+    op: OperationType,
+    width: AccessWidth,
+) {
+    let word_offset = offset & !0b11;
+    match op {
+        OperationType::Read { .. } => {
+            let gpio = gpio.map(|gpio| &*gpio);
+            data.set(concat16(
+                read_cart_rom16(rom, gpio, word_offset | 0b10),
+                read_cart_rom16(rom, gpio, word_offset),
+            ));
+        }
+        OperationType::Write => match width {
+            AccessWidth::Bit8 | AccessWidth::Bit16 => {
+                write_cart_rom16(gpio, offset & !0b1, data.get() as u16)
+            }
+            AccessWidth::Bit32 => {
+                if let Some(gpio) = gpio {
+                    write_cart_rom16(Some(gpio), word_offset, data.get() as u16);
+                    write_cart_rom16(Some(gpio), word_offset | 0b10, (data.get() >> 16) as u16);
+                }
+            }
+        },
+    }
+}
+
+/// Cart SRAM is on an 8-bit bus. Reads return the addressed byte on all lanes, and writes store
+/// the byte in the addressed lane.
+fn do_cart_sram_rw(data: &Cell<u32>, sram: &mut [u8], offset: u32, op: OperationType) {
+    match op {
+        OperationType::Read { .. } => data.set(mirror_8to32(sram[offset as usize])),
+        OperationType::Write => {
+            sram[offset as usize] = (data.get() >> ((offset & 0b11) * 8)) as u8;
+        }
+    }
+}
+
+/// Extracts a value of `width` from the aligned word containing `address`.
+fn extract_lane(word: u32, address: u32, width: AccessWidth) -> u32 {
+    match width {
+        AccessWidth::Bit8 => (word >> ((address & 0b11) * 8)) & 0xFF,
+        AccessWidth::Bit16 => (word >> ((address & 0b10) * 8)) & 0xFFFF,
+        AccessWidth::Bit32 => word,
+    }
+}
+
+fn read_bytes(memory: &[u8], offset: u32, width: AccessWidth) -> u32 {
+    let offset = offset as usize;
+    match width {
+        AccessWidth::Bit8 => memory[offset] as u32,
+        AccessWidth::Bit16 => LE::read_u16(&memory[offset & !0b1..]) as u32,
+        AccessWidth::Bit32 => LE::read_u32(&memory[offset & !0b11..]),
+    }
+}
+
+fn write_bytes(memory: &mut [u8], offset: u32, width: AccessWidth, data: u32) {
+    let offset = offset as usize;
+    match width {
+        AccessWidth::Bit8 => memory[offset] = data as u8,
+        AccessWidth::Bit16 => LE::write_u16(&mut memory[offset & !0b1..], data as u16),
+        AccessWidth::Bit32 => LE::write_u32(&mut memory[offset & !0b11..], data),
+    }
+}
+
+fn read_halfwords(memory: &[u16], offset: u32, width: AccessWidth) -> u32 {
+    let index = (offset >> 1) as usize;
+    let word = concat16(memory[index | 1], memory[index & !1]);
+    extract_lane(word, offset, width)
+}
+
+fn write_halfwords(memory: &mut [u16], offset: u32, width: AccessWidth, data: u32) {
+    let index = (offset >> 1) as usize;
+    match width {
+        AccessWidth::Bit8 => {
+            let shift = (offset & 1) * 8;
+            memory[index] = memory[index] & !(0xFF << shift) | (data as u8 as u16) << shift;
+        }
+        AccessWidth::Bit16 => memory[index] = data as u16,
+        AccessWidth::Bit32 => {
+            memory[index & !1] = data as u16;
+            memory[index | 1] = (data >> 16) as u16;
+        }
+    }
+}
+
+fn io_read16(io: &IoUnits, address: u32) -> u16 {
+    match address & 0x3FE {
+        0x000..=0x05E => io.lcd_regs.borrow().read(address),
+        0x060..=0x0A6 => io.apu.borrow().read(address),
+        0x0B0..=0x0DE => io.dma.borrow().read(address),
+        0x120..=0x12A | 0x134 => io.sio.borrow().read(address),
+        0x130..=0x132 => io.keypad.borrow().read(address),
+        0x200 | 0x202 | 0x208 | 0x300 => io.interrupts.read(address),
+        _ => {
+            println!("Unsupported I/O read: [0x{:08X}]", address);
+            0
+        }
+    }
+}
+
+/// Bits of each I/O register that can be written. The rest are read-only or unused, and writes to
+/// them are ignored. Registers not listed here are fully writable, or handled by their unit.
+fn io_write_mask(address: u32) -> u16 {
+    match address & 0x3FE {
+        0x000 => 0xFFF7,         // DISPCNT, the CGB mode bit can only be set by the BIOS
+        0x004 => 0xFF38,         // DISPSTAT, the status flags are read-only
+        0x006 => 0x0000,         // VCOUNT
+        0x008 | 0x00A => 0xDFFF, // BG0CNT/BG1CNT, no overflow mode
+        0x010..=0x01E => 0x01FF, // BGxHOFS/BGxVOFS
+        0x048 | 0x04A => 0x3F3F, // WININ/WINOUT
+        0x050 => 0x3FFF,         // BLDCNT
+        0x052 => 0x1F1F,         // BLDALPHA
+        0x054 => 0x001F,         // BLDY
+        0x060 => 0x007F,         // SOUND1CNT_L
+        0x064 | 0x06C | 0x074 => 0xC7FF, // SOUND1CNT_X/SOUND2CNT_H/SOUND3CNT_X
+        0x070 => 0x00E0,         // SOUND3CNT_L
+        0x072 => 0xE0FF,         // SOUND3CNT_H
+        0x078 => 0xFF3F,         // SOUND4CNT_L
+        0x07C => 0xC0FF,         // SOUND4CNT_H
+        0x080 => 0xFF77,         // SOUNDCNT_L
+        0x082 => 0xFF0F,         // SOUNDCNT_H
+        0x084 => 0x0080,         // SOUNDCNT_X, the channel flags are read-only
+        0x088 => 0xC3FE,         // SOUNDBIAS
+        0x130 => 0x0000,         // KEYINPUT
+        0x132 => 0xC3FF,         // KEYCNT
+        0x200 | 0x202 => 0x3FFF, // IE/IF
+        0x208 => 0x0001,         // IME
+        _ => 0xFFFF,
+    }
+}
+
+fn io_write16(io: &IoUnits, address: u32, data: u16) {
+    let data = data & io_write_mask(address);
+    match address & 0x3FE {
+        0x000..=0x05E => io
+            .lcd_regs
+            .borrow_mut()
+            .write(address, AccessWidth::Bit16, data as u32),
+        0x060..=0x0A6 => io.apu.borrow_mut().write(address, data),
+        0x0B0..=0x0DE => io.dma.borrow_mut().write(address, data),
+        0x120..=0x12A | 0x134 => io.sio.borrow_mut().write(address, data),
+        0x130..=0x132 => io.keypad.borrow_mut().write(address, data),
+        0x200 | 0x202 | 0x208 | 0x300 => io.interrupts.write(address, data),
+        _ => println!(
+            "Unsupported I/O write: [0x{:08X}] <= 0x{:04X}",
+            address, data
+        ),
+    }
+}
+
+/// Handles byte writes to the registers that are only a byte wide, and to the LCD registers, which
+/// merge the byte into the rest of the register. Neither affects the other half of the halfword.
+/// Returns false for all other registers.
+fn io_write8(io: &IoUnits, address: u32, data: u8) -> bool {
+    match address & 0x3FF {
+        0x000..=0x05F => {
+            let mask = io_write_mask(address) >> ((address & 0b1) * 8);
+            let data = data & mask as u8;
+            io.lcd_regs
+                .borrow_mut()
+                .write(address, AccessWidth::Bit8, data as u32);
+            true
+        }
+        0x300 | 0x301 => {
+            io.interrupts.write8(address, data);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// I/O registers are mostly 16-bit wide. Reads always return the whole aligned word (the CPU picks
+/// out the lane it needs), while 32-bit writes are split into two 16-bit register writes.
+fn do_io_rw(data: &Cell<u32>, io: &IoUnits, address: u32, op: OperationType, width: AccessWidth) {
+    let word_address = address & !0b11;
+    match op {
+        OperationType::Read { .. } => {
+            let low = io_read16(io, word_address);
+            let high = io_read16(io, word_address | 0b10);
+            data.set(concat16(high, low));
+        }
+        OperationType::Write => match width {
+            AccessWidth::Bit8 => {
+                if !io_write8(io, address, data.get() as u8) {
+                    // TODO: Byte writes should only modify half of the register. For now the
+                    // mirrored byte is written to the whole register.
+                    io_write16(io, address & !0b1, data.get() as u16);
+                }
+            }
+            AccessWidth::Bit16 => io_write16(io, address & !0b1, data.get() as u16),
+            AccessWidth::Bit32 => {
+                io_write16(io, word_address, data.get() as u16);
+                io_write16(io, word_address | 0b10, (data.get() >> 16) as u16);
+            }
+        },
+    }
+}
+
+/// Kinds of save memory a cart can have. Only SRAM is emulated so far, and carts are assumed to
+/// have it unless told otherwise, as there's no detection.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SaveType {
+    /// No save memory. Reads from the SRAM region return 0xFF and writes are ignored.
+    None,
+    Sram,
+}
+
+/// Value of SRAM at power on, before a save is loaded. Its contents are really undefined then, but
+/// they're commonly all set, like erased Flash, which games checking for an existing save expect.
+pub const DEFAULT_SRAM_FILL: u8 = 0xFF;
+
 impl Memory {
-    fn run_task(&mut self, bus: Rc<Bus>) -> impl Task<Return = ()> {
+    /// Creates the memory with the given BIOS and cart ROM. The cart header is parsed and returned
+    /// too, but problems with it aren't treated as errors here.
+    pub fn new(bios: &[u8], cart_rom: &[u8], io: IoUnits) -> (Memory, CartHeader) {
+        let mut bios_buf = Box::new([0; 16 * 1024]);
+        bios_buf[..bios.len()].copy_from_slice(bios);
+
+        let cart_header = CartHeader::parse(cart_rom);
+        let memory = Memory {
+            bios: bios_buf,
+            bios_unlocked: false,
+            last_bios_read: 0,
+
+            ewram: Box::new(Cell::new([0; 256 * 1024])),
+            iwram: Box::new(Cell::new([0; 32 * 1024])),
+
+            palettes: Cell::new([0; 512]),
+            vram: Box::new(Cell::new([0; 96 * 1024])),
+            oam: Cell::new([0; 128 * 4]),
+            vram_dirty: DirtyBits::all(VRAM_BLOCKS),
+            palettes_dirty: true,
+            oam_dirty: DirtyBits::all(OAM_ENTRIES),
+
+            cart_rom: cart_rom.into(),
+            cart_sram: vec![DEFAULT_SRAM_FILL; 64 * 1024].into_boxed_slice(),
+            save_type: SaveType::Sram,
+            cart_sram_written: false,
+            cart_gpio: CartGpio::detect(cart_rom, &cart_header),
+
+            io,
+            timing_accuracy: TimingAccuracy::Fast,
+            stats: None,
+        };
+        (memory, cart_header)
+    }
+
+    pub fn timing_accuracy(&self) -> TimingAccuracy {
+        self.timing_accuracy
+    }
+
+    pub fn set_timing_accuracy(&mut self, accuracy: TimingAccuracy) {
+        self.timing_accuracy = accuracy;
+    }
+
+    /// Starts counting bus accesses from zero, or stops counting them.
+    pub fn set_stats_enabled(&mut self, enabled: bool) {
+        self.stats = if enabled {
+            Some(Box::new(MemoryStats::default()))
+        } else {
+            None
+        };
+    }
+
+    /// Returns a snapshot of the access counts, if they're enabled.
+    pub fn access_stats(&self) -> Option<MemoryStats> {
+        self.stats.as_ref().map(|stats| (**stats).clone())
+    }
+
+    /// Returns VRAM, palette RAM and OAM, for use by the renderer.
+    pub fn video_memory(&mut self) -> (&[u8], &[u16], &[u16]) {
+        (
+            self.vram.get_mut(),
+            self.palettes.get_mut(),
+            self.oam.get_mut(),
+        )
+    }
+
+    pub fn bios(&self) -> &[u8] {
+        &self.bios[..]
+    }
+
+    pub fn cart_rom(&self) -> &[u8] {
+        &self.cart_rom
+    }
+
+    /// Moves the save memory and GPIO hardware state over from the memory of a console that was
+    /// reset, since they live in the cart and aren't affected by the reset.
+    pub fn take_cart_state(&mut self, old: &mut Memory) {
+        ::std::mem::swap(&mut self.cart_sram, &mut old.cart_sram);
+        ::std::mem::swap(&mut self.cart_gpio, &mut old.cart_gpio);
+        self.save_type = old.save_type;
+        self.cart_sram_written = old.cart_sram_written;
+    }
+
+    /// Returns the cart GPIO port, if the cart has one.
+    pub fn cart_gpio_mut(&mut self) -> Option<&mut CartGpio> {
+        self.cart_gpio.as_mut()
+    }
+
+    pub fn save_type(&self) -> SaveType {
+        self.save_type
+    }
+
+    pub fn set_save_type(&mut self, save_type: SaveType) {
+        self.save_type = save_type;
+    }
+
+    pub fn cart_sram(&self) -> &[u8] {
+        &self.cart_sram
+    }
+
+    pub fn cart_sram_mut(&mut self) -> &mut [u8] {
+        &mut self.cart_sram
+    }
+
+    /// Sets all of SRAM to `value`, for a different power on state than `DEFAULT_SRAM_FILL`. Saves
+    /// must be loaded after this.
+    pub fn fill_cart_sram(&mut self, value: u8) {
+        for byte in self.cart_sram.iter_mut() {
+            *byte = value;
+        }
+    }
+
+    /// Returns whether SRAM was written since the last call.
+    pub fn take_cart_sram_written(&mut self) -> bool {
+        ::std::mem::replace(&mut self.cart_sram_written, false)
+    }
+
+    /// Returns the 1 KiB blocks of VRAM written since the last call.
+    pub fn take_vram_dirty(&mut self) -> DirtyBits {
+        ::std::mem::replace(&mut self.vram_dirty, DirtyBits::default())
+    }
+
+    /// Returns whether palette RAM was written since the last call.
+    pub fn take_palettes_dirty(&mut self) -> bool {
+        ::std::mem::replace(&mut self.palettes_dirty, false)
+    }
+
+    /// Returns the OAM entries written since the last call.
+    pub fn take_oam_dirty(&mut self) -> DirtyBits {
+        ::std::mem::replace(&mut self.oam_dirty, DirtyBits::default())
+    }
+
+    /// Marks the part of video memory at `offset` in `region` as written. Other regions aren't
+    /// tracked.
+    fn mark_written(&mut self, region: Region, offset: u32) {
+        match region {
+            Region::Vram => self.vram_dirty.mark((offset / VRAM_BLOCK_SIZE) as usize),
+            Region::Palettes => self.palettes_dirty = true,
+            Region::Oam => self.oam_dirty.mark((offset / OAM_ENTRY_SIZE) as usize),
+            _ => {}
+        }
+    }
+
+    /// Gives direct access to VRAM, which marks all of it as written.
+    pub fn vram_mut(&mut self) -> &mut [u8] {
+        self.vram_dirty = DirtyBits::all(VRAM_BLOCKS);
+        self.vram.get_mut()
+    }
+
+    /// Gives direct access to palette RAM, which marks it as written.
+    pub fn palettes_mut(&mut self) -> &mut [u16] {
+        self.palettes_dirty = true;
+        self.palettes.get_mut()
+    }
+
+    /// Returns the work RAM (EWRAM or IWRAM) backing `address`, and the offset of the address in
+    /// it, taking mirroring into account.
+    fn work_ram_mut(&mut self, address: u32) -> Option<(&mut [u8], u32)> {
+        match decode_address(address) {
+            (Region::Ewram, offset) => Some((self.ewram.get_mut(), offset)),
+            (Region::Iwram, offset) => Some((self.iwram.get_mut(), offset)),
+            _ => None,
+        }
+    }
+
+    /// Reads from EWRAM or IWRAM without going through the bus, so without taking any cycles or
+    /// affecting open bus. The address is force-aligned. Returns None for other regions.
+    pub fn read_work_ram(&mut self, address: u32, width: AccessWidth) -> Option<u32> {
+        self.work_ram_mut(address)
+            .map(|(ram, offset)| read_bytes(ram, offset, width))
+    }
+
+    /// Writes to EWRAM or IWRAM without going through the bus. Returns false for other regions.
+    pub fn write_work_ram(&mut self, address: u32, width: AccessWidth, data: u32) -> bool {
+        match self.work_ram_mut(address) {
+            Some((ram, offset)) => {
+                write_bytes(ram, offset, width, data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Reads memory without going through the bus, for debugging tools. This takes no cycles,
+    /// doesn't affect open bus and ignores BIOS locking. The address is force-aligned to `width`.
+    ///
+    /// Regions are decoded the same way as on the bus, including mirrors and the 8-bit SRAM bus
+    /// (returning the byte repeated over `width`). I/O registers are read through their units,
+    /// none of which currently have read side effects. Unmapped memory reads as 0, since open bus
+    /// depends on what the bus last transferred.
+    pub fn debug_read(&mut self, address: u32, width: AccessWidth) -> u32 {
+        match decode_address(address) {
+            (Region::Bios, offset) => read_bytes(&self.bios[..], offset, width),
+            (Region::Ewram, offset) => read_bytes(self.ewram.get_mut(), offset, width),
+            (Region::Iwram, offset) => read_bytes(self.iwram.get_mut(), offset, width),
+            (Region::Io, address) => {
+                let word_address = address & !0b11;
+                let word = concat16(
+                    io_read16(&self.io, word_address | 0b10),
+                    io_read16(&self.io, word_address),
+                );
+                extract_lane(word, address, width)
+            }
+            (Region::Palettes, offset) => read_halfwords(self.palettes.get_mut(), offset, width),
+            (Region::Vram, offset) => read_bytes(self.vram.get_mut(), offset, width),
+            (Region::Oam, offset) => read_halfwords(self.oam.get_mut(), offset, width),
+            (Region::CartRom, offset) => {
+                let word_offset = offset & !0b11;
+                let gpio = self.cart_gpio.as_ref();
+                let word = concat16(
+                    read_cart_rom16(&self.cart_rom, gpio, word_offset | 0b10),
+                    read_cart_rom16(&self.cart_rom, gpio, word_offset),
+                );
+                extract_lane(word, offset, width)
+            }
+            (Region::CartSram, offset) if self.save_type == SaveType::Sram => {
+                extract_lane(mirror_8to32(self.cart_sram[offset as usize]), 0, width)
+            }
+            (Region::CartSram, _) => extract_lane(0xFFFF_FFFF, 0, width),
+            (Region::Unmapped, _) => 0,
+        }
+    }
+
+    /// Writes memory without going through the bus, for debugging tools. This takes no cycles and
+    /// doesn't affect open bus. The address is force-aligned to `width`.
+    ///
+    /// Unlike on the bus, byte writes to palette RAM, VRAM and OAM only modify the addressed byte.
+    /// Only the low byte of `data` is written to SRAM. I/O registers are written through their
+    /// units, so writes have the same side effects as CPU writes, and the same goes for the cart
+    /// GPIO registers. Other writes to BIOS, ROM and unmapped memory are ignored.
+    pub fn debug_write(&mut self, address: u32, width: AccessWidth, data: u32) {
+        let (region, offset) = decode_address(address);
+        self.mark_written(region, offset);
+        match (region, offset) {
+            (Region::Ewram, offset) => write_bytes(self.ewram.get_mut(), offset, width, data),
+            (Region::Iwram, offset) => write_bytes(self.iwram.get_mut(), offset, width, data),
+            (Region::Io, address) => match width {
+                AccessWidth::Bit8 if io_write8(&self.io, address, data as u8) => {}
+                AccessWidth::Bit8 => {
+                    // Merge the byte into the rest of the register
+                    let halfword_address = address & !0b1;
+                    let shift = (address & 0b1) * 8;
+                    let old = io_read16(&self.io, halfword_address);
+                    let new = old & !(0xFF << shift) | (data as u8 as u16) << shift;
+                    io_write16(&self.io, halfword_address, new);
+                }
+                AccessWidth::Bit16 => io_write16(&self.io, address & !0b1, data as u16),
+                AccessWidth::Bit32 => {
+                    io_write16(&self.io, address & !0b11, data as u16);
+                    io_write16(&self.io, address & !0b11 | 0b10, (data >> 16) as u16);
+                }
+            },
+            (Region::Palettes, offset) => {
+                write_halfwords(self.palettes.get_mut(), offset, width, data)
+            }
+            (Region::Vram, offset) => write_bytes(self.vram.get_mut(), offset, width, data),
+            (Region::Oam, offset) => write_halfwords(self.oam.get_mut(), offset, width, data),
+            (Region::CartRom, offset) => match width {
+                AccessWidth::Bit8 | AccessWidth::Bit16 => {
+                    write_cart_rom16(self.cart_gpio.as_mut(), offset & !0b1, data as u16)
+                }
+                AccessWidth::Bit32 => {
+                    let offset = offset & !0b11;
+                    write_cart_rom16(self.cart_gpio.as_mut(), offset, data as u16);
+                    write_cart_rom16(self.cart_gpio.as_mut(), offset | 0b10, (data >> 16) as u16);
+                }
+            },
+            (Region::CartSram, offset) if self.save_type == SaveType::Sram => {
+                self.cart_sram[offset as usize] = data as u8;
+                self.cart_sram_written = true;
+            }
+            (Region::Bios, _) | (Region::CartSram, _) | (Region::Unmapped, _) => {}
+        }
+    }
+
+    /// Services requests made on the bus. The memory is only borrowed while a request is being
+    /// processed, never across a wait, so that other units can access it in between.
+    pub fn run_task(
+        memory: Rc<RefCell<Memory>>,
+        bus: Rc<Bus>,
+        clock: Rc<Clock>,
+    ) -> impl Task<'static, Return = ()> {
         GeneratorTask::new(move || {
             loop {
-                if let Some(request) = bus.request.get() {
+                if let Some(request) = bus.take_pending() {
                     let address = request.address;
+                    let start_time = clock.now();
 
                     // Handle BIOS locking
                     if let OperationType::Read {
@@ -123,36 +801,56 @@ impl Memory {
                     } = request.op
                     {
                         // TODO: Need to confirm range for this check
-                        self.bios_unlocked = address < 0x4000;
+                        memory.borrow_mut().bios_unlocked = address < 0x4000;
+                    }
+
+                    let (region, offset) = decode_address(address);
+                    if let Some(ref mut stats) = memory.borrow_mut().stats {
+                        stats.record(address, region, request.op, request.width);
                     }
 
-                    match bit!(address[24:31]) {
-                        // BIOS
-                        0x0 => {
-                            if self.bios_unlocked {
-                                let offset = request.address & 0x3FFC;
-                                self.last_bios_read = LE::read_u32(&self.bios[offset as usize..]);
+                    if memory.borrow().timing_accuracy == TimingAccuracy::Accurate {
+                        // Video memory accesses wait for the PPU's to finish
+                        let lcd_regs = memory.borrow().io.lcd_regs.clone();
+                        match region {
+                            Region::Palettes | Region::Vram if lcd_regs.borrow().is_drawing() => {
+                                wait_cycles!(1);
                             }
-                            bus.data.set(self.last_bios_read);
+                            Region::Oam if lcd_regs.borrow().is_using_oam() => {
+                                while lcd_regs.borrow().is_using_oam() {
+                                    wait_cycles!(1);
+                                }
+                            }
+                            _ => {}
                         }
-                        // TODO: 0x1 Unused, or BIOS?
-                        // EWRAM
-                        0x2 => {
-                            bus.busy.set(true);
+                    }
+
+                    // Devices work on a copy of the data lines, holding the data to write, or the
+                    // last value on the bus for reads that leave some of it alone
+                    let data = Cell::new(bus.data_lines());
+                    match (region, offset) {
+                        (Region::Bios, offset) => {
+                            let mut memory = memory.borrow_mut();
+                            if memory.bios_unlocked {
+                                memory.last_bios_read =
+                                    LE::read_u32(&memory.bios[(offset & !0b11) as usize..]);
+                            }
+                            data.set(memory.last_bios_read);
+                        }
+                        (Region::Ewram, offset) => {
                             wait_cycles!(2);
 
-                            let offset = request.address & 0x3FFFF;
-                            let mut low_latch = bus.data.get() as u16;
-                            let mut high_latch = (bus.data.get() >> 16) as u16;
+                            let mut low_latch = data.get() as u16;
+                            let mut high_latch = (data.get() >> 16) as u16;
 
                             do_ewram_rw16(
                                 &mut low_latch,
-                                self.ewram.get_mut(),
+                                memory.borrow_mut().ewram.get_mut(),
                                 offset,
                                 request.op,
                                 request.width,
                             );
-                            bus.data.set(mirror_16to32(low_latch));
+                            data.set(mirror_16to32(low_latch));
 
                             if request.width == AccessWidth::Bit32 {
                                 wait_cycles!(1 + 2);
@@ -162,41 +860,100 @@ impl Memory {
                                 // that affects the open-bus behavior.
                                 do_ewram_rw16(
                                     &mut high_latch,
-                                    self.ewram.get_mut(),
+                                    memory.borrow_mut().ewram.get_mut(),
                                     offset ^ 0b10,
                                     request.op,
                                     request.width,
                                 );
-                                bus.data.set(concat16(high_latch, low_latch));
+                                data.set(concat16(high_latch, low_latch));
                             }
-
-                            bus.busy.set(false);
                         }
-                        // IWRAM
-                        0x3 => {
-                            let offset = request.address & 0x7FFF;
+                        (Region::Iwram, offset) => {
                             do_iwram_rw32(
-                                &bus.data,
-                                self.iwram.get_mut(),
+                                &data,
+                                memory.borrow_mut().iwram.get_mut(),
                                 offset,
                                 request.op,
                                 request.width,
                             );
                         }
-                        // I/O registers
-                        0x4 => {}
-                        // Palette RAM
-                        0x5 => {}
-                        // VRAM
-                        0x6 => {}
-                        // OAM
-                        0x7 => {}
-                        // Cart ROM mirrors
-                        0x8..=0xD => {}
-                        // Cart SRAM
-                        0xE => {}
-                        // TODO: 0xF Unused, or Cart SRAM?
-                        _ => {}
+                        (Region::Io, address) => {
+                            let memory = memory.borrow();
+                            do_io_rw(&data, &memory.io, address, request.op, request.width);
+                        }
+                        (Region::Palettes, offset) => {
+                            do_video_halfword_rw16(
+                                &data,
+                                memory.borrow_mut().palettes.get_mut(),
+                                offset,
+                                request.op,
+                                request.width,
+                                false,
+                            );
+                        }
+                        (Region::Vram, offset) => {
+                            do_video_rw16(
+                                &data,
+                                memory.borrow_mut().vram.get_mut(),
+                                offset,
+                                request.op,
+                                request.width,
+                            );
+                        }
+                        (Region::Oam, offset) => {
+                            do_video_halfword_rw16(
+                                &data,
+                                memory.borrow_mut().oam.get_mut(),
+                                offset,
+                                request.op,
+                                request.width,
+                                true,
+                            );
+                        }
+                        // TODO: Wait states
+                        (Region::CartRom, offset) => {
+                            let memory = &mut *memory.borrow_mut();
+                            do_cart_rom_rw(
+                                &data,
+                                &memory.cart_rom,
+                                memory.cart_gpio.as_mut(),
+                                offset,
+                                request.op,
+                                request.width,
+                            );
+                        }
+                        (Region::CartSram, offset) => {
+                            let mut memory = memory.borrow_mut();
+                            match memory.save_type {
+                                SaveType::Sram => {
+                                    do_cart_sram_rw(
+                                        &data,
+                                        &mut memory.cart_sram,
+                                        offset,
+                                        request.op,
+                                    );
+                                    if request.op == OperationType::Write {
+                                        memory.cart_sram_written = true;
+                                    }
+                                }
+                                SaveType::None => {
+                                    if request.op != OperationType::Write {
+                                        data.set(0xFFFF_FFFF);
+                                    }
+                                }
+                            }
+                        }
+                        (Region::Unmapped, _) => do_open_bus_rw(&data, request.op),
+                    }
+                    if request.op == OperationType::Write {
+                        memory.borrow_mut().mark_written(region, offset);
+                    }
+                    if let Some(ref mut stats) = memory.borrow_mut().stats {
+                        stats.record_wait(region, clock.now() - start_time);
+                    }
+                    match request.op {
+                        OperationType::Read { .. } => bus.complete_read(data.get()),
+                        OperationType::Write => bus.complete_write(),
                     }
                 }
                 wait_cycles!(1);
@@ -204,3 +961,310 @@ impl Memory {
         })
     }
 }
+
+/// HLE functions access memory through the debug path, since their accesses don't happen on the
+/// bus.
+impl HleMemory for Memory {
+    fn read_u8(&mut self, address: u32) -> u8 {
+        self.debug_read(address, AccessWidth::Bit8) as u8
+    }
+
+    fn write_u8(&mut self, address: u32, data: u8) {
+        self.debug_write(address, AccessWidth::Bit8, data as u32);
+    }
+
+    fn read_u16(&mut self, address: u32) -> u16 {
+        self.debug_read(address, AccessWidth::Bit16) as u16
+    }
+
+    fn read_u32(&mut self, address: u32) -> u32 {
+        self.debug_read(address, AccessWidth::Bit32)
+    }
+
+    fn write_u16(&mut self, address: u32, data: u16) {
+        self.debug_write(address, AccessWidth::Bit16, data as u32);
+    }
+
+    fn write_u32(&mut self, address: u32, data: u32) {
+        self.debug_write(address, AccessWidth::Bit32, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scheduler::TaskScheduler;
+    use system::MemoryRequest;
+    use system::System;
+
+    fn assemble(program: &[u32]) -> Vec<u8> {
+        let mut buf = vec![0; program.len() * 4];
+        LE::write_u32_into(program, &mut buf);
+        buf
+    }
+
+    #[test]
+    fn test_debug_read_matches_bus() {
+        let bios = assemble(&[
+            0xE3A010AB, // mov r1, #0xAB
+            0xE3811CCD, // orr r1, r1, #0xCD00
+            0xE381170D, // orr r1, r1, #0x34_0000
+            0xE3811412, // orr r1, r1, #0x1200_0000
+            0xE3A00402, // mov r0, #0x0200_0000
+            0xE5801010, // str r1, [r0, #0x10]
+            0xE5902010, // ldr r2, [r0, #0x10]
+            0xE3A00403, // mov r0, #0x0300_0000
+            0xE5801020, // str r1, [r0, #0x20]
+            0xE5903020, // ldr r3, [r0, #0x20]
+            0xE3A00301, // mov r0, #0x0400_0000
+            0xE5801048, // str r1, [r0, #0x48]
+            0xE5904048, // ldr r4, [r0, #0x48]
+            0xE3A00405, // mov r0, #0x0500_0000
+            0xE5801030, // str r1, [r0, #0x30]
+            0xE5905030, // ldr r5, [r0, #0x30]
+            0xE3A00406, // mov r0, #0x0600_0000
+            0xE5801100, // str r1, [r0, #0x100]
+            0xE5906100, // ldr r6, [r0, #0x100]
+            0xE3A00407, // mov r0, #0x0700_0000
+            0xE5801040, // str r1, [r0, #0x40]
+            0xE5907040, // ldr r7, [r0, #0x40]
+            0xE3A0040E, // mov r0, #0x0E00_0000
+            0xE5801004, // str r1, [r0, #0x4]
+            0xE5908004, // ldr r8, [r0, #0x4]
+            0xE3A00302, // mov r0, #0x0800_0000
+            0xE5909000, // ldr r9, [r0]
+            0xE590A100, // ldr r10, [r0, #0x100]
+            0xE3A00000, // mov r0, #0
+            0xE590B004, // ldr r11, [r0, #4]
+            0xEAFFFFFE, // b .
+        ]);
+        let rom: Vec<u8> = (0..0x100).map(|i| i as u8).collect();
+        let mut system = System::new(&bios, &rom);
+        system.run_for(2000);
+
+        let regs = *system.cpu.borrow().regs();
+        let mut memory = system.memory.borrow_mut();
+        let cases = [
+            (2, 0x0200_0010),  // EWRAM
+            (3, 0x0300_0020),  // IWRAM
+            (4, 0x0400_0048),  // WININ/WINOUT
+            (5, 0x0500_0030),  // Palette RAM
+            (6, 0x0600_0100),  // VRAM
+            (7, 0x0700_0040),  // OAM
+            (8, 0x0E00_0004),  // SRAM
+            (9, 0x0800_0000),  // ROM
+            (10, 0x0800_0100), // Past the end of ROM
+            (11, 0x0000_0004), // BIOS
+        ];
+        for &(reg, address) in cases.iter() {
+            assert_eq!(
+                memory.debug_read(address, AccessWidth::Bit32),
+                regs[reg],
+                "address 0x{:08X}",
+                address
+            );
+        }
+
+        assert_eq!(regs[2], 0x1234_CDAB);
+        assert_eq!(regs[8], 0xABAB_ABAB);
+        assert_eq!(regs[10], 0x0081_0080);
+        // Mirrors decode to the same memory
+        assert_eq!(memory.debug_read(0x0204_0010, AccessWidth::Bit32), regs[2]);
+        assert_eq!(memory.debug_read(0x0602_0100, AccessWidth::Bit32), regs[6]);
+        assert_eq!(memory.debug_read(0x0A00_0000, AccessWidth::Bit32), regs[9]);
+    }
+
+    #[test]
+    fn test_debug_write_widths() {
+        let system = System::new(&[], &[]);
+        let mut memory = system.memory.borrow_mut();
+
+        for &address in [0x0200_0000, 0x0500_0000, 0x0600_0000, 0x0700_0000].iter() {
+            memory.debug_write(address, AccessWidth::Bit32, 0x1122_3344);
+            memory.debug_write(address + 1, AccessWidth::Bit8, 0xAA);
+            memory.debug_write(address + 2, AccessWidth::Bit16, 0xBBCC);
+            assert_eq!(memory.debug_read(address, AccessWidth::Bit32), 0xBBCC_AA44);
+            assert_eq!(memory.debug_read(address + 3, AccessWidth::Bit8), 0xBB);
+            assert_eq!(memory.debug_read(address + 1, AccessWidth::Bit16), 0xAA44);
+        }
+
+        // Byte writes to I/O only modify half of the register
+        memory.debug_write(0x0400_0048, AccessWidth::Bit16, 0x0102);
+        memory.debug_write(0x0400_0049, AccessWidth::Bit8, 0x03);
+        assert_eq!(memory.debug_read(0x0400_0048, AccessWidth::Bit16), 0x0302);
+
+        memory.debug_write(0x0E00_0001, AccessWidth::Bit32, 0x1234_5678);
+        assert_eq!(memory.debug_read(0x0E00_0001, AccessWidth::Bit16), 0x7878);
+
+        // ROM and BIOS are read-only
+        memory.debug_write(0x0800_0000, AccessWidth::Bit32, 0x1234_5678);
+        memory.debug_write(0x0000_0000, AccessWidth::Bit32, 0x1234_5678);
+        assert_eq!(
+            memory.debug_read(0x0800_0000, AccessWidth::Bit32),
+            0x0001_0000
+        );
+        assert_eq!(memory.debug_read(0x0000_0000, AccessWidth::Bit32), 0);
+    }
+
+    #[test]
+    fn test_io_write_masks() {
+        let system = System::new(&[], &[]);
+        let mut memory = system.memory.borrow_mut();
+
+        // The VBlank, HBlank and VCount flags can't be set by writes
+        memory.debug_write(0x0400_0004, AccessWidth::Bit16, 0xFFFF);
+        assert_eq!(memory.debug_read(0x0400_0004, AccessWidth::Bit16), 0xFF38);
+
+        memory.debug_write(0x0400_0048, AccessWidth::Bit32, 0xFFFF_FFFF);
+        assert_eq!(
+            memory.debug_read(0x0400_0048, AccessWidth::Bit32),
+            0x3F3F_3F3F
+        );
+        memory.debug_write(0x0400_0208, AccessWidth::Bit16, 0xFFFF);
+        assert_eq!(memory.debug_read(0x0400_0208, AccessWidth::Bit16), 0x0001);
+    }
+
+    #[test]
+    fn test_access_stats() {
+        let system = System::new(&[], &[]);
+        let memory = system.memory.clone();
+        memory.borrow_mut().set_stats_enabled(true);
+
+        // The memory serves a bus of its own, so that only these requests are made on it
+        let requests = [
+            (0x0000_0000, AccessWidth::Bit32, true, false),
+            (0x0200_0010, AccessWidth::Bit16, false, false),
+            (0x0200_1000, AccessWidth::Bit32, false, false),
+            (0x0300_0000, AccessWidth::Bit8, false, true),
+            (0x0300_0004, AccessWidth::Bit32, false, true),
+        ];
+        let bus = Rc::new(Bus::default());
+        let mut scheduler = TaskScheduler::new();
+        {
+            let bus = bus.clone();
+            scheduler.add_new_task(Box::pinned(GeneratorTask::new(move || {
+                for i in 0..requests.len() {
+                    let (address, width, is_instruction, write) = requests[i];
+                    bus.begin(MemoryRequest {
+                        address,
+                        width,
+                        op: if write {
+                            OperationType::Write
+                        } else {
+                            OperationType::Read { is_instruction }
+                        },
+                        seq: false,
+                    });
+                    wait_cycles!(1);
+                    while bus.is_busy() {
+                        wait_cycles!(1);
+                    }
+                }
+                // Stay idle instead of finishing, which would renumber the memory's task
+                loop {
+                    wait_cycles!(100);
+                }
+            })));
+        }
+        let clock = scheduler.clock();
+        scheduler.add_new_task(Box::pinned(Memory::run_task(memory.clone(), bus, clock)));
+        scheduler.run_for(100);
+
+        let stats = memory.borrow().access_stats().unwrap();
+        assert_eq!(stats.fetches(Region::Bios, AccessWidth::Bit32), 1);
+        assert_eq!(stats.reads(Region::Bios, AccessWidth::Bit32), 0);
+        assert_eq!(stats.reads(Region::Ewram, AccessWidth::Bit16), 1);
+        assert_eq!(stats.reads(Region::Ewram, AccessWidth::Bit32), 1);
+        assert_eq!(stats.writes(Region::Iwram, AccessWidth::Bit8), 1);
+        assert_eq!(stats.writes(Region::Iwram, AccessWidth::Bit32), 1);
+        assert_eq!(stats.total(Region::Iwram), 2);
+        // EWRAM has 2 wait states, for each halfword of a word
+        assert_eq!(stats.wait_cycles(Region::Bios), 0);
+        assert_eq!(stats.wait_cycles(Region::Ewram), 2 + (2 + 3));
+        assert_eq!(stats.wait_cycles(Region::Iwram), 0);
+        assert_eq!(
+            stats.pages().collect::<Vec<_>>(),
+            [
+                (0x0000_0000, 1),
+                (0x0200_0000, 1),
+                (0x0200_1000, 1),
+                (0x0300_0000, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_video_dirty() {
+        let bios = assemble(&[
+            0xE3A00406, // mov r0, #0x0600_0000
+            0xE2800B05, // add r0, r0, #0x1400
+            0xE3A01012, // mov r1, #0x12
+            0xE5801000, // str r1, [r0]
+            0xEAFFFFFE, // b .
+        ]);
+        let mut system = System::new(&bios, &[]);
+        {
+            // Everything starts dirty
+            let mut memory = system.memory.borrow_mut();
+            assert_eq!(memory.take_vram_dirty().count(), 96);
+            assert!(memory.take_palettes_dirty());
+            assert_eq!(memory.take_oam_dirty().count(), 128);
+        }
+
+        system.run_for(20);
+        let mut memory = system.memory.borrow_mut();
+        assert_eq!(memory.take_vram_dirty().iter().collect::<Vec<_>>(), [5]);
+        assert!(memory.take_vram_dirty().is_empty());
+        assert!(!memory.take_palettes_dirty());
+
+        // The last 32 KiB of VRAM mirror the previous 32 KiB
+        memory.debug_write(0x0601_FC00, AccessWidth::Bit16, 0x34);
+        assert_eq!(memory.take_vram_dirty().iter().collect::<Vec<_>>(), [95]);
+        memory.debug_write(0x0700_0016, AccessWidth::Bit16, 0x100);
+        memory.debug_write(0x0500_0000, AccessWidth::Bit8, 0x1F);
+        assert_eq!(memory.take_oam_dirty().iter().collect::<Vec<_>>(), [2]);
+        assert!(memory.take_palettes_dirty());
+    }
+
+    #[test]
+    fn test_sram_fill() {
+        let system = System::new(&[], &[]);
+        let mut memory = system.memory.borrow_mut();
+        assert_eq!(memory.debug_read(0x0E00_0000, AccessWidth::Bit8), 0xFF);
+        assert_eq!(memory.debug_read(0x0E00_FFFF, AccessWidth::Bit8), 0xFF);
+
+        memory.fill_cart_sram(0x00);
+        assert!(memory.cart_sram().iter().all(|&byte| byte == 0x00));
+        // Filling isn't a write by the game
+        assert!(!memory.take_cart_sram_written());
+    }
+
+    #[test]
+    fn test_gpio_read_enable() {
+        let mut rom = vec![0; 0x200];
+        rom[0x100..0x108].copy_from_slice(b"SIIRTC_V");
+        rom[0xC4..0xCA].copy_from_slice(&[0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+        let system = System::new(&[], &rom);
+        let mut memory = system.memory.borrow_mut();
+
+        // Registers are write-only by default, so the ROM shows through
+        memory.debug_write(0x0800_00C6, AccessWidth::Bit16, 0b0101);
+        assert_eq!(
+            memory.debug_read(0x0800_00C4, AccessWidth::Bit32),
+            0x4433_2211
+        );
+        assert_eq!(memory.debug_read(0x0800_00C8, AccessWidth::Bit16), 0x6655);
+
+        memory.debug_write(0x0800_00C8, AccessWidth::Bit16, 1);
+        assert_eq!(
+            memory.debug_read(0x0800_00C4, AccessWidth::Bit32),
+            0x0005_0000
+        );
+        assert_eq!(memory.debug_read(0x0800_00C8, AccessWidth::Bit16), 1);
+        // Other ROM data is unaffected
+        assert_eq!(memory.debug_read(0x0800_00C0, AccessWidth::Bit32), 0);
+
+        memory.debug_write(0x0800_00C8, AccessWidth::Bit16, 0);
+        assert_eq!(memory.debug_read(0x0800_00C8, AccessWidth::Bit16), 0x6655);
+    }
+}
@@ -0,0 +1,187 @@
+//! RAM search for finding the addresses of game variables, e.g. to make cheats.
+
+use memory::Memory;
+use system::AccessWidth;
+
+const EWRAM_RANGE: (u32, u32) = (0x0200_0000, 0x0204_0000);
+const IWRAM_RANGE: (u32, u32) = (0x0300_0000, 0x0300_8000);
+
+fn width_bytes(width: AccessWidth) -> u32 {
+    match width {
+        AccessWidth::Bit8 => 1,
+        AccessWidth::Bit16 => 2,
+        AccessWidth::Bit32 => 4,
+    }
+}
+
+/// Condition that candidates must satisfy to be kept by a search.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SearchFilter {
+    /// The current value is equal to the given one.
+    Equal(u32),
+    /// The value is greater than in the previous snapshot.
+    Increased,
+    /// The value is less than in the previous snapshot.
+    Decreased,
+    Changed,
+    Unchanged,
+}
+
+impl SearchFilter {
+    fn matches(self, previous: u32, current: u32) -> bool {
+        match self {
+            SearchFilter::Equal(value) => current == value,
+            SearchFilter::Increased => current > previous,
+            SearchFilter::Decreased => current < previous,
+            SearchFilter::Changed => current != previous,
+            SearchFilter::Unchanged => current == previous,
+        }
+    }
+}
+
+struct FrozenValue {
+    address: u32,
+    width: AccessWidth,
+    value: u32,
+}
+
+/// Narrows down a set of candidate addresses in EWRAM and IWRAM by repeatedly filtering them
+/// against their values in the previous snapshot. Memory is accessed directly, without going
+/// through the bus, so searching doesn't disturb the emulation.
+pub struct MemorySearch {
+    width: AccessWidth,
+    /// Candidate addresses, along with their values when the last search was done.
+    candidates: Vec<(u32, u32)>,
+    frozen: Vec<FrozenValue>,
+}
+
+impl MemorySearch {
+    /// Starts a new search for values of the given width, with every aligned address as a
+    /// candidate.
+    pub fn new(memory: &mut Memory, width: AccessWidth) -> MemorySearch {
+        let step = width_bytes(width) as usize;
+        let mut candidates = Vec::new();
+        for &(start, end) in [EWRAM_RANGE, IWRAM_RANGE].iter() {
+            for address in (start..end).step_by(step) {
+                let value = memory.read_work_ram(address, width).unwrap();
+                candidates.push((address, value));
+            }
+        }
+
+        MemorySearch {
+            width,
+            candidates,
+            frozen: Vec::new(),
+        }
+    }
+
+    pub fn width(&self) -> AccessWidth {
+        self.width
+    }
+
+    /// Removes candidates whose current value doesn't satisfy `filter`, and takes a new snapshot
+    /// of the remaining ones.
+    pub fn filter(&mut self, memory: &mut Memory, filter: SearchFilter) {
+        let width = self.width;
+        self.candidates.retain(|candidate| {
+            let current = memory.read_work_ram(candidate.0, width).unwrap();
+            filter.matches(candidate.1, current)
+        });
+        for candidate in self.candidates.iter_mut() {
+            candidate.1 = memory.read_work_ram(candidate.0, width).unwrap();
+        }
+    }
+
+    /// Remaining candidates, as pairs of address and value in the last snapshot.
+    pub fn candidates(&self) -> &[(u32, u32)] {
+        &self.candidates
+    }
+
+    /// Keeps the value at `address` fixed to `value`, replacing any previous freeze of it. The
+    /// value is written every time `apply_freezes` is called.
+    pub fn freeze(&mut self, address: u32, width: AccessWidth, value: u32) {
+        self.unfreeze(address);
+        self.frozen.push(FrozenValue {
+            address,
+            width,
+            value,
+        });
+    }
+
+    pub fn unfreeze(&mut self, address: u32) {
+        self.frozen.retain(|frozen| frozen.address != address);
+    }
+
+    /// Writes all frozen values to memory. Should be called once per frame.
+    pub fn apply_freezes(&self, memory: &mut Memory) {
+        for frozen in self.frozen.iter() {
+            memory.write_work_ram(frozen.address, frozen.width, frozen.value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use system::System;
+
+    #[test]
+    fn test_search_increased() {
+        let system = System::new(&[], &[]);
+        let mut memory = system.memory.borrow_mut();
+        for &address in [0x0200_0010, 0x0200_0020, 0x0300_0100].iter() {
+            memory.write_work_ram(address, AccessWidth::Bit16, 100);
+        }
+
+        let mut search = MemorySearch::new(&mut memory, AccessWidth::Bit16);
+        assert_eq!(search.candidates().len(), (256 + 32) * 1024 / 2);
+
+        search.filter(&mut memory, SearchFilter::Equal(100));
+        assert_eq!(
+            search.candidates(),
+            &[(0x0200_0010, 100), (0x0200_0020, 100), (0x0300_0100, 100)]
+        );
+
+        memory.write_work_ram(0x0200_0010, AccessWidth::Bit16, 101);
+        memory.write_work_ram(0x0200_0020, AccessWidth::Bit16, 99);
+        memory.write_work_ram(0x0300_0100, AccessWidth::Bit16, 150);
+        search.filter(&mut memory, SearchFilter::Increased);
+        assert_eq!(
+            search.candidates(),
+            &[(0x0200_0010, 101), (0x0300_0100, 150)]
+        );
+
+        memory.write_work_ram(0x0300_0100, AccessWidth::Bit16, 151);
+        search.filter(&mut memory, SearchFilter::Unchanged);
+        assert_eq!(search.candidates(), &[(0x0200_0010, 101)]);
+    }
+
+    #[test]
+    fn test_freeze() {
+        let system = System::new(&[], &[]);
+        let mut memory = system.memory.borrow_mut();
+
+        let mut search = MemorySearch::new(&mut memory, AccessWidth::Bit8);
+        search.freeze(0x0300_7F00, AccessWidth::Bit32, 0x1234_5678);
+        search.apply_freezes(&mut memory);
+        assert_eq!(
+            memory.read_work_ram(0x0300_7F00, AccessWidth::Bit32),
+            Some(0x1234_5678)
+        );
+
+        memory.write_work_ram(0x0300_7F00, AccessWidth::Bit32, 0);
+        search.apply_freezes(&mut memory);
+        assert_eq!(
+            memory.read_work_ram(0x0300_7F00, AccessWidth::Bit32),
+            Some(0x1234_5678)
+        );
+
+        search.unfreeze(0x0300_7F00);
+        memory.write_work_ram(0x0300_7F00, AccessWidth::Bit32, 0);
+        search.apply_freezes(&mut memory);
+        assert_eq!(
+            memory.read_work_ram(0x0300_7F00, AccessWidth::Bit32),
+            Some(0)
+        );
+    }
+}
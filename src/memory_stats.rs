@@ -0,0 +1,176 @@
+//! Counters of the accesses made to each memory region over the bus, for performance analysis.
+//! Counting is off by default, and costs a single check per access while off.
+
+use memory::Region;
+use std::collections::BTreeMap;
+use std::fmt;
+use system::AccessWidth;
+use system::OperationType;
+
+const NUM_REGIONS: usize = Region::Unmapped as usize + 1;
+
+const REGIONS: [Region; NUM_REGIONS] = [
+    Region::Bios,
+    Region::Ewram,
+    Region::Iwram,
+    Region::Io,
+    Region::Palettes,
+    Region::Vram,
+    Region::Oam,
+    Region::CartRom,
+    Region::CartSram,
+    Region::Unmapped,
+];
+
+const WIDTHS: [AccessWidth; 3] = [AccessWidth::Bit8, AccessWidth::Bit16, AccessWidth::Bit32];
+
+/// Pages listed by the summary, from the most accessed one.
+const SUMMARY_PAGES: usize = 10;
+
+fn width_index(width: AccessWidth) -> usize {
+    match width {
+        AccessWidth::Bit8 => 0,
+        AccessWidth::Bit16 => 1,
+        AccessWidth::Bit32 => 2,
+    }
+}
+
+fn kind_index(op: OperationType) -> usize {
+    match op {
+        OperationType::Read {
+            is_instruction: false,
+        } => 0,
+        OperationType::Write => 1,
+        OperationType::Read {
+            is_instruction: true,
+        } => 2,
+    }
+}
+
+/// Access counts per region, split by data reads, writes and instruction fetches and by width,
+/// along with the cycles spent waiting on each region, and the accesses to each 4 KiB page.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MemoryStats {
+    // [region][read/write/fetch][width]
+    counts: [[[u64; 3]; 3]; NUM_REGIONS],
+    wait_cycles: [u64; NUM_REGIONS],
+    /// Accesses to each page, by the address it starts at.
+    pages: BTreeMap<u32, u64>,
+}
+
+impl MemoryStats {
+    pub fn record(&mut self, address: u32, region: Region, op: OperationType, width: AccessWidth) {
+        self.counts[region as usize][kind_index(op)][width_index(width)] += 1;
+        *self.pages.entry(address & !0xFFF).or_insert(0) += 1;
+    }
+
+    /// Records that an access to `region` took `cycles` more than the cycle it was made in.
+    pub fn record_wait(&mut self, region: Region, cycles: u64) {
+        self.wait_cycles[region as usize] += cycles;
+    }
+
+    /// Data reads, not counting instruction fetches.
+    pub fn reads(&self, region: Region, width: AccessWidth) -> u64 {
+        self.counts[region as usize][0][width_index(width)]
+    }
+
+    pub fn writes(&self, region: Region, width: AccessWidth) -> u64 {
+        self.counts[region as usize][1][width_index(width)]
+    }
+
+    pub fn fetches(&self, region: Region, width: AccessWidth) -> u64 {
+        self.counts[region as usize][2][width_index(width)]
+    }
+
+    /// Accesses of any kind and width to `region`.
+    pub fn total(&self, region: Region) -> u64 {
+        self.counts[region as usize]
+            .iter()
+            .flat_map(|c| c.iter())
+            .sum()
+    }
+
+    /// Cycles accesses to `region` spent waiting, on wait states or on other units using it.
+    pub fn wait_cycles(&self, region: Region) -> u64 {
+        self.wait_cycles[region as usize]
+    }
+
+    /// Accesses to each 4 KiB page that was accessed, by the address it starts at, in order.
+    pub fn pages<'a>(&'a self) -> impl Iterator<Item = (u32, u64)> + 'a {
+        self.pages.iter().map(|(&page, &count)| (page, count))
+    }
+}
+
+/// Summarizes the counts in a table by region, followed by the most accessed pages.
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<10} {:>12} {:>12} {:>12} {:>12}",
+            "region", "reads", "writes", "fetches", "wait cycles"
+        )?;
+        for &region in REGIONS.iter() {
+            if self.total(region) == 0 {
+                continue;
+            }
+            let sum = |count: fn(&MemoryStats, Region, AccessWidth) -> u64| -> u64 {
+                WIDTHS.iter().map(|&width| count(self, region, width)).sum()
+            };
+            writeln!(
+                f,
+                "{:<10} {:>12} {:>12} {:>12} {:>12}",
+                format!("{:?}", region),
+                sum(MemoryStats::reads),
+                sum(MemoryStats::writes),
+                sum(MemoryStats::fetches),
+                self.wait_cycles(region)
+            )?;
+        }
+
+        let mut pages: Vec<(u32, u64)> = self.pages().collect();
+        pages.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        writeln!(f, "Most accessed pages:")?;
+        for &(page, count) in pages.iter().take(SUMMARY_PAGES) {
+            writeln!(f, "  {:08X} {:>12}", page, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut stats = MemoryStats::default();
+        let fetch = OperationType::Read {
+            is_instruction: true,
+        };
+        stats.record(0x0800_0000, Region::CartRom, fetch, AccessWidth::Bit16);
+        stats.record(0x0800_0002, Region::CartRom, fetch, AccessWidth::Bit16);
+        stats.record(
+            0x0800_1000,
+            Region::CartRom,
+            OperationType::Write,
+            AccessWidth::Bit8,
+        );
+        stats.record_wait(Region::CartRom, 4);
+
+        assert_eq!(stats.fetches(Region::CartRom, AccessWidth::Bit16), 2);
+        assert_eq!(stats.reads(Region::CartRom, AccessWidth::Bit16), 0);
+        assert_eq!(stats.writes(Region::CartRom, AccessWidth::Bit8), 1);
+        assert_eq!(stats.writes(Region::CartRom, AccessWidth::Bit16), 0);
+        assert_eq!(stats.total(Region::CartRom), 3);
+        assert_eq!(stats.total(Region::Ewram), 0);
+        assert_eq!(stats.wait_cycles(Region::CartRom), 4);
+        assert_eq!(
+            stats.pages().collect::<Vec<_>>(),
+            [(0x0800_0000, 2), (0x0800_1000, 1)]
+        );
+
+        let summary = stats.to_string();
+        assert!(summary.contains("CartRom               0            1            2            4"));
+        assert!(summary.contains("  08000000            2\n  08001000            1\n"));
+    }
+}
@@ -0,0 +1,340 @@
+//! Frame pacing for frontends, to run the emulation at a chosen speed in real time.
+
+use ppu::CYCLES_PER_LINE;
+use ppu::LINES_PER_FRAME;
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Duration of a frame on hardware, running at 2^24 Hz. About 16.74 ms, or 59.73 FPS.
+pub const FRAME_DURATION_NANOS: u64 =
+    CYCLES_PER_LINE * LINES_PER_FRAME as u64 * 1_000_000_000 / (1 << 24);
+
+/// If the pacer falls behind by more than this many frames (e.g. because the host was busy), it
+/// stops trying to catch up instead of running a burst of frames without waiting.
+const MAX_LAG_FRAMES: u32 = 4;
+
+/// Number of frames the FPS is averaged over.
+const FPS_WINDOW_FRAMES: usize = 60;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Speed {
+    Quarter,
+    Half,
+    Normal,
+    Double,
+    Quadruple,
+    /// Runs frames as fast as possible.
+    Uncapped,
+}
+
+const SPEEDS: [Speed; 6] = [
+    Speed::Quarter,
+    Speed::Half,
+    Speed::Normal,
+    Speed::Double,
+    Speed::Quadruple,
+    Speed::Uncapped,
+];
+
+impl Speed {
+    /// Returns the speed relative to hardware, or None if uncapped.
+    pub fn multiplier(self) -> Option<f64> {
+        match self {
+            Speed::Quarter => Some(0.25),
+            Speed::Half => Some(0.5),
+            Speed::Normal => Some(1.0),
+            Speed::Double => Some(2.0),
+            Speed::Quadruple => Some(4.0),
+            Speed::Uncapped => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Speed::Quarter => "0.25x",
+            Speed::Half => "0.5x",
+            Speed::Normal => "1x",
+            Speed::Double => "2x",
+            Speed::Quadruple => "4x",
+            Speed::Uncapped => "uncapped",
+        }
+    }
+
+    /// Returns the next faster speed, stopping at uncapped.
+    pub fn faster(self) -> Speed {
+        let i = SPEEDS.iter().position(|&s| s == self).unwrap();
+        SPEEDS[(i + 1).min(SPEEDS.len() - 1)]
+    }
+
+    /// Returns the next slower speed, stopping at 0.25x.
+    pub fn slower(self) -> Speed {
+        let i = SPEEDS.iter().position(|&s| s == self).unwrap();
+        SPEEDS[i.saturating_sub(1)]
+    }
+
+    /// Real time taken by each frame at this speed.
+    pub fn frame_duration(self) -> Option<Duration> {
+        self.multiplier().map(|multiplier| {
+            Duration::from_nanos((FRAME_DURATION_NANOS as f64 / multiplier) as u64)
+        })
+    }
+}
+
+/// Source of time for the pacer, so tests can control it.
+pub trait Clock {
+    /// Returns the time elapsed since an arbitrary fixed point.
+    fn now(&self) -> Duration;
+    fn sleep(&mut self, duration: Duration);
+}
+
+/// Uses the host's monotonic clock.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Sleeps between frames so that they're run at the selected speed. Frame deadlines are kept on
+/// an absolute schedule, so that time spent emulating and oversleeping don't accumulate as drift.
+pub struct FramePacer<C: Clock> {
+    clock: C,
+    speed: Speed,
+    /// Time at which the next frame should start.
+    next_frame: Duration,
+}
+
+impl<C: Clock> FramePacer<C> {
+    pub fn new(clock: C) -> FramePacer<C> {
+        let now = clock.now();
+        FramePacer {
+            clock,
+            speed: Speed::Normal,
+            next_frame: now,
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.reset();
+    }
+
+    /// Restarts the schedule from the current time, e.g. after being paused.
+    pub fn reset(&mut self) {
+        self.next_frame = self.clock.now();
+    }
+
+    /// Waits until it's time to run the next frame. Should be called once per emulated frame.
+    pub fn wait_for_next_frame(&mut self) {
+        let frame_duration = match self.speed.frame_duration() {
+            Some(duration) => duration,
+            None => return,
+        };
+
+        self.next_frame += frame_duration;
+        let now = self.clock.now();
+        if self.next_frame > now {
+            self.clock.sleep(self.next_frame - now);
+        } else if now - self.next_frame > frame_duration * MAX_LAG_FRAMES {
+            self.next_frame = now;
+        }
+    }
+}
+
+/// Measures how many frames are run per second of real time, averaged over the last
+/// `FPS_WINDOW_FRAMES` frames.
+pub struct FpsCounter<C: Clock> {
+    clock: C,
+    /// When each of the frames in the window completed, oldest first.
+    frame_times: VecDeque<Duration>,
+}
+
+impl<C: Clock> FpsCounter<C> {
+    pub fn new(clock: C) -> FpsCounter<C> {
+        FpsCounter {
+            clock,
+            frame_times: VecDeque::with_capacity(FPS_WINDOW_FRAMES + 1),
+        }
+    }
+
+    /// Records that a frame was completed now.
+    pub fn frame(&mut self) {
+        if self.frame_times.len() > FPS_WINDOW_FRAMES {
+            self.frame_times.pop_front();
+        }
+        self.frame_times.push_back(self.clock.now());
+    }
+
+    /// Forgets the frames recorded so far, e.g. after being paused, so the pause isn't averaged in.
+    pub fn reset(&mut self) {
+        self.frame_times.clear();
+    }
+
+    /// Returns the average FPS, or None until at least two frames were recorded.
+    pub fn fps(&self) -> Option<f64> {
+        let (first, last) = match (self.frame_times.front(), self.frame_times.back()) {
+            (Some(&first), Some(&last)) if last > first => (first, last),
+            _ => return None,
+        };
+        let elapsed = last - first;
+        let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        Some((self.frame_times.len() - 1) as f64 / seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// Clock which only advances when told to, recording how long the pacer sleeps.
+    struct TestClock {
+        now: Rc<Cell<Duration>>,
+        sleeps: Rc<Cell<Vec<Duration>>>,
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> Duration {
+            self.now.get()
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.now.set(self.now.get() + duration);
+            let mut sleeps = self.sleeps.take();
+            sleeps.push(duration);
+            self.sleeps.set(sleeps);
+        }
+    }
+
+    fn make_pacer() -> (
+        FramePacer<TestClock>,
+        Rc<Cell<Duration>>,
+        Rc<Cell<Vec<Duration>>>,
+    ) {
+        let now = Rc::new(Cell::new(Duration::from_secs(100)));
+        let sleeps = Rc::new(Cell::new(Vec::new()));
+        let clock = TestClock {
+            now: now.clone(),
+            sleeps: sleeps.clone(),
+        };
+        (FramePacer::new(clock), now, sleeps)
+    }
+
+    #[test]
+    fn test_sleep_targets() {
+        let frame = Duration::from_nanos(FRAME_DURATION_NANOS);
+        let work = Duration::from_millis(2);
+        for &(speed, expected_frame) in [
+            (Speed::Quarter, frame * 4),
+            (Speed::Half, frame * 2),
+            (Speed::Normal, frame),
+            (Speed::Double, frame / 2),
+            (Speed::Quadruple, frame / 4),
+        ]
+        .iter()
+        {
+            let (mut pacer, now, sleeps) = make_pacer();
+            pacer.set_speed(speed);
+            for _ in 0..3 {
+                // Time spent emulating the frame is subtracted from the sleep
+                now.set(now.get() + work);
+                pacer.wait_for_next_frame();
+            }
+            let expected_sleep = expected_frame - work;
+            let sleeps = sleeps.take();
+            assert_eq!(sleeps.len(), 3, "{:?}", speed);
+            for &sleep in sleeps.iter() {
+                let error = if sleep > expected_sleep {
+                    sleep - expected_sleep
+                } else {
+                    expected_sleep - sleep
+                };
+                assert!(error.subsec_nanos() <= 1, "{:?}: {:?}", speed, sleep);
+            }
+        }
+    }
+
+    #[test]
+    fn test_uncapped_never_sleeps() {
+        let (mut pacer, _, sleeps) = make_pacer();
+        pacer.set_speed(Speed::Uncapped);
+        for _ in 0..10 {
+            pacer.wait_for_next_frame();
+        }
+        assert!(sleeps.take().is_empty());
+    }
+
+    #[test]
+    fn test_lag_recovery() {
+        let frame = Duration::from_nanos(FRAME_DURATION_NANOS);
+        let (mut pacer, now, sleeps) = make_pacer();
+
+        // Falling slightly behind is caught up on the following frames
+        now.set(now.get() + frame * 2);
+        pacer.wait_for_next_frame();
+        assert!(sleeps.take().is_empty());
+        pacer.wait_for_next_frame();
+        assert_eq!(sleeps.take(), []);
+        pacer.wait_for_next_frame();
+        assert_eq!(sleeps.take(), [frame]);
+
+        // After a long stall, the schedule restarts instead of running frames back to back
+        now.set(now.get() + frame * 100);
+        pacer.wait_for_next_frame();
+        pacer.wait_for_next_frame();
+        assert_eq!(sleeps.take(), [frame]);
+    }
+
+    #[test]
+    fn test_fps_counter() {
+        let (pacer, now, _) = make_pacer();
+        let mut fps_counter = FpsCounter::new(pacer.clock);
+        fps_counter.frame();
+        assert_eq!(fps_counter.fps(), None);
+
+        // 20 ms per frame is 50 FPS, and only the last frames count
+        for _ in 0..FPS_WINDOW_FRAMES * 2 {
+            now.set(now.get() + Duration::from_millis(20));
+            fps_counter.frame();
+        }
+        assert!((fps_counter.fps().unwrap() - 50.0).abs() < 1e-6);
+        for _ in 0..FPS_WINDOW_FRAMES {
+            now.set(now.get() + Duration::from_millis(10));
+            fps_counter.frame();
+        }
+        assert!((fps_counter.fps().unwrap() - 100.0).abs() < 1e-6);
+
+        fps_counter.reset();
+        assert_eq!(fps_counter.fps(), None);
+    }
+
+    #[test]
+    fn test_speed_cycling() {
+        assert_eq!(Speed::Normal.faster(), Speed::Double);
+        assert_eq!(Speed::Uncapped.faster(), Speed::Uncapped);
+        assert_eq!(Speed::Normal.slower(), Speed::Half);
+        assert_eq!(Speed::Quarter.slower(), Speed::Quarter);
+    }
+}
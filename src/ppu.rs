@@ -1,14 +1,76 @@
 use byteorder::ByteOrder;
 use byteorder::LE;
+use dma::DmaController;
+use dma::StartTiming;
+use interrupt::Interrupt;
+use interrupt::InterruptController;
+use interrupt::PowerState;
+use memory::Memory;
+use scheduler::Clock;
+use scheduler::EventSource;
+use scheduler::GeneratorTask;
+use scheduler::Task;
+use std::cell::RefCell;
+use std::fmt;
 use std::mem;
+use std::rc::Rc;
+use system::AccessWidth;
+use video_dirty::VideoDirty;
 
-#[derive(Copy, Clone, Debug, PartialEq)]
-enum BgPaletteMode {
+pub const SCREEN_WIDTH: usize = 240;
+pub const SCREEN_HEIGHT: usize = 160;
+
+pub const HDRAW_CYCLES: u64 = 960;
+pub const HBLANK_CYCLES: u64 = 272;
+pub const CYCLES_PER_LINE: u64 = HDRAW_CYCLES + HBLANK_CYCLES;
+pub const LINES_PER_FRAME: u16 = 228;
+
+/// How often the PPU checks whether the system has left Stop mode.
+const STOPPED_POLL_CYCLES: u64 = 64;
+
+pub type FrameBuffer = [[u16; SCREEN_WIDTH]; SCREEN_HEIGHT];
+
+/// Length of the phases of a scanline, in scheduler cycles.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LineTiming {
+    pub hdraw: u64,
+    pub hblank: u64,
+}
+
+impl LineTiming {
+    pub const NORMAL: LineTiming = LineTiming {
+        hdraw: HDRAW_CYCLES,
+        hblank: HBLANK_CYCLES,
+    };
+
+    /// Stretches the line by `clock_multiplier`, giving the rest of the system that many times
+    /// more cycles to run per line, while keeping the proportion between HDraw and HBlank.
+    pub fn scaled(clock_multiplier: f64) -> LineTiming {
+        let line = (CYCLES_PER_LINE as f64 * clock_multiplier).round() as u64;
+        let hdraw = (HDRAW_CYCLES as f64 * clock_multiplier).round() as u64;
+        LineTiming {
+            hdraw,
+            hblank: line - hdraw,
+        }
+    }
+
+    pub fn line_cycles(&self) -> u64 {
+        self.hdraw + self.hblank
+    }
+
+    pub fn frame_cycles(&self) -> u64 {
+        self.line_cycles() * LINES_PER_FRAME as u64
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BgPaletteMode {
     Pal16,
     Pal256,
 }
 
 const NUM_BG_LAYERS: usize = 4;
+const NUM_AFFINE_BG_LAYERS: usize = 2;
 
 #[derive(Copy, Clone)]
 struct BgAttributes {
@@ -35,15 +97,140 @@ impl BgAttributes {
     }
 }
 
+/// Rotation and scaling parameters of BG2 and BG3, used when they're affine or bitmap BGs.
+#[derive(Copy, Clone)]
+struct AffineBgAttributes {
+    // BGxPA-BGxPD, 8.8 fixed point
+    pa: i16,
+    pb: i16,
+    pc: i16,
+    pd: i16,
+    // BGxX, BGxY, 20.8 fixed point
+    x: i32,
+    y: i32,
+    /// Reference point for the line being drawn. It's loaded from BGxX/BGxY when they're written
+    /// and at the start of VBlank, and moves by (PB, PD) after each line drawn.
+    current_x: i32,
+    current_y: i32,
+}
+
+impl AffineBgAttributes {
+    const fn new() -> Self {
+        AffineBgAttributes {
+            pa: 0,
+            pb: 0,
+            pc: 0,
+            pd: 0,
+            x: 0,
+            y: 0,
+            current_x: 0,
+            current_y: 0,
+        }
+    }
+}
+
+/// Replaces the low or high halfword of a 28-bit signed BGxX/BGxY value.
+fn write_reference_point(old: i32, high: bool, data: u16) -> i32 {
+    let raw = if high {
+        old as u32 & 0xFFFF | (data as u32) << 16
+    } else {
+        old as u32 & 0xFFFF_0000 | data as u32
+    };
+    (raw << 4) as i32 >> 4
+}
+
+/// The parts of the LCD registers that the PPU changes as it draws, besides VCOUNT.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LcdStatus {
+    pub vblank_flag: bool,
+    pub hblank_flag: bool,
+    pub vcount_flag: bool,
+    /// Current reference points of BG2 and BG3.
+    pub affine_reference_points: [(i32, i32); NUM_AFFINE_BG_LAYERS],
+}
+
+const NUM_WINDOWS: usize = 2;
+
+/// Window control bits, as found in WININ/WINOUT: bits 0-3 enable BG0-3, bit 4 enables OBJ and bit
+/// 5 enables color special effects.
+const WINDOW_CONTROL_ALL: u8 = 0x3F;
+const WINDOW_CONTROL_OBJ: u8 = 1 << 4;
+
+#[derive(Copy, Clone)]
+struct WindowBounds {
+    // Left/top edges are inclusive, right/bottom edges exclusive
+    left: u8,
+    right: u8,
+    top: u8,
+    bottom: u8,
+}
+
+impl WindowBounds {
+    const fn new() -> Self {
+        WindowBounds {
+            left: 0,
+            right: 0,
+            top: 0,
+            bottom: 0,
+        }
+    }
+
+    fn contains(&self, screen_y: u16, screen_x: u16) -> bool {
+        // If the start is past the end, the window wraps around the edge of the screen
+        fn in_range(x: u16, start: u8, end: u8) -> bool {
+            let (start, end) = (start as u16, end as u16);
+            if start <= end {
+                x >= start && x < end
+            } else {
+                x >= start || x < end
+            }
+        }
+        in_range(screen_x, self.left, self.right) && in_range(screen_y, self.top, self.bottom)
+    }
+}
+
+/// Debugging overrides that hide layers regardless of the DISPCNT enables.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct LayerOverrides {
+    pub force_disable_bg: [bool; NUM_BG_LAYERS],
+    pub force_disable_obj: bool,
+}
+
 pub struct LcdControllerRegs {
     // DISPCNT
     video_mode: u8,
     active_display_page: u8,
+    hblank_interval_free: bool,
+    obj_mapping_1d: bool,
     forced_blank_enabled: bool,
     bg_layer_enabled: [bool; NUM_BG_LAYERS],
+    obj_enabled: bool,
+    window_enabled: [bool; NUM_WINDOWS],
+    obj_window_enabled: bool,
+
+    // DISPSTAT. The flags are updated by the PPU through `update_status`.
+    vblank_flag: bool,
+    hblank_flag: bool,
+    vcount_flag: bool,
+    vblank_irq_enabled: bool,
+    hblank_irq_enabled: bool,
+    vcount_irq_enabled: bool,
+    vcount_setting: u8,
+    // VCOUNT
+    vcount: u16,
 
     // BGxCNT
     bg_attributes: [BgAttributes; NUM_BG_LAYERS],
+    // BG2 and BG3 rotation/scaling
+    bg_affine: [AffineBgAttributes; NUM_AFFINE_BG_LAYERS],
+
+    // WINxH, WINxV
+    window_bounds: [WindowBounds; NUM_WINDOWS],
+    // WININ
+    window_inside_control: [u8; NUM_WINDOWS],
+    // WINOUT
+    window_outside_control: u8,
+    obj_window_control: u8,
 }
 
 impl LcdControllerRegs {
@@ -51,42 +238,271 @@ impl LcdControllerRegs {
         LcdControllerRegs {
             video_mode: 0,
             active_display_page: 0,
+            hblank_interval_free: false,
+            obj_mapping_1d: false,
             forced_blank_enabled: false,
             bg_layer_enabled: [false; NUM_BG_LAYERS],
+            obj_enabled: false,
+            window_enabled: [false; NUM_WINDOWS],
+            obj_window_enabled: false,
+            vblank_flag: false,
+            hblank_flag: false,
+            vcount_flag: false,
+            vblank_irq_enabled: false,
+            hblank_irq_enabled: false,
+            vcount_irq_enabled: false,
+            vcount_setting: 0,
+            vcount: 0,
             bg_attributes: [BgAttributes::new(); NUM_BG_LAYERS],
+            bg_affine: [AffineBgAttributes::new(); NUM_AFFINE_BG_LAYERS],
+            window_bounds: [WindowBounds::new(); NUM_WINDOWS],
+            window_inside_control: [0; NUM_WINDOWS],
+            window_outside_control: 0,
+            obj_window_control: 0,
         }
     }
 
-    pub fn write(&mut self, address: u32, data: u32) {
+    /// Writes the registers like an access of `width` from the CPU, with the address force-aligned
+    /// to it. Byte writes only modify their half of the register, and 32-bit writes set two
+    /// registers.
+    pub fn write(&mut self, address: u32, width: AccessWidth, data: u32) {
+        match width {
+            AccessWidth::Bit8 => {
+                let halfword_address = address & !0b1;
+                let shift = (address & 0b1) * 8;
+                let old = self.register_contents(halfword_address);
+                let new = old & !(0xFF << shift) | (data as u8 as u16) << shift;
+                self.write16(halfword_address, new);
+            }
+            AccessWidth::Bit16 => self.write16(address & !0b1, data as u16),
+            AccessWidth::Bit32 => {
+                self.write16(address & !0b11, data as u16);
+                self.write16(address & !0b11 | 0b10, (data >> 16) as u16);
+            }
+        }
+    }
+
+    fn write16(&mut self, address: u32, data: u16) {
         match address & 0xFFF {
-            0x000 => self.write_dispcnt(data as u16),
-            0x008 => self.write_bgcnt(0, data as u16),
-            0x00A => self.write_bgcnt(1, data as u16),
-            0x00C => self.write_bgcnt(2, data as u16),
-            0x00E => self.write_bgcnt(3, data as u16),
-            0x010 => self.write_bghofs(0, data as u16),
-            0x012 => self.write_bgvofs(0, data as u16),
-            0x014 => self.write_bghofs(1, data as u16),
-            0x016 => self.write_bgvofs(1, data as u16),
-            0x018 => self.write_bghofs(2, data as u16),
-            0x01A => self.write_bgvofs(2, data as u16),
-            0x01C => self.write_bghofs(3, data as u16),
-            0x01E => self.write_bgvofs(3, data as u16),
+            0x000 => self.write_dispcnt(data),
+            0x004 => self.write_dispstat(data),
+            0x006 => {} // VCOUNT is read-only
+            0x008 => self.write_bgcnt(0, data),
+            0x00A => self.write_bgcnt(1, data),
+            0x00C => self.write_bgcnt(2, data),
+            0x00E => self.write_bgcnt(3, data),
+            0x010 => self.write_bghofs(0, data),
+            0x012 => self.write_bgvofs(0, data),
+            0x014 => self.write_bghofs(1, data),
+            0x016 => self.write_bgvofs(1, data),
+            0x018 => self.write_bghofs(2, data),
+            0x01A => self.write_bgvofs(2, data),
+            0x01C => self.write_bghofs(3, data),
+            0x01E => self.write_bgvofs(3, data),
+            0x020..=0x03E => self.write_bg_affine(address, data),
+            0x040 => self.write_winh(0, data),
+            0x042 => self.write_winh(1, data),
+            0x044 => self.write_winv(0, data),
+            0x046 => self.write_winv(1, data),
+            0x048 => self.write_winin(data),
+            0x04A => self.write_winout(data),
             _ => println!(
-                "Unsupported LCD write: [0x{:08X}] <= 0x{:08X}",
+                "Unsupported LCD write: [0x{:08X}] <= 0x{:04X}",
                 address, data
             ),
         }
     }
 
+    /// Returns the current value of a register, including the write-only ones, for merging byte
+    /// writes into it.
+    fn register_contents(&self, address: u32) -> u16 {
+        match address & 0xFFF {
+            0x010..=0x01E => {
+                let bg = &self.bg_attributes[((address & 0xF) / 4) as usize];
+                if address & 0b10 == 0 {
+                    bg.x_scroll
+                } else {
+                    bg.y_scroll
+                }
+            }
+            0x020..=0x03E => {
+                let bg = &self.bg_affine[((address & 0x10) >> 4) as usize];
+                match address & 0xE {
+                    0x0 => bg.pa as u16,
+                    0x2 => bg.pb as u16,
+                    0x4 => bg.pc as u16,
+                    0x6 => bg.pd as u16,
+                    0x8 => bg.x as u16,
+                    0xA => (bg.x >> 16) as u16 & 0x0FFF,
+                    0xC => bg.y as u16,
+                    0xE => (bg.y >> 16) as u16 & 0x0FFF,
+                    _ => unreachable!(),
+                }
+            }
+            0x040 | 0x042 => {
+                let window = &self.window_bounds[((address & 0b10) / 2) as usize];
+                (window.left as u16) << 8 | window.right as u16
+            }
+            0x044 | 0x046 => {
+                let window = &self.window_bounds[((address & 0b10) / 2) as usize];
+                (window.top as u16) << 8 | window.bottom as u16
+            }
+            0x000..=0x00E | 0x048 | 0x04A => self.read(address),
+            _ => 0,
+        }
+    }
+
+    pub fn read(&self, address: u32) -> u16 {
+        match address & 0xFFF {
+            0x000 => self.read_dispcnt(),
+            0x004 => self.read_dispstat(),
+            0x006 => self.vcount,
+            0x008 => self.read_bgcnt(0),
+            0x00A => self.read_bgcnt(1),
+            0x00C => self.read_bgcnt(2),
+            0x00E => self.read_bgcnt(3),
+            // BGxHOFS, BGxVOFS and the BG2/BG3 rotation/scaling registers are write-only
+            0x010..=0x03E => 0,
+            0x048 => self.read_winin(),
+            0x04A => self.read_winout(),
+            _ => {
+                println!("Unsupported LCD read: [0x{:08X}]", address);
+                0
+            }
+        }
+    }
+
+    fn read_dispcnt(&self) -> u16 {
+        let mut data = 0;
+        bits_set!(data[0:2] = self.video_mode as u16);
+        bits_set!(data[4] = self.active_display_page as u16);
+        bits_set!(data[5] = self.hblank_interval_free as u16);
+        bits_set!(data[6] = self.obj_mapping_1d as u16);
+        bits_set!(data[7] = self.forced_blank_enabled as u16);
+        for i in 0..NUM_BG_LAYERS {
+            bits_set!(data[8 + i] = self.bg_layer_enabled[i] as u16);
+        }
+        bits_set!(data[12] = self.obj_enabled as u16);
+        bits_set!(data[13] = self.window_enabled[0] as u16);
+        bits_set!(data[14] = self.window_enabled[1] as u16);
+        bits_set!(data[15] = self.obj_window_enabled as u16);
+        data
+    }
+
+    fn read_dispstat(&self) -> u16 {
+        let mut data = 0;
+        bits_set!(data[0] = self.vblank_flag as u16);
+        bits_set!(data[1] = self.hblank_flag as u16);
+        bits_set!(data[2] = self.vcount_flag as u16);
+        bits_set!(data[3] = self.vblank_irq_enabled as u16);
+        bits_set!(data[4] = self.hblank_irq_enabled as u16);
+        bits_set!(data[5] = self.vcount_irq_enabled as u16);
+        bits_set!(data[8:15] = self.vcount_setting as u16);
+        data
+    }
+
+    fn write_dispstat(&mut self, data: u16) {
+        self.vblank_irq_enabled = bit!(data[3]) != 0;
+        self.hblank_irq_enabled = bit!(data[4]) != 0;
+        self.vcount_irq_enabled = bit!(data[5]) != 0;
+        self.vcount_setting = bit!(data[8:15]) as u8;
+    }
+
+    /// Updates VCOUNT and the DISPSTAT flags for the current position of the PPU, requesting the
+    /// enabled interrupts for the flags that just got set.
+    pub fn update_status(&mut self, vcount: u16, hblank: bool, interrupts: &InterruptController) {
+        // The VBlank flag is already cleared on the last line
+        let vblank = vcount as usize >= SCREEN_HEIGHT && vcount != LINES_PER_FRAME - 1;
+        let vcount_match = vcount == self.vcount_setting as u16;
+
+        if vblank && !self.vblank_flag && self.vblank_irq_enabled {
+            interrupts.request(Interrupt::VBlank);
+        }
+        if hblank && !self.hblank_flag && self.hblank_irq_enabled {
+            interrupts.request(Interrupt::HBlank);
+        }
+        if vcount_match && !self.vcount_flag && self.vcount_irq_enabled {
+            interrupts.request(Interrupt::VCount);
+        }
+
+        self.vblank_flag = vblank;
+        self.hblank_flag = hblank;
+        self.vcount_flag = vcount_match;
+        self.vcount = vcount;
+    }
+
+    /// Returns the flags and reference points that the PPU updates, to save them.
+    pub fn status(&self) -> LcdStatus {
+        let mut affine_reference_points = [(0, 0); NUM_AFFINE_BG_LAYERS];
+        for (point, bg) in affine_reference_points.iter_mut().zip(&self.bg_affine) {
+            *point = (bg.current_x, bg.current_y);
+        }
+        LcdStatus {
+            vblank_flag: self.vblank_flag,
+            hblank_flag: self.hblank_flag,
+            vcount_flag: self.vcount_flag,
+            affine_reference_points,
+        }
+    }
+
+    /// Puts back a status saved with `status`, along with VCOUNT. Unlike `update_status`, it
+    /// doesn't request any interrupts, since that already happened before the status was saved.
+    pub fn restore_status(&mut self, vcount: u16, status: &LcdStatus) {
+        self.vblank_flag = status.vblank_flag;
+        self.hblank_flag = status.hblank_flag;
+        self.vcount_flag = status.vcount_flag;
+        self.vcount = vcount;
+        for (bg, &(x, y)) in self
+            .bg_affine
+            .iter_mut()
+            .zip(&status.affine_reference_points)
+        {
+            bg.current_x = x;
+            bg.current_y = y;
+        }
+    }
+
+    /// Moves the reference points of BG2 and BG3 on to the next line, once a line is drawn.
+    fn advance_affine_reference_points(&mut self) {
+        for bg in self.bg_affine.iter_mut() {
+            bg.current_x = bg.current_x.wrapping_add(bg.pb as i32);
+            bg.current_y = bg.current_y.wrapping_add(bg.pd as i32);
+        }
+    }
+
+    /// Reloads the reference points of BG2 and BG3 from BGxX/BGxY, at the start of VBlank.
+    fn reload_affine_reference_points(&mut self) {
+        for bg in self.bg_affine.iter_mut() {
+            bg.current_x = bg.x;
+            bg.current_y = bg.y;
+        }
+    }
+
+    fn read_bgcnt(&self, i: usize) -> u16 {
+        let bg = &self.bg_attributes[i];
+        let mut data = 0;
+        bits_set!(data[0:1] = bg.priority as u16);
+        bits_set!(data[2:3] = bg.char_base as u16);
+        bits_set!(data[7] = (bg.palette_mode == BgPaletteMode::Pal256) as u16);
+        bits_set!(data[8:12] = bg.map_base as u16);
+        bits_set!(data[14:15] = bg.size_mode as u16);
+        data
+    }
+
     fn write_dispcnt(&mut self, data: u16) {
         self.video_mode = bit!(data[0:2]) as u8;
         self.active_display_page = bit!(data[4]) as u8;
+        self.hblank_interval_free = bit!(data[5]) != 0;
+        self.obj_mapping_1d = bit!(data[6]) != 0;
         self.forced_blank_enabled = bit!(data[7]) != 0;
         self.bg_layer_enabled[0] = bit!(data[8]) != 0;
         self.bg_layer_enabled[1] = bit!(data[9]) != 0;
         self.bg_layer_enabled[2] = bit!(data[10]) != 0;
         self.bg_layer_enabled[3] = bit!(data[11]) != 0;
+        self.obj_enabled = bit!(data[12]) != 0;
+        self.window_enabled[0] = bit!(data[13]) != 0;
+        self.window_enabled[1] = bit!(data[14]) != 0;
+        self.obj_window_enabled = bit!(data[15]) != 0;
     }
 
     fn write_bgcnt(&mut self, i: usize, data: u16) {
@@ -109,6 +525,231 @@ impl LcdControllerRegs {
     fn write_bgvofs(&mut self, i: usize, data: u16) {
         self.bg_attributes[i].y_scroll = bit!(data[0:8]);
     }
+
+    fn write_bg_affine(&mut self, address: u32, data: u16) {
+        let bg = &mut self.bg_affine[((address & 0x10) >> 4) as usize];
+        match address & 0xE {
+            0x0 => bg.pa = data as i16,
+            0x2 => bg.pb = data as i16,
+            0x4 => bg.pc = data as i16,
+            0x6 => bg.pd = data as i16,
+            0x8 | 0xA => {
+                bg.x = write_reference_point(bg.x, address & 0x2 != 0, data);
+                bg.current_x = bg.x;
+            }
+            0xC | 0xE => {
+                bg.y = write_reference_point(bg.y, address & 0x2 != 0, data);
+                bg.current_y = bg.y;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_winh(&mut self, i: usize, data: u16) {
+        self.window_bounds[i].left = bit!(data[8:15]) as u8;
+        self.window_bounds[i].right = bit!(data[0:7]) as u8;
+    }
+
+    fn write_winv(&mut self, i: usize, data: u16) {
+        self.window_bounds[i].top = bit!(data[8:15]) as u8;
+        self.window_bounds[i].bottom = bit!(data[0:7]) as u8;
+    }
+
+    fn write_winin(&mut self, data: u16) {
+        self.window_inside_control[0] = bit!(data[0:5]) as u8;
+        self.window_inside_control[1] = bit!(data[8:13]) as u8;
+    }
+
+    fn read_winin(&self) -> u16 {
+        let mut data = 0;
+        bits_set!(data[0:5] = self.window_inside_control[0] as u16);
+        bits_set!(data[8:13] = self.window_inside_control[1] as u16);
+        data
+    }
+
+    fn write_winout(&mut self, data: u16) {
+        self.window_outside_control = bit!(data[0:5]) as u8;
+        self.obj_window_control = bit!(data[8:13]) as u8;
+    }
+
+    fn read_winout(&self) -> u16 {
+        let mut data = 0;
+        bits_set!(data[0:5] = self.window_outside_control as u16);
+        bits_set!(data[8:13] = self.obj_window_control as u16);
+        data
+    }
+
+    /// First OBJ tile that sprites can use. In the bitmap modes, the bitmap extends into the first
+    /// half of OBJ VRAM, and sprites using tiles from there are transparent.
+    fn first_obj_tile(&self) -> usize {
+        match self.video_mode {
+            3..=5 => 512,
+            _ => 0,
+        }
+    }
+
+    /// Returns the OBJ tile holding the block at (`tile_x`, `tile_y`) of a sprite `width_tiles`
+    /// tiles wide, starting at `base_tile`. With 2D mapping, tiles are laid out in rows of 32
+    /// tiles, while with 1D mapping the rows of the sprite follow each other. 256 color tiles take
+    /// up two tile numbers in both.
+    fn obj_tile(
+        &self,
+        base_tile: usize,
+        tile_x: usize,
+        tile_y: usize,
+        width_tiles: usize,
+        pal256: bool,
+    ) -> usize {
+        let tile_step = if pal256 { 2 } else { 1 };
+        let row_step = if self.obj_mapping_1d {
+            width_tiles * tile_step
+        } else {
+            32
+        };
+        (base_tile + tile_y * row_step + tile_x * tile_step) % 1024
+    }
+
+    /// Whether the PPU is fetching from VRAM and palette RAM, which it does while drawing the
+    /// visible lines unless the display is blanked.
+    pub fn is_drawing(&self) -> bool {
+        !self.forced_blank_enabled && !self.vblank_flag && !self.hblank_flag
+    }
+
+    /// Whether the PPU is reading OAM. It also does so during HBlank, to prepare the sprites of the
+    /// next line, unless the HBlank interval is freed for accessing OAM.
+    pub fn is_using_oam(&self) -> bool {
+        !self.forced_blank_enabled
+            && !self.vblank_flag
+            && (!self.hblank_flag || !self.hblank_interval_free)
+    }
+
+    /// Cycles available for rendering sprites on each line. Freeing up VRAM for access during
+    /// HBlank leaves fewer of them.
+    fn obj_cycle_budget(&self) -> u32 {
+        if self.hblank_interval_free {
+            954
+        } else {
+            1210
+        }
+    }
+
+    /// Returns the window control bits in effect for a pixel. WIN0 takes precedence over WIN1,
+    /// which takes precedence over the OBJ window, and WINOUT applies everywhere else.
+    fn window_control_for_pixel(&self, screen_y: u16, screen_x: u16) -> u8 {
+        if !self.window_enabled[0] && !self.window_enabled[1] && !self.obj_window_enabled {
+            return WINDOW_CONTROL_ALL;
+        }
+
+        for i in 0..NUM_WINDOWS {
+            if self.window_enabled[i] && self.window_bounds[i].contains(screen_y, screen_x) {
+                return self.window_inside_control[i];
+            }
+        }
+        // TODO: OBJ window, once sprites are supported
+        self.window_outside_control
+    }
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Dumps the registers as a table, for debugging.
+impl fmt::Display for LcdControllerRegs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Mode {}, page {}, forced blank {}, OBJ {}, VCOUNT {}",
+            self.video_mode,
+            self.active_display_page,
+            on_off(self.forced_blank_enabled),
+            on_off(self.obj_enabled),
+            self.vcount
+        )?;
+        writeln!(
+            f,
+            "BG  Enabled  Priority  Chars     Map       Colors  Scroll"
+        )?;
+        for (i, bg) in self.bg_attributes.iter().enumerate() {
+            writeln!(
+                f,
+                "{}   {:<7}  {:<8}  {:08X}  {:08X}  {:<6}  {}, {}",
+                i,
+                on_off(self.bg_layer_enabled[i]),
+                bg.priority,
+                0x0600_0000 + bg.char_base as u32 * 0x4000,
+                0x0600_0000 + bg.map_base as u32 * 0x800,
+                match bg.palette_mode {
+                    BgPaletteMode::Pal16 => "16x16",
+                    BgPaletteMode::Pal256 => "256",
+                },
+                bg.x_scroll,
+                bg.y_scroll
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Palette RAM, split into the 256 colors used by backgrounds and the 256 used by OBJs. 16 color
+/// tiles and sprites pick one of 16 banks of 16 colors within their half.
+#[derive(Copy, Clone)]
+struct Palettes<'a> {
+    pals: &'a [u16],
+}
+
+impl<'a> Palettes<'a> {
+    fn new(pals: &'a [u16]) -> Palettes<'a> {
+        assert_eq!(pals.len(), 512);
+        Palettes { pals }
+    }
+
+    fn bg(&self, index: usize) -> u16 {
+        self.pals[index]
+    }
+
+    fn obj(&self, index: usize) -> u16 {
+        self.pals[256 + index]
+    }
+}
+
+const BG_VRAM_SIZE: usize = 64 * 1024;
+/// The bitmap modes use part of OBJ VRAM too, where the second page of modes 4 and 5 ends.
+const BITMAP_VRAM_SIZE: usize = 80 * 1024;
+
+/// Wraps an offset computed for a tile or map fetch into BG VRAM, like hardware does. Games only
+/// get there with bad register values, so it's reported in debug builds.
+fn bg_vram_offset(offset: usize) -> usize {
+    if cfg!(debug_assertions) && offset >= BG_VRAM_SIZE {
+        println!("BG fetch past the end of BG VRAM, at offset 0x{:X}", offset);
+    }
+    offset % BG_VRAM_SIZE
+}
+
+/// Reads pixel (`x`, `y`) of a BG tile from the character data at `char_base` (in units of
+/// 16 KiB). For 16 color tiles, it's the color within the tile's palette bank. Color 0 is
+/// transparent.
+fn bg_tile_pixel(
+    vram: &[u8],
+    char_base: u8,
+    palette_mode: BgPaletteMode,
+    tile_id: usize,
+    x: usize,
+    y: usize,
+) -> u8 {
+    let charmap_base = char_base as usize * 0x4000;
+    let charmap_offset = tile_id * (8 * 8) + (y * 8) + x;
+    match palette_mode {
+        BgPaletteMode::Pal16 => {
+            let read_byte = vram[bg_vram_offset(charmap_base + charmap_offset / 2)];
+            read_byte >> (x % 2 * 4) & 0xF
+        }
+        BgPaletteMode::Pal256 => vram[bg_vram_offset(charmap_base + charmap_offset)],
+    }
 }
 
 fn render_text_bg_pixel(
@@ -117,9 +758,11 @@ fn render_text_bg_pixel(
     bg_id: u8,
     bg_regs: &BgAttributes,
     vram: &[u8],
-    pals: &[u16],
+    pals: Palettes,
 ) -> Option<Layer> {
-    // Calculate tile and background coordinates
+    // Calculate tile and background coordinates. The submap selects the 256x256 screenblock along
+    // the axis, which is only used if the BG's size extends to 512 pixels on it, so that smaller
+    // BGs wrap at 256.
     fn calc_bg_coords(screen_y: u16, bg_y_scroll: u16) -> (usize, usize, usize) {
         let bg_y = screen_y.wrapping_add(bg_y_scroll) % 512;
         let tile_y = bg_y % 8;
@@ -144,36 +787,31 @@ fn render_text_bg_pixel(
     let screenblock_offset = map_y * 32 + map_x;
 
     // Read map entry from VRAM
-    let entry = LE::read_u16(&vram[screenblock_base + screenblock_offset * 2..]);
+    let entry = LE::read_u16(&vram[bg_vram_offset(screenblock_base + screenblock_offset * 2)..]);
     let tile_id = bit!(entry[0:9]) as usize;
     let h_flip = bit!(entry[10]) != 0;
     let v_flip = bit!(entry[11]) != 0;
     let pal_id = bit!(entry[12:15]);
 
-    // Calculate character data offset
+    // Read pixel data and compute palette index
     let flipped_tile_x = if h_flip { 7 - tile_x } else { tile_x };
     let flipped_tile_y = if v_flip { 7 - tile_y } else { tile_y };
-    let charmap_base = bg_regs.char_base as usize * 0x4000;
-    let charmap_offset = tile_id * (8 * 8) + (flipped_tile_y * 8) + flipped_tile_x;
-
-    // Read pixel data and compute palette index
-    let palette_index;
-    let opaque;
-    match bg_regs.palette_mode {
-        BgPaletteMode::Pal16 => {
-            let read_byte = vram[charmap_base + charmap_offset / 2];
-            let pixel = read_byte >> (flipped_tile_x % 2 * 4) & 0xF;
-            palette_index = pixel + (pal_id * 16) as u8;
-            opaque = pixel != 0;
-        }
-        BgPaletteMode::Pal256 => {
-            palette_index = vram[charmap_base + charmap_offset];
-            opaque = palette_index != 0;
-        }
-    }
+    let pixel = bg_tile_pixel(
+        vram,
+        bg_regs.char_base,
+        bg_regs.palette_mode,
+        tile_id,
+        flipped_tile_x,
+        flipped_tile_y,
+    );
+    let opaque = pixel != 0;
+    let palette_index = match bg_regs.palette_mode {
+        BgPaletteMode::Pal16 => pixel + (pal_id * 16) as u8,
+        BgPaletteMode::Pal256 => pixel,
+    };
 
     // Read palette entry
-    let color = pals[palette_index as usize];
+    let color = pals.bg(palette_index as usize);
 
     if opaque {
         Some(Layer {
@@ -206,7 +844,7 @@ fn pick_top_two<T: Copy, K: Ord>(
 
 #[derive(Copy, Clone)]
 enum LayerId {
-    _Obj,
+    Obj,
     Bg(u8),
     Backdrop,
 }
@@ -229,13 +867,21 @@ fn pick_top_two_layers(layers: &[Option<Layer>; 6]) -> (&Layer, Option<&Layer>)
 pub fn render_lcd_line(
     screen_y: u16,
     regs: &LcdControllerRegs,
+    overrides: &LayerOverrides,
     vram: &[u8],
     pals: &[u16],
+    oam: &[u16],
 ) -> [u16; 240] {
-    let bg_vram = &vram[..64 * 1024];
-    let bg_pals = &pals[..16 * 16];
-    let _obj_vram = &vram[64 * 1024..];
-    let bitmap_vram = &vram[..80 * 1024];
+    let bg_vram = &vram[..BG_VRAM_SIZE];
+    let pals = Palettes::new(pals);
+    let obj_vram = &vram[64 * 1024..];
+    let bitmap_vram = &vram[..BITMAP_VRAM_SIZE];
+
+    let obj_line = if regs.obj_enabled {
+        render_obj_line(screen_y, regs, obj_vram, pals, oam)
+    } else {
+        [None; SCREEN_WIDTH]
+    };
 
     let mut buf = [0; 240];
 
@@ -243,30 +889,45 @@ pub fn render_lcd_line(
         // [OBJ, BG0, BG1, BG2, BG3, backdrop]
         let mut layers = [None; 6];
 
-        // TODO: OBJ support
+        layers[0] = obj_line[screen_x as usize];
 
         // Background layers
         match regs.video_mode {
-            0 => render_mode0_backgrounds(&mut layers, screen_y, screen_x, regs, bg_vram, bg_pals),
-            1 => render_mode1_backgrounds(&mut layers, screen_y, screen_x, regs, bg_vram, bg_pals),
+            0 => render_mode0_backgrounds(&mut layers, screen_y, screen_x, regs, bg_vram, pals),
+            1 => render_mode1_backgrounds(&mut layers, screen_y, screen_x, regs, bg_vram, pals),
             2 => unimplemented!(),
             3 => render_mode3_backgrounds(&mut layers, screen_y, screen_x, regs, bitmap_vram),
-            4 => render_mode4_backgrounds(
-                &mut layers,
-                screen_y,
-                screen_x,
-                regs,
-                bitmap_vram,
-                bg_pals,
-            ),
+            4 => render_mode4_backgrounds(&mut layers, screen_y, screen_x, regs, bitmap_vram, pals),
             5 => render_mode5_backgrounds(&mut layers, screen_y, screen_x, regs, bitmap_vram),
-            invalid_mode => println!("Invalid display mode: {}", invalid_mode),
+            // Modes 6 and 7 are prohibited. No backgrounds are displayed in them, leaving only OBJs
+            // and the backdrop.
+            _ => {}
+        }
+
+        // Hide layers disabled by the window covering this pixel
+        let window_control = regs.window_control_for_pixel(screen_y, screen_x);
+        for bg in 0..NUM_BG_LAYERS {
+            if window_control & (1 << bg) == 0 {
+                layers[bg + 1] = None;
+            }
+        }
+        if window_control & WINDOW_CONTROL_OBJ == 0 {
+            layers[0] = None;
+        }
+
+        for bg in 0..NUM_BG_LAYERS {
+            if overrides.force_disable_bg[bg] {
+                layers[bg + 1] = None;
+            }
+        }
+        if overrides.force_disable_obj {
+            layers[0] = None;
         }
 
         // Backdrop layer
         layers[5] = Some(Layer {
             id: LayerId::Backdrop,
-            color: bg_pals[0],
+            color: pals.bg(0),
             priority: 4,
             force_alpha_blend: false,
         });
@@ -286,7 +947,7 @@ fn render_mode0_backgrounds(
     screen_x: u16,
     regs: &LcdControllerRegs,
     bg_vram: &[u8],
-    bg_pals: &[u16],
+    pals: Palettes,
 ) {
     for bg in 0..=3 {
         if regs.bg_layer_enabled[bg] {
@@ -296,7 +957,7 @@ fn render_mode0_backgrounds(
                 bg as u8,
                 &regs.bg_attributes[bg],
                 bg_vram,
-                bg_pals,
+                pals,
             );
         }
     }
@@ -308,7 +969,7 @@ fn render_mode1_backgrounds(
     screen_x: u16,
     regs: &LcdControllerRegs,
     bg_vram: &[u8],
-    bg_pals: &[u16],
+    pals: Palettes,
 ) {
     for bg in 0..=1 {
         if regs.bg_layer_enabled[bg] {
@@ -318,13 +979,84 @@ fn render_mode1_backgrounds(
                 bg as u8,
                 &regs.bg_attributes[bg],
                 bg_vram,
-                bg_pals,
+                pals,
             );
         }
     }
     // TODO: affine backgrounds
 }
 
+/// Width in pixels of the tile sheets made by `dump_tiles`, 16 tiles across.
+pub const TILE_SHEET_WIDTH: usize = 16 * 8;
+
+/// Decodes all the BG tiles of the 16 KiB character block `char_base` into a sheet
+/// `TILE_SHEET_WIDTH` pixels across, in tile order, for debug viewers. 16 color tiles all use
+/// bank `palette` of the BG palette, and show color 0 of that bank where they're transparent.
+pub fn dump_tiles(
+    vram: &[u8],
+    pals: &[u16],
+    char_base: u8,
+    palette_mode: BgPaletteMode,
+    palette: u8,
+) -> Vec<u16> {
+    let pals = Palettes::new(pals);
+    let (num_tiles, bank) = match palette_mode {
+        BgPaletteMode::Pal16 => (512, palette as usize % 16 * 16),
+        BgPaletteMode::Pal256 => (256, 0),
+    };
+    let tiles_across = TILE_SHEET_WIDTH / 8;
+    let height = num_tiles / tiles_across * 8;
+    let mut pixels = Vec::with_capacity(TILE_SHEET_WIDTH * height);
+    for y in 0..height {
+        for x in 0..TILE_SHEET_WIDTH {
+            let tile_id = y / 8 * tiles_across + x / 8;
+            let pixel = bg_tile_pixel(vram, char_base % 4, palette_mode, tile_id, x % 8, y % 8);
+            pixels.push(pals.bg(bank + pixel as usize));
+        }
+    }
+    pixels
+}
+
+/// A whole BG map decoded into pixels, by `dump_bg_map`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BgMapImage {
+    pub width: usize,
+    pub height: usize,
+    /// `width` by `height` pixels, line by line.
+    pub pixels: Vec<u16>,
+}
+
+/// Decodes the whole map of BG `bg`, as its registers currently set it up, without scrolling,
+/// for debug viewers. Transparent pixels show the backdrop color. Affine BGs aren't rendered yet,
+/// so their maps are decoded as if they were text BGs.
+pub fn dump_bg_map(bg: usize, regs: &LcdControllerRegs, vram: &[u8], pals: &[u16]) -> BgMapImage {
+    let mut attributes = regs.bg_attributes[bg];
+    attributes.x_scroll = 0;
+    attributes.y_scroll = 0;
+    let (width, height) = match attributes.size_mode {
+        0 => (256, 256),
+        1 => (512, 256),
+        2 => (256, 512),
+        3 => (512, 512),
+        _ => unreachable!(),
+    };
+    let pals = Palettes::new(pals);
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let layer = render_text_bg_pixel(y as u16, x as u16, bg as u8, &attributes, vram, pals);
+            pixels.push(layer.map_or(pals.bg(0), |layer| layer.color));
+        }
+    }
+    BgMapImage {
+        width,
+        height,
+        pixels,
+    }
+}
+
+/// The bitmap modes show their bitmap as BG2. Its pixels go in `layers[BITMAP_BG_LAYER + 1]`, since
+/// slot 0 of the layers of a pixel holds the OBJ and BG n is in slot n + 1.
 const BITMAP_BG_LAYER: usize = 2;
 
 fn render_mode3_bg_pixel(
@@ -357,7 +1089,7 @@ fn render_mode3_backgrounds(
 ) {
     if regs.bg_layer_enabled[BITMAP_BG_LAYER] {
         // TODO: affine support
-        layers[BITMAP_BG_LAYER] = render_mode3_bg_pixel(
+        layers[BITMAP_BG_LAYER + 1] = render_mode3_bg_pixel(
             screen_y,
             screen_x,
             &regs.bg_attributes[BITMAP_BG_LAYER],
@@ -372,7 +1104,7 @@ fn render_mode4_bg_pixel(
     bg_regs: &BgAttributes,
     display_page: u8,
     vram: &[u8],
-    bg_pals: &[u16],
+    pals: Palettes,
 ) -> Option<Layer> {
     if screen_y >= 160 || screen_x >= 240 {
         return None;
@@ -382,7 +1114,7 @@ fn render_mode4_bg_pixel(
     let page_base = display_page as usize * 0xA000;
 
     let palette_index = vram[page_base + page_offset];
-    let color = bg_pals[palette_index as usize];
+    let color = pals.bg(palette_index as usize);
 
     if palette_index != 0 {
         Some(Layer {
@@ -402,17 +1134,17 @@ fn render_mode4_backgrounds(
     screen_x: u16,
     regs: &LcdControllerRegs,
     vram: &[u8],
-    bg_pals: &[u16],
+    pals: Palettes,
 ) {
     if regs.bg_layer_enabled[BITMAP_BG_LAYER] {
         // TODO: affine support
-        layers[BITMAP_BG_LAYER] = render_mode4_bg_pixel(
+        layers[BITMAP_BG_LAYER + 1] = render_mode4_bg_pixel(
             screen_y,
             screen_x,
             &regs.bg_attributes[BITMAP_BG_LAYER],
             regs.active_display_page,
             vram,
-            bg_pals,
+            pals,
         );
     }
 }
@@ -449,8 +1181,9 @@ fn render_mode5_backgrounds(
     vram: &[u8],
 ) {
     if regs.bg_layer_enabled[BITMAP_BG_LAYER] {
-        // TODO: affine support
-        layers[BITMAP_BG_LAYER] = render_mode5_bg_pixel(
+        // TODO: affine support. The frame is placed on screen by the BG2 reference point, so it's
+        // only at the top left while that's 0, as it is after reset.
+        layers[BITMAP_BG_LAYER + 1] = render_mode5_bg_pixel(
             screen_y,
             screen_x,
             &regs.bg_attributes[BITMAP_BG_LAYER],
@@ -459,3 +1192,926 @@ fn render_mode5_backgrounds(
         );
     }
 }
+
+/// Width and height of sprites in pixels, by shape (square, horizontal, vertical) and size.
+const OBJ_DIMENSIONS: [[(u16, u16); 4]; 3] = [
+    [(8, 8), (16, 16), (32, 32), (64, 64)],
+    [(16, 8), (32, 8), (32, 16), (64, 32)],
+    [(8, 16), (8, 32), (16, 32), (32, 64)],
+];
+
+const OBJ_VRAM_SIZE: usize = 32 * 1024;
+
+/// Renders the sprites covering a line into the OBJ layer. Where sprites overlap, the one first in
+/// OAM wins the pixel, regardless of their priorities. Only the winning pixel's priority is then
+/// compared against the backgrounds, so a sprite with a worse priority can hide another sprite
+/// behind a background, which games use for masking effects.
+fn render_obj_line(
+    screen_y: u16,
+    regs: &LcdControllerRegs,
+    obj_vram: &[u8],
+    pals: Palettes,
+    oam: &[u16],
+) -> [Option<Layer>; SCREEN_WIDTH] {
+    let mut line = [None; SCREEN_WIDTH];
+    let first_tile = regs.first_obj_tile();
+    let mut cycles_left = regs.obj_cycle_budget();
+
+    for attrs in oam.chunks(4) {
+        let (attr0, attr1, attr2) = (attrs[0], attrs[1], attrs[2]);
+        // TODO: affine sprites
+        if bit!(attr0[8]) != 0 {
+            continue;
+        }
+        // Bit 9 hides non-affine sprites
+        if bit!(attr0[9]) != 0 {
+            continue;
+        }
+        let mode = bit!(attr0[10:11]);
+        // TODO: OBJ window sprites. Mode 3 is prohibited.
+        if mode >= 2 {
+            continue;
+        }
+        let shape = bit!(attr0[14:15]);
+        if shape == 3 {
+            continue;
+        }
+        let (width, height) = OBJ_DIMENSIONS[shape as usize][bit!(attr1[14:15]) as usize];
+
+        // Sprites wrap around at the bottom and right edges of the 512x256 OBJ plane
+        let sprite_y = screen_y.wrapping_sub(bit!(attr0[0:7])) % 256;
+        if sprite_y >= height {
+            continue;
+        }
+
+        // Each sprite on the line takes a cycle per pixel of its width, even if it's off screen.
+        // Once the budget runs out, the rest of the sprites are dropped.
+        if width as u32 > cycles_left {
+            break;
+        }
+        cycles_left -= width as u32;
+        let sprite_y = if bit!(attr1[13]) != 0 {
+            height - 1 - sprite_y
+        } else {
+            sprite_y
+        };
+
+        let pal256 = bit!(attr0[13]) != 0;
+        let base_tile = bit!(attr2[0:9]) as usize;
+        let priority = bit!(attr2[10:11]) as u8;
+        let pal_bank = bit!(attr2[12:15]) as usize;
+        let x = bit!(attr1[0:8]);
+        let h_flip = bit!(attr1[12]) != 0;
+
+        for sprite_x in 0..width {
+            let screen_x = x.wrapping_add(sprite_x) % 512;
+            if screen_x as usize >= SCREEN_WIDTH || line[screen_x as usize].is_some() {
+                continue;
+            }
+            let sprite_x = if h_flip {
+                width - 1 - sprite_x
+            } else {
+                sprite_x
+            };
+
+            let (tile_x, tile_y) = ((sprite_x / 8) as usize, (sprite_y / 8) as usize);
+            let (pixel_x, pixel_y) = ((sprite_x % 8) as usize, (sprite_y % 8) as usize);
+            let tile = regs.obj_tile(base_tile, tile_x, tile_y, width as usize / 8, pal256);
+            if tile < first_tile {
+                continue;
+            }
+            // The last 256 color tile wraps around to the start of OBJ VRAM
+            let palette_index = if pal256 {
+                obj_vram[(tile * 32 + pixel_y * 8 + pixel_x) % OBJ_VRAM_SIZE] as usize
+            } else {
+                let byte = obj_vram[tile * 32 + pixel_y * 4 + pixel_x / 2];
+                (byte >> (pixel_x % 2 * 4) & 0xF) as usize
+            };
+            if palette_index == 0 {
+                continue;
+            }
+
+            let color = if pal256 {
+                pals.obj(palette_index)
+            } else {
+                pals.obj(pal_bank * 16 + palette_index)
+            };
+            line[screen_x as usize] = Some(Layer {
+                id: LayerId::Obj,
+                color,
+                priority,
+                force_alpha_blend: mode == 1,
+            });
+        }
+    }
+    line
+}
+
+/// Part of a scanline.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LinePhase {
+    HDraw,
+    HBlank,
+}
+
+/// Snapshot of the PPU and the LCD status it keeps up to date, enough to resume a frame saved
+/// partway through. The settings of the frontend, like `layer_overrides`, aren't part of it.
+#[derive(Clone)]
+pub struct PpuState {
+    pub vcount: u16,
+    pub phase: LinePhase,
+    /// Cycles left until the end of `phase`.
+    pub cycles_left: u64,
+    pub frame_count: u64,
+    pub framebuffer: Box<FrameBuffer>,
+    pub displayed_lines: bool,
+    pub lcd_off: bool,
+    pub lcd_status: LcdStatus,
+}
+
+/// Scanline timing and output state of the LCD controller.
+pub struct Ppu {
+    timing: LineTiming,
+    vcount: u16,
+    phase: LinePhase,
+    /// When the task is done waiting for the end of `phase`. None until it starts waiting for it,
+    /// when the phase lasts as long as `timing` says.
+    phase_end: Option<u64>,
+    frame_count: u64,
+    framebuffer: Box<FrameBuffer>,
+    /// Whether any line of the frame in progress was displayed, and of the last one completed.
+    displayed_lines: bool,
+    lcd_off: bool,
+    /// Video memory written between the starts of the last two VBlanks.
+    video_dirty: VideoDirty,
+    pub layer_overrides: LayerOverrides,
+}
+
+impl Ppu {
+    pub fn new() -> Ppu {
+        Ppu {
+            timing: LineTiming::NORMAL,
+            vcount: 0,
+            phase: LinePhase::HDraw,
+            phase_end: None,
+            frame_count: 0,
+            framebuffer: Box::new([[0; SCREEN_WIDTH]; SCREEN_HEIGHT]),
+            displayed_lines: false,
+            lcd_off: false,
+            video_dirty: VideoDirty::default(),
+            layer_overrides: LayerOverrides::default(),
+        }
+    }
+
+    pub fn set_timing(&mut self, timing: LineTiming) {
+        self.timing = timing;
+    }
+
+    pub fn timing(&self) -> LineTiming {
+        self.timing
+    }
+
+    pub fn vcount(&self) -> u16 {
+        self.vcount
+    }
+
+    /// Number of frames completed (i.e. number of times VBlank has been entered).
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Whether forced blank was on for every visible line of the last frame completed, so that
+    /// nothing was displayed.
+    pub fn lcd_off(&self) -> bool {
+        self.lcd_off
+    }
+
+    /// The parts of video memory written between the starts of the last two VBlanks, taken from
+    /// the memory when the last one started. Debug viewers can use it to only decode what changed.
+    pub fn video_dirty(&self) -> &VideoDirty {
+        &self.video_dirty
+    }
+
+    pub fn framebuffer(&self) -> &FrameBuffer {
+        &self.framebuffer
+    }
+
+    /// The framebuffer as a flat slice of pixels, line by line.
+    pub fn framebuffer_pixels(&self) -> &[u16] {
+        let framebuffer: &FrameBuffer = &self.framebuffer;
+        // Nested arrays have no padding, so this is the same memory as the 2D array
+        unsafe {
+            ::std::slice::from_raw_parts(
+                framebuffer.as_ptr() as *const u16,
+                SCREEN_WIDTH * SCREEN_HEIGHT,
+            )
+        }
+    }
+
+    /// Saves the state of the PPU at time `now`.
+    pub fn save_state(&self, lcd_regs: &LcdControllerRegs, now: u64) -> PpuState {
+        PpuState {
+            vcount: self.vcount,
+            phase: self.phase,
+            cycles_left: self.phase_end(now) - now,
+            frame_count: self.frame_count,
+            framebuffer: self.framebuffer.clone(),
+            displayed_lines: self.displayed_lines,
+            lcd_off: self.lcd_off,
+            lcd_status: lcd_regs.status(),
+        }
+    }
+
+    /// Loads a state saved with `save_state`, at time `now`. The task only notices when it wakes
+    /// up, so it has to be woken up right away unless it hasn't started waiting yet, which
+    /// `System::restore_ppu_state` takes care of.
+    pub fn restore_state(&mut self, lcd_regs: &mut LcdControllerRegs, state: &PpuState, now: u64) {
+        self.vcount = state.vcount;
+        self.phase = state.phase;
+        self.phase_end = Some(now + state.cycles_left);
+        self.frame_count = state.frame_count;
+        self.framebuffer = state.framebuffer.clone();
+        self.displayed_lines = state.displayed_lines;
+        self.lcd_off = state.lcd_off;
+        lcd_regs.restore_status(state.vcount, &state.lcd_status);
+    }
+
+    /// When the current phase ends, starting it at `now` if the task hasn't started waiting for
+    /// it.
+    fn phase_end(&self, now: u64) -> u64 {
+        self.phase_end.unwrap_or_else(|| match self.phase {
+            LinePhase::HDraw => now + self.timing.hdraw,
+            LinePhase::HBlank => now + self.timing.hblank,
+        })
+    }
+
+    /// Renders each visible line when it enters HBlank, and advances VCOUNT at the end of it.
+    /// Lines are rendered with the registers as they are at that point, so raster effects that
+    /// change them during HBlank, like HBlank DMAs, apply from the next line on. While the system
+    /// is in Stop mode, the PPU stops at the start of the next line.
+    ///
+    /// The position within the frame is kept in the PPU, so that the task resumes from a restored
+    /// state.
+    pub fn run_task(
+        ppu: Rc<RefCell<Ppu>>,
+        lcd_regs: Rc<RefCell<LcdControllerRegs>>,
+        memory: Rc<RefCell<Memory>>,
+        dma: Rc<RefCell<DmaController>>,
+        interrupts: Rc<InterruptController>,
+        clock: Rc<Clock>,
+    ) -> impl Task<'static, Return = ()> {
+        GeneratorTask::new(move || loop {
+            let line_start = {
+                let ppu = ppu.borrow();
+                ppu.phase == LinePhase::HDraw && ppu.phase_end.is_none()
+            };
+            if line_start {
+                while interrupts.power_state() == PowerState::Stopped {
+                    clock.announce_event(EventSource::Ppu, clock.now() + STOPPED_POLL_CYCLES);
+                    wait_cycles!(STOPPED_POLL_CYCLES);
+                }
+            }
+
+            let phase_end = {
+                let mut ppu = ppu.borrow_mut();
+                let phase_end = ppu.phase_end(clock.now());
+                ppu.phase_end = Some(phase_end);
+                phase_end
+            };
+            if clock.now() < phase_end {
+                clock.announce_event(EventSource::Ppu, phase_end);
+                wait_cycles!(phase_end - clock.now());
+                continue;
+            }
+
+            let mut ppu = ppu.borrow_mut();
+            ppu.phase_end = None;
+            match ppu.phase {
+                LinePhase::HDraw => {
+                    let screen_y = ppu.vcount;
+                    if (screen_y as usize) < SCREEN_HEIGHT {
+                        let mut memory = memory.borrow_mut();
+                        let (vram, pals, oam) = memory.video_memory();
+                        let overrides = ppu.layer_overrides;
+                        let mut lcd_regs = lcd_regs.borrow_mut();
+                        ppu.framebuffer[screen_y as usize] =
+                            render_lcd_line(screen_y, &lcd_regs, &overrides, vram, pals, oam);
+                        ppu.displayed_lines |= !lcd_regs.forced_blank_enabled;
+                        lcd_regs.advance_affine_reference_points();
+                        // HBlank DMAs only run during the visible lines
+                        dma.borrow_mut().trigger(StartTiming::HBlank);
+                    }
+                    lcd_regs
+                        .borrow_mut()
+                        .update_status(screen_y, true, &interrupts);
+                    ppu.phase = LinePhase::HBlank;
+                }
+                LinePhase::HBlank => {
+                    ppu.vcount = (ppu.vcount + 1) % LINES_PER_FRAME;
+                    if ppu.vcount as usize == SCREEN_HEIGHT {
+                        ppu.frame_count += 1;
+                        ppu.lcd_off = !ppu.displayed_lines;
+                        ppu.displayed_lines = false;
+                        let mut memory = memory.borrow_mut();
+                        ppu.video_dirty = VideoDirty {
+                            vram: memory.take_vram_dirty(),
+                            palettes: memory.take_palettes_dirty(),
+                            oam: memory.take_oam_dirty(),
+                        };
+                        dma.borrow_mut().trigger(StartTiming::VBlank);
+                        lcd_regs.borrow_mut().reload_affine_reference_points();
+                    }
+                    lcd_regs
+                        .borrow_mut()
+                        .update_status(ppu.vcount, false, &interrupts);
+                    ppu.phase = LinePhase::HDraw;
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test;
+    use test::Bencher;
+
+    #[test]
+    fn test_overlapping_windows() {
+        let mut regs = LcdControllerRegs::new();
+        // Mode 3, BG2, WIN0 and WIN1 enabled
+        regs.write(0x0400_0000, AccessWidth::Bit16, 0x6403);
+        // WIN0 covers (10, 10)-(50, 50) and WIN1 covers (30, 30)-(70, 70)
+        regs.write(0x0400_0040, AccessWidth::Bit16, 10 << 8 | 50);
+        regs.write(0x0400_0044, AccessWidth::Bit16, 10 << 8 | 50);
+        regs.write(0x0400_0042, AccessWidth::Bit16, 30 << 8 | 70);
+        regs.write(0x0400_0046, AccessWidth::Bit16, 30 << 8 | 70);
+        // BG2 is hidden inside WIN0, and shown inside WIN1 and outside
+        regs.write(0x0400_0048, AccessWidth::Bit16, 0x3F3B);
+        regs.write(0x0400_004A, AccessWidth::Bit16, 0x003F);
+        assert_eq!(regs.read(0x0400_0048), 0x3F3B);
+
+        assert_eq!(regs.window_control_for_pixel(40, 40), 0x3B);
+        assert_eq!(regs.window_control_for_pixel(60, 60), 0x3F);
+        assert_eq!(regs.window_control_for_pixel(20, 20), 0x3B);
+        assert_eq!(regs.window_control_for_pixel(100, 100), 0x3F);
+
+        let mut vram = vec![0; 96 * 1024];
+        for i in 0..240 * 160 {
+            LE::write_u16(&mut vram[i * 2..], 0x001F);
+        }
+        let mut pals = [0; 512];
+        let oam = [0; 512];
+        pals[0] = 0x7C00;
+
+        let line = render_lcd_line(40, &regs, &LayerOverrides::default(), &vram, &pals, &oam);
+        assert_eq!(line[5], 0x001F); // Outside
+        assert_eq!(line[20], 0x7C00); // WIN0 only
+        assert_eq!(line[40], 0x7C00); // Both, WIN0 has precedence
+        assert_eq!(line[60], 0x001F); // WIN1 only
+    }
+
+    #[test]
+    fn test_affine_reference_points() {
+        let mut regs = LcdControllerRegs::new();
+        // BG3 reference point at (-1.0, 2.5), moving by (-0.5, 1.0) each line
+        regs.write(0x0400_0032, AccessWidth::Bit16, 0xFF80);
+        regs.write(0x0400_0036, AccessWidth::Bit16, 0x0100);
+        regs.write(0x0400_0038, AccessWidth::Bit32, 0x0FFF_FF00);
+        regs.write(0x0400_003C, AccessWidth::Bit16, 0x0280);
+        regs.write(0x0400_003E, AccessWidth::Bit16, 0x0000);
+        assert_eq!(regs.status().affine_reference_points[1], (-0x100, 0x280));
+
+        for _ in 0..3 {
+            regs.advance_affine_reference_points();
+        }
+        assert_eq!(regs.status().affine_reference_points[1], (-0x280, 0x580));
+        assert_eq!(regs.status().affine_reference_points[0], (0, 0));
+
+        // Writing a byte of BGxX reloads the X coordinate, leaving the rest of the register alone
+        regs.write(0x0400_0039, AccessWidth::Bit8, 0xFE);
+        assert_eq!(regs.status().affine_reference_points[1], (-0x200, 0x580));
+
+        regs.reload_affine_reference_points();
+        assert_eq!(regs.status().affine_reference_points[1], (-0x200, 0x280));
+        // The registers are write-only
+        assert_eq!(regs.read(0x0400_0038), 0);
+    }
+
+    #[test]
+    fn test_bg_vram_wraps() {
+        let mut regs = LcdControllerRegs::new();
+        // Mode 0, BG0 enabled, 256 colors with char base 3 and map base 31, 512x512
+        regs.write(0x0400_0000, AccessWidth::Bit16, 0x0100);
+        regs.write(0x0400_0008, AccessWidth::Bit16, 0xDF8C);
+
+        let mut vram = vec![0; 96 * 1024];
+        // Tile 1000 at the top left of the map, whose pixels are past the end of BG VRAM and wrap
+        // to 0xC000 + 1000 * 64 - 0x10000 = 0xBA00
+        LE::write_u16(&mut vram[31 * 0x800..], 1000);
+        for byte in vram[0xBA00..0xBA40].iter_mut() {
+            *byte = 1;
+        }
+        // Screenblock 34 is past the end too, and wraps to screenblock 2
+        LE::write_u16(&mut vram[2 * 0x800..], 1000);
+        let mut pals = [0; 512];
+        let oam = [0; 512];
+        pals[0] = 0x7C00;
+        pals[1] = 0x001F;
+
+        let line = render_lcd_line(0, &regs, &LayerOverrides::default(), &vram, &pals, &oam);
+        assert_eq!(line[0], 0x001F);
+        assert_eq!(line[8], 0x7C00);
+
+        // Scrolled to the bottom right quarter, in screenblock 34
+        regs.write(0x0400_0010, AccessWidth::Bit16, 256);
+        regs.write(0x0400_0012, AccessWidth::Bit16, 256);
+        let line = render_lcd_line(0, &regs, &LayerOverrides::default(), &vram, &pals, &oam);
+        assert_eq!(line[0], 0x001F);
+        assert_eq!(line[8], 0x7C00);
+    }
+
+    #[test]
+    fn test_bitmap_page1_last_line() {
+        let mut vram = vec![0; 96 * 1024];
+        let mut pals = [0; 512];
+        let oam = [0; 512];
+        pals[0] = 0x7C00;
+        pals[1] = 0x001F;
+
+        // Mode 4, page 1, BG2 enabled. The last pixel of the page is the last byte before OBJ
+        // tiles.
+        let mut regs = LcdControllerRegs::new();
+        regs.write(0x0400_0000, AccessWidth::Bit16, 0x0414);
+        vram[0xA000 + 159 * 240 + 239] = 1;
+        vram[159 * 240 + 238] = 1;
+        let line = render_lcd_line(159, &regs, &LayerOverrides::default(), &vram, &pals, &oam);
+        assert_eq!(line[239], 0x001F);
+        assert_eq!(line[238], 0x7C00);
+
+        // Mode 5, page 1. The last pixel of the 160x128 frame ends at the end of bitmap VRAM.
+        regs.write(0x0400_0000, AccessWidth::Bit16, 0x0415);
+        assert_eq!(0xA000 + (127 * 160 + 159) * 2 + 2, BITMAP_VRAM_SIZE);
+        LE::write_u16(&mut vram[0xA000 + (127 * 160 + 159) * 2..], 0x03E0);
+        let line = render_lcd_line(127, &regs, &LayerOverrides::default(), &vram, &pals, &oam);
+        assert_eq!(line[159], 0x03E0);
+        assert_eq!(line[160], 0x7C00);
+        // Lines below the frame show the backdrop
+        let line = render_lcd_line(159, &regs, &LayerOverrides::default(), &vram, &pals, &oam);
+        assert!(line.iter().all(|&pixel| pixel == 0x7C00));
+    }
+
+    #[test]
+    fn test_invalid_video_mode() {
+        let mut regs = LcdControllerRegs::new();
+        // Mode 7, all BGs enabled
+        regs.write(0x0400_0000, AccessWidth::Bit16, 0x0F07);
+        // The mode reads back as written
+        assert_eq!(regs.read(0x0400_0000), 0x0F07);
+
+        let vram = vec![0x55; 96 * 1024];
+        let mut pals = [0x1234; 512];
+        let oam = [0; 512];
+        pals[0] = 0x7C00;
+
+        let line = render_lcd_line(0, &regs, &LayerOverrides::default(), &vram, &pals, &oam);
+        assert!(line.iter().all(|&pixel| pixel == 0x7C00));
+    }
+
+    #[test]
+    fn test_force_disable_bg() {
+        let mut regs = LcdControllerRegs::new();
+        // Mode 0, BG0 and BG1 enabled. Both use tile 0, which is filled with color 1. BG0 has
+        // priority 0 and uses palette 0, BG1 has priority 1 and uses palette 1.
+        regs.write(0x0400_0000, AccessWidth::Bit16, 0x0300);
+        regs.write(0x0400_0008, AccessWidth::Bit16, 0x0100);
+        regs.write(0x0400_000A, AccessWidth::Bit16, 0x0201);
+
+        let mut vram = vec![0; 96 * 1024];
+        for byte in vram[..32].iter_mut() {
+            *byte = 0x11;
+        }
+        for i in 0..32 * 32 {
+            LE::write_u16(&mut vram[0x1000 + i * 2..], 0x1000);
+        }
+        let mut pals = [0; 512];
+        let oam = [0; 512];
+        pals[0] = 0x7C00;
+        pals[1] = 0x001F;
+        pals[16 + 1] = 0x03E0;
+
+        let mut overrides = LayerOverrides::default();
+        let line = render_lcd_line(0, &regs, &overrides, &vram, &pals, &oam);
+        assert_eq!(line[0], 0x001F);
+
+        overrides.force_disable_bg[0] = true;
+        let line = render_lcd_line(0, &regs, &overrides, &vram, &pals, &oam);
+        assert_eq!(line[0], 0x03E0);
+
+        // The game's settings are untouched
+        assert_eq!(regs.read(0x0400_0000), 0x0300);
+    }
+
+    #[test]
+    fn test_scroll_registers_write_only() {
+        let mut regs = LcdControllerRegs::new();
+        regs.write(0x0400_0010, AccessWidth::Bit16, 0xFFFF);
+        regs.write(0x0400_001E, AccessWidth::Bit16, 0x01FA);
+        assert_eq!(regs.bg_attributes[0].x_scroll, 0x1FF);
+        assert_eq!(regs.bg_attributes[3].y_scroll, 0x1FA);
+        for address in (0x0400_0010..0x0400_0020).step_by(2) {
+            assert_eq!(regs.read(address), 0);
+        }
+    }
+
+    #[test]
+    fn test_write_widths() {
+        let mut regs = LcdControllerRegs::new();
+        regs.write(0x0400_0000, AccessWidth::Bit16, 0x0403);
+        // Byte writes only modify their half of the register
+        regs.write(0x0400_0001, AccessWidth::Bit8, 0x1F);
+        assert_eq!(regs.read(0x0400_0000), 0x1F03);
+        regs.write(0x0400_0000, AccessWidth::Bit8, 0x05);
+        assert_eq!(regs.read(0x0400_0000), 0x1F05);
+        // Halfword writes are force-aligned
+        regs.write(0x0400_0001, AccessWidth::Bit16, 0x0403);
+        assert_eq!(regs.read(0x0400_0000), 0x0403);
+
+        // 32-bit writes set both BG0CNT and BG1CNT, even from an unaligned address
+        regs.write(0x0400_0008, AccessWidth::Bit32, 0x1E05_1C84);
+        assert_eq!(regs.read(0x0400_0008), 0x1C84);
+        assert_eq!(regs.read(0x0400_000A), 0x1E05);
+        regs.write(0x0400_000A, AccessWidth::Bit32, 0x0001_0002);
+        assert_eq!(regs.read(0x0400_0008), 0x0002);
+        assert_eq!(regs.read(0x0400_000A), 0x0001);
+        regs.write(0x0400_000B, AccessWidth::Bit8, 0x1F);
+        assert_eq!(regs.read(0x0400_0008), 0x0002);
+        assert_eq!(regs.read(0x0400_000A), 0x1F01);
+
+        // Byte writes to write-only registers keep the other half too
+        regs.write(0x0400_0010, AccessWidth::Bit16, 0x0123);
+        regs.write(0x0400_0010, AccessWidth::Bit8, 0x45);
+        assert_eq!(regs.bg_attributes[0].x_scroll, 0x0145);
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut regs = LcdControllerRegs::new();
+        // Mode 1, BG0 and BG2 enabled
+        regs.write(0x0400_0000, AccessWidth::Bit16, 0x0501);
+        // BG0 at priority 3, 256 colors, chars at block 1, map at block 2
+        regs.write(0x0400_0008, AccessWidth::Bit16, 0x0287);
+        regs.write(0x0400_0010, AccessWidth::Bit16, 10);
+        regs.write(0x0400_0012, AccessWidth::Bit16, 300);
+        assert_eq!(
+            regs.to_string(),
+            "Mode 1, page 0, forced blank off, OBJ off, VCOUNT 0\n\
+             BG  Enabled  Priority  Chars     Map       Colors  Scroll\n\
+             0   on       3         06004000  06001000  256     10, 300\n\
+             1   off      0         06000000  06000000  16x16   0, 0\n\
+             2   on       0         06000000  06000000  16x16   0, 0\n\
+             3   off      0         06000000  06000000  16x16   0, 0\n"
+        );
+    }
+
+    #[test]
+    fn test_text_bg_sizes() {
+        // Screenblocks 8-11 are filled with tiles 1-4, which show colors 1-4 respectively
+        let mut vram = vec![0; 96 * 1024];
+        for tile in 1..5 {
+            for byte in vram[tile * 32..(tile + 1) * 32].iter_mut() {
+                *byte = tile as u8 * 0x11;
+            }
+            let screenblock_base = (8 + tile - 1) * 0x800;
+            for i in 0..32 * 32 {
+                LE::write_u16(&mut vram[screenblock_base + i * 2..], tile as u16);
+            }
+        }
+        let mut pals = [0; 512];
+        let oam = [0; 512];
+        pals[1..5].copy_from_slice(&[0x0001, 0x0002, 0x0003, 0x0004]);
+
+        // Screenblock used at (0, 0), (6, 0), (0, 6) and (6, 6) on the screen, with both scrolls at
+        // 250, and at (0, 0) and (12, 0) with x scrolled to 500
+        let cases: [(u32, [u16; 4], [u16; 2]); 4] = [
+            (0, [1, 1, 1, 1], [1, 1]),
+            (1, [1, 2, 1, 2], [2, 1]),
+            (2, [1, 1, 2, 2], [1, 1]),
+            (3, [1, 2, 3, 4], [2, 1]),
+        ];
+        for &(size_mode, expected, expected_x_wrap) in &cases {
+            let mut regs = LcdControllerRegs::new();
+            // Mode 0, BG0 enabled, map at screenblock 8
+            regs.write(0x0400_0000, AccessWidth::Bit16, 0x0100);
+            regs.write(0x0400_0008, AccessWidth::Bit16, size_mode << 14 | 8 << 8);
+            regs.write(0x0400_0010, AccessWidth::Bit16, 250);
+            regs.write(0x0400_0012, AccessWidth::Bit16, 250);
+            let overrides = LayerOverrides::default();
+            let line = render_lcd_line(0, &regs, &overrides, &vram, &pals, &oam);
+            assert_eq!([line[0], line[6]], expected[0..2], "size {}", size_mode);
+            let line = render_lcd_line(6, &regs, &overrides, &vram, &pals, &oam);
+            assert_eq!([line[0], line[6]], expected[2..4], "size {}", size_mode);
+
+            regs.write(0x0400_0010, AccessWidth::Bit16, 500);
+            let line = render_lcd_line(0, &regs, &overrides, &vram, &pals, &oam);
+            assert_eq!([line[0], line[12]], expected_x_wrap, "size {}", size_mode);
+        }
+    }
+
+    #[test]
+    fn test_obj_mapping() {
+        let mut regs = LcdControllerRegs::new();
+        // Bit 6 selects 1D mapping, independently of the HBlank interval free bit
+        regs.write(0x0400_0000, AccessWidth::Bit16, 0x0060);
+        assert_eq!(regs.read(0x0400_0000), 0x0060);
+        assert!(regs.obj_mapping_1d && regs.hblank_interval_free);
+
+        // The second row of a 16x16 sprite follows the first in 1D, and is a row of 32 tiles down
+        // in 2D. Tiles in the same row are next to each other in both.
+        assert_eq!(regs.obj_tile(4, 1, 0, 2, false), 5);
+        assert_eq!(regs.obj_tile(4, 0, 1, 2, false), 6);
+        assert_eq!(regs.obj_tile(4, 1, 1, 2, true), 10);
+        regs.write(0x0400_0000, AccessWidth::Bit16, 0x0000);
+        assert!(!regs.obj_mapping_1d);
+        assert_eq!(regs.obj_tile(4, 1, 0, 2, false), 5);
+        assert_eq!(regs.obj_tile(4, 0, 1, 2, false), 36);
+        assert_eq!(regs.obj_tile(4, 1, 1, 2, true), 38);
+        // Tile numbers wrap around the end of OBJ VRAM
+        assert_eq!(regs.obj_tile(1020, 0, 1, 2, false), 28);
+    }
+
+    /// Sets up BG0 with priority `bg_priority`, showing color 1 everywhere, and the OBJ layer, with
+    /// OBJ tile 1 filled with color 1.
+    fn setup_obj_and_bg(bg_priority: u32) -> (LcdControllerRegs, Vec<u8>) {
+        let mut regs = LcdControllerRegs::new();
+        // Mode 0, BG0 and OBJ enabled
+        regs.write(0x0400_0000, AccessWidth::Bit16, 0x1100);
+        regs.write(0x0400_0008, AccessWidth::Bit16, 0x0200 | bg_priority);
+
+        let mut vram = vec![0; 96 * 1024];
+        for byte in vram[..32].iter_mut() {
+            *byte = 0x11;
+        }
+        for byte in vram[0x10020..0x10040].iter_mut() {
+            *byte = 0x11;
+        }
+        (regs, vram)
+    }
+
+    /// OAM attributes for an 8x8 sprite using OBJ tile 1.
+    fn sprite_8x8(x: u16, priority: u16, pal_bank: u16) -> [u16; 4] {
+        [0, x, 1 | priority << 10 | pal_bank << 12, 0]
+    }
+
+    #[test]
+    fn test_obj_bg_priority() {
+        let mut pals = [0; 512];
+        pals[1] = 0x001F;
+        pals[256 + 1] = 0x03E0;
+        let mut oam = [0; 512];
+        oam[..4].copy_from_slice(&sprite_8x8(0, 1, 0));
+        // Hide the rest of the sprites
+        for attrs in oam[4..].chunks_mut(4) {
+            attrs[0] = 1 << 9;
+        }
+
+        // A BG with the same priority is behind the OBJ, and one with a better priority in front
+        for &(bg_priority, color) in &[(0, 0x001F), (1, 0x03E0), (2, 0x03E0)] {
+            let (regs, vram) = setup_obj_and_bg(bg_priority);
+            let line = render_lcd_line(0, &regs, &LayerOverrides::default(), &vram, &pals, &oam);
+            assert_eq!(line[0], color, "BG priority {}", bg_priority);
+            assert_eq!(line[8], 0x001F);
+        }
+    }
+
+    #[test]
+    fn test_obj_priority_quirk() {
+        let mut pals = [0; 512];
+        pals[0] = 0x7C00;
+        pals[1] = 0x001F;
+        pals[256 + 1] = 0x03E0;
+        pals[256 + 16 + 1] = 0x7FFF;
+        let mut oam = [0; 512];
+        // Sprite A at 0-7, with the worst priority. Sprite B at 4-11, with the best priority and
+        // using palette bank 1.
+        oam[..4].copy_from_slice(&sprite_8x8(0, 3, 0));
+        oam[4..8].copy_from_slice(&sprite_8x8(4, 0, 1));
+        for attrs in oam[8..].chunks_mut(4) {
+            attrs[0] = 1 << 9;
+        }
+
+        let (mut regs, vram) = setup_obj_and_bg(1);
+        let line = render_lcd_line(0, &regs, &LayerOverrides::default(), &vram, &pals, &oam);
+        // A only, behind the BG
+        assert_eq!(line[0], 0x001F);
+        // A wins the OBJ layer by OAM order, hiding B, and is then hidden by the BG
+        assert_eq!(line[4], 0x001F);
+        assert_eq!(line[7], 0x001F);
+        // B only, in front of the BG
+        assert_eq!(line[8], 0x7FFF);
+        assert_eq!(line[12], 0x001F);
+
+        // Without the BG, A is shown on top of B despite its priority
+        regs.write(0x0400_0000, AccessWidth::Bit16, 0x1000);
+        let line = render_lcd_line(0, &regs, &LayerOverrides::default(), &vram, &pals, &oam);
+        assert_eq!(line[4], 0x03E0);
+        assert_eq!(line[8], 0x7FFF);
+        assert_eq!(line[12], 0x7C00);
+    }
+
+    #[test]
+    fn test_palettes() {
+        let mut pals = [0; 512];
+        pals[5] = 0x001F;
+        pals[256 + 5] = 0x03E0;
+        let pals = Palettes::new(&pals);
+        assert_eq!(pals.bg(5), 0x001F);
+        assert_eq!(pals.obj(5), 0x03E0);
+    }
+
+    #[test]
+    fn test_dump_tiles() {
+        let mut vram = vec![0; 96 * 1024];
+        let mut pals = [0; 512];
+        pals[0] = 0x7C00;
+        pals[2 * 16] = 0x7FFF;
+        pals[2 * 16 + 1] = 0x001F;
+        pals[2 * 16 + 0xA] = 0x03E0;
+        // Tile 17 of char block 1 has color 1 in its top left pixel and color 0xA at the right
+        // end of its second row
+        let tile = 0x4000 + 17 * 32;
+        vram[tile] = 0x01;
+        vram[tile + 4 + 3] = 0xA0;
+
+        let sheet = dump_tiles(&vram, &pals, 1, BgPaletteMode::Pal16, 2);
+        assert_eq!(sheet.len(), TILE_SHEET_WIDTH * 256);
+        // Tile 17 is the second one of the second row
+        let pixel = |x: usize, y: usize| sheet[(8 + y) * TILE_SHEET_WIDTH + 8 + x];
+        assert_eq!(pixel(0, 0), 0x001F);
+        assert_eq!(pixel(1, 0), 0x7FFF);
+        assert_eq!(pixel(7, 1), 0x03E0);
+        assert_eq!(pixel(6, 1), 0x7FFF);
+
+        // BG0 maps tile 17 with bank 2 at the top left, flipped horizontally, then tile 0
+        let mut regs = LcdControllerRegs::new();
+        regs.write(0x0400_0008, AccessWidth::Bit16, 0x1004);
+        LE::write_u16(&mut vram[0x8000..], 0x2400 | 17);
+        let map = dump_bg_map(0, &regs, &vram, &pals);
+        assert_eq!((map.width, map.height), (256, 256));
+        assert_eq!(map.pixels[7], 0x001F);
+        assert_eq!(map.pixels[256], 0x03E0);
+        assert_eq!(map.pixels[0], 0x7C00);
+    }
+
+    #[test]
+    fn test_obj_palettes() {
+        let mut pals = [0; 512];
+        pals[0] = 0x7C00;
+        pals[1] = 0x0001;
+        // Color 0 is transparent, whatever its value
+        pals[256] = 0x7FFF;
+        pals[256 + 1] = 0x0002;
+        pals[256 + 3 * 16 + 1] = 0x03E0;
+        pals[256 + 0x11] = 0x001F;
+        let (mut regs, vram) = setup_obj_and_bg(0);
+        // Only OBJ enabled
+        regs.write(0x0400_0000, AccessWidth::Bit16, 0x1000);
+
+        let mut oam = [0; 512];
+        for attrs in oam.chunks_mut(4) {
+            attrs[0] = 1 << 9;
+        }
+        // 16 colors from bank 3
+        oam[..4].copy_from_slice(&sprite_8x8(0, 0, 3));
+        // 256 colors, which ignore the bank. Tile 1 has pixels of color 0x11.
+        oam[4..8].copy_from_slice(&sprite_8x8(8, 0, 3));
+        oam[4] |= 1 << 13;
+        // 256 colors, using tile 2, which is blank
+        oam[8..12].copy_from_slice(&sprite_8x8(16, 0, 0));
+        oam[8] |= 1 << 13;
+        oam[10] += 1;
+
+        let line = render_lcd_line(0, &regs, &LayerOverrides::default(), &vram, &pals, &oam);
+        assert_eq!(line[0], 0x03E0);
+        assert_eq!(line[8], 0x001F);
+        assert_eq!(line[16], 0x7C00);
+    }
+
+    #[test]
+    fn test_obj_tiles_in_bitmap_modes() {
+        let mut pals = [0; 512];
+        pals[0] = 0x7C00;
+        pals[256 + 1] = 0x03E0;
+        let mut vram = vec![0; 96 * 1024];
+        // OBJ tiles 100 and 512 filled with color 1. In the bitmap modes, tile 100 is part of the
+        // bitmap instead.
+        for byte in vram[0x10000 + 100 * 32..][..32].iter_mut() {
+            *byte = 0x11;
+        }
+        for byte in vram[0x10000 + 512 * 32..][..32].iter_mut() {
+            *byte = 0x11;
+        }
+        let mut oam = [0; 512];
+        for attrs in oam.chunks_mut(4) {
+            attrs[0] = 1 << 9;
+        }
+        oam[..4].copy_from_slice(&[0, 0, 100, 0]);
+        oam[4..8].copy_from_slice(&[0, 8, 512, 0]);
+
+        let mut regs = LcdControllerRegs::new();
+        for &(mode, tile_100_color) in &[(0, 0x03E0), (3, 0x7C00), (4, 0x7C00), (5, 0x7C00)] {
+            // Only OBJ enabled
+            regs.write(0x0400_0000, AccessWidth::Bit16, 0x1000 | mode);
+            let line = render_lcd_line(0, &regs, &LayerOverrides::default(), &vram, &pals, &oam);
+            assert_eq!(line[0], tile_100_color, "mode {}", mode);
+            assert_eq!(line[8], 0x03E0, "mode {}", mode);
+        }
+    }
+
+    #[test]
+    fn test_obj_cycle_budget() {
+        let mut pals = [0; 512];
+        pals[0] = 0x7C00;
+        pals[256 + 1] = 0x03E0;
+        let (mut regs, vram) = setup_obj_and_bg(0);
+        let mut oam = [0; 512];
+        for attrs in oam.chunks_mut(4) {
+            attrs[0] = 1 << 9;
+        }
+
+        // 64x64 sprites, 64 cycles each, with the visible one after `offscreen` sprites that are
+        // on the line but not on screen
+        let render_with = |regs: &LcdControllerRegs, oam: &mut [u16; 512], offscreen: usize| {
+            for attrs in oam[..offscreen * 4].chunks_mut(4) {
+                attrs.copy_from_slice(&[0, 3 << 14 | 300, 1, 0]);
+            }
+            oam[offscreen * 4..][..4].copy_from_slice(&[0, 3 << 14, 1, 0]);
+            render_lcd_line(0, regs, &LayerOverrides::default(), &vram, &pals, &oam[..])[0]
+        };
+
+        // Only OBJ enabled. 1210 cycles fit 18 sprites.
+        regs.write(0x0400_0000, AccessWidth::Bit16, 0x1000);
+        assert_eq!(render_with(&regs, &mut oam, 17), 0x03E0);
+        assert_eq!(render_with(&regs, &mut oam, 18), 0x7C00);
+
+        // With HBlank interval free, 954 cycles fit 14 sprites
+        regs.write(0x0400_0000, AccessWidth::Bit16, 0x1020);
+        assert_eq!(regs.read(0x0400_0000), 0x1020);
+        assert_eq!(render_with(&regs, &mut oam, 13), 0x03E0);
+        assert_eq!(render_with(&regs, &mut oam, 14), 0x7C00);
+    }
+
+    /// Renders a mode 0 frame with all four backgrounds per iteration, scrolling them between
+    /// frames, so frames/sec is 1e9 / (ns/iter). VRAM is filled with pseudorandom tiles and maps,
+    /// so that every pixel looks up a different tile.
+    #[bench]
+    fn bench_render_mode0_frame(b: &mut Bencher) {
+        let mut regs = LcdControllerRegs::new();
+        // Mode 0, BG0-3 enabled
+        regs.write(0x0400_0000, AccessWidth::Bit16, 0x0F00);
+        for bg in 0..4 {
+            // 256x256, maps in screenblocks 28-31. BG3 uses 256 colors, from char base 1.
+            let bgcnt = match bg {
+                3 => 0x0080 | 1 << 2,
+                _ => 0x0000,
+            };
+            regs.write(
+                0x0400_0008 + bg * 2,
+                AccessWidth::Bit16,
+                bgcnt | bg as u32 | (28 + bg) << 8,
+            );
+        }
+
+        let mut vram = vec![0; 96 * 1024];
+        let mut state: u32 = 1;
+        for byte in vram.iter_mut() {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            *byte = (state >> 16) as u8;
+        }
+        // Keep the tile numbers within the tiles that were filled in
+        for entry in 0..4 * 0x400 {
+            let offset = 28 * 0x800 + entry * 2;
+            let map_entry = LE::read_u16(&vram[offset..]) & !0x0200;
+            LE::write_u16(&mut vram[offset..], map_entry);
+        }
+        let mut pals = [0; 512];
+        let oam = [0; 512];
+        for (i, color) in pals.iter_mut().enumerate() {
+            *color = (i as u16).wrapping_mul(0x0421) & 0x7FFF;
+        }
+
+        let overrides = LayerOverrides::default();
+        let mut scroll = 0;
+        b.iter(|| {
+            scroll += 1;
+            for bg in 0..4 {
+                regs.write(0x0400_0010 + bg * 4, AccessWidth::Bit16, scroll * (bg + 1));
+                regs.write(0x0400_0012 + bg * 4, AccessWidth::Bit16, scroll * (4 - bg));
+            }
+            for y in 0..SCREEN_HEIGHT as u16 {
+                test::black_box(render_lcd_line(y, &regs, &overrides, &vram, &pals, &oam));
+            }
+        });
+    }
+}
@@ -0,0 +1,74 @@
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use byteorder::LE;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+/// Input recordings start with this, followed by the KEYINPUT value of each frame as a LE u16.
+const MAGIC: &[u8; 4] = b"AVIR";
+
+pub struct InputRecorder {
+    writer: BufWriter<File>,
+}
+
+impl InputRecorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<InputRecorder> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        Ok(InputRecorder { writer })
+    }
+
+    pub fn record_frame(&mut self, keyinput: u16) -> io::Result<()> {
+        self.writer.write_u16::<LE>(keyinput)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub struct InputPlayer {
+    frames: Vec<u16>,
+    position: usize,
+}
+
+impl InputPlayer {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<InputPlayer> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an input recording",
+            ));
+        }
+
+        let mut frames = Vec::new();
+        loop {
+            match reader.read_u16::<LE>() {
+                Ok(keyinput) => frames.push(keyinput),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(InputPlayer {
+            frames,
+            position: 0,
+        })
+    }
+
+    /// Returns the input for the next frame, or None if the recording is over.
+    pub fn next_frame(&mut self) -> Option<u16> {
+        let keyinput = self.frames.get(self.position).cloned();
+        self.position += 1;
+        keyinput
+    }
+}
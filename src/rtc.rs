@@ -0,0 +1,316 @@
+//! Seiko S-3511 real-time clock, connected to the cart GPIO pins.
+//!
+//! The clock is accessed through a bit-banged serial protocol. A transfer starts when CS goes high,
+//! and then bits are clocked on the rising edges of SCK, LSB first. The first byte is the command,
+//! which has the fixed code 0110 in its low nibble, the command number in bits 4-6, and is a read
+//! if bit 7 is set. Games send it MSB first, so it's seen here with its bits reversed.
+
+use gpio::GpioDevice;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+pub const PIN_SCK: u8 = 1 << 0;
+pub const PIN_SIO: u8 = 1 << 1;
+pub const PIN_CS: u8 = 1 << 2;
+
+const COMMAND_RESET: u8 = 0;
+const COMMAND_DATE_TIME: u8 = 2;
+const COMMAND_CONTROL: u8 = 4;
+const COMMAND_TIME: u8 = 6;
+
+/// Control register bit selecting 24-hour mode instead of 12-hour mode.
+const CONTROL_24_HOUR: u8 = 0x40;
+
+/// Date and time, in their regular (not BCD) values.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DateTime {
+    /// Year since 2000, 0-99.
+    pub year: u8,
+    pub month: u8,
+    pub day: u8,
+    /// Day of the week, with 0 being Sunday.
+    pub weekday: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Converts a Unix timestamp to a date and time in UTC.
+    pub fn from_unix_time(timestamp: u64) -> DateTime {
+        let days = (timestamp / 86400) as i64;
+        let seconds_of_day = timestamp % 86400;
+
+        // Civil date from days since the epoch, from Howard Hinnant's date algorithms
+        let z = days + 719468;
+        let era = z / 146097;
+        let day_of_era = z - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+        DateTime {
+            year: (year % 100) as u8,
+            month: month as u8,
+            day: day as u8,
+            // 1970-01-01 was a Thursday
+            weekday: ((days + 4) % 7) as u8,
+            hour: (seconds_of_day / 3600) as u8,
+            minute: (seconds_of_day / 60 % 60) as u8,
+            second: (seconds_of_day % 60) as u8,
+        }
+    }
+
+    pub fn now() -> DateTime {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        DateTime::from_unix_time(timestamp)
+    }
+}
+
+fn bcd(x: u8) -> u8 {
+    (x / 10) << 4 | x % 10
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum TransferState {
+    /// CS is low.
+    Inactive,
+    /// Receiving the command byte.
+    Command,
+    /// Sending or receiving the data bytes of a command.
+    Data { command: u8, read: bool },
+}
+
+pub struct Rtc {
+    clock: Box<Fn() -> DateTime>,
+    control: u8,
+
+    state: TransferState,
+    sck: bool,
+    sio_out: bool,
+    shift: u8,
+    bits: u8,
+    /// Bytes to be sent, or that were received, for the current command.
+    buffer: [u8; 7],
+    buffer_pos: usize,
+    buffer_len: usize,
+}
+
+impl Rtc {
+    /// Creates a clock which reports the host time.
+    pub fn new() -> Rtc {
+        Rtc::with_clock(Box::new(DateTime::now))
+    }
+
+    /// Creates a clock which reports the time returned by `clock`.
+    pub fn with_clock(clock: Box<Fn() -> DateTime>) -> Rtc {
+        Rtc {
+            clock,
+            control: CONTROL_24_HOUR,
+            state: TransferState::Inactive,
+            sck: false,
+            sio_out: false,
+            shift: 0,
+            bits: 0,
+            buffer: [0; 7],
+            buffer_pos: 0,
+            buffer_len: 0,
+        }
+    }
+
+    fn receive_byte(&mut self, byte: u8) {
+        match self.state {
+            TransferState::Command => {
+                if byte & 0xF != 0b0110 {
+                    println!("Invalid RTC command byte: 0x{:02X}", byte);
+                    return;
+                }
+                let command = bit!(byte[4:6]);
+                let read = bit!(byte[7]) != 0;
+                self.start_command(command, read);
+            }
+            TransferState::Data {
+                command,
+                read: false,
+            } => {
+                if command == COMMAND_CONTROL {
+                    self.control = byte;
+                }
+                // TODO: Setting the date and time
+            }
+            _ => {}
+        }
+    }
+
+    fn start_command(&mut self, command: u8, read: bool) {
+        self.buffer_pos = 0;
+        self.buffer_len = 0;
+        match command {
+            COMMAND_RESET => self.control = 0,
+            COMMAND_DATE_TIME | COMMAND_TIME => {
+                let now = (self.clock)();
+                let hour = if self.control & CONTROL_24_HOUR != 0 {
+                    bcd(now.hour)
+                } else {
+                    bcd(now.hour % 12) | (now.hour >= 12) as u8 * 0x80
+                };
+                let date_time = [
+                    bcd(now.year),
+                    bcd(now.month),
+                    bcd(now.day),
+                    bcd(now.weekday),
+                    hour,
+                    bcd(now.minute),
+                    bcd(now.second),
+                ];
+                // The time command only returns the last 3 bytes
+                let start = if command == COMMAND_TIME { 4 } else { 0 };
+                self.buffer_len = date_time.len() - start;
+                self.buffer[..self.buffer_len].copy_from_slice(&date_time[start..]);
+            }
+            COMMAND_CONTROL => {
+                self.buffer[0] = self.control;
+                self.buffer_len = 1;
+            }
+            _ => println!("Unsupported RTC command: {}", command),
+        }
+        self.state = TransferState::Data { command, read };
+    }
+}
+
+impl GpioDevice for Rtc {
+    fn write_pins(&mut self, pins: u8) {
+        let sck = pins & PIN_SCK != 0;
+        let sio = pins & PIN_SIO != 0;
+        let cs = pins & PIN_CS != 0;
+
+        let rising_edge = sck && !self.sck;
+        self.sck = sck;
+
+        if !cs {
+            self.state = TransferState::Inactive;
+            return;
+        }
+        if self.state == TransferState::Inactive {
+            self.state = TransferState::Command;
+            self.shift = 0;
+            self.bits = 0;
+        }
+        if !rising_edge {
+            return;
+        }
+
+        match self.state {
+            TransferState::Data { read: true, .. } => {
+                if self.buffer_pos < self.buffer_len {
+                    let byte = self.buffer[self.buffer_pos];
+                    self.sio_out = byte >> self.bits & 1 != 0;
+                }
+                self.bits += 1;
+                if self.bits == 8 {
+                    self.bits = 0;
+                    self.buffer_pos += 1;
+                }
+            }
+            _ => {
+                self.shift |= (sio as u8) << self.bits;
+                self.bits += 1;
+                if self.bits == 8 {
+                    let byte = self.shift;
+                    self.shift = 0;
+                    self.bits = 0;
+                    self.receive_byte(byte);
+                }
+            }
+        }
+    }
+
+    /// SIO is only driven while CS is high, so other devices can use it.
+    fn read_pins(&self) -> u8 {
+        if self.state != TransferState::Inactive && self.sio_out {
+            PIN_SIO
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_clock() -> DateTime {
+        // Friday 2024-03-15 13:45:30
+        DateTime::from_unix_time(1710510330)
+    }
+
+    /// Drives the pins like games do, sending `command` MSB first and then reading `len` bytes
+    /// LSB first.
+    fn read_command(rtc: &mut Rtc, command: u8, len: usize) -> Vec<u8> {
+        rtc.write_pins(PIN_SCK);
+        rtc.write_pins(PIN_SCK | PIN_CS);
+        for i in (0..8).rev() {
+            let sio = (command >> i & 1) * PIN_SIO;
+            rtc.write_pins(PIN_CS | sio);
+            rtc.write_pins(PIN_CS | PIN_SCK | sio);
+        }
+
+        let mut result = Vec::new();
+        for _ in 0..len {
+            let mut byte = 0;
+            for i in 0..8 {
+                rtc.write_pins(PIN_CS);
+                rtc.write_pins(PIN_CS | PIN_SCK);
+                byte |= (rtc.read_pins() & PIN_SIO) >> 1 << i;
+            }
+            result.push(byte);
+        }
+        rtc.write_pins(PIN_SCK);
+        result
+    }
+
+    #[test]
+    fn test_unix_time_conversion() {
+        assert_eq!(
+            test_clock(),
+            DateTime {
+                year: 24,
+                month: 3,
+                day: 15,
+                weekday: 5,
+                hour: 13,
+                minute: 45,
+                second: 30,
+            }
+        );
+        assert_eq!(DateTime::from_unix_time(951782400).day, 29); // 2000-02-29
+        assert_eq!(DateTime::from_unix_time(0).weekday, 4);
+    }
+
+    #[test]
+    fn test_read_date_time() {
+        let mut rtc = Rtc::with_clock(Box::new(test_clock));
+        assert_eq!(
+            read_command(&mut rtc, 0x65, 7),
+            [0x24, 0x03, 0x15, 0x05, 0x13, 0x45, 0x30]
+        );
+        assert_eq!(read_command(&mut rtc, 0x67, 3), [0x13, 0x45, 0x30]);
+        assert_eq!(read_command(&mut rtc, 0x63, 1), [0x40]);
+    }
+
+    #[test]
+    fn test_reset_and_12_hour_mode() {
+        let mut rtc = Rtc::with_clock(Box::new(test_clock));
+        // Reset clears the control register, which selects 12-hour mode
+        read_command(&mut rtc, 0x60, 0);
+        assert_eq!(read_command(&mut rtc, 0x63, 1), [0x00]);
+        assert_eq!(read_command(&mut rtc, 0x67, 3), [0x81, 0x45, 0x30]);
+    }
+}
@@ -0,0 +1,117 @@
+//! Persistence of the cart's save memory in a `.sav` file next to the ROM.
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Frames to wait after the last write to save memory before flushing it. Games write saves in
+/// bursts, which this turns into a single flush.
+pub const FLUSH_DELAY_FRAMES: u32 = 60;
+
+pub struct SaveFile {
+    path: PathBuf,
+    /// Frames since the last write to save memory, while there are changes not flushed yet.
+    dirty_frames: Option<u32>,
+}
+
+impl SaveFile {
+    pub fn new<P: Into<PathBuf>>(path: P) -> SaveFile {
+        SaveFile {
+            path: path.into(),
+            dirty_frames: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty_frames.is_some()
+    }
+
+    /// Loads the file into `save_memory`, if it exists. Files of other sizes are loaded as far as
+    /// they go, with the rest left as is. Returns whether there was a file to load.
+    pub fn load(&self, save_memory: &mut [u8]) -> io::Result<bool> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let len = data.len().min(save_memory.len());
+        save_memory[..len].copy_from_slice(&data[..len]);
+        Ok(true)
+    }
+
+    /// Writes `save_memory` to the file. It's written to a temporary file first, which then
+    /// replaces the old one, so that a crash halfway through can't leave a corrupted save behind.
+    pub fn write(&mut self, save_memory: &[u8]) -> io::Result<()> {
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        {
+            let mut file = File::create(&temp_path)?;
+            file.write_all(save_memory)?;
+            file.sync_all()?;
+        }
+        fs::rename(&temp_path, &self.path)?;
+        self.dirty_frames = None;
+        Ok(())
+    }
+
+    /// Advances the flush timer by a frame, restarting it if save memory was `written` during the
+    /// frame. Returns true once it's time to flush.
+    pub fn update(&mut self, written: bool) -> bool {
+        self.dirty_frames = if written {
+            Some(0)
+        } else {
+            self.dirty_frames.map(|frames| frames.saturating_add(1))
+        };
+        // Only signaled once, so that a failing flush isn't retried every frame
+        self.dirty_frames == Some(FLUSH_DELAY_FRAMES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_flush_timer() {
+        let mut save_file = SaveFile::new("unused.sav");
+        assert!(!save_file.update(false));
+        assert!(!save_file.update(true));
+        for _ in 1..FLUSH_DELAY_FRAMES {
+            assert!(!save_file.update(false));
+        }
+        // Another write restarts the timer
+        assert!(!save_file.update(true));
+        for _ in 1..FLUSH_DELAY_FRAMES {
+            assert!(!save_file.update(false));
+        }
+        assert!(save_file.update(false));
+    }
+
+    #[test]
+    fn test_write_and_load() {
+        let path = env::temp_dir().join("advance_test_save_file.sav");
+        let _ = fs::remove_file(&path);
+        let mut save_file = SaveFile::new(&path);
+
+        let mut data = [0xFF; 4];
+        assert!(!save_file.load(&mut data).unwrap());
+        save_file.update(true);
+        save_file.write(&[1, 2, 3]).unwrap();
+        assert!(!save_file.is_dirty());
+
+        assert!(save_file.load(&mut data).unwrap());
+        assert_eq!(data, [1, 2, 3, 0xFF]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+//! Placement of the LCD image in a frontend's window, for the supported ways of upscaling it.
+
+use ppu::SCREEN_HEIGHT;
+use ppu::SCREEN_WIDTH;
+
+const LCD_WIDTH: u32 = SCREEN_WIDTH as u32;
+const LCD_HEIGHT: u32 = SCREEN_HEIGHT as u32;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Scaling {
+    /// Scales by the largest whole factor that fits, with nearest neighbor filtering, so that all
+    /// pixels are the same size. The rest of the window is left as a black border.
+    Integer,
+    /// Fills as much of the window as possible while keeping the aspect ratio, with bilinear
+    /// filtering.
+    Bilinear,
+}
+
+impl Scaling {
+    pub const ALL: [Scaling; 2] = [Scaling::Integer, Scaling::Bilinear];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scaling::Integer => "integer",
+            Scaling::Bilinear => "bilinear",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Scaling> {
+        Scaling::ALL
+            .iter()
+            .cloned()
+            .find(|scaling| scaling.name() == name)
+    }
+
+    /// The mode after this one, for cycling through all of them.
+    pub fn next(self) -> Scaling {
+        match self {
+            Scaling::Integer => Scaling::Bilinear,
+            Scaling::Bilinear => Scaling::Integer,
+        }
+    }
+
+    pub fn uses_linear_filtering(self) -> bool {
+        self == Scaling::Bilinear
+    }
+
+    /// Returns where to draw the LCD image in a window of the given size.
+    pub fn viewport(self, window_width: u32, window_height: u32) -> Viewport {
+        let (width, height) = match self {
+            Scaling::Integer => {
+                let scale = integer_scale(window_width, window_height);
+                (LCD_WIDTH * scale, LCD_HEIGHT * scale)
+            }
+            // Compare the aspect ratios without dividing, to avoid rounding
+            Scaling::Bilinear if window_width * LCD_HEIGHT <= window_height * LCD_WIDTH => {
+                (window_width, window_width * LCD_HEIGHT / LCD_WIDTH)
+            }
+            Scaling::Bilinear => (window_height * LCD_WIDTH / LCD_HEIGHT, window_height),
+        };
+        Viewport {
+            x: (window_width as i32 - width as i32) / 2,
+            y: (window_height as i32 - height as i32) / 2,
+            width,
+            height,
+        }
+    }
+}
+
+/// Area of the window that the LCD image covers. The offsets are negative if the window is smaller
+/// than the image, which is then cropped on all sides.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Viewport {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Viewport {
+    /// Scale factor relative to the LCD resolution.
+    pub fn scale(&self) -> f64 {
+        self.width as f64 / LCD_WIDTH as f64
+    }
+}
+
+/// Largest whole factor the LCD can be scaled by and still fit in the window. This is never less
+/// than 1, even if the window is smaller than the LCD.
+pub fn integer_scale(window_width: u32, window_height: u32) -> u32 {
+    (window_width / LCD_WIDTH)
+        .min(window_height / LCD_HEIGHT)
+        .max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_integer_scale() {
+        assert_eq!(integer_scale(240, 160), 1);
+        assert_eq!(integer_scale(720, 480), 3);
+        // Limited by the height
+        assert_eq!(integer_scale(1920, 1080), 6);
+        // Limited by the width
+        assert_eq!(integer_scale(500, 1000), 2);
+        assert_eq!(integer_scale(100, 100), 1);
+    }
+
+    #[test]
+    fn test_integer_viewport() {
+        assert_eq!(
+            Scaling::Integer.viewport(1920, 1080),
+            Viewport {
+                x: 240,
+                y: 60,
+                width: 1440,
+                height: 960,
+            }
+        );
+        assert_eq!(
+            Scaling::Integer.viewport(720, 480),
+            Viewport {
+                x: 0,
+                y: 0,
+                width: 720,
+                height: 480,
+            }
+        );
+        // Cropped when the window is too small
+        assert_eq!(
+            Scaling::Integer.viewport(200, 160),
+            Viewport {
+                x: -20,
+                y: 0,
+                width: 240,
+                height: 160,
+            }
+        );
+        assert_eq!(Scaling::Integer.viewport(1920, 1080).scale(), 6.0);
+    }
+
+    #[test]
+    fn test_bilinear_viewport() {
+        assert_eq!(
+            Scaling::Bilinear.viewport(1920, 1080),
+            Viewport {
+                x: 150,
+                y: 0,
+                width: 1620,
+                height: 1080,
+            }
+        );
+        assert_eq!(
+            Scaling::Bilinear.viewport(600, 800),
+            Viewport {
+                x: 0,
+                y: 200,
+                width: 600,
+                height: 400,
+            }
+        );
+        assert_eq!(Scaling::Bilinear.viewport(600, 800).scale(), 2.5);
+    }
+
+    #[test]
+    fn test_names() {
+        for &scaling in Scaling::ALL.iter() {
+            assert_eq!(Scaling::from_name(scaling.name()), Some(scaling));
+            assert_ne!(scaling.next(), scaling);
+        }
+        assert_eq!(Scaling::from_name("nearest"), None);
+    }
+}
@@ -1,11 +1,14 @@
+use std::cell::Cell;
 use std::cmp::Ord;
 use std::cmp::Ordering;
 use std::collections::binary_heap::PeekMut;
 use std::collections::BinaryHeap;
 use std::marker::PhantomPinned;
+use std::mem;
 use std::ops::Generator;
 use std::ops::GeneratorState;
 use std::pin::Pin;
+use std::rc::Rc;
 
 pub struct WaitCycles {
     cycles: u64,
@@ -95,8 +98,52 @@ impl PartialOrd for ScheduledTask {
     }
 }
 
+/// Units whose events can change what the CPU sees without it accessing the bus, by raising
+/// interrupts or updating status registers.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EventSource {
+    Ppu,
+    Sio,
+    Dma,
+}
+
+const NUM_EVENT_SOURCES: usize = 3;
+
+/// The scheduler's time, shared with the tasks so that they can tell when they're running. Units
+/// also announce the time of their next event here, so that the CPU can skip ahead to it when it's
+/// only waiting for something to happen.
+pub struct Clock {
+    now: Cell<u64>,
+    events: [Cell<u64>; NUM_EVENT_SOURCES],
+}
+
+impl Clock {
+    fn new() -> Clock {
+        Clock {
+            now: Cell::new(0),
+            events: Default::default(),
+        }
+    }
+
+    /// Time at which the running task was scheduled.
+    pub fn now(&self) -> u64 {
+        self.now.get()
+    }
+
+    /// Sets the time of the next event of `source`, replacing the previous one.
+    pub fn announce_event(&self, source: EventSource, time: u64) {
+        self.events[source as usize].set(time);
+    }
+
+    /// Time of the earliest event announced, which might already be in the past.
+    pub fn next_event(&self) -> u64 {
+        self.events.iter().map(Cell::get).min().unwrap()
+    }
+}
+
 pub struct TaskScheduler<'g> {
     current_time: u64,
+    clock: Rc<Clock>,
 
     // TODO: Optimize this using a fixed-size ring buffer for events in the near future, to get fast
     // O(1) push for those instead of using the heap.
@@ -109,6 +156,7 @@ impl<'g> TaskScheduler<'g> {
     pub fn new() -> TaskScheduler<'g> {
         TaskScheduler {
             current_time: 0,
+            clock: Rc::new(Clock::new()),
             scheduled_tasks: BinaryHeap::new(),
             active_tasks: Vec::new(),
         }
@@ -118,13 +166,34 @@ impl<'g> TaskScheduler<'g> {
         self.current_time
     }
 
-    pub fn add_new_task(&mut self, task: Pin<Box<dyn Task<'g, Return = ()>>>) {
+    pub fn clock(&self) -> Rc<Clock> {
+        self.clock.clone()
+    }
+
+    /// Adds a task, which first runs at the current time. Returns its ID, for `wake_task`.
+    pub fn add_new_task(&mut self, task: Pin<Box<dyn Task<'g, Return = ()>>>) -> usize {
         let task_id = self.active_tasks.len();
         self.active_tasks.push(Some(task));
         self.scheduled_tasks.push(ScheduledTask {
             scheduled_at: self.current_time,
             task_id,
         });
+        task_id
+    }
+
+    /// Makes a waiting task run at the current time instead, for when what it's waiting for has
+    /// been changed under it. The task has to check whether it's done waiting when it wakes up.
+    pub fn wake_task(&mut self, task_id: usize) {
+        let mut tasks = mem::replace(&mut self.scheduled_tasks, BinaryHeap::new()).into_vec();
+        for task in tasks.iter_mut().filter(|task| task.task_id == task_id) {
+            task.scheduled_at = self.current_time;
+        }
+        self.scheduled_tasks = BinaryHeap::from(tasks);
+    }
+
+    /// Time at which the next task is scheduled to run, if there are any tasks left.
+    pub fn next_event_time(&self) -> Option<u64> {
+        self.scheduled_tasks.peek().map(|task| task.scheduled_at)
     }
 
     pub fn run_for(&mut self, cycles: u64) {
@@ -133,43 +202,63 @@ impl<'g> TaskScheduler<'g> {
         }
         let stop_time = self.current_time + cycles;
 
-        'l: loop {
-            let mut next_task = match self.scheduled_tasks.peek_mut() {
-                Some(task) => task,
-                None => break 'l,
-            };
+        while self.step_next_task(stop_time) {}
 
-            if next_task.scheduled_at >= stop_time {
-                break 'l;
-            }
+        self.current_time = stop_time;
+    }
 
-            let task_id = next_task.task_id;
-            let result = {
-                let task = self
-                    .active_tasks
-                    .get_mut(task_id)
-                    .and_then(|x| x.as_mut())
-                    .unwrap();
-                task.as_mut().step()
-            };
-            match result {
-                GeneratorState::Yielded(WaitCycles { cycles }) => {
-                    next_task.scheduled_at += cycles;
-                }
-                GeneratorState::Complete(()) => {
-                    PeekMut::pop(next_task);
-                    self.active_tasks.remove(task_id);
-                }
-            }
+    /// Advances the current time to the next scheduled task and steps only that task, returning
+    /// control to the caller. This is the finest granularity the system can be run at, and is
+    /// meant for debuggers. Returns false if there were no tasks to run.
+    pub fn run_one_event(&mut self) -> bool {
+        let scheduled_at = match self.next_event_time() {
+            Some(time) => time,
+            None => return false,
+        };
+        self.current_time = scheduled_at;
+        self.step_next_task(scheduled_at + 1)
+    }
+
+    /// Steps the next scheduled task, if it is scheduled before `stop_time`. Returns false if no
+    /// task was run.
+    fn step_next_task(&mut self, stop_time: u64) -> bool {
+        let mut next_task = match self.scheduled_tasks.peek_mut() {
+            Some(task) => task,
+            None => return false,
+        };
+
+        if next_task.scheduled_at >= stop_time {
+            return false;
         }
 
-        self.current_time = stop_time;
+        let task_id = next_task.task_id;
+        self.clock.now.set(next_task.scheduled_at);
+        let result = {
+            let task = self
+                .active_tasks
+                .get_mut(task_id)
+                .and_then(|x| x.as_mut())
+                .unwrap();
+            task.as_mut().step()
+        };
+        match result {
+            GeneratorState::Yielded(WaitCycles { cycles }) => {
+                next_task.scheduled_at += cycles;
+            }
+            GeneratorState::Complete(()) => {
+                PeekMut::pop(next_task);
+                self.active_tasks.remove(task_id);
+            }
+        }
+        true
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
     use test::Bencher;
 
     fn big_task2(delay: u64) -> impl Task<'static, Return = u32> {
@@ -186,6 +275,90 @@ mod tests {
         })
     }
 
+    fn logging_task(
+        id: u32,
+        delay: u64,
+        log: Rc<RefCell<Vec<u32>>>,
+    ) -> impl Task<'static, Return = ()> {
+        GeneratorTask::new(move || loop {
+            log.borrow_mut().push(id);
+            wait_cycles!(delay);
+        })
+    }
+
+    #[test]
+    fn test_run_one_event() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut scheduler = TaskScheduler::new();
+        scheduler.add_new_task(Box::pinned(logging_task(0, 10, log.clone())));
+        scheduler.add_new_task(Box::pinned(logging_task(1, 3, log.clone())));
+
+        // Both tasks start at time 0, and run one at a time
+        assert!(scheduler.run_one_event());
+        assert_eq!(scheduler.current_time(), 0);
+        assert_eq!(*log.borrow(), [0]);
+        assert!(scheduler.run_one_event());
+        assert_eq!(scheduler.current_time(), 0);
+        assert_eq!(*log.borrow(), [0, 1]);
+
+        for &(time, id) in &[(3, 1), (6, 1), (9, 1), (10, 0), (12, 1)] {
+            log.borrow_mut().clear();
+            assert_eq!(scheduler.next_event_time(), Some(time));
+            assert!(scheduler.run_one_event());
+            assert_eq!(scheduler.current_time(), time);
+            assert_eq!(*log.borrow(), [id]);
+        }
+
+        // run_for picks up from where the last event left off
+        log.borrow_mut().clear();
+        scheduler.run_for(4);
+        assert_eq!(scheduler.current_time(), 16);
+        assert_eq!(*log.borrow(), [1]);
+        assert_eq!(scheduler.next_event_time(), Some(18));
+    }
+
+    #[test]
+    fn test_wake_task() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut scheduler = TaskScheduler::new();
+        let slow_task = scheduler.add_new_task(Box::pinned(logging_task(0, 10, log.clone())));
+        scheduler.add_new_task(Box::pinned(logging_task(1, 4, log.clone())));
+        scheduler.run_for(6);
+        assert_eq!(*log.borrow(), [0, 1, 1]);
+
+        // The woken task runs right away, and keeps its delay from then on
+        scheduler.wake_task(slow_task);
+        assert_eq!(scheduler.next_event_time(), Some(6));
+        scheduler.run_for(15);
+        assert_eq!(*log.borrow(), [0, 1, 1, 0, 1, 1, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_clock() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut scheduler = TaskScheduler::new();
+        let clock = scheduler.clock();
+        {
+            let log = log.clone();
+            let clock = clock.clone();
+            scheduler.add_new_task(Box::pinned(GeneratorTask::new(move || loop {
+                log.borrow_mut().push(clock.now());
+                clock.announce_event(EventSource::Ppu, clock.now() + 5);
+                wait_cycles!(5);
+            })));
+        }
+        scheduler.run_for(12);
+        assert_eq!(*log.borrow(), [0, 5, 10]);
+
+        // The earliest event of all sources is the next one
+        assert_eq!(clock.next_event(), 0);
+        clock.announce_event(EventSource::Dma, u64::max_value());
+        clock.announce_event(EventSource::Sio, 20);
+        assert_eq!(clock.next_event(), 15);
+        clock.announce_event(EventSource::Sio, 13);
+        assert_eq!(clock.next_event(), 13);
+    }
+
     #[bench]
     fn bench_task_switch_overhead(b: &mut Bencher) {
         // Measures speed of cycling between 16 tasks, without any scheduler overhead
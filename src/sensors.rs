@@ -0,0 +1,205 @@
+//! Sensors connected to the cart GPIO pins. Their values are set by the frontend.
+
+use gpio::GpioDevice;
+
+/// Solar sensor from the Boktai carts.
+///
+/// The light level is converted by a counter: the game resets it, then clocks it until the FLAG
+/// pin goes high, which happens when the count reaches a threshold that gets lower the brighter
+/// the light is.
+pub struct SolarSensor {
+    /// Light level, from 0 (darkness) to 255.
+    light_level: u8,
+    counter: u8,
+    /// Threshold sampled from the light level when the counter was reset.
+    threshold: u8,
+    clk: bool,
+    selected: bool,
+}
+
+pub const SOLAR_PIN_CLK: u8 = 1 << 0;
+pub const SOLAR_PIN_RESET: u8 = 1 << 1;
+/// Selects the sensor when low.
+pub const SOLAR_PIN_CS: u8 = 1 << 2;
+pub const SOLAR_PIN_FLAG: u8 = 1 << 3;
+
+/// Amount the light level changes for each step of `adjust_sensor`.
+const LIGHT_LEVEL_STEP: i32 = 16;
+
+impl SolarSensor {
+    pub fn new() -> SolarSensor {
+        SolarSensor {
+            light_level: 0,
+            counter: 0,
+            threshold: 0xFF,
+            clk: false,
+            selected: false,
+        }
+    }
+
+    pub fn light_level(&self) -> u8 {
+        self.light_level
+    }
+
+    pub fn set_light_level(&mut self, light_level: u8) {
+        self.light_level = light_level;
+    }
+}
+
+impl GpioDevice for SolarSensor {
+    fn write_pins(&mut self, pins: u8) {
+        self.selected = pins & SOLAR_PIN_CS == 0;
+        if !self.selected {
+            return;
+        }
+
+        let clk = pins & SOLAR_PIN_CLK != 0;
+        if pins & SOLAR_PIN_RESET != 0 {
+            self.counter = 0;
+            self.threshold = 0xFF - self.light_level;
+        } else if clk && !self.clk {
+            self.counter = self.counter.saturating_add(1);
+        }
+        self.clk = clk;
+    }
+
+    fn read_pins(&self) -> u8 {
+        if self.selected && self.counter >= self.threshold {
+            SOLAR_PIN_FLAG
+        } else {
+            0
+        }
+    }
+
+    fn adjust_sensor(&mut self, steps: i32) {
+        let level = self.light_level as i32 + steps * LIGHT_LEVEL_STEP;
+        self.light_level = level.max(0).min(0xFF) as u8;
+    }
+}
+
+/// Gyro sensor from WarioWare: Twisted!, measuring the rotation rate around the axis
+/// perpendicular to the screen.
+///
+/// The rate is sampled by its ADC when the game sets the START pin, and then shifted out on DATA
+/// on falling edges of CLK, MSB first. The sample is 16 bits long, with the 12-bit ADC value in
+/// the low bits.
+pub struct GyroSensor {
+    /// Rotation rate in ADC units, relative to the value when not rotating.
+    rotation_rate: i16,
+    sample: u16,
+    clk: bool,
+    data_out: bool,
+}
+
+pub const GYRO_PIN_START: u8 = 1 << 0;
+pub const GYRO_PIN_CLK: u8 = 1 << 1;
+pub const GYRO_PIN_DATA: u8 = 1 << 2;
+
+/// ADC value when not rotating.
+const GYRO_CENTER: i32 = 0x6C0;
+/// Amount the rotation rate changes for each step of `adjust_sensor`.
+const ROTATION_RATE_STEP: i32 = 0x40;
+
+impl GyroSensor {
+    pub fn new() -> GyroSensor {
+        GyroSensor {
+            rotation_rate: 0,
+            sample: 0,
+            clk: false,
+            data_out: false,
+        }
+    }
+
+    pub fn rotation_rate(&self) -> i16 {
+        self.rotation_rate
+    }
+
+    pub fn set_rotation_rate(&mut self, rotation_rate: i16) {
+        self.rotation_rate = rotation_rate;
+    }
+}
+
+impl GpioDevice for GyroSensor {
+    fn write_pins(&mut self, pins: u8) {
+        if pins & GYRO_PIN_START != 0 {
+            self.sample = (GYRO_CENTER + self.rotation_rate as i32).max(0).min(0xFFF) as u16;
+        }
+
+        let clk = pins & GYRO_PIN_CLK != 0;
+        if self.clk && !clk {
+            self.data_out = self.sample & 0x8000 != 0;
+            self.sample <<= 1;
+        }
+        self.clk = clk;
+    }
+
+    fn read_pins(&self) -> u8 {
+        if self.data_out {
+            GYRO_PIN_DATA
+        } else {
+            0
+        }
+    }
+
+    fn adjust_sensor(&mut self, steps: i32) {
+        let rate = self.rotation_rate as i32 + steps * ROTATION_RATE_STEP;
+        let max_rate = 0xFFF - GYRO_CENTER;
+        self.rotation_rate = rate.max(-GYRO_CENTER).min(max_rate) as i16;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads the light level like Boktai does, returning the number of clocks it took for the
+    /// flag to go up.
+    fn read_solar_sensor(sensor: &mut SolarSensor) -> u32 {
+        sensor.write_pins(SOLAR_PIN_RESET);
+        sensor.write_pins(0);
+        let mut clocks = 0;
+        while sensor.read_pins() & SOLAR_PIN_FLAG == 0 {
+            sensor.write_pins(SOLAR_PIN_CLK);
+            sensor.write_pins(0);
+            clocks += 1;
+            assert!(clocks <= 0x100);
+        }
+        clocks
+    }
+
+    #[test]
+    fn test_solar_sensor() {
+        let mut sensor = SolarSensor::new();
+        assert_eq!(read_solar_sensor(&mut sensor), 0xFF);
+        sensor.set_light_level(0x60);
+        assert_eq!(read_solar_sensor(&mut sensor), 0x9F);
+        sensor.set_light_level(0xFF);
+        assert_eq!(read_solar_sensor(&mut sensor), 0);
+
+        // Not driving FLAG while deselected
+        sensor.write_pins(SOLAR_PIN_CS);
+        assert_eq!(sensor.read_pins(), 0);
+
+        sensor.adjust_sensor(-2);
+        assert_eq!(sensor.light_level(), 0xDF);
+    }
+
+    #[test]
+    fn test_gyro_sensor() {
+        let mut sensor = GyroSensor::new();
+        sensor.set_rotation_rate(-0x100);
+
+        sensor.write_pins(GYRO_PIN_START | GYRO_PIN_CLK);
+        sensor.write_pins(GYRO_PIN_CLK);
+        let mut sample = 0;
+        for _ in 0..16 {
+            sensor.write_pins(0);
+            sample = sample << 1 | (sensor.read_pins() & GYRO_PIN_DATA) as u16 >> 2;
+            sensor.write_pins(GYRO_PIN_CLK);
+        }
+        assert_eq!(sample, 0x5C0);
+
+        sensor.adjust_sensor(100);
+        assert_eq!(sensor.rotation_rate(), 0x93F);
+    }
+}
@@ -0,0 +1,304 @@
+//! Serial port, emulated as if no link cable was connected. Transfers that the GBA clocks itself
+//! still complete, reading the idle state of the lines, so that games probing for other players
+//! find none instead of hanging.
+
+use interrupt::Interrupt;
+use interrupt::InterruptController;
+use scheduler::Clock;
+use scheduler::EventSource;
+use scheduler::GeneratorTask;
+use scheduler::Task;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SioMode {
+    Normal8,
+    Normal32,
+    Multiplayer,
+    Uart,
+    GeneralPurpose,
+    JoyBus,
+}
+
+const SIOCNT_INTERNAL_CLOCK: u16 = 1 << 0;
+const SIOCNT_2MHZ_CLOCK: u16 = 1 << 1;
+const SIOCNT_SI_STATE: u16 = 1 << 2;
+const SIOCNT_MULTI_CHILD: u16 = 1 << 2;
+const SIOCNT_MULTI_READY: u16 = 1 << 3;
+const SIOCNT_MULTI_ID: u16 = 0b11 << 4;
+const SIOCNT_MULTI_ERROR: u16 = 1 << 6;
+const SIOCNT_START: u16 = 1 << 7;
+const SIOCNT_IRQ_ENABLE: u16 = 1 << 14;
+
+/// Bits of SIOCNT that only reflect the state of the link, per mode.
+const SIOCNT_READ_ONLY_NORMAL: u16 = SIOCNT_SI_STATE;
+const SIOCNT_READ_ONLY_MULTI: u16 =
+    SIOCNT_MULTI_CHILD | SIOCNT_MULTI_READY | SIOCNT_MULTI_ID | SIOCNT_MULTI_ERROR;
+
+/// Length of a multiplayer transfer for each baud rate setting, with no children connected.
+const MULTIPLAYER_TRANSFER_CYCLES: [u32; 4] = [31976, 8378, 5750, 3140];
+
+/// How often the task checks for new transfers while the port is idle. Transfers can start up to
+/// this many cycles late, which is well below the length of the shortest one.
+const IDLE_POLL_CYCLES: u64 = 32;
+
+pub struct Sio {
+    // SIOMULTI0-3, also SIODATA32 in the first two
+    multi_data: [u16; 4],
+    // SIOCNT
+    siocnt: u16,
+    // SIOMLT_SEND, also SIODATA8
+    send_data: u16,
+    // RCNT
+    rcnt: u16,
+    /// Length of the transfer in progress, started by setting SIOCNT's start bit.
+    transfer_cycles: Option<u32>,
+    interrupts: Rc<InterruptController>,
+}
+
+impl Sio {
+    pub fn new(interrupts: Rc<InterruptController>) -> Sio {
+        Sio {
+            multi_data: [0; 4],
+            siocnt: SIOCNT_SI_STATE,
+            send_data: 0,
+            rcnt: 0,
+            transfer_cycles: None,
+            interrupts,
+        }
+    }
+
+    pub fn mode(&self) -> SioMode {
+        let (rcnt, siocnt) = (self.rcnt, self.siocnt);
+        match (bit!(rcnt[14:15]), bit!(siocnt[12:13])) {
+            (0b00, 0b00) | (0b01, 0b00) => SioMode::Normal8,
+            (0b00, 0b01) | (0b01, 0b01) => SioMode::Normal32,
+            (0b00, 0b10) | (0b01, 0b10) => SioMode::Multiplayer,
+            (0b00, 0b11) | (0b01, 0b11) => SioMode::Uart,
+            (0b10, _) => SioMode::GeneralPurpose,
+            _ => SioMode::JoyBus,
+        }
+    }
+
+    /// Returns true while SIOCNT's start/busy bit is set, including for transfers that never
+    /// complete because they're waiting for another device.
+    pub fn is_transfer_active(&self) -> bool {
+        self.siocnt & SIOCNT_START != 0
+    }
+
+    pub fn read(&self, address: u32) -> u16 {
+        match address & 0xFFF {
+            0x120 => self.multi_data[0],
+            0x122 => self.multi_data[1],
+            0x124 => self.multi_data[2],
+            0x126 => self.multi_data[3],
+            0x128 => self.siocnt,
+            0x12A => self.send_data,
+            0x134 => {
+                if self.mode() == SioMode::GeneralPurpose {
+                    // Pins set as inputs are pulled high, with nothing driving them
+                    let rcnt = self.rcnt;
+                    let inputs = !bit!(rcnt[4:7]) & 0xF;
+                    self.rcnt | inputs
+                } else {
+                    self.rcnt
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, address: u32, data: u16) {
+        match address & 0xFFF {
+            0x120 => self.multi_data[0] = data,
+            0x122 => self.multi_data[1] = data,
+            0x124 => self.multi_data[2] = data,
+            0x126 => self.multi_data[3] = data,
+            0x128 => self.write_siocnt(data),
+            0x12A => self.send_data = data,
+            0x134 => {
+                self.rcnt = data & 0xC1FF;
+                self.update_link_state();
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Sets the read-only bits of SIOCNT that reflect the state of the lines for the current mode.
+    fn update_link_state(&mut self) {
+        match self.mode() {
+            // SI idles high
+            SioMode::Normal8 | SioMode::Normal32 => self.siocnt |= SIOCNT_SI_STATE,
+            // Act as the parent of a cable with no children connected, so that transfers can
+            // start but no other GBA is ever ready
+            SioMode::Multiplayer => self.siocnt &= !(SIOCNT_MULTI_CHILD | SIOCNT_MULTI_READY),
+            _ => {}
+        }
+    }
+
+    fn write_siocnt(&mut self, data: u16) {
+        let read_only = match self.mode() {
+            SioMode::Multiplayer => SIOCNT_READ_ONLY_MULTI,
+            _ => SIOCNT_READ_ONLY_NORMAL,
+        };
+        self.siocnt = (self.siocnt & read_only) | (data & !read_only & 0x7FFF);
+        self.update_link_state();
+
+        if self.siocnt & SIOCNT_START == 0 {
+            self.transfer_cycles = None;
+        } else if self.transfer_cycles.is_none() {
+            self.transfer_cycles = self.start_transfer();
+        }
+    }
+
+    /// Returns the length of a transfer started in the current mode, or None if it depends on
+    /// another device, in which case it never completes.
+    fn start_transfer(&self) -> Option<u32> {
+        let cycles_per_bit = if self.siocnt & SIOCNT_2MHZ_CLOCK != 0 {
+            8
+        } else {
+            64
+        };
+        let has_internal_clock = self.siocnt & SIOCNT_INTERNAL_CLOCK != 0;
+        match self.mode() {
+            SioMode::Normal8 if has_internal_clock => Some(8 * cycles_per_bit),
+            SioMode::Normal32 if has_internal_clock => Some(32 * cycles_per_bit),
+            SioMode::Multiplayer => {
+                let siocnt = self.siocnt;
+                Some(MULTIPLAYER_TRANSFER_CYCLES[bit!(siocnt[0:1]) as usize])
+            }
+            _ => None,
+        }
+    }
+
+    fn finish_transfer(&mut self) {
+        // The lines idle high, so the data received from missing devices is all ones
+        match self.mode() {
+            SioMode::Normal8 => self.send_data |= 0xFF,
+            SioMode::Normal32 => {
+                self.multi_data[0] = 0xFFFF;
+                self.multi_data[1] = 0xFFFF;
+            }
+            SioMode::Multiplayer => {
+                self.multi_data = [self.send_data, 0xFFFF, 0xFFFF, 0xFFFF];
+                self.siocnt &= !(SIOCNT_MULTI_ID | SIOCNT_MULTI_ERROR);
+            }
+            _ => {}
+        }
+
+        self.siocnt &= !SIOCNT_START;
+        self.transfer_cycles = None;
+        if self.siocnt & SIOCNT_IRQ_ENABLE != 0 {
+            self.interrupts.request(Interrupt::Serial);
+        }
+    }
+
+    /// Completes transfers once they've taken as long as on hardware.
+    ///
+    /// The end of each transfer is announced as an event, but polling for new ones isn't, since
+    /// only the CPU starts them. If it goes idle before a transfer is noticed, it can wake up late
+    /// for its end, by up to the time until another unit's event.
+    pub fn run_task(sio: Rc<RefCell<Sio>>, clock: Rc<Clock>) -> impl Task<'static, Return = ()> {
+        GeneratorTask::new(move || loop {
+            let transfer_cycles = sio.borrow().transfer_cycles;
+            match transfer_cycles {
+                Some(cycles) => {
+                    clock.announce_event(EventSource::Sio, clock.now() + cycles as u64);
+                    wait_cycles!(cycles as u64);
+                    let mut sio = sio.borrow_mut();
+                    // The transfer might have been cancelled meanwhile
+                    if sio.transfer_cycles.is_some() {
+                        sio.finish_transfer();
+                    }
+                }
+                None => {
+                    clock.announce_event(EventSource::Sio, u64::max_value());
+                    wait_cycles!(IDLE_POLL_CYCLES);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scheduler::TaskScheduler;
+    use system::Bus;
+
+    fn setup() -> (
+        Rc<InterruptController>,
+        Rc<RefCell<Sio>>,
+        TaskScheduler<'static>,
+    ) {
+        let interrupts = Rc::new(InterruptController::new(Rc::new(Bus::default())));
+        interrupts.write(0x0400_0200, 1 << 7);
+        let sio = Rc::new(RefCell::new(Sio::new(interrupts.clone())));
+        let mut scheduler = TaskScheduler::new();
+        let clock = scheduler.clock();
+        scheduler.add_new_task(Box::pinned(Sio::run_task(sio.clone(), clock)));
+        (interrupts, sio, scheduler)
+    }
+
+    #[test]
+    fn test_normal_transfer() {
+        let (interrupts, sio, mut scheduler) = setup();
+        sio.borrow_mut().write(0x0400_012A, 0x5A);
+        // 8-bit, internal 256KHz clock, with IRQ
+        sio.borrow_mut().write(0x0400_0128, 0x4081);
+        assert_eq!(sio.borrow().mode(), SioMode::Normal8);
+
+        scheduler.run_for(8 * 64);
+        assert_eq!(sio.borrow().read(0x0400_0128) & SIOCNT_START, SIOCNT_START);
+        scheduler.run_for(1);
+        assert_eq!(sio.borrow().read(0x0400_0128) & SIOCNT_START, 0);
+        assert_eq!(sio.borrow().read(0x0400_012A), 0xFF);
+        assert_eq!(interrupts.read(0x0400_0202), 1 << 7);
+
+        // With an external clock, the transfer waits forever for the other side
+        sio.borrow_mut().write(0x0400_0128, 0x0080);
+        scheduler.run_for(100_000);
+        assert!(sio.borrow().is_transfer_active());
+    }
+
+    #[test]
+    fn test_multiplayer_transfer() {
+        let (interrupts, sio, mut scheduler) = setup();
+        sio.borrow_mut().write(0x0400_0120, 0x1111);
+        sio.borrow_mut().write(0x0400_012A, 0x1234);
+        // Multiplayer at 115200 bps, without IRQ
+        sio.borrow_mut().write(0x0400_0128, 0x2003);
+        assert_eq!(sio.borrow().mode(), SioMode::Multiplayer);
+        assert_eq!(sio.borrow().read(0x0400_0128), 0x2003);
+        sio.borrow_mut().write(0x0400_0128, 0x2083);
+
+        scheduler.run_for(3140);
+        assert!(sio.borrow().is_transfer_active());
+        scheduler.run_for(1);
+        let sio = sio.borrow();
+        assert_eq!(sio.read(0x0400_0128), 0x2003);
+        assert_eq!(
+            [0x120, 0x122, 0x124, 0x126]
+                .iter()
+                .map(|&offset| sio.read(0x0400_0000 | offset))
+                .collect::<Vec<_>>(),
+            [0x1234, 0xFFFF, 0xFFFF, 0xFFFF]
+        );
+        assert_eq!(interrupts.read(0x0400_0202), 0);
+    }
+
+    #[test]
+    fn test_general_purpose() {
+        let (_, sio, _) = setup();
+        let mut sio = sio.borrow_mut();
+        sio.write(0x0400_0128, 0x1000);
+        assert_eq!(sio.mode(), SioMode::Normal32);
+        // SO and SD as outputs driven low
+        sio.write(0x0400_0134, 0x8000 | 0b1100_0000);
+        assert_eq!(sio.mode(), SioMode::GeneralPurpose);
+        assert_eq!(sio.read(0x0400_0134), 0x8000 | 0b1100_0011);
+        sio.write(0x0400_0134, 0xC000);
+        assert_eq!(sio.mode(), SioMode::JoyBus);
+    }
+}
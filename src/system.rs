@@ -1,4 +1,43 @@
+use apu::Apu;
+use apu::StereoSample;
+use cart::CartHeader;
+use cheats::CheatEngine;
+use cpu::ArmCpu;
+use dma::DmaController;
+use frame_sink::FrameSink;
+use frame_sink::NullSink;
+use hle;
+use hle::HleMemory;
+use interrupt::InterruptController;
+use keypad::Keypad;
+use memory::IoUnits;
+use memory::Memory;
+use memory::SaveType;
+use memory::DEFAULT_SRAM_FILL;
+use pacer::FpsCounter;
+use pacer::SystemClock;
+use ppu;
+use ppu::BgMapImage;
+use ppu::BgPaletteMode;
+use ppu::FrameBuffer;
+use ppu::LcdControllerRegs;
+use ppu::LineTiming;
+use ppu::Ppu;
+use ppu::PpuState;
+use ppu::SCREEN_HEIGHT;
+use replay::InputPlayer;
+use replay::InputRecorder;
+use save_file::SaveFile;
+use scheduler::EventSource;
+use scheduler::TaskScheduler;
+use sio::Sio;
 use std::cell::Cell;
+use std::cell::Ref;
+use std::cell::RefCell;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::rc::Rc;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum AccessWidth {
@@ -21,42 +60,1995 @@ pub struct MemoryRequest {
     pub seq: bool,
 }
 
+/// How closely memory timings are emulated, for trading speed for accuracy.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TimingAccuracy {
+    Fast,
+    /// Also stalls CPU and DMA accesses to video memory while the PPU is using it.
+    Accurate,
+}
+
+impl TimingAccuracy {
+    pub const ALL: [TimingAccuracy; 2] = [TimingAccuracy::Fast, TimingAccuracy::Accurate];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TimingAccuracy::Fast => "fast",
+            TimingAccuracy::Accurate => "accurate",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<TimingAccuracy> {
+        TimingAccuracy::ALL
+            .iter()
+            .cloned()
+            .find(|accuracy| accuracy.name() == name)
+    }
+}
+
+/// Value on the data bus at power on, before any device has driven it.
+pub const BUS_RESET_VALUE: u32 = 0xFFFFFFFF;
+
+/// Progress of the transaction on the bus.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum TransactionState {
+    /// No transaction is in progress. The result of the last read is on the data lines.
+    Idle,
+    /// Issued by a master, waiting for the memory to pick it up.
+    Pending(MemoryRequest),
+    /// Picked up by the memory, which hasn't completed it yet.
+    InProgress,
+}
+
+/// Connects the bus masters (CPU and DMA) to the memory. A master issues a request with `begin`,
+/// driving the data lines with `drive_data` for writes. The memory picks it up with
+/// `take_pending`, which can take several cycles to service, and then finishes it with
+/// `complete_read` or `complete_write`. Masters wait while it's busy, and read the result after.
 pub struct Bus {
-    /// Active memory request. Set only by the CPU/DMA.
-    pub request: Cell<Option<MemoryRequest>>,
-    /// True if a device is still processing a request and the read/write hasn't completed yet.
-    pub busy: Cell<bool>,
-    /// Overrides `busy` when being read by the CPU, so that DMA can take over the bus.
+    state: Cell<TransactionState>,
+    /// Keeps the CPU waiting even between transactions, so that DMA can take over the bus.
     pub dma_active: Cell<bool>,
     /// Last value read/written on the bus. For writes, it is assumed that the data is properly
     /// mirrored across all 32 bits no matter the access width.
-    pub data: Cell<u32>,
+    data: Cell<u32>,
+    /// Interrupt request line to the CPU, driven by the interrupt controller.
+    pub irq: Cell<bool>,
+    /// Set while the CPU is halted or stopped by HALTCNT, until an interrupt wakes it up.
+    pub halted: Cell<bool>,
 }
 
 impl Bus {
+    /// Issues a request. Only one master can do so at a time, and only once the previous
+    /// transaction has completed.
+    #[inline]
+    pub fn begin(&self, request: MemoryRequest) {
+        debug_assert!(
+            self.state.get() == TransactionState::Idle,
+            "Bus request {:?} issued during {:?}",
+            request,
+            self.state.get()
+        );
+        self.state.set(TransactionState::Pending(request));
+    }
+
+    /// Returns the request waiting to be picked up by the memory, if any.
     #[inline]
-    pub fn make_request(&self, request: MemoryRequest) {
-        self.request.set(Some(request));
+    pub fn pending(&self) -> Option<MemoryRequest> {
+        match self.state.get() {
+            TransactionState::Pending(request) => Some(request),
+            _ => None,
+        }
+    }
+
+    /// Picks up the waiting request, which stays in progress until completed.
+    #[inline]
+    pub fn take_pending(&self) -> Option<MemoryRequest> {
+        let request = self.pending();
+        if request.is_some() {
+            self.state.set(TransactionState::InProgress);
+        }
+        request
+    }
+
+    /// Finishes the read in progress, putting `value` on the data lines.
+    #[inline]
+    pub fn complete_read(&self, value: u32) {
+        debug_assert!(
+            self.state.get() == TransactionState::InProgress,
+            "Read completed during {:?}",
+            self.state.get()
+        );
+        self.data.set(value);
+        self.state.set(TransactionState::Idle);
+    }
+
+    /// Finishes the write in progress.
+    #[inline]
+    pub fn complete_write(&self) {
+        debug_assert!(
+            self.state.get() == TransactionState::InProgress,
+            "Write completed during {:?}",
+            self.state.get()
+        );
+        self.state.set(TransactionState::Idle);
+    }
+
+    /// Returns the value on the data lines, which a master can only read once its transaction has
+    /// completed.
+    #[inline]
+    pub fn read_data(&self) -> u32 {
+        debug_assert!(
+            self.state.get() == TransactionState::Idle,
+            "Bus data read during {:?}",
+            self.state.get()
+        );
+        self.data.get()
+    }
+
+    /// Drives the data lines from a master, with the data of the write it just issued.
+    #[inline]
+    pub fn drive_data(&self, value: u32) {
+        self.data.set(value);
+    }
+
+    /// Returns the value on the data lines to the memory servicing a transaction. For writes this
+    /// is the data to write, and for reads whatever was left on the bus before.
+    #[inline]
+    pub fn data_lines(&self) -> u32 {
+        debug_assert!(
+            self.state.get() == TransactionState::InProgress,
+            "Bus data lines sampled during {:?}",
+            self.state.get()
+        );
+        self.data.get()
+    }
+
+    /// True from when a request is issued until it completes.
+    #[inline]
+    pub fn is_busy(&self) -> bool {
+        self.state.get() != TransactionState::Idle
     }
 
     #[inline]
     pub fn should_cpu_wait(&self) -> bool {
-        self.busy.get() || self.dma_active.get()
+        self.is_busy() || self.dma_active.get() || self.halted.get()
     }
 
     #[inline]
     pub fn should_dma_wait(&self) -> bool {
-        self.busy.get()
+        self.is_busy()
     }
 }
 
 impl Default for Bus {
     fn default() -> Bus {
         Bus {
-            request: None.into(),
-            busy: false.into(),
+            state: TransactionState::Idle.into(),
             dma_active: false.into(),
-            data: 0xFFFFFFFF.into(),
+            data: BUS_RESET_VALUE.into(),
+            irq: false.into(),
+            halted: false.into(),
+        }
+    }
+}
+
+enum InputReplay {
+    Inactive,
+    Recording(InputRecorder),
+    Playing(InputPlayer),
+}
+
+/// What a frame produced, for frontends to present: its picture and the audio samples generated
+/// while it ran, so that both can be kept in sync.
+pub struct FrameOutput<'a> {
+    ppu: Ref<'a, Ppu>,
+    samples: &'a [StereoSample],
+}
+
+impl<'a> FrameOutput<'a> {
+    /// The 240x160 BGR555 pixels of the frame, line by line.
+    pub fn pixels(&self) -> &[u16] {
+        self.ppu.framebuffer_pixels()
+    }
+
+    pub fn framebuffer(&self) -> &FrameBuffer {
+        self.ppu.framebuffer()
+    }
+
+    /// Number of frames completed so far, including this one.
+    pub fn frame_count(&self) -> u64 {
+        self.ppu.frame_count()
+    }
+
+    /// Whether the display was blanked for the whole frame, which frontends may show as a black
+    /// screen instead of the white the LCD outputs.
+    pub fn lcd_off(&self) -> bool {
+        self.ppu.lcd_off()
+    }
+
+    pub fn samples(&self) -> &[StereoSample] {
+        self.samples
+    }
+
+    pub fn sample_count(&self) -> usize {
+        self.samples.len()
+    }
+}
+
+/// Settings of the console a System is created with. Display settings, like color correction, are
+/// left to the frontend's frame sink.
+#[derive(Clone, Debug)]
+pub struct SystemConfig {
+    /// Runs BIOS functions with HLE even if a BIOS is loaded. Without one, HLE is always used.
+    pub hle_bios: bool,
+    /// Starts the cart directly, without the BIOS boot animation. Without a BIOS, the boot is
+    /// always skipped.
+    pub skip_bios_boot: bool,
+    pub save_type: SaveType,
+    /// Value SRAM holds at power on, before a save file is loaded.
+    pub sram_fill: u8,
+    /// See `System::set_clock_multiplier`.
+    pub clock_multiplier: f64,
+    pub timing_accuracy: TimingAccuracy,
+}
+
+impl Default for SystemConfig {
+    fn default() -> SystemConfig {
+        SystemConfig {
+            hle_bios: false,
+            skip_bios_boot: false,
+            save_type: SaveType::Sram,
+            sram_fill: DEFAULT_SRAM_FILL,
+            clock_multiplier: 1.0,
+            timing_accuracy: TimingAccuracy::Fast,
+        }
+    }
+}
+
+/// Ties together all units of the console and the scheduler that drives them.
+pub struct System {
+    scheduler: TaskScheduler<'static>,
+    /// ID of the PPU's task in the scheduler, to wake it up when the PPU state is replaced.
+    ppu_task: usize,
+
+    pub bus: Rc<Bus>,
+    pub cpu: Rc<RefCell<ArmCpu>>,
+    pub memory: Rc<RefCell<Memory>>,
+    pub lcd_regs: Rc<RefCell<LcdControllerRegs>>,
+    pub ppu: Rc<RefCell<Ppu>>,
+    pub apu: Rc<RefCell<Apu>>,
+    pub dma: Rc<RefCell<DmaController>>,
+    pub keypad: Rc<RefCell<Keypad>>,
+    pub sio: Rc<RefCell<Sio>>,
+    pub interrupts: Rc<InterruptController>,
+    pub cheats: CheatEngine,
+    pub cart_header: CartHeader,
+
+    /// False if started without a BIOS, to do so again on reset.
+    has_bios: bool,
+    clock_multiplier: f64,
+    paused: bool,
+    input_replay: InputReplay,
+    save_file: Option<SaveFile>,
+    frame_sink: Box<FrameSink>,
+    /// Samples generated during the last frame run.
+    frame_samples: Vec<StereoSample>,
+    fps_counter: FpsCounter<SystemClock>,
+    /// Kept to create the system the same way again on reset.
+    config: SystemConfig,
+}
+
+impl System {
+    /// Creates the system with the given BIOS and cart ROM, and the default settings. If `bios` is
+    /// empty, BIOS functions are emulated with HLE and the cart is started directly.
+    pub fn new(bios: &[u8], cart_rom: &[u8]) -> System {
+        System::with_config(bios, cart_rom, &SystemConfig::default())
+    }
+
+    /// Creates the system with the given BIOS and cart ROM, set up as `config` says.
+    pub fn with_config(bios: &[u8], cart_rom: &[u8], config: &SystemConfig) -> System {
+        let bus = Rc::new(Bus::default());
+        let cpu = Rc::new(RefCell::new(ArmCpu::new()));
+        let lcd_regs = Rc::new(RefCell::new(LcdControllerRegs::new()));
+        let apu = Rc::new(RefCell::new(Apu::new()));
+        let interrupts = Rc::new(InterruptController::new(bus.clone()));
+        let dma = Rc::new(RefCell::new(DmaController::new(interrupts.clone())));
+        let keypad = Rc::new(RefCell::new(Keypad::new(interrupts.clone())));
+        let sio = Rc::new(RefCell::new(Sio::new(interrupts.clone())));
+        let io = IoUnits {
+            lcd_regs: lcd_regs.clone(),
+            apu: apu.clone(),
+            dma: dma.clone(),
+            keypad: keypad.clone(),
+            sio: sio.clone(),
+            interrupts: interrupts.clone(),
+        };
+        // Without a BIOS, its IRQ handler is still needed to call the game's
+        let bios_image = if bios.is_empty() {
+            hle::stand_in_bios()
+        } else {
+            bios.to_vec()
+        };
+        let (memory, cart_header) = Memory::new(&bios_image, cart_rom, io);
+        let memory = Rc::new(RefCell::new(memory));
+
+        let ppu = Rc::new(RefCell::new(Ppu::new()));
+
+        let mut scheduler = TaskScheduler::new();
+        let clock = scheduler.clock();
+        // The CPU needs to be scheduled before the memory so that requests made in a cycle are
+        // serviced in that same cycle.
+        scheduler.add_new_task(Box::pinned(ArmCpu::run_task(
+            cpu.clone(),
+            bus.clone(),
+            dma.clone(),
+            clock.clone(),
+        )));
+        scheduler.add_new_task(Box::pinned(Memory::run_task(
+            memory.clone(),
+            bus.clone(),
+            clock.clone(),
+        )));
+        // DMA takes the bus after the memory has serviced the CPU's request for the cycle
+        scheduler.add_new_task(Box::pinned(DmaController::run_task(
+            dma.clone(),
+            bus.clone(),
+            clock.clone(),
+        )));
+        let ppu_task = scheduler.add_new_task(Box::pinned(Ppu::run_task(
+            ppu.clone(),
+            lcd_regs.clone(),
+            memory.clone(),
+            dma.clone(),
+            interrupts.clone(),
+            clock.clone(),
+        )));
+        scheduler.add_new_task(Box::pinned(Apu::run_frame_sequencer_task(apu.clone())));
+        scheduler.add_new_task(Box::pinned(Apu::run_sample_task(apu.clone())));
+        scheduler.add_new_task(Box::pinned(Sio::run_task(sio.clone(), clock)));
+
+        let mut system = System {
+            scheduler,
+            ppu_task,
+            bus,
+            cpu,
+            memory,
+            lcd_regs,
+            ppu,
+            apu,
+            dma,
+            keypad,
+            sio,
+            interrupts,
+            cheats: CheatEngine::new(),
+            cart_header,
+            has_bios: !bios.is_empty(),
+            clock_multiplier: 1.0,
+            paused: false,
+            input_replay: InputReplay::Inactive,
+            save_file: None,
+            frame_sink: Box::new(NullSink),
+            frame_samples: Vec::new(),
+            fps_counter: FpsCounter::new(SystemClock::new()),
+            config: config.clone(),
+        };
+        {
+            let mut memory = system.memory.borrow_mut();
+            memory.set_save_type(config.save_type);
+            memory.fill_cart_sram(config.sram_fill);
+        }
+        system.set_clock_multiplier(config.clock_multiplier);
+        system.set_timing_accuracy(config.timing_accuracy);
+        if config.hle_bios || bios.is_empty() {
+            system.set_hle_bios(true);
+        }
+        if config.skip_bios_boot || bios.is_empty() {
+            system.skip_bios_boot();
+        }
+        system
+    }
+
+    /// Sets up the state the BIOS leaves after its boot animation and starts running the cart, to
+    /// skip the animation. This must be done before running anything.
+    pub fn skip_bios_boot(&mut self) {
+        self.cpu.borrow_mut().skip_bios_boot();
+
+        let mut memory = self.memory.borrow_mut();
+        // The BIOS clears the area at the top of IWRAM where it keeps its variables, like the IRQ
+        // handler pointer
+        for address in (0x0300_7E00..0x0300_8000).step_by(4) {
+            memory.debug_write(address, AccessWidth::Bit32, 0);
+        }
+        // Sound is left disabled, with SOUNDBIAS centered
+        memory.debug_write(0x0400_0084, AccessWidth::Bit16, 0);
+        memory.debug_write(0x0400_0088, AccessWidth::Bit16, 0x200);
+        // POSTFLG
+        memory.debug_write(0x0400_0300, AccessWidth::Bit8, 1);
+    }
+
+    /// Resets the console, like turning it off and on again without removing the cart. The cart's
+    /// save memory and hardware keep their state, as do the emulator settings.
+    pub fn reset(&mut self) {
+        // The tasks can't be restarted in place, so everything is created again
+        let mut system = {
+            let memory = self.memory.borrow();
+            let bios = if self.has_bios { memory.bios() } else { &[] };
+            System::with_config(bios, memory.cart_rom(), &self.config)
+        };
+        system
+            .memory
+            .borrow_mut()
+            .take_cart_state(&mut self.memory.borrow_mut());
+
+        system.set_hle_bios(self.cpu.borrow().uses_hle_bios());
+        system.set_timing_accuracy(self.timing_accuracy());
+        system
+            .memory
+            .borrow_mut()
+            .set_stats_enabled(self.memory.borrow().access_stats().is_some());
+        {
+            let old_ppu = self.ppu.borrow();
+            let mut ppu = system.ppu.borrow_mut();
+            ppu.set_timing(old_ppu.timing());
+            ppu.layer_overrides = old_ppu.layer_overrides;
         }
+        system
+            .keypad
+            .borrow_mut()
+            .set_keyinput(self.keypad.borrow().keyinput());
+        system.clock_multiplier = self.clock_multiplier;
+        system.paused = self.paused;
+        mem::swap(&mut system.cheats, &mut self.cheats);
+        mem::swap(&mut system.input_replay, &mut self.input_replay);
+        mem::swap(&mut system.save_file, &mut self.save_file);
+        mem::swap(&mut system.frame_sink, &mut self.frame_sink);
+        mem::swap(&mut system.fps_counter, &mut self.fps_counter);
+        *self = system;
+    }
+
+    pub fn timing_accuracy(&self) -> TimingAccuracy {
+        self.memory.borrow().timing_accuracy()
+    }
+
+    /// Selects how accurately memory timings are emulated. The default is `TimingAccuracy::Fast`.
+    pub fn set_timing_accuracy(&mut self, accuracy: TimingAccuracy) {
+        self.memory.borrow_mut().set_timing_accuracy(accuracy);
+    }
+
+    /// Selects between running BIOS functions from the BIOS or with HLE, even if a BIOS is loaded.
+    pub fn set_hle_bios(&mut self, enabled: bool) {
+        let hle_memory = if enabled {
+            Some(self.memory.clone() as Rc<RefCell<HleMemory>>)
+        } else {
+            None
+        };
+        self.cpu.borrow_mut().set_hle_bios(hle_memory);
+    }
+
+    pub fn clock_multiplier(&self) -> f64 {
+        self.clock_multiplier
+    }
+
+    /// Changes how many cycles the system runs for each emulated frame, relative to the real
+    /// hardware. Values above 1.0 overclock the system, values below underclock it. This is meant
+    /// for benchmarking and stress-testing, and will break timing-sensitive software.
+    pub fn set_clock_multiplier(&mut self, clock_multiplier: f64) {
+        assert!(clock_multiplier > 0.0);
+        self.clock_multiplier = clock_multiplier;
+        self.ppu
+            .borrow_mut()
+            .set_timing(LineTiming::scaled(clock_multiplier));
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Pauses or resumes `run_frame`. The system is left as is while paused, so it resumes exactly
+    /// where it stopped.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.fps_counter.reset();
+    }
+
+    /// Sets where frames completed by `run_frame` are sent. Frames are discarded by default.
+    pub fn set_frame_sink(&mut self, frame_sink: Box<FrameSink>) {
+        self.frame_sink = frame_sink;
+    }
+
+    /// Total number of cycles executed since power on.
+    pub fn current_cycle(&self) -> u64 {
+        self.scheduler.current_time()
+    }
+
+    /// Saves the position of the PPU in the frame, what it has drawn of it, and the LCD status.
+    pub fn save_ppu_state(&self) -> PpuState {
+        self.ppu
+            .borrow()
+            .save_state(&self.lcd_regs.borrow(), self.current_cycle())
+    }
+
+    /// Loads a state saved with `save_ppu_state`, making the PPU carry on from there.
+    pub fn restore_ppu_state(&mut self, state: &PpuState) {
+        let now = self.current_cycle();
+        self.ppu
+            .borrow_mut()
+            .restore_state(&mut self.lcd_regs.borrow_mut(), state, now);
+        // The task may be waiting for the end of a phase that's no longer the current one
+        self.scheduler.wake_task(self.ppu_task);
+        self.scheduler.clock().announce_event(EventSource::Ppu, now);
+    }
+
+    /// Number of frames completed since power on.
+    pub fn frame_count(&self) -> u64 {
+        self.ppu.borrow().frame_count()
+    }
+
+    /// Frames run per second of real time, averaged over the last second or so of frames run with
+    /// `run_frame` or `step_frame`. None until two frames were run, including after pausing.
+    pub fn emulated_fps(&self) -> Option<f64> {
+        self.fps_counter.fps()
+    }
+
+    /// Decodes the BG tiles of a character block of VRAM into a sheet `ppu::TILE_SHEET_WIDTH`
+    /// pixels across, for debug viewers.
+    pub fn dump_tiles(&self, char_base: u8, palette_mode: BgPaletteMode, palette: u8) -> Vec<u16> {
+        let mut memory = self.memory.borrow_mut();
+        let (vram, pals, _) = memory.video_memory();
+        ppu::dump_tiles(vram, pals, char_base, palette_mode, palette)
+    }
+
+    /// Decodes the whole map of BG `bg_index` as it's currently set up, for debug viewers.
+    pub fn dump_map(&self, bg_index: usize) -> BgMapImage {
+        let mut memory = self.memory.borrow_mut();
+        let (vram, pals, _) = memory.video_memory();
+        ppu::dump_bg_map(bg_index, &self.lcd_regs.borrow(), vram, pals)
+    }
+
+    pub fn run_for(&mut self, cycles: u64) {
+        self.scheduler.run_for(cycles);
+    }
+
+    /// Runs only the next scheduled event. Returns false if there was nothing left to run.
+    pub fn run_one_event(&mut self) -> bool {
+        self.scheduler.run_one_event()
+    }
+
+    /// Runs whole cycles until the CPU is about to start executing a new instruction, finishing
+    /// the one in progress. Cycles spent waiting on the bus count towards the instruction that
+    /// made the access. Returns the number of cycles that were run.
+    pub fn step_instruction(&mut self) -> u64 {
+        let start_time = self.current_cycle();
+        loop {
+            self.run_for(1);
+            // A halted CPU doesn't wait on the bus, so it's not stepped over
+            let bus_stalled = self.bus.is_busy() || self.bus.dma_active.get();
+            if self.cpu.borrow().at_instruction_boundary() && !bus_stalled {
+                return self.current_cycle() - start_time;
+            }
+        }
+    }
+
+    /// Starts logging the KEYINPUT state of every frame to `path`. Combined with a known initial
+    /// state, this allows reproducing a session deterministically with `play_inputs`.
+    pub fn record_inputs<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.stop_input_replay()?;
+        self.input_replay = InputReplay::Recording(InputRecorder::create(path)?);
+        Ok(())
+    }
+
+    /// Feeds back inputs recorded with `record_inputs`, one per frame, overriding the keypad.
+    pub fn play_inputs<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.stop_input_replay()?;
+        self.input_replay = InputReplay::Playing(InputPlayer::open(path)?);
+        Ok(())
+    }
+
+    /// Stops any active recording or playback, flushing the recording to disk.
+    pub fn stop_input_replay(&mut self) -> io::Result<()> {
+        match mem::replace(&mut self.input_replay, InputReplay::Inactive) {
+            InputReplay::Recording(recorder) => recorder.finish(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Loads the cart's save memory from `path`, if the file exists, and keeps the file updated
+    /// with the changes made by the game from then on. Changes are flushed once the game stops
+    /// writing for a while, and should be flushed with `flush_save_file` before exiting too. Carts
+    /// without save memory don't use the file, so that an existing one is left alone.
+    pub fn attach_save_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        if self.memory.borrow().save_type() == SaveType::None {
+            return Ok(());
+        }
+        let save_file = SaveFile::new(path.as_ref());
+        save_file.load(self.memory.borrow_mut().cart_sram_mut())?;
+        self.save_file = Some(save_file);
+        Ok(())
+    }
+
+    /// Writes any changes to save memory to the save file.
+    pub fn flush_save_file(&mut self) -> io::Result<()> {
+        let mut memory = self.memory.borrow_mut();
+        let written = memory.take_cart_sram_written();
+        match self.save_file {
+            Some(ref mut save_file) if written || save_file.is_dirty() => {
+                save_file.write(memory.cart_sram())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn update_save_file(&mut self) {
+        let flush = match self.save_file {
+            Some(ref mut save_file) => {
+                save_file.update(self.memory.borrow_mut().take_cart_sram_written())
+            }
+            None => false,
+        };
+        if flush {
+            if let Err(e) = self.flush_save_file() {
+                println!("Failed to write the save file: {}", e);
+            }
+        }
+    }
+
+    fn update_input_replay(&mut self) {
+        let mut finished = false;
+        match self.input_replay {
+            InputReplay::Inactive => {}
+            InputReplay::Recording(ref mut recorder) => {
+                let keyinput = self.keypad.borrow().keyinput();
+                if let Err(e) = recorder.record_frame(keyinput) {
+                    println!("Failed to record inputs: {}", e);
+                    finished = true;
+                }
+            }
+            InputReplay::Playing(ref mut player) => match player.next_frame() {
+                Some(keyinput) => self.keypad.borrow_mut().set_keyinput(keyinput),
+                None => finished = true,
+            },
+        }
+
+        if finished {
+            if let Err(e) = self.stop_input_replay() {
+                println!("Failed to finish input recording: {}", e);
+            }
+        }
+    }
+
+    /// Runs for the duration of one frame, taking the clock multiplier into account, and then
+    /// presents it to the frame sink. Does nothing and returns None while paused.
+    pub fn run_frame(&mut self) -> Option<FrameOutput> {
+        if self.paused {
+            None
+        } else {
+            Some(self.step_frame())
+        }
+    }
+
+    /// Runs one frame like `run_frame`, even while paused, for advancing frame by frame.
+    pub fn step_frame(&mut self) -> FrameOutput {
+        self.update_input_replay();
+
+        // Cheats are applied at the start of VBlank, where games usually read their state
+        let timing = self.ppu.borrow().timing();
+        let vdraw_cycles = timing.line_cycles() * SCREEN_HEIGHT as u64;
+        self.run_for(vdraw_cycles);
+        self.cheats.apply(&mut *self.memory.borrow_mut());
+        self.run_for(timing.frame_cycles() - vdraw_cycles);
+
+        self.update_save_file();
+
+        self.frame_samples.clear();
+        self.frame_samples
+            .extend(self.apu.borrow_mut().drain_samples());
+
+        self.fps_counter.frame();
+
+        let ppu = self.ppu.borrow();
+        self.frame_sink.present(ppu.framebuffer_pixels());
+        FrameOutput {
+            ppu,
+            samples: &self.frame_samples,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ByteOrder;
+    use byteorder::LE;
+    use cpu::asm;
+    use cpu::Mode;
+    use interrupt::Interrupt;
+    use interrupt::PowerState;
+    use keypad::Button;
+    use memory::Region;
+    use ppu::CYCLES_PER_LINE;
+    use ppu::HDRAW_CYCLES;
+    use ppu::LINES_PER_FRAME;
+    use test::Bencher;
+
+    fn assemble(program: &[u32]) -> Vec<u8> {
+        let mut buf = vec![0; program.len() * 4];
+        LE::write_u32_into(program, &mut buf);
+        buf
+    }
+
+    fn read_request(address: u32) -> MemoryRequest {
+        MemoryRequest {
+            address,
+            width: AccessWidth::Bit32,
+            op: OperationType::Read {
+                is_instruction: false,
+            },
+            seq: false,
+        }
+    }
+
+    #[test]
+    fn test_bus_transaction() {
+        let bus = Bus::default();
+        assert!(!bus.is_busy());
+        bus.begin(read_request(0x0300_0000));
+        assert!(bus.is_busy());
+        assert_eq!(bus.pending(), Some(read_request(0x0300_0000)));
+        assert_eq!(bus.take_pending(), Some(read_request(0x0300_0000)));
+        // Stays busy until completed, over as many cycles as the memory needs
+        assert_eq!(bus.take_pending(), None);
+        assert!(bus.is_busy());
+        bus.complete_read(0x1234_5678);
+        assert!(!bus.is_busy());
+        assert_eq!(bus.read_data(), 0x1234_5678);
+    }
+
+    #[test]
+    #[should_panic(expected = "issued during Pending")]
+    fn test_bus_two_masters() {
+        let bus = Bus::default();
+        bus.begin(read_request(0x0300_0000));
+        bus.begin(read_request(0x0200_0000));
+    }
+
+    #[test]
+    #[should_panic(expected = "issued during InProgress")]
+    fn test_bus_begin_while_busy() {
+        let bus = Bus::default();
+        bus.begin(read_request(0x0300_0000));
+        bus.take_pending();
+        bus.begin(read_request(0x0200_0000));
+    }
+
+    #[test]
+    #[should_panic(expected = "Read completed during Idle")]
+    fn test_bus_complete_without_begin() {
+        let bus = Bus::default();
+        bus.complete_read(0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    #[should_panic(expected = "Write completed during Pending")]
+    fn test_bus_complete_before_take() {
+        let bus = Bus::default();
+        bus.begin(read_request(0x0300_0000));
+        bus.complete_write();
+    }
+
+    #[test]
+    #[should_panic(expected = "data read during InProgress")]
+    fn test_bus_read_before_completion() {
+        let bus = Bus::default();
+        bus.begin(read_request(0x0300_0000));
+        bus.take_pending();
+        bus.read_data();
+    }
+
+    #[test]
+    fn test_str_to_dispcnt() {
+        let bios = assemble(&[
+            0xE3A01301, // mov r1, #0x0400'0000
+            0xE3A00003, // mov r0, #0x03
+            0xE3800B01, // orr r0, r0, #0x400
+            0xE5810000, // str r0, [r1]
+            0xEAFFFFFE, // b .
+        ]);
+        let mut system = System::new(&bios, &[]);
+        system.run_for(32);
+        assert_eq!(system.lcd_regs.borrow().read(0x0400_0000), 0x0403);
+    }
+
+    #[test]
+    fn test_strb_to_dispcnt() {
+        let bios = assemble(&[
+            0xE3A01301,                                // mov r1, #0x0400'0000
+            0xE3A00003,                                // mov r0, #0x03
+            0xE5810000,                                // str r0, [r1]
+            0xE3A00004,                                // mov r0, #0x04
+            asm::strb_imm(0, 1, 1, true, true, false), // strb r0, [r1, #1]
+            0xEAFFFFFE,                                // b .
+        ]);
+        let mut system = System::new(&bios, &[]);
+        system.run_for(48);
+        // Only the high byte is written
+        assert_eq!(system.lcd_regs.borrow().read(0x0400_0000), 0x0403);
+    }
+
+    #[test]
+    fn test_hle_swi_without_bios() {
+        let rom = assemble(&[
+            0xE3A00064, // mov r0, #100
+            0xE3A01007, // mov r1, #7
+            0xEF060000, // swi #0x60000 (Div)
+            0xE3A02001, // mov r2, #1
+            0xEAFFFFFE, // b .
+        ]);
+        let mut system = System::new(&[], &rom);
+        system.run_for(12);
+        // Still waiting for Div to finish
+        assert_eq!(system.cpu.borrow().regs()[2], 0);
+        system.run_for(100);
+
+        let cpu = system.cpu.borrow();
+        assert_eq!(&cpu.regs()[..4], &[14, 2, 1, 14]);
+        assert_eq!(cpu.regs()[13], 0x0300_7F00);
+    }
+
+    #[test]
+    fn test_vblank_intr_wait_without_bios() {
+        let rom = assemble(&[
+            0xE3A00301, // mov r0, #0x0400_0000
+            0xE3A01018, // mov r1, #0x18
+            0xE5801004, // str r1, [r0, #4] (DISPSTAT VBlank and HBlank IRQs)
+            0xE3A01003, // mov r1, #3
+            0xE5801200, // str r1, [r0, #0x200] (IE = VBlank | HBlank)
+            0xE3A01302, // mov r1, #0x0800_0000
+            0xE3811040, // orr r1, r1, #0x40
+            0xE3A02403, // mov r2, #0x0300_0000
+            0xE3822C7F, // orr r2, r2, #0x7F00
+            0xE58210FC, // str r1, [r2, #0xFC] (IRQ handler at 0x0800_0040)
+            // loop:
+            0xEF050000, // swi #0x50000 (VBlankIntrWait)
+            0xE2855001, // add r5, r5, #1
+            0xEAFFFFFC, // b loop
+            0x00000000, 0x00000000, 0x00000000,
+            // IRQ handler, called by the BIOS with r0 = 0x0400_0000:
+            0xE5901200, // ldr r1, [r0, #0x200]
+            0xE0011821, // and r1, r1, r1, lsr #16 (IE & IF)
+            0xE1A02801, // mov r2, r1, lsl #16
+            0xE3822003, // orr r2, r2, #3
+            0xE5802200, // str r2, [r0, #0x200] (acknowledge in IF, keeping IE)
+            0xE5102008, // ldr r2, [r0, #-8]
+            0xE1822001, // orr r2, r2, r1
+            0xE5002008, // str r2, [r0, #-8] (set the same flags in the BIOS IF mirror)
+            0xE12FFF1E, // bx lr
+        ]);
+        let mut system = System::new(&[], &rom);
+        system.step_frame();
+        let waits = system.cpu.borrow().regs()[5];
+        // HBlank interrupts wake the CPU up on every line, but only VBlank ends the wait
+        for frame in 1..4 {
+            system.step_frame();
+            assert_eq!(system.cpu.borrow().regs()[5], waits + frame);
+        }
+
+        // IntrWait enabled IME, and cleared VBlank from the mirror, but not HBlank, which it
+        // wasn't waiting for
+        let mut memory = system.memory.borrow_mut();
+        assert_eq!(memory.debug_read(0x0400_0208, AccessWidth::Bit16), 1);
+        assert_eq!(memory.debug_read(0x0300_7FF8, AccessWidth::Bit16), 0b10);
+    }
+
+    #[test]
+    fn test_skip_bios_boot_with_bios() {
+        // The BIOS would hang instead of booting the cart
+        let bios = assemble(&[
+            0xEAFFFFFE, // b .
+        ]);
+        let rom = assemble(&[
+            0xE3A00403, // mov r0, #0x0300'0000
+            0xE580D000, // str sp, [r0]
+            0xEAFFFFFE, // b .
+        ]);
+        let mut system = System::new(&bios, &rom);
+        system
+            .memory
+            .borrow_mut()
+            .debug_write(0x0300_7FFC, AccessWidth::Bit32, 0x1234_5678);
+        system.skip_bios_boot();
+        system.run_for(100);
+
+        let mut memory = system.memory.borrow_mut();
+        assert_eq!(
+            memory.debug_read(0x0300_0000, AccessWidth::Bit32),
+            0x0300_7F00
+        );
+        assert_eq!(memory.debug_read(0x0300_7FFC, AccessWidth::Bit32), 0);
+        assert_eq!(memory.debug_read(0x0400_0088, AccessWidth::Bit16), 0x200);
+        assert_eq!(memory.debug_read(0x0400_0300, AccessWidth::Bit8), 1);
+    }
+
+    #[test]
+    fn test_skip_bios_boot_config() {
+        let mut bios = assemble(&[
+            0xE3A00001, // mov r0, #1 (boot code)
+            0xEAFFFFFE, // b .
+        ]);
+        bios.extend(assemble(&[
+            0xE3A01042, // mov r1, #0x42 (SWI handler)
+            0xEAFFFFFE, // b .
+        ]));
+        let rom = assemble(&[
+            0xE3A02002, // mov r2, #2
+            0xEF000000, // swi #0
+            0xEAFFFFFE, // b .
+        ]);
+        let config = SystemConfig {
+            skip_bios_boot: true,
+            ..SystemConfig::default()
+        };
+        let mut system = System::with_config(&bios, &rom, &config);
+        assert_eq!(system.cpu.borrow().regs()[15], 0x0800_0000);
+
+        // The first instruction executed, once the pipeline is filled, is the cart's first one
+        system.step_instruction();
+        system.step_instruction();
+        assert_eq!(&system.cpu.borrow().regs()[..3], &[0, 0, 2]);
+
+        // The BIOS still handles SWIs
+        system.run_for(100);
+        let cpu = system.cpu.borrow();
+        assert_eq!(&cpu.regs()[..3], &[0, 0x42, 2]);
+        assert_eq!(cpu.cpsr().mode(), Mode::Supervisor);
+    }
+
+    #[test]
+    fn test_hle_soft_reset() {
+        let rom = assemble(&[
+            0xE3A05005, // mov r5, #5
+            0xEF000000, // swi #0 (SoftReset)
+        ]);
+        let mut system = System::new(&[], &rom);
+        {
+            let mut memory = system.memory.borrow_mut();
+            // Returns to EWRAM instead of ROM
+            memory.debug_write(0x0300_7FFA, AccessWidth::Bit8, 1);
+            memory.debug_write(0x0300_7E00, AccessWidth::Bit32, 0x1234_5678);
+            let ewram_program = [
+                0xE3A00403, // mov r0, #0x0300'0000
+                0xE580D000, // str sp, [r0]
+                0xEAFFFFFE, // b .
+            ];
+            for (i, &word) in ewram_program.iter().enumerate() {
+                memory.debug_write(0x0200_0000 + i as u32 * 4, AccessWidth::Bit32, word);
+            }
+        }
+        system.run_for(400);
+
+        assert_eq!(system.cpu.borrow().regs()[5], 0);
+        let mut memory = system.memory.borrow_mut();
+        assert_eq!(
+            memory.debug_read(0x0300_0000, AccessWidth::Bit32),
+            0x0300_7F00
+        );
+        assert_eq!(memory.debug_read(0x0300_7E00, AccessWidth::Bit32), 0);
+        assert_eq!(memory.debug_read(0x0300_7FFA, AccessWidth::Bit8), 0);
+    }
+
+    #[test]
+    fn test_reset() {
+        let bios = assemble(&[
+            0xE3A0040E, // mov r0, #0x0E00_0000
+            0xE3A01042, // mov r1, #0x42
+            0xE5C01010, // strb r1, [r0, #0x10]
+            0xE3A00402, // mov r0, #0x0200_0000
+            0xE5801000, // str r1, [r0]
+            0xE3A00301, // mov r0, #0x0400_0000
+            0xE5801000, // str r1, [r0]
+            0xEAFFFFFE, // b .
+        ]);
+        let mut system = System::new(&bios, &[]);
+        system.set_clock_multiplier(2.0);
+        system.keypad.borrow_mut().set_pressed(Button::A, true);
+        system.run_for(100);
+        system.reset();
+
+        assert_eq!(system.current_cycle(), 0);
+        assert_eq!(system.clock_multiplier(), 2.0);
+        assert!(system.keypad.borrow().is_pressed(Button::A));
+        assert_eq!(system.cpu.borrow().regs()[1], 0);
+        let mut memory = system.memory.borrow_mut();
+        assert_eq!(memory.debug_read(0x0200_0000, AccessWidth::Bit32), 0);
+        assert_eq!(memory.debug_read(0x0400_0000, AccessWidth::Bit16), 0);
+        // Save memory is in the cart, so it survives
+        assert_eq!(memory.debug_read(0x0E00_0010, AccessWidth::Bit8), 0x42);
+        assert!(memory.take_cart_sram_written());
+    }
+
+    #[test]
+    fn test_config() {
+        let bios = assemble(&[
+            0xE3A0040E, // mov r0, #0x0E00_0000
+            0xE3A01042, // mov r1, #0x42
+            0xE5C01010, // strb r1, [r0, #0x10]
+            0xEAFFFFFE, // b .
+        ]);
+        let config = SystemConfig {
+            hle_bios: true,
+            save_type: SaveType::None,
+            sram_fill: 0x00,
+            clock_multiplier: 2.0,
+            timing_accuracy: TimingAccuracy::Accurate,
+            ..SystemConfig::default()
+        };
+        let mut system = System::with_config(&bios, &[], &config);
+        assert!(system.cpu.borrow().uses_hle_bios());
+        assert_eq!(system.clock_multiplier(), 2.0);
+        assert_eq!(system.timing_accuracy(), TimingAccuracy::Accurate);
+        // The BIOS still boots, as the boot wasn't skipped
+        assert_eq!(system.cpu.borrow().regs()[15], 0);
+        system.run_for(100);
+        system.reset();
+
+        // Without save memory, the write is ignored, and it stays that way after a reset
+        let mut memory = system.memory.borrow_mut();
+        assert_eq!(memory.save_type(), SaveType::None);
+        assert!(memory.cart_sram().iter().all(|&byte| byte == 0x00));
+        assert_eq!(memory.debug_read(0x0E00_0010, AccessWidth::Bit8), 0xFF);
+        assert!(!memory.take_cart_sram_written());
+    }
+
+    #[test]
+    fn test_frame_and_cycle_counts() {
+        let bios = assemble(&[
+            0xEAFFFFFE, // b .
+        ]);
+        let mut system = System::new(&bios, &[]);
+        assert_eq!(system.emulated_fps(), None);
+        for _ in 0..3 {
+            system.run_frame();
+        }
+        assert_eq!(system.frame_count(), 3);
+        let frame_cycles = CYCLES_PER_LINE * LINES_PER_FRAME as u64;
+        assert_eq!(system.current_cycle(), 3 * frame_cycles);
+        assert!(system.emulated_fps().unwrap() > 0.0);
+
+        system.set_paused(true);
+        assert_eq!(system.emulated_fps(), None);
+    }
+
+    #[test]
+    fn test_step_instruction() {
+        let bios = assemble(&[
+            0xE3A00001, // mov r0, #1
+            0xE3A01002, // mov r1, #2
+            0xE1A02310, // mov r2, r0, lsl r3
+            0xE3A03004, // mov r3, #4
+        ]);
+        let mut system = System::new(&bios, &[]);
+        // Fill the pipeline
+        system.step_instruction();
+
+        assert_eq!(system.step_instruction(), 1);
+        assert_eq!(&system.cpu.borrow().regs()[..4], &[1, 0, 0, 0]);
+        assert_eq!(system.step_instruction(), 1);
+        assert_eq!(&system.cpu.borrow().regs()[..4], &[1, 2, 0, 0]);
+        // Register-specified shifts take an extra cycle
+        assert_eq!(system.step_instruction(), 2);
+        assert_eq!(&system.cpu.borrow().regs()[..4], &[1, 2, 1, 0]);
+        assert_eq!(system.step_instruction(), 1);
+        assert_eq!(&system.cpu.borrow().regs()[..4], &[1, 2, 1, 4]);
+    }
+
+    #[test]
+    fn test_idle_loop_skipping() {
+        let mut bios = vec![0; 0x18];
+        bios.extend(assemble(&[
+            0xE3A05001, // mov r5, #1 (IRQ handler)
+            0xEAFFFFFE, // b .
+        ]));
+        let cart = assemble(&[0xEAFFFFFE]); // b .
+        let mut system = System::new(&bios, &cart);
+        // Boots into System mode, with IRQs enabled
+        system.skip_bios_boot();
+        system.interrupts.write(0x0400_0200, 1);
+        system.interrupts.write(0x0400_0208, 1);
+        system
+            .lcd_regs
+            .borrow_mut()
+            .write(0x0400_0004, AccessWidth::Bit16, 1 << 3);
+        system.memory.borrow_mut().set_stats_enabled(true);
+
+        // Waiting for VBlank only runs the loop for a few iterations after each PPU event, instead
+        // of for every cycle
+        let vblank_start = CYCLES_PER_LINE * SCREEN_HEIGHT as u64;
+        system.run_for(vblank_start - 10);
+        let rom_reads = system
+            .memory
+            .borrow()
+            .access_stats()
+            .unwrap()
+            .total(Region::CartRom);
+        assert!(rom_reads < SCREEN_HEIGHT as u64 * 2 * 20, "{}", rom_reads);
+        assert_eq!(system.cpu.borrow().regs()[5], 0);
+
+        // The interrupt still comes in on time
+        system.run_for(20);
+        assert_eq!(system.cpu.borrow().regs()[5], 1);
+    }
+
+    #[test]
+    fn test_idle_loop_dma_irq() {
+        let mut bios = vec![0; 0x18];
+        bios.extend(assemble(&[
+            0xE3A05001, // mov r5, #1 (IRQ handler)
+            0xEAFFFFFE, // b .
+        ]));
+        // The NOPs delay reaching the loop until around the start of HBlank, so that some of
+        // them get there just after the DMA is triggered, but before it takes the bus
+        for nops in 936..952 {
+            let mut cart = vec![0xE1A00000; nops]; // mov r0, r0
+            cart.push(0xEAFFFFFE); // b .
+            let mut system = System::new(&bios, &assemble(&cart));
+            system.skip_bios_boot();
+            {
+                // Channel 3 copies 4 words within IWRAM in the first HBlank, with IRQ
+                let mut memory = system.memory.borrow_mut();
+                memory.debug_write(0x0400_00D4, AccessWidth::Bit32, 0x0300_0100);
+                memory.debug_write(0x0400_00D8, AccessWidth::Bit32, 0x0300_0200);
+                memory.debug_write(0x0400_00DC, AccessWidth::Bit16, 4);
+                memory.debug_write(0x0400_00DE, AccessWidth::Bit16, 0xE400);
+            }
+            system.interrupts.write(0x0400_0200, 0x0800);
+            system.interrupts.write(0x0400_0208, 1);
+
+            system.run_for(HDRAW_CYCLES - 10);
+            assert_eq!(system.cpu.borrow().regs()[5], 0, "{} NOPs", nops);
+            // The IRQ comes as soon as the transfer ends, not at the next PPU event
+            system.run_for(40);
+            assert_eq!(system.cpu.borrow().regs()[5], 1, "{} NOPs", nops);
+        }
+    }
+
+    #[test]
+    fn test_busy_loop_not_skipped() {
+        let bios = assemble(&[
+            0xE3A01402, // mov r1, #0x0200_0000
+            0xE5910000, // ldr r0, [r1]
+            0xE2800001, // add r0, r0, #1
+            0xE5810000, // str r0, [r1]
+            0xE3A00000, // mov r0, #0
+            0xEAFFFFFA, // b 0x04
+        ]);
+        let mut system = System::new(&bios, &[]);
+        // The registers are the same at the start of each iteration, but it counts in memory
+        system.run_for(10_000);
+        let count = system
+            .memory
+            .borrow_mut()
+            .debug_read(0x0200_0000, AccessWidth::Bit32);
+        assert!(count > 10_000 / 30, "{}", count);
+    }
+
+    #[test]
+    fn test_stop_mode() {
+        let bios = assemble(&[
+            0xE3A00301, // mov r0, #0x0400_0000
+            0xE3A01008, // mov r1, #0x08
+            0xE5801004, // str r1, [r0, #4] (VBlank IRQ enabled in DISPSTAT)
+            0xE2800C02, // add r0, r0, #0x200
+            0xE3A01A01, // mov r1, #0x1000
+            0xE3811001, // orr r1, r1, #1
+            0xE5801000, // str r1, [r0] (Keypad and VBlank enabled in IE)
+            0xE2800C01, // add r0, r0, #0x100
+            0xE3A01080, // mov r1, #0x80
+            0xE5C01001, // strb r1, [r0, #1] (Stop)
+            0xE3A02001, // mov r2, #1
+            0xEAFFFFFE, // b .
+        ]);
+        let mut system = System::new(&bios, &[]);
+        // Keypad IRQ when A is pressed
+        system.keypad.borrow_mut().write(0x0400_0132, 0x4001);
+        system.run_for(200);
+        assert_eq!(system.interrupts.power_state(), PowerState::Stopped);
+        assert_eq!(
+            system
+                .memory
+                .borrow_mut()
+                .debug_read(0x0400_0300, AccessWidth::Bit8),
+            0
+        );
+
+        // The PPU doesn't run, so there are no frames or VBlank interrupts
+        for _ in 0..3 {
+            system.run_frame();
+        }
+        assert_eq!(system.ppu.borrow().frame_count(), 0);
+        assert_eq!(system.interrupts.read(0x0400_0202), 0);
+
+        // Only some interrupts wake the system up
+        system.interrupts.request(Interrupt::VBlank);
+        system.run_for(100);
+        assert_eq!(system.interrupts.power_state(), PowerState::Stopped);
+        assert_eq!(system.cpu.borrow().regs()[2], 0);
+
+        system.keypad.borrow_mut().set_pressed(Button::A, true);
+        assert_eq!(system.interrupts.power_state(), PowerState::Running);
+        system.run_for(100);
+        assert_eq!(system.cpu.borrow().regs()[2], 1);
+
+        system.interrupts.write(0x0400_0202, 0xFFFF);
+        system.run_frame();
+        assert_eq!(system.ppu.borrow().frame_count(), 1);
+        assert_eq!(system.interrupts.read(0x0400_0202), 1);
+    }
+
+    #[test]
+    fn test_sound_length() {
+        let bios = assemble(&[0xEAFFFFFE]); // b .
+        let mut system = System::new(&bios, &[]);
+        {
+            let mut memory = system.memory.borrow_mut();
+            memory.debug_write(0x0400_0084, AccessWidth::Bit16, 0x0080);
+            memory.debug_write(0x0400_0080, AccessWidth::Bit16, 0x1177);
+            memory.debug_write(0x0400_0082, AccessWidth::Bit16, 0x0002);
+            // Channel 1 at full volume for 16/256 s, about 3.7 frames
+            memory.debug_write(0x0400_0062, AccessWidth::Bit16, 0xF000 | 48);
+            memory.debug_write(0x0400_0064, AccessWidth::Bit16, 0xC000);
+        }
+
+        for _ in 0..3 {
+            let frame = system.run_frame().unwrap();
+            assert!(frame.samples().iter().any(|&sample| sample.left > 0));
+        }
+        let frame = system.run_frame().unwrap();
+        assert_eq!(frame.samples().last(), Some(&StereoSample::default()));
+        drop(frame);
+        assert_eq!(system.apu.borrow().read(0x0400_0084), 0x0080);
+    }
+
+    #[test]
+    fn test_clock_multiplier() {
+        let bios = assemble(&[
+            0xEAFFFFFE, // b .
+        ]);
+
+        let mut system = System::new(&bios, &[]);
+        system.run_frame();
+        let normal_cycles = system.current_cycle();
+        assert_eq!(system.ppu.borrow().frame_count(), 1);
+
+        let mut system = System::new(&bios, &[]);
+        system.set_clock_multiplier(2.0);
+        system.run_frame();
+        assert_eq!(system.current_cycle(), normal_cycles * 2);
+        assert_eq!(system.ppu.borrow().frame_count(), 1);
+    }
+
+    #[test]
+    fn test_mode5_page_flip() {
+        let bios = assemble(&[
+            0xEAFFFFFE, // b .
+        ]);
+        let mut system = System::new(&bios, &[]);
+
+        {
+            let mut memory = system.memory.borrow_mut();
+            memory.palettes_mut()[0] = 0x7FFF;
+            let vram = memory.vram_mut();
+            for y in 0..128 {
+                for x in 0..160 {
+                    let offset = (y * 160 + x) * 2;
+                    LE::write_u16(&mut vram[offset..], 0x001F);
+                    LE::write_u16(&mut vram[0xA000 + offset..], 0x03E0);
+                }
+            }
+        }
+
+        let check_frame = |system: &System, top_color: u16, bottom_color: u16, split: usize| {
+            let ppu = system.ppu.borrow();
+            let framebuffer = ppu.framebuffer();
+            for y in 0..160 {
+                let expected = if y >= 128 {
+                    0x7FFF // Backdrop
+                } else if y < split {
+                    top_color
+                } else {
+                    bottom_color
+                };
+                assert_eq!(framebuffer[y][0], expected, "line {}", y);
+                assert_eq!(framebuffer[y][159], expected, "line {}", y);
+                assert_eq!(framebuffer[y][160], 0x7FFF, "line {}", y);
+            }
+        };
+
+        // Mode 5, BG2 enabled, page 0
+        system
+            .lcd_regs
+            .borrow_mut()
+            .write(0x0400_0000, AccessWidth::Bit16, 0x0405);
+        system.run_frame();
+        check_frame(&system, 0x001F, 0x001F, 160);
+
+        // Page 1
+        system
+            .lcd_regs
+            .borrow_mut()
+            .write(0x0400_0000, AccessWidth::Bit16, 0x0415);
+        system.run_frame();
+        check_frame(&system, 0x03E0, 0x03E0, 160);
+
+        // Flip back to page 0 in the middle of the frame
+        let line_cycles = system.ppu.borrow().timing().line_cycles();
+        system.run_for(line_cycles * 64);
+        system
+            .lcd_regs
+            .borrow_mut()
+            .write(0x0400_0000, AccessWidth::Bit16, 0x0405);
+        system.run_for(line_cycles * (228 - 64));
+        check_frame(&system, 0x03E0, 0x001F, 64);
+    }
+
+    #[test]
+    fn test_mid_frame_scroll_change() {
+        let bios = assemble(&[
+            0xEAFFFFFE, // b .
+        ]);
+        let mut system = System::new(&bios, &[]);
+
+        {
+            let mut memory = system.memory.borrow_mut();
+            // Mode 0, BG0 enabled, map at screenblock 8
+            memory.debug_write(0x0400_0000, AccessWidth::Bit16, 0x0100);
+            memory.debug_write(0x0400_0008, AccessWidth::Bit16, 0x0800);
+            memory.palettes_mut()[1] = 0x001F;
+            memory.palettes_mut()[2] = 0x03E0;
+            let vram = memory.vram_mut();
+            // Tile 1 is filled with color 1 and tile 2 with color 2. The first column of the map
+            // uses tile 1, and the rest tile 2.
+            for byte in vram[0x20..0x40].iter_mut() {
+                *byte = 0x11;
+            }
+            for byte in vram[0x40..0x60].iter_mut() {
+                *byte = 0x22;
+            }
+            for i in 0..32 * 32 {
+                let tile = if i % 32 == 0 { 1 } else { 2 };
+                LE::write_u16(&mut vram[0x4000 + i * 2..], tile);
+            }
+        }
+
+        // Scroll the first column out of view during the HBlank of line 79
+        let timing = system.ppu.borrow().timing();
+        system.run_for(timing.line_cycles() * 79 + timing.hdraw + 1);
+        system
+            .memory
+            .borrow_mut()
+            .debug_write(0x0400_0010, AccessWidth::Bit16, 8);
+        system.run_for(timing.frame_cycles() - timing.line_cycles() * 79 - timing.hdraw - 1);
+
+        let ppu = system.ppu.borrow();
+        let framebuffer = ppu.framebuffer();
+        for y in 0..160 {
+            let expected = if y < 80 { 0x001F } else { 0x03E0 };
+            assert_eq!(framebuffer[y][0], expected, "line {}", y);
+        }
+    }
+
+    #[test]
+    fn test_ppu_state_mid_frame() {
+        use ppu::LinePhase;
+        use ppu::SCREEN_WIDTH;
+
+        let bios = assemble(&[
+            0xEAFFFFFE, // b .
+        ]);
+        // Mode 3 with BG2, each line in its own color
+        let draw = |system: &System, first_color: u32| {
+            let mut memory = system.memory.borrow_mut();
+            memory.debug_write(0x0400_0000, AccessWidth::Bit16, 0x0403);
+            for y in 0..SCREEN_HEIGHT as u32 {
+                for x in 0..SCREEN_WIDTH as u32 {
+                    let address = 0x0600_0000 + (y * SCREEN_WIDTH as u32 + x) * 2;
+                    memory.debug_write(address, AccessWidth::Bit16, first_color + y);
+                }
+            }
+        };
+        let run_to_vblank = |system: &mut System, cycles: u64| {
+            system.run_for(cycles);
+            assert_eq!(system.ppu.borrow().frame_count(), 0);
+            system.run_for(1);
+            assert_eq!(system.ppu.borrow().frame_count(), 1);
+        };
+
+        // Saved partway through the HDraw of line 80, then the picture changes
+        let mut system = System::new(&bios, &[]);
+        draw(&system, 0);
+        {
+            let mut memory = system.memory.borrow_mut();
+            // BG2 reference point at (1.0, -2.0), moving by (0.25, 1.5) each line
+            memory.debug_write(0x0400_0022, AccessWidth::Bit16, 0x0040);
+            memory.debug_write(0x0400_0026, AccessWidth::Bit16, 0x0180);
+            memory.debug_write(0x0400_0028, AccessWidth::Bit32, 0x0000_0100);
+            memory.debug_write(0x0400_002C, AccessWidth::Bit32, 0x0FFF_FE00);
+        }
+        system.run_for(80 * CYCLES_PER_LINE + 100);
+        let state = system.save_ppu_state();
+        assert_eq!(state.vcount, 80);
+        assert_eq!(state.phase, LinePhase::HDraw);
+        assert_eq!(state.cycles_left, HDRAW_CYCLES - 100);
+        assert_eq!(
+            state.lcd_status.affine_reference_points[0],
+            (0x100 + 80 * 0x40, -0x200 + 80 * 0x180)
+        );
+        draw(&system, 0x100);
+        let cycles_to_vblank = 80 * CYCLES_PER_LINE - 100;
+        run_to_vblank(&mut system, cycles_to_vblank);
+
+        // A system running a line in VBlank, with the PPU waiting for a later time than the end of
+        // the restored phase, picks up the frame at the same point
+        let mut restored = System::new(&bios, &[]);
+        draw(&restored, 0x100);
+        restored.run_for(200 * CYCLES_PER_LINE + 50);
+        restored.restore_ppu_state(&state);
+        assert_eq!(restored.lcd_regs.borrow().status(), state.lcd_status);
+        {
+            let mut memory = restored.memory.borrow_mut();
+            assert_eq!(memory.debug_read(0x0400_0006, AccessWidth::Bit16), 80);
+            // Not in VBlank anymore
+            assert_eq!(
+                memory.debug_read(0x0400_0004, AccessWidth::Bit16) & 0b111,
+                0
+            );
+        }
+        run_to_vblank(&mut restored, cycles_to_vblank);
+
+        let framebuffer = restored.ppu.borrow().framebuffer().clone();
+        assert_eq!(framebuffer[79][0], 79);
+        assert_eq!(framebuffer[80][0], 0x100 + 80);
+        assert!(framebuffer[..] == system.ppu.borrow().framebuffer()[..]);
+    }
+
+    /// Runs until the `str` at `address` in the BIOS, and returns the cycles it takes.
+    fn time_store(system: &mut System, address: u32) -> u64 {
+        while system.cpu.borrow().regs()[15] != address + 8 {
+            system.step_instruction();
+        }
+        system.step_instruction()
+    }
+
+    #[test]
+    fn test_video_memory_contention() {
+        let bios = assemble(&[
+            0xE3A01406, // mov r1, #0x0600'0000
+            0xE5810000, // loop: str r0, [r1]
+            0xEAFFFFFD, // b loop
+        ]);
+        for &(accuracy, drawing_cycles) in
+            [(TimingAccuracy::Fast, 2), (TimingAccuracy::Accurate, 3)].iter()
+        {
+            let mut system = System::new(&bios, &[]);
+            system.set_timing_accuracy(accuracy);
+            // The PPU is drawing line 0
+            assert_eq!(time_store(&mut system, 0x4), drawing_cycles);
+
+            let vblank_start = system.ppu.borrow().timing().line_cycles() * SCREEN_HEIGHT as u64;
+            let current_cycle = system.current_cycle();
+            system.run_for(vblank_start - current_cycle);
+            assert_eq!(time_store(&mut system, 0x4), 2);
+        }
+    }
+
+    #[test]
+    fn test_oam_access_during_drawing() {
+        let bios = assemble(&[
+            0xE3A01407, // mov r1, #0x0700'0000
+            0xE3A00012, // mov r0, #0x12
+            0xE5810000, // loop: str r0, [r1]
+            0xEAFFFFFD, // b loop
+        ]);
+        let timing = LineTiming::NORMAL;
+        // With the HBlank interval free, OAM can be accessed from HBlank on. Otherwise, it has to
+        // wait until VBlank.
+        for &(dispcnt, available_time) in [
+            (0x0020, timing.hdraw),
+            (0x0000, timing.line_cycles() * SCREEN_HEIGHT as u64),
+        ]
+        .iter()
+        {
+            let mut system = System::new(&bios, &[]);
+            system.set_timing_accuracy(TimingAccuracy::Accurate);
+            system
+                .lcd_regs
+                .borrow_mut()
+                .write(0x0400_0000, AccessWidth::Bit16, dispcnt);
+
+            time_store(&mut system, 0x8);
+            let current_cycle = system.current_cycle();
+            assert!(current_cycle > available_time, "{}", current_cycle);
+            assert!(current_cycle <= available_time + 3, "{}", current_cycle);
+            let mut memory = system.memory.borrow_mut();
+            assert_eq!(memory.debug_read(0x0700_0000, AccessWidth::Bit16), 0x12);
+        }
+    }
+
+    #[test]
+    fn test_dma_priority() {
+        let bios = assemble(&[
+            0xE3A00042, // mov r0, #0x42
+            0xEAFFFFFE, // b .
+        ]);
+        let mut system = System::new(&bios, &[]);
+        {
+            let mut memory = system.memory.borrow_mut();
+            for i in 0..8 {
+                memory.debug_write(
+                    0x0300_0000 + i * 4,
+                    AccessWidth::Bit32,
+                    0x1111_1111 * (i + 1),
+                );
+            }
+            // DMA0 and DMA1 IRQs enabled
+            memory.debug_write(0x0400_0200, AccessWidth::Bit16, 0x0300);
+            // Both copy 8 words, with IRQ, each to its own buffer. Channel 1 starts first, but
+            // channel 0 still gets the bus before it.
+            for &(channel, dest) in [(1, 0x0300_0200), (0, 0x0300_0100)].iter() {
+                let base = 0x0400_00B0 + channel * 12;
+                memory.debug_write(base, AccessWidth::Bit32, 0x0300_0000);
+                memory.debug_write(base + 4, AccessWidth::Bit32, dest);
+                memory.debug_write(base + 8, AccessWidth::Bit16, 8);
+                memory.debug_write(base + 10, AccessWidth::Bit16, 0xC400);
+            }
+        }
+
+        // Each word takes a read and a write cycle
+        system.run_for(20);
+        {
+            let mut memory = system.memory.borrow_mut();
+            assert_eq!(
+                memory.debug_read(0x0300_011C, AccessWidth::Bit32),
+                0x8888_8888
+            );
+            assert_eq!(memory.debug_read(0x0400_00BA, AccessWidth::Bit16), 0x4400);
+            assert_eq!(memory.debug_read(0x0300_021C, AccessWidth::Bit32), 0);
+            assert_eq!(system.interrupts.read(0x0400_0202), 0x0100);
+        }
+
+        system.run_for(100);
+        let mut memory = system.memory.borrow_mut();
+        assert_eq!(
+            memory.debug_read(0x0300_021C, AccessWidth::Bit32),
+            0x8888_8888
+        );
+        assert_eq!(memory.debug_read(0x0400_00C6, AccessWidth::Bit16), 0x4400);
+        assert_eq!(system.interrupts.read(0x0400_0202), 0x0300);
+        // The CPU continues where it was stalled
+        assert_eq!(system.cpu.borrow().regs()[0], 0x42);
+    }
+
+    #[test]
+    fn test_hblank_dma() {
+        let bios = assemble(&[
+            0xEAFFFFFE, // b .
+        ]);
+        let mut system = System::new(&bios, &[]);
+        {
+            let mut memory = system.memory.borrow_mut();
+            for line in 0..SCREEN_HEIGHT as u32 + 1 {
+                memory.debug_write(0x0300_0400 + line * 2, AccessWidth::Bit16, 0x100 + line);
+            }
+            // Channel 0 copies the next halfword to the same address in each HBlank, repeating
+            memory.debug_write(0x0400_00B0, AccessWidth::Bit32, 0x0300_0400);
+            memory.debug_write(0x0400_00B4, AccessWidth::Bit32, 0x0300_0800);
+            memory.debug_write(0x0400_00B8, AccessWidth::Bit16, 1);
+            memory.debug_write(0x0400_00BA, AccessWidth::Bit16, 0xA240);
+        }
+        let value_at = |system: &mut System, cycle: u64| {
+            let now = system.current_cycle();
+            system.run_for(cycle - now);
+            system
+                .memory
+                .borrow_mut()
+                .debug_read(0x0300_0800, AccessWidth::Bit16)
+        };
+
+        // Each line's value lands right after it enters HBlank
+        assert_eq!(value_at(&mut system, HDRAW_CYCLES - 1), 0);
+        assert_eq!(value_at(&mut system, HDRAW_CYCLES + 4), 0x100);
+        assert_eq!(
+            value_at(&mut system, CYCLES_PER_LINE + HDRAW_CYCLES - 1),
+            0x100
+        );
+        assert_eq!(
+            value_at(&mut system, CYCLES_PER_LINE + HDRAW_CYCLES + 4),
+            0x101
+        );
+
+        // There are no transfers during VBlank, and the source keeps going in the next frame
+        let frame_cycles = CYCLES_PER_LINE * LINES_PER_FRAME as u64;
+        assert_eq!(value_at(&mut system, frame_cycles - 1), 0x100 + 159);
+        assert_eq!(
+            value_at(&mut system, frame_cycles + HDRAW_CYCLES + 4),
+            0x100 + 160
+        );
+        assert_eq!(
+            system
+                .memory
+                .borrow_mut()
+                .debug_read(0x0400_00BA, AccessWidth::Bit16),
+            0xA240
+        );
+    }
+
+    #[test]
+    fn test_vblank_dma() {
+        let bios = assemble(&[
+            0xEAFFFFFE, // b .
+        ]);
+        let mut system = System::new(&bios, &[]);
+        {
+            let mut memory = system.memory.borrow_mut();
+            for i in 0..8 {
+                memory.debug_write(
+                    0x0200_0000 + i * 4,
+                    AccessWidth::Bit32,
+                    0x1111_1111 * (i + 1),
+                );
+            }
+            // Channel 3 copies 4 words to the start of OAM in each VBlank, with IRQ. The source
+            // keeps going, while the destination goes back to the start each time.
+            memory.debug_write(0x0400_00D4, AccessWidth::Bit32, 0x0200_0000);
+            memory.debug_write(0x0400_00D8, AccessWidth::Bit32, 0x0700_0000);
+            memory.debug_write(0x0400_00DC, AccessWidth::Bit16, 4);
+            memory.debug_write(0x0400_00DE, AccessWidth::Bit16, 0xD660);
+        }
+        system.interrupts.write(0x0400_0200, 0x0800);
+        let oam = |system: &System| -> Vec<u32> {
+            let mut memory = system.memory.borrow_mut();
+            (0..5)
+                .map(|i| memory.debug_read(0x0700_0000 + i * 4, AccessWidth::Bit32))
+                .collect()
+        };
+
+        let vblank_start = CYCLES_PER_LINE * SCREEN_HEIGHT as u64;
+        system.run_for(vblank_start - 1);
+        assert_eq!(oam(&system), [0; 5]);
+        assert_eq!(system.interrupts.read(0x0400_0202), 0);
+        // EWRAM reads take a few wait states
+        system.run_for(60);
+        assert_eq!(
+            oam(&system),
+            [0x1111_1111, 0x2222_2222, 0x3333_3333, 0x4444_4444, 0]
+        );
+        assert_eq!(system.interrupts.read(0x0400_0202), 0x0800);
+        system.interrupts.write(0x0400_0202, 0x0800);
+
+        // Nothing else happens until the next VBlank
+        let frame_cycles = CYCLES_PER_LINE * LINES_PER_FRAME as u64;
+        system.run_for(frame_cycles - 60);
+        assert_eq!(oam(&system)[0], 0x1111_1111);
+        assert_eq!(system.interrupts.read(0x0400_0202), 0);
+        system.run_for(60);
+        assert_eq!(
+            oam(&system),
+            [0x5555_5555, 0x6666_6666, 0x7777_7777, 0x8888_8888, 0]
+        );
+        assert_eq!(system.interrupts.read(0x0400_0202), 0x0800);
+    }
+
+    #[test]
+    fn test_vblank_flag_lines() {
+        let bios = assemble(&[
+            0xEAFFFFFE, // b .
+        ]);
+        let mut system = System::new(&bios, &[]);
+        let status = |system: &System| -> (u16, bool) {
+            let mut memory = system.memory.borrow_mut();
+            let dispstat = memory.debug_read(0x0400_0004, AccessWidth::Bit16);
+            let vcount = memory.debug_read(0x0400_0006, AccessWidth::Bit16);
+            (vcount as u16, dispstat & 1 != 0)
+        };
+
+        // Check each line halfway through its HDraw, from the start of the frame
+        let mut line = 0;
+        system.run_for(CYCLES_PER_LINE / 2);
+        for &(vcount, vblank) in [
+            (0, false),
+            (159, false),
+            (160, true),
+            (161, true),
+            (226, true),
+            // The flag is already cleared on the last line, which is still part of VBlank
+            (227, false),
+            (0, false),
+        ]
+        .iter()
+        {
+            let target = if vcount < line {
+                vcount + LINES_PER_FRAME
+            } else {
+                vcount
+            };
+            system.run_for((target - line) as u64 * CYCLES_PER_LINE);
+            line = vcount;
+            assert_eq!(status(&system), (vcount, vblank), "line {}", vcount);
+        }
+    }
+
+    #[test]
+    fn test_dma_marks_video_dirty() {
+        let bios = assemble(&[
+            0xEAFFFFFE, // b .
+        ]);
+        let mut system = System::new(&bios, &[]);
+        {
+            let mut memory = system.memory.borrow_mut();
+            memory.take_vram_dirty();
+            memory.take_palettes_dirty();
+            memory.take_oam_dirty();
+
+            // Channel 3 fills 3 KiB of VRAM from 0x0600_0800 with a fixed word
+            memory.debug_write(0x0300_0000, AccessWidth::Bit32, 0x1234_5678);
+            memory.debug_write(0x0400_00D4, AccessWidth::Bit32, 0x0300_0000);
+            memory.debug_write(0x0400_00D8, AccessWidth::Bit32, 0x0600_0800);
+            memory.debug_write(0x0400_00DC, AccessWidth::Bit16, 0x300);
+            memory.debug_write(0x0400_00DE, AccessWidth::Bit16, 0x8500);
+        }
+
+        // The PPU takes the dirty parts when VBlank starts
+        system.run_for(CYCLES_PER_LINE * SCREEN_HEIGHT as u64 + 1);
+        let dirty = *system.ppu.borrow().video_dirty();
+        assert_eq!(dirty.vram.iter().collect::<Vec<_>>(), [2, 3, 4]);
+        assert!(!dirty.palettes);
+        assert!(dirty.oam.is_empty());
+        assert!(system.memory.borrow_mut().take_vram_dirty().is_empty());
+    }
+
+    #[test]
+    fn test_frame_sink() {
+        use frame_sink::FrameSink;
+
+        struct CaptureSink {
+            last_frame: Option<Vec<u16>>,
+            frames_presented: usize,
+        }
+
+        impl FrameSink for CaptureSink {
+            fn present(&mut self, framebuffer: &[u16]) {
+                self.last_frame = Some(framebuffer.to_vec());
+                self.frames_presented += 1;
+            }
+        }
+
+        let bios = assemble(&[
+            0xE3A00405, // mov r0, #0x0500'0000
+            0xE3A01B1F, // mov r1, #0x7C00
+            0xE5801000, // str r1, [r0]
+            0xEAFFFFFE, // b .
+        ]);
+        let sink = Rc::new(RefCell::new(CaptureSink {
+            last_frame: None,
+            frames_presented: 0,
+        }));
+        let mut system = System::new(&bios, &[]);
+        system.set_frame_sink(Box::new(sink.clone()));
+
+        system.run_frame();
+        system.run_frame();
+
+        let sink = sink.borrow();
+        assert_eq!(sink.frames_presented, 2);
+        let frame = sink.last_frame.as_ref().unwrap();
+        assert_eq!(frame.len(), 240 * 160);
+        // Nothing is enabled, so the whole screen shows the backdrop
+        assert!(frame.iter().all(|&pixel| pixel == 0x7C00));
+    }
+
+    #[test]
+    fn test_input_replay() {
+        use keypad::Button;
+        use std::env;
+        use std::fs;
+
+        let bios = assemble(&[
+            0xEAFFFFFE, // b .
+        ]);
+        let path = env::temp_dir().join("advance_test_input_replay.bin");
+        let sequence = [
+            vec![],
+            vec![Button::A],
+            vec![Button::A, Button::Right],
+            vec![Button::Start],
+        ];
+
+        let mut recorded = Vec::new();
+        let mut system = System::new(&bios, &[]);
+        system.record_inputs(&path).unwrap();
+        for pressed in sequence.iter() {
+            {
+                let mut keypad = system.keypad.borrow_mut();
+                keypad.set_keyinput(0x03FF);
+                for &button in pressed {
+                    keypad.set_pressed(button, true);
+                }
+                recorded.push(keypad.keyinput());
+            }
+            system.run_frame();
+        }
+        system.stop_input_replay().unwrap();
+
+        let mut system = System::new(&bios, &[]);
+        system.play_inputs(&path).unwrap();
+        for &keyinput in recorded.iter() {
+            system.run_frame();
+            assert_eq!(system.keypad.borrow().keyinput(), keyinput);
+        }
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(recorded, [0x03FF, 0x03FE, 0x03EE, 0x03F7]);
+    }
+
+    #[test]
+    fn test_save_file() {
+        use std::env;
+        use std::fs;
+
+        let bios = assemble(&[
+            0xE3A0040E, // mov r0, #0x0E00_0000
+            0xE3A01042, // mov r1, #0x42
+            0xE5C01010, // strb r1, [r0, #0x10]
+            0xEAFFFFFE, // b .
+        ]);
+        let path = env::temp_dir().join("advance_test_save_file_system.sav");
+        let _ = fs::remove_file(&path);
+
+        let mut system = System::new(&bios, &[]);
+        system.attach_save_file(&path).unwrap();
+        system.run_for(100);
+        system.flush_save_file().unwrap();
+
+        let mut system = System::new(&assemble(&[0xEAFFFFFE]), &[]);
+        system.attach_save_file(&path).unwrap();
+        let mut memory = system.memory.borrow_mut();
+        assert_eq!(memory.debug_read(0x0E00_0010, AccessWidth::Bit8), 0x42);
+        // Loading the save isn't a change that needs to be written back
+        assert!(!memory.take_cart_sram_written());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let bios = assemble(&[0xEA7FFFFE]); // b 0x02000000
+        let mut system = System::new(&bios, &[]);
+        {
+            let mut memory = system.memory.borrow_mut();
+            memory.debug_write(0x0200_0000, AccessWidth::Bit32, 0xEA3FFFFE); // b 0x03000000
+
+            // The first pass patches the code for the second one, which then stops. The branch at
+            // the end is already in the pipeline when it's patched, so it still runs once.
+            let program = asm::assemble(
+                "
+                mov r1, #0x03000000
+                ldr r2, [r1, #0x100]
+                ldr r4, [r1, #0x104]
+                again:
+                mov r0, #1
+                add r3, r3, r0
+                str r2, [r1, #0xC]
+                str r4, [r1, #0x20]
+                add r5, r5, #1
+                b again
+                ",
+            );
+            for (i, &word) in program.iter().enumerate() {
+                memory.debug_write(0x0300_0000 + i as u32 * 4, AccessWidth::Bit32, word);
+            }
+            memory.debug_write(0x0300_0100, AccessWidth::Bit32, 0xE3A00002); // mov r0, #2
+            memory.debug_write(0x0300_0104, AccessWidth::Bit32, 0xEAFFFFFE); // b .
+        }
+        system.run_for(500);
+
+        let cpu = system.cpu.borrow();
+        assert_eq!(cpu.regs()[5], 2);
+        assert_eq!(cpu.regs()[3], 1 + 2);
+    }
+
+    #[test]
+    fn test_execute_open_bus() {
+        let bios = assemble(&[
+            0xEA3FFFFE, // b $0100'0000
+            0xE3A04044, // mov r4, #0x44
+            0xE3A05055, // mov r5, #0x55
+        ]);
+        let mut system = System::new(&bios, &[]);
+        system.run_for(16);
+
+        // The last instruction prefetched before the jump keeps being executed from open bus
+        let cpu = system.cpu.borrow();
+        assert_eq!(cpu.regs()[4], 0);
+        assert_eq!(cpu.regs()[5], 0x55);
+        assert!(cpu.regs()[15] > 0x0100_0008);
+    }
+
+    #[test]
+    fn test_step_frame_while_paused() {
+        let bios = assemble(&[0xEAFFFFFE]); // b .
+        let mut system = System::new(&bios, &[]);
+        system.run_frame();
+        let start_time = system.current_cycle();
+        let frame_count = system.ppu.borrow().frame_count();
+
+        system.set_paused(true);
+        system.run_frame();
+        assert_eq!(system.current_cycle(), start_time);
+
+        system.step_frame();
+        assert!(system.is_paused());
+        let frame_cycles = system.ppu.borrow().timing().frame_cycles();
+        assert_eq!(system.current_cycle(), start_time + frame_cycles);
+        assert_eq!(system.ppu.borrow().frame_count(), frame_count + 1);
+
+        system.set_paused(false);
+        system.run_frame();
+        assert_eq!(system.current_cycle(), start_time + 2 * frame_cycles);
+    }
+
+    #[test]
+    fn test_memory_stats() {
+        let bios = assemble(&[
+            0xE3A01403, // mov r1, #0x03000000
+            0xE5810000, // str r0, [r1]
+            0xE5810004, // str r0, [r1, #4]
+            0xE5C10008, // strb r0, [r1, #8]
+            0xE5910000, // ldr r0, [r1]
+            0xEAFFFFFE, // b .
+        ]);
+        let mut system = System::new(&bios, &[]);
+        system.memory.borrow_mut().set_stats_enabled(true);
+        system.run_for(100);
+
+        let stats = system.memory.borrow().access_stats().unwrap();
+        assert_eq!(stats.writes(Region::Iwram, AccessWidth::Bit32), 2);
+        assert_eq!(stats.writes(Region::Iwram, AccessWidth::Bit8), 1);
+        assert_eq!(stats.reads(Region::Iwram, AccessWidth::Bit32), 1);
+        assert_eq!(stats.total(Region::Iwram), 4);
+        assert!(stats.fetches(Region::Bios, AccessWidth::Bit32) > 6);
+
+        system.memory.borrow_mut().set_stats_enabled(false);
+        assert_eq!(system.memory.borrow().access_stats(), None);
+    }
+
+    /// Runs 10k instructions of a loop in IWRAM per iteration, through the whole system, so
+    /// instructions/sec is 1e13 / (ns/iter).
+    #[bench]
+    fn bench_execute_10k_instructions(b: &mut Bencher) {
+        let bios = assemble(&[0xEA7FFFFE]); // b 0x02000000
+        let mut system = System::new(&bios, &[]);
+        {
+            let mut memory = system.memory.borrow_mut();
+            memory.debug_write(0x0200_0000, AccessWidth::Bit32, 0xEA3FFFFE); // b 0x03000000
+            let program = asm::assemble(
+                "
+                mov r1, #0x03000000
+                loop:
+                add r0, r0, #1
+                ldr r2, [r1, #0x100]
+                str r0, [r1, #0x104]
+                add r3, r3, r2, lsl #2
+                eor r4, r4, r3, ror r0
+                b loop
+                ",
+            );
+            for (i, &word) in program.iter().enumerate() {
+                memory.debug_write(0x0300_0000 + i as u32 * 4, AccessWidth::Bit32, word);
+            }
+        }
+
+        b.iter(|| {
+            for _ in 0..10_000 {
+                system.step_instruction();
+            }
+        });
+    }
+}
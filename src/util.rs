@@ -1,5 +1,88 @@
+/// Extracts a field from `$data`: a single bit with `[bit]`, `[base; len]` bits, or the bits from
+/// `[base:limit]` inclusive. The bounds of the last form have to be literals or constants.
 macro_rules! bit {
-    ($data:ident[$base:literal : $limit:literal]) => (bit!($data[$base; $limit - $base + 1]));
+    ($data:ident[$base:tt : $limit:tt]) => (bit!($data[$base; $limit - $base + 1]));
     ($data:ident[$bit:expr]) => (($data >> $bit) & 1);
     ($data:ident[$base:expr; $len:expr]) => (($data >> $base) & (1 << $len) - 1);
 }
+
+/// Replaces a field of `$data` with `$value`, which must be of the same type. Fields are selected
+/// like with `bit!`. Values that don't fit in the field are caught in debug builds.
+macro_rules! bits_set {
+    ($data:ident[$base:tt : $limit:tt] = $value:expr) => (
+        bits_set!($data[$base; $limit - $base + 1] = $value)
+    );
+    ($data:ident[$bit:expr] = $value:expr) => (bits_set!($data[$bit; 1] = $value));
+    ($data:ident[$base:expr; $len:expr] = $value:expr) => {{
+        let value = $value;
+        let mask = (1 << $len) - 1;
+        debug_assert!(
+            value & !mask == 0,
+            "0x{:X} doesn't fit in a {} bit field",
+            value,
+            $len
+        );
+        $data = $data & !(mask << $base) | value << $base;
+    }};
+}
+
+/// Sign-extends the lowest `$bits` bits of `$data` to an i32.
+macro_rules! sign_extend {
+    ($data:ident[$bits:expr]) => ((($data as i32) << (32 - $bits)) >> (32 - $bits));
+}
+
+#[cfg(test)]
+mod tests {
+    const FIELD_BASE: u32 = 4;
+    const FIELD_LIMIT: u32 = 7;
+
+    #[test]
+    fn test_bit() {
+        let data: u32 = 0x1234_5678;
+        assert_eq!(bit!(data[3]), 1);
+        assert_eq!(bit!(data[0]), 0);
+        assert_eq!(bit!(data[4:7]), 0x7);
+        assert_eq!(bit!(data[16:31]), 0x1234);
+        assert_eq!(bit!(data[4; 4]), 0x7);
+        // Constants select the same bits as literals
+        assert_eq!(bit!(data[FIELD_BASE:FIELD_LIMIT]), 0x7);
+        let base = 8;
+        assert_eq!(bit!(data[base + 4; 8]), 0x45);
+        assert_eq!(bit!(data[base]), 0);
+    }
+
+    #[test]
+    fn test_bits_set() {
+        let mut data: u16 = 0xFFFF;
+        bits_set!(data[4:7] = 0x5);
+        assert_eq!(data, 0xFF5F);
+        bits_set!(data[0] = 0);
+        assert_eq!(data, 0xFF5E);
+        bits_set!(data[FIELD_BASE:FIELD_LIMIT] = 0);
+        assert_eq!(data, 0xFF0E);
+        for i in 0..4 {
+            bits_set!(data[8 + i] = (i % 2) as u16);
+        }
+        assert_eq!(data, 0xFA0E);
+        bits_set!(data[12; 4] = 0x3);
+        assert_eq!(data, 0x3A0E);
+        assert_eq!(bit!(data[12:15]), 0x3);
+    }
+
+    #[test]
+    #[should_panic(expected = "0x10 doesn't fit in a 4 bit field")]
+    fn test_bits_set_overflow() {
+        let mut data: u32 = 0;
+        bits_set!(data[8:11] = 0x10);
+    }
+
+    #[test]
+    fn test_sign_extend() {
+        let data: u32 = 0x00FF_FFFE;
+        assert_eq!(sign_extend!(data[24]), -2);
+        assert_eq!(sign_extend!(data[25]), 0xFF_FFFE);
+        let byte: u16 = 0x80;
+        assert_eq!(sign_extend!(byte[8]), -128);
+        assert_eq!(sign_extend!(byte[16]), 0x80);
+    }
+}
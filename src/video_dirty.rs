@@ -0,0 +1,80 @@
+//! Tracking of the parts of VRAM, palette RAM and OAM that were written, so that anything derived
+//! from them, like decoded tiles, only needs to be redone for the parts that changed.
+
+/// Size of the VRAM blocks tracked separately.
+pub const VRAM_BLOCK_SIZE: u32 = 1024;
+pub const VRAM_BLOCKS: usize = 96;
+/// Size of each OAM entry, including the affine parameter interleaved with its attributes.
+pub const OAM_ENTRY_SIZE: u32 = 8;
+pub const OAM_ENTRIES: usize = 128;
+
+/// A set of dirty flags, for up to 128 blocks of memory.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DirtyBits {
+    bits: u128,
+}
+
+impl DirtyBits {
+    /// All of the first `count` blocks dirty.
+    pub fn all(count: usize) -> DirtyBits {
+        let mut dirty = DirtyBits::default();
+        dirty.mark_range(0, count);
+        dirty
+    }
+
+    pub fn mark(&mut self, index: usize) {
+        self.bits |= 1 << index;
+    }
+
+    /// Marks the blocks from `start` up to `end` (exclusive).
+    pub fn mark_range(&mut self, start: usize, end: usize) {
+        for index in start..end {
+            self.mark(index);
+        }
+    }
+
+    pub fn is_dirty(&self, index: usize) -> bool {
+        self.bits & 1 << index != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+
+    pub fn count(&self) -> usize {
+        self.bits.count_ones() as usize
+    }
+
+    /// Indices of the dirty blocks, in order.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = usize> + 'a {
+        (0..128).filter(move |&index| self.is_dirty(index))
+    }
+}
+
+/// What was written in each kind of video memory.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct VideoDirty {
+    /// One flag for each 1 KiB block of VRAM.
+    pub vram: DirtyBits,
+    pub palettes: bool,
+    /// One flag for each of the 128 OAM entries.
+    pub oam: DirtyBits,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dirty_bits() {
+        let mut dirty = DirtyBits::default();
+        assert!(dirty.is_empty());
+        dirty.mark(3);
+        dirty.mark_range(95, 97);
+        assert!(dirty.is_dirty(3) && !dirty.is_dirty(4));
+        assert_eq!(dirty.iter().collect::<Vec<_>>(), [3, 95, 96]);
+        assert_eq!(dirty.count(), 3);
+        assert_eq!(DirtyBits::all(96).count(), 96);
+        assert!(!DirtyBits::all(96).is_dirty(96));
+    }
+}
@@ -0,0 +1,169 @@
+//! Golden frame tests. Each case sets up the video registers and memory for a scene, renders a
+//! frame and compares it against a reference image in `tests/golden`. References are PPM files
+//! with 5-bit channels, so that they store the BGR555 output exactly while still opening in image
+//! viewers.
+//!
+//! To create or update the references after an intended change to the output, run the tests with
+//! `ADVANCE_UPDATE_GOLDEN=1` and review the new images before committing them.
+
+extern crate advance;
+
+use advance::memory::Memory;
+use advance::ppu::SCREEN_HEIGHT;
+use advance::ppu::SCREEN_WIDTH;
+use advance::system::AccessWidth;
+use advance::GbaSystem;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Number of mismatched pixels listed individually in failures.
+const MAX_LISTED_MISMATCHES: usize = 10;
+
+fn reference_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(format!("{}.ppm", name))
+}
+
+fn encode_ppm(pixels: &[u16]) -> Vec<u8> {
+    let mut data = format!("P6\n{} {}\n31\n", SCREEN_WIDTH, SCREEN_HEIGHT).into_bytes();
+    for &color in pixels {
+        data.extend(&[
+            (color & 0x1F) as u8,
+            (color >> 5 & 0x1F) as u8,
+            (color >> 10 & 0x1F) as u8,
+        ]);
+    }
+    data
+}
+
+fn decode_ppm(data: &[u8]) -> Result<Vec<u16>, String> {
+    let header = format!("P6\n{} {}\n31\n", SCREEN_WIDTH, SCREEN_HEIGHT).into_bytes();
+    if !data.starts_with(&header) {
+        return Err("unsupported header, expected a 240x160 PPM with 5-bit channels".to_string());
+    }
+    let rgb = &data[header.len()..];
+    if rgb.len() != SCREEN_WIDTH * SCREEN_HEIGHT * 3 {
+        return Err(format!(
+            "expected {} bytes of pixels, found {}",
+            SCREEN_WIDTH * SCREEN_HEIGHT * 3,
+            rgb.len()
+        ));
+    }
+    Ok(rgb
+        .chunks(3)
+        .map(|c| c[0] as u16 | (c[1] as u16) << 5 | (c[2] as u16) << 10)
+        .collect())
+}
+
+/// Renders a frame of the scene set up by `setup`, with a BIOS that does nothing.
+fn render_frame<F: FnOnce(&mut Memory)>(setup: F) -> Vec<u16> {
+    let bios = [0xFE, 0xFF, 0xFF, 0xEA]; // b .
+    let mut system = GbaSystem::new(&bios, &[]);
+    setup(&mut system.memory.borrow_mut());
+    let frame = system.run_frame().unwrap();
+    frame.pixels().to_vec()
+}
+
+/// Describes where `actual` differs from `expected`, or returns None if they're the same.
+fn compare_frames(expected: &[u16], actual: &[u16]) -> Option<String> {
+    let mismatches: Vec<(usize, usize)> = (0..expected.len())
+        .filter(|&i| expected[i] != actual[i])
+        .map(|i| (i % SCREEN_WIDTH, i / SCREEN_WIDTH))
+        .collect();
+    if mismatches.is_empty() {
+        return None;
+    }
+
+    let min_x = mismatches.iter().map(|&(x, _)| x).min().unwrap();
+    let max_x = mismatches.iter().map(|&(x, _)| x).max().unwrap();
+    let min_y = mismatches.iter().map(|&(_, y)| y).min().unwrap();
+    let max_y = mismatches.iter().map(|&(_, y)| y).max().unwrap();
+    let mut report = format!(
+        "{} pixels differ, within ({}, {})-({}, {})",
+        mismatches.len(),
+        min_x,
+        min_y,
+        max_x,
+        max_y
+    );
+    for &(x, y) in mismatches.iter().take(MAX_LISTED_MISMATCHES) {
+        let i = y * SCREEN_WIDTH + x;
+        report += &format!(
+            "\n  ({}, {}): expected {:04X}, got {:04X}",
+            x, y, expected[i], actual[i]
+        );
+    }
+    if mismatches.len() > MAX_LISTED_MISMATCHES {
+        report.push_str("\n  ...");
+    }
+    Some(report)
+}
+
+/// Renders the scene and compares it against the reference image `name`, or replaces the
+/// reference with it if updating them.
+fn check_golden_frame<F: FnOnce(&mut Memory)>(name: &str, setup: F) {
+    let actual = render_frame(setup);
+    let path = reference_path(name);
+
+    if env::var_os("ADVANCE_UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, encode_ppm(&actual)).unwrap();
+        return;
+    }
+
+    let data = fs::read(&path).unwrap_or_else(|e| {
+        panic!(
+            "{}: can't read {}: {}. Run with ADVANCE_UPDATE_GOLDEN=1 to create it.",
+            name,
+            path.display(),
+            e
+        )
+    });
+    let expected = decode_ppm(&data).unwrap_or_else(|e| panic!("{}: {}", name, e));
+    if let Some(report) = compare_frames(&expected, &actual) {
+        panic!(
+            "{}: frame doesn't match {}: {}",
+            name,
+            path.display(),
+            report
+        );
+    }
+}
+
+#[test]
+fn test_compare_frames() {
+    let expected = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+    assert_eq!(compare_frames(&expected, &expected), None);
+
+    let mut actual = expected.clone();
+    actual[SCREEN_WIDTH + 2] = 0x7FFF;
+    actual[3 * SCREEN_WIDTH + 5] = 0x001F;
+    assert_eq!(
+        compare_frames(&expected, &actual).unwrap(),
+        "2 pixels differ, within (2, 1)-(5, 3)\n  (2, 1): expected 0000, got 7FFF\n  \
+         (5, 3): expected 0000, got 001F"
+    );
+
+    assert_eq!(decode_ppm(&encode_ppm(&actual)).unwrap(), actual);
+}
+
+#[test]
+fn test_mode3() {
+    check_golden_frame("mode3", |memory| {
+        // Mode 3, BG2 enabled
+        memory.debug_write(0x0400_0000, AccessWidth::Bit16, 0x0403);
+        // Red increases to the right and green downwards, with a checkerboard in blue
+        for y in 0..SCREEN_HEIGHT as u32 {
+            for x in 0..SCREEN_WIDTH as u32 {
+                let red = x * 31 / (SCREEN_WIDTH as u32 - 1);
+                let green = y * 31 / (SCREEN_HEIGHT as u32 - 1);
+                let blue = if (x / 8 + y / 8) % 2 == 0 { 31 } else { 0 };
+                let address = 0x0600_0000 + (y * SCREEN_WIDTH as u32 + x) * 2;
+                memory.debug_write(address, AccessWidth::Bit16, blue << 10 | green << 5 | red);
+            }
+        }
+    });
+}
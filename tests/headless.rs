@@ -0,0 +1,67 @@
+extern crate advance;
+extern crate byteorder;
+
+use advance::apu;
+use advance::ppu::CYCLES_PER_LINE;
+use advance::ppu::LINES_PER_FRAME;
+use advance::system::AccessWidth;
+use advance::GbaSystem;
+use byteorder::ByteOrder;
+use byteorder::LE;
+
+const CYCLES_PER_FRAME: u64 = CYCLES_PER_LINE * LINES_PER_FRAME as u64;
+
+fn idle_system() -> GbaSystem {
+    let mut bios = vec![0; 4];
+    LE::write_u32(&mut bios, 0xEAFFFFFE); // b .
+    GbaSystem::new(&bios, &[])
+}
+
+#[test]
+fn test_run_frame_without_frontend() {
+    let mut system = idle_system();
+    system.run_frame();
+
+    let ppu = system.ppu.borrow();
+    assert_eq!(ppu.frame_count(), 1);
+    assert_eq!(ppu.framebuffer().len(), 160);
+}
+
+#[test]
+fn test_frame_output() {
+    let mut system = idle_system();
+    let samples_per_frame = (apu::SAMPLE_RATE as u64 * CYCLES_PER_FRAME / (1 << 24)) as isize;
+    for frame_count in 1..4 {
+        let frame = system.run_frame().unwrap();
+        assert_eq!(frame.frame_count(), frame_count);
+        assert_eq!(frame.pixels().len(), 240 * 160);
+        assert!(!frame.lcd_off());
+        assert!(
+            (frame.sample_count() as isize - samples_per_frame).abs() <= 1,
+            "{} samples in frame {}, expected about {}",
+            frame.sample_count(),
+            frame_count,
+            samples_per_frame
+        );
+        assert_eq!(frame.samples().len(), frame.sample_count());
+    }
+
+    // The frame is only reported as blank if forced blank was on for all of it
+    system
+        .memory
+        .borrow_mut()
+        .debug_write(0x0400_0000, AccessWidth::Bit16, 0x0080);
+    assert!(system.run_frame().unwrap().lcd_off());
+    system.run_for(CYCLES_PER_FRAME / 2);
+    system
+        .memory
+        .borrow_mut()
+        .debug_write(0x0400_0000, AccessWidth::Bit16, 0x0000);
+    assert!(!system.run_frame().unwrap().lcd_off());
+
+    // Nothing runs while paused
+    system.set_paused(true);
+    assert!(system.run_frame().is_none());
+    system.set_paused(false);
+    assert_eq!(system.run_frame().unwrap().frame_count(), 6);
+}
@@ -0,0 +1,209 @@
+//! Instruction timing tests. Each case runs a short program from a memory region and checks how
+//! many cycles its timed instructions take, against the counts documented for the ARM7TDMI
+//! combined with the GBA's memory timings. Instructions implemented from now on should get rows
+//! here too.
+//!
+//! Documented timings still pending: MUL 1S+mI.
+
+extern crate advance;
+
+use advance::system::AccessWidth;
+use advance::GbaSystem;
+
+#[derive(Copy, Clone, Debug)]
+enum Region {
+    Iwram,
+    Ewram,
+    Rom,
+}
+
+impl Region {
+    fn base_address(self) -> u32 {
+        match self {
+            Region::Iwram => 0x0300_0000,
+            Region::Ewram => 0x0200_0000,
+            Region::Rom => 0x0800_0000,
+        }
+    }
+}
+
+struct TimingCase {
+    name: &'static str,
+    region: Region,
+    /// Instructions run before the timed ones, to set up registers and fill the pipeline.
+    setup: &'static [u32],
+    timed: &'static [u32],
+    cycles: u64,
+    /// Set while the emulator doesn't match the documented timing yet, with the reason. These
+    /// cases fail once the timing is fixed, so that they're not forgotten.
+    known_issue: Option<&'static str>,
+}
+
+// Access timings for 32-bit code fetches, with the default wait states.
+const IWRAM: u64 = 1;
+const EWRAM: u64 = 6;
+const ROM_N: u64 = 8;
+const ROM_S: u64 = 6;
+// The same for 16-bit Thumb code fetches.
+const EWRAM_THUMB: u64 = 3;
+const ROM_N_THUMB: u64 = 5;
+const ROM_S_THUMB: u64 = 3;
+
+const SETUP: &[u32] = &[
+    0xE3A01403, // mov r1, #0x03000000
+    0xE3A02001, // mov r2, #1
+];
+const DATA_OP: &[u32] = &[0xE3A00001]; // mov r0, #1
+const DATA_OP_REG_SHIFT: &[u32] = &[0xE1A00211]; // mov r0, r1, lsl r2
+const LDR_IWRAM: &[u32] = &[0xE5910100]; // ldr r0, [r1, #0x100]
+const STR_IWRAM: &[u32] = &[0xE5810100]; // str r0, [r1, #0x100]
+const BRANCH: &[u32] = &[0xEAFFFFFF]; // b next
+const SETUP_LDR_PC: &[u32] = &[
+    0xE3A01403, // mov r1, #0x03000000
+    0xE28F0004, // add r0, pc, #4 (the address after the ldr)
+    0xE5810100, // str r0, [r1, #0x100]
+];
+const LDR_PC_IWRAM: &[u32] = &[0xE591F100]; // ldr pc, [r1, #0x100]
+const SETUP_LDM_STM: &[u32] = &[
+    0xE3A01403, // mov r1, #0x03000000
+    0xE2811C01, // add r1, r1, #0x100
+];
+const LDM_IWRAM: &[u32] = &[0xE891000C]; // ldmia r1, {r2, r3}
+const STM_IWRAM: &[u32] = &[0xE881000C]; // stmia r1, {r2, r3}
+const SETUP_THUMB: &[u32] = &[
+    0xE3A0D403, // mov sp, #0x03000000
+    0xE28DDC01, // add sp, sp, #0x100
+    0xE28F0001, // add r0, pc, #1 (the Thumb code after the bx)
+    0xE12FFF10, // bx r0
+];
+// Each word holds the timed Thumb instruction, followed by a `b .` that isn't timed.
+const PUSH_IWRAM: &[u32] = &[0xE7FE_B403]; // push {r0, r1}
+const POP_IWRAM: &[u32] = &[0xE7FE_BC0C]; // pop {r2, r3}
+
+const ROM_WAIT_STATES: Option<&str> = Some("ROM wait states aren't emulated");
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const TIMING_CASES: &[TimingCase] = &[
+    // Data processing: 1S
+    TimingCase { name: "data op", region: Region::Iwram, setup: SETUP, timed: DATA_OP, cycles: IWRAM, known_issue: None },
+    TimingCase { name: "data op", region: Region::Ewram, setup: SETUP, timed: DATA_OP, cycles: EWRAM, known_issue: None },
+    TimingCase { name: "data op", region: Region::Rom, setup: SETUP, timed: DATA_OP, cycles: ROM_S, known_issue: ROM_WAIT_STATES },
+    // Data processing with register shift: 1S+1I
+    TimingCase { name: "reg shift", region: Region::Iwram, setup: SETUP, timed: DATA_OP_REG_SHIFT, cycles: IWRAM + 1, known_issue: None },
+    TimingCase { name: "reg shift", region: Region::Ewram, setup: SETUP, timed: DATA_OP_REG_SHIFT, cycles: EWRAM + 1, known_issue: None },
+    TimingCase { name: "reg shift", region: Region::Rom, setup: SETUP, timed: DATA_OP_REG_SHIFT, cycles: ROM_S + 1, known_issue: ROM_WAIT_STATES },
+    // LDR: 1S+1N+1I, loading from IWRAM
+    TimingCase { name: "ldr", region: Region::Iwram, setup: SETUP, timed: LDR_IWRAM, cycles: IWRAM + 1 + 1, known_issue: None },
+    TimingCase { name: "ldr", region: Region::Ewram, setup: SETUP, timed: LDR_IWRAM, cycles: EWRAM + 1 + 1, known_issue: None },
+    TimingCase { name: "ldr", region: Region::Rom, setup: SETUP, timed: LDR_IWRAM, cycles: ROM_S + 1 + 1, known_issue: ROM_WAIT_STATES },
+    // STR: 2N, storing to IWRAM
+    TimingCase { name: "str", region: Region::Iwram, setup: SETUP, timed: STR_IWRAM, cycles: IWRAM + 1, known_issue: None },
+    TimingCase { name: "str", region: Region::Ewram, setup: SETUP, timed: STR_IWRAM, cycles: EWRAM + 1, known_issue: None },
+    TimingCase { name: "str", region: Region::Rom, setup: SETUP, timed: STR_IWRAM, cycles: ROM_N + 1, known_issue: ROM_WAIT_STATES },
+    // B: 2S+1N
+    TimingCase { name: "b", region: Region::Iwram, setup: SETUP, timed: BRANCH, cycles: 3 * IWRAM, known_issue: None },
+    TimingCase { name: "b", region: Region::Ewram, setup: SETUP, timed: BRANCH, cycles: 3 * EWRAM, known_issue: None },
+    TimingCase { name: "b", region: Region::Rom, setup: SETUP, timed: BRANCH, cycles: 2 * ROM_S + ROM_N, known_issue: ROM_WAIT_STATES },
+    // LDR PC: 2S+2N+1I, loading from IWRAM
+    TimingCase { name: "ldr pc", region: Region::Iwram, setup: SETUP_LDR_PC, timed: LDR_PC_IWRAM, cycles: 3 * IWRAM + 1 + 1, known_issue: None },
+    TimingCase { name: "ldr pc", region: Region::Ewram, setup: SETUP_LDR_PC, timed: LDR_PC_IWRAM, cycles: 3 * EWRAM + 1 + 1, known_issue: None },
+    TimingCase { name: "ldr pc", region: Region::Rom, setup: SETUP_LDR_PC, timed: LDR_PC_IWRAM, cycles: 2 * ROM_S + ROM_N + 1 + 1, known_issue: ROM_WAIT_STATES },
+    // LDM: nS+1N+1I, loading 2 registers from IWRAM
+    TimingCase { name: "ldm", region: Region::Iwram, setup: SETUP_LDM_STM, timed: LDM_IWRAM, cycles: IWRAM + 2 + 1, known_issue: None },
+    TimingCase { name: "ldm", region: Region::Ewram, setup: SETUP_LDM_STM, timed: LDM_IWRAM, cycles: EWRAM + 2 + 1, known_issue: None },
+    TimingCase { name: "ldm", region: Region::Rom, setup: SETUP_LDM_STM, timed: LDM_IWRAM, cycles: ROM_S + 2 + 1, known_issue: ROM_WAIT_STATES },
+    // STM: (n-1)S+2N, storing 2 registers to IWRAM
+    TimingCase { name: "stm", region: Region::Iwram, setup: SETUP_LDM_STM, timed: STM_IWRAM, cycles: IWRAM + 2, known_issue: None },
+    TimingCase { name: "stm", region: Region::Ewram, setup: SETUP_LDM_STM, timed: STM_IWRAM, cycles: EWRAM + 2, known_issue: None },
+    TimingCase { name: "stm", region: Region::Rom, setup: SETUP_LDM_STM, timed: STM_IWRAM, cycles: ROM_N + 2, known_issue: ROM_WAIT_STATES },
+    // Thumb PUSH: (n-1)S+2N, storing 2 registers to IWRAM
+    TimingCase { name: "push", region: Region::Iwram, setup: SETUP_THUMB, timed: PUSH_IWRAM, cycles: IWRAM + 2, known_issue: None },
+    TimingCase { name: "push", region: Region::Ewram, setup: SETUP_THUMB, timed: PUSH_IWRAM, cycles: EWRAM_THUMB + 2, known_issue: None },
+    TimingCase { name: "push", region: Region::Rom, setup: SETUP_THUMB, timed: PUSH_IWRAM, cycles: ROM_N_THUMB + 2, known_issue: ROM_WAIT_STATES },
+    // Thumb POP: nS+1N+1I, loading 2 registers from IWRAM
+    TimingCase { name: "pop", region: Region::Iwram, setup: SETUP_THUMB, timed: POP_IWRAM, cycles: IWRAM + 2 + 1, known_issue: None },
+    TimingCase { name: "pop", region: Region::Ewram, setup: SETUP_THUMB, timed: POP_IWRAM, cycles: EWRAM_THUMB + 2 + 1, known_issue: None },
+    TimingCase { name: "pop", region: Region::Rom, setup: SETUP_THUMB, timed: POP_IWRAM, cycles: ROM_S_THUMB + 2 + 1, known_issue: ROM_WAIT_STATES },
+];
+
+const B_SELF: u32 = 0xEAFFFFFE; // b .
+const BIOS_TO_EWRAM: u32 = 0xEA7FFFFE; // b 0x02000000, from 0x00000000
+const EWRAM_TO_IWRAM: u32 = 0xEA3FFFFE; // b 0x03000000, from 0x02000000
+
+fn to_bytes(words: &[u32]) -> Vec<u8> {
+    words
+        .iter()
+        .flat_map(|word| (0..4).map(move |i| (word >> (i * 8)) as u8))
+        .collect()
+}
+
+/// Creates a system that starts running `program` from the start of `region`. RAM is reached by
+/// branching from the BIOS, while ROM is started directly by booting without a BIOS.
+fn load_program(region: Region, program: &[u32]) -> GbaSystem {
+    let system = match region {
+        Region::Rom => return GbaSystem::new(&[], &to_bytes(program)),
+        Region::Ewram | Region::Iwram => GbaSystem::new(&to_bytes(&[BIOS_TO_EWRAM]), &[]),
+    };
+    {
+        let mut memory = system.memory.borrow_mut();
+        if let Region::Iwram = region {
+            memory.debug_write(0x0200_0000, AccessWidth::Bit32, EWRAM_TO_IWRAM);
+        }
+        for (i, &word) in program.iter().enumerate() {
+            let address = region.base_address() + i as u32 * 4;
+            memory.debug_write(address, AccessWidth::Bit32, word);
+        }
+    }
+    system
+}
+
+/// Runs the case's program and returns how many cycles the timed instructions took.
+fn measure(case: &TimingCase) -> u64 {
+    let program: Vec<u32> = case
+        .setup
+        .iter()
+        .chain(case.timed)
+        .cloned()
+        .chain(Some(B_SELF))
+        .collect();
+    let mut system = load_program(case.region, &program);
+
+    // Run up to the first instruction of the program. PC is 8 bytes ahead while executing.
+    let start_pc = case.region.base_address() + 8;
+    for _ in 0..10 {
+        if system.cpu.borrow().regs()[15] == start_pc {
+            break;
+        }
+        system.step_instruction();
+    }
+    assert_eq!(system.cpu.borrow().regs()[15], start_pc, "{}", case.name);
+
+    for _ in case.setup {
+        system.step_instruction();
+    }
+    let start_time = system.current_cycle();
+    for _ in case.timed {
+        system.step_instruction();
+    }
+    system.current_cycle() - start_time
+}
+
+#[test]
+fn test_instruction_timings() {
+    let mut failures = Vec::new();
+    for case in TIMING_CASES {
+        let cycles = measure(case);
+        match case.known_issue {
+            None if cycles != case.cycles => failures.push(format!(
+                "{} in {:?}: expected {} cycles, took {}",
+                case.name, case.region, case.cycles, cycles
+            )),
+            Some(issue) if cycles == case.cycles => failures.push(format!(
+                "{} in {:?}: now takes the expected {} cycles, remove the known issue: {}",
+                case.name, case.region, cycles, issue
+            )),
+            _ => {}
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}